@@ -0,0 +1,33 @@
+// Fuzzes the slicing in `Prompts::make_markdown_message` and
+// `Prompts::decouple_prompt_from_message` with arbitrary templates, prompts and outputs,
+// including outputs that are shorter than the template or don't start with it.
+// Run with `cargo fuzz run markdown_message` from the repository root.
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// The bot is a binary crate, so the module is included directly rather than depended on
+#[path = "../../src/prompts.rs"]
+#[allow(dead_code)]
+mod prompts;
+
+use prompts::Prompts;
+
+fuzz_target!(|input: (String, String, String, bool)| {
+    let (template, user, output, show_prompt_template) = input;
+    let (prefix, suffix) = template.split_once("{{PROMPT}}").unwrap_or_default();
+    let prompts = Prompts {
+        show_prompt_template,
+        processed: template.replace("{{PROMPT}}", &user),
+        user,
+        prefix: prefix.to_string(),
+        suffix: suffix.to_string(),
+    };
+
+    // Neither function may panic, and everything they return must still be valid UTF-8
+    // (a slice at a non-character boundary would have panicked above)
+    let decoupled = prompts.decouple_prompt_from_message(&output);
+    assert!(std::str::from_utf8(decoupled.as_bytes()).is_ok());
+    let markdown = prompts.make_markdown_message(&output);
+    assert!(std::str::from_utf8(markdown.as_bytes()).is_ok());
+});
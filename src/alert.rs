@@ -0,0 +1,239 @@
+// This file holds the operator error notifications.
+// When `error_webhook_url` is set, internal errors (not user mistakes) are posted to that
+// Discord webhook so that the operator hears about failures before users complain.
+// Posting happens on a spawned task, so a slow or dead webhook never holds up a request,
+// and each class of error is posted at most once every few minutes to avoid webhook storms.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serenity::http::Http;
+
+use crate::generation;
+
+// The shortest time between two posts for the same class of error
+const MIN_POST_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+// The longest the error text can be, leaving room for the rest of the post
+// within Discord's 2000 character limit on messages
+const MAX_ERROR_TEXT_LENGTH: usize = 1500;
+
+// The Discord metadata surrounding an error, included in the post
+pub struct AlertContext<'a> {
+    // The name of the command that failed
+    pub command_name: &'a str,
+    // The guild (server) the command was used in, if it wasn't a DM
+    pub guild_id: Option<u64>,
+}
+
+// A handle for reporting errors to the operator. Cheap to clone; every clone shares
+// the same rate limiter. If no webhook is configured, reporting only logs the error
+#[derive(Clone)]
+pub struct Alerter {
+    // The webhook to post to, along with the client used to post
+    webhook: Option<(Arc<Http>, String)>,
+    // Tracks when each class of error was last posted
+    limiter: Arc<Mutex<RateLimiter>>,
+}
+
+impl Alerter {
+    // function to create an alerter that posts to the given webhook, if any
+    pub fn new(webhook_url: Option<String>) -> Self {
+        Self {
+            // Executing a webhook only needs the token in its URL, not a bot token
+            webhook: webhook_url.map(|url| (Arc::new(Http::new("")), url)),
+            limiter: Arc::new(Mutex::new(RateLimiter::new(
+                MIN_POST_INTERVAL,
+                Box::new(Instant::now),
+            ))),
+        }
+    }
+
+    // function to report an internal error. This returns immediately;
+    // the webhook is posted to in the background
+    pub fn report(&self, err: &anyhow::Error, context: AlertContext) {
+        // The correlation id ties the log line to the webhook post
+        let correlation_id = format!("{:016x}", rand::random::<u64>());
+        let class = classify(err);
+        error!(
+            "[{correlation_id}] {class} error in /{} (guild {:?}): {err:?}",
+            context.command_name, context.guild_id
+        );
+
+        let Some((http, url)) = self.webhook.clone() else {
+            return;
+        };
+        let Some(suppressed) = self.limiter.lock().unwrap().check(class) else {
+            return; // This class of error was posted recently
+        };
+
+        let mut error_text = format!("{err:#}");
+        if error_text.len() > MAX_ERROR_TEXT_LENGTH {
+            let mut end = MAX_ERROR_TEXT_LENGTH;
+            while !error_text.is_char_boundary(end) {
+                end -= 1;
+            }
+            error_text.truncate(end);
+            error_text += "…";
+        }
+
+        let mut content = format!(
+            "**{class} error** in `/{}` (guild {})\nCorrelation id: `{correlation_id}`\n```\n{error_text}\n```\n{}",
+            context.command_name,
+            context
+                .guild_id
+                .map_or_else(|| "none".to_string(), |id| id.to_string()),
+            host_stats()
+        );
+        if suppressed > 0 {
+            content += &format!("\n({suppressed} similar errors were not posted)");
+        }
+
+        tokio::spawn(async move {
+            let result = async {
+                let webhook = http.get_webhook_from_url(&url).await?;
+                webhook
+                    .execute(&http, false, |w| w.content(content))
+                    .await?;
+                anyhow::Ok(())
+            }
+            .await;
+
+            if let Err(post_err) = result {
+                error!("[{correlation_id}] Failed to post to the error webhook: {post_err:?}");
+            }
+        });
+    }
+}
+
+// function to sort an error into a class, which is what posts are rate-limited by
+fn classify(err: &anyhow::Error) -> &'static str {
+    if let Some(generation::InferenceError::OomError) = err.downcast_ref() {
+        "Out of memory"
+    } else if err.downcast_ref::<generation::InferenceError>().is_some() {
+        "Inference"
+    } else if err.downcast_ref::<serenity::Error>().is_some() {
+        "Discord"
+    } else {
+        "Internal"
+    }
+}
+
+// function to gather some basic stats about the host, to help tell OOMs and the like apart.
+// These are read from /proc, so they're only available on Linux
+fn host_stats() -> String {
+    let read = |path: &str| std::fs::read_to_string(path).ok();
+
+    let hostname = read("/proc/sys/kernel/hostname")
+        .map(|h| h.trim().to_string())
+        .unwrap_or_else(|| "unknown".into());
+    let load = read("/proc/loadavg")
+        .and_then(|l| {
+            let averages: Vec<_> = l.split_whitespace().take(3).collect();
+            (averages.len() == 3).then(|| averages.join(" "))
+        })
+        .unwrap_or_else(|| "unknown".into());
+    let available_memory = read("/proc/meminfo")
+        .and_then(|m| {
+            m.lines()
+                .find_map(|l| l.strip_prefix("MemAvailable:"))
+                .map(|v| v.trim().to_string())
+        })
+        .unwrap_or_else(|| "unknown".into());
+
+    format!("Host: `{hostname}`, load: `{load}`, available memory: `{available_memory}`")
+}
+
+// The source of the current time. The alerter reads the real clock; tests move a fake one
+type Clock = Box<dyn Fn() -> Instant + Send>;
+
+// This struct limits posts to one per class of error per interval
+struct RateLimiter {
+    // The shortest time between two posts for the same class
+    interval: Duration,
+    // Where the current time comes from
+    clock: Clock,
+    // When each class was last posted, and how many errors of it were held back since
+    classes: HashMap<&'static str, (Instant, usize)>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration, clock: Clock) -> Self {
+        Self {
+            interval,
+            clock,
+            classes: HashMap::new(),
+        }
+    }
+
+    // Checks whether an error of the given class may be posted now. If it may, this
+    // returns the number of errors of that class that were held back since the last post
+    fn check(&mut self, class: &'static str) -> Option<usize> {
+        let now = (self.clock)();
+        match self.classes.get_mut(class) {
+            Some((last_posted, suppressed)) if now.duration_since(*last_posted) < self.interval => {
+                *suppressed += 1;
+                None
+            }
+            Some((last_posted, suppressed)) => {
+                *last_posted = now;
+                Some(std::mem::take(suppressed))
+            }
+            None => {
+                self.classes.insert(class, (now, 0));
+                Some(0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INTERVAL: Duration = MIN_POST_INTERVAL;
+
+    // function to make a rate limiter on a fake clock, along with the time it reads
+    fn limiter() -> (RateLimiter, Arc<Mutex<Instant>>) {
+        let now = Arc::new(Mutex::new(Instant::now()));
+        let clock = now.clone();
+        let limiter = RateLimiter::new(INTERVAL, Box::new(move || *clock.lock().unwrap()));
+        (limiter, now)
+    }
+
+    // function to move a fake clock forward
+    fn advance(now: &Mutex<Instant>, by: Duration) {
+        *now.lock().unwrap() += by;
+    }
+
+    #[test]
+    fn each_class_is_posted_once_per_interval() {
+        let (mut limiter, now) = limiter();
+        assert_eq!(limiter.check("Inference"), Some(0));
+        assert_eq!(limiter.check("Inference"), None);
+        // Another class has a window of its own
+        assert_eq!(limiter.check("Discord"), Some(0));
+
+        advance(&now, INTERVAL - Duration::from_secs(1));
+        assert_eq!(limiter.check("Inference"), None);
+        assert_eq!(limiter.check("Discord"), None);
+    }
+
+    #[test]
+    fn the_window_resets_once_the_interval_has_passed() {
+        let (mut limiter, now) = limiter();
+        assert_eq!(limiter.check("Inference"), Some(0));
+        assert_eq!(limiter.check("Inference"), None);
+        assert_eq!(limiter.check("Inference"), None);
+
+        // The next post counts the errors that were held back, then a new window starts
+        advance(&now, INTERVAL);
+        assert_eq!(limiter.check("Inference"), Some(2));
+        assert_eq!(limiter.check("Inference"), None);
+
+        advance(&now, INTERVAL * 2);
+        assert_eq!(limiter.check("Inference"), Some(1));
+    }
+}
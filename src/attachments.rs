@@ -0,0 +1,118 @@
+// This file holds `{{ATTACHMENTS}}`, the text of the `.txt` and `.md` files attached to the
+// message a command is used on (from its Apps menu), for commands like "summarize this
+// document". The files are downloaded together when the command is used, within two seconds
+// so that the interaction can still be answered in time, and put together, each under its
+// name, up to `max_attachment_bytes` under `[inference]`; anything past that is cut off.
+// Other attachments are left out.
+use std::{path::Path, time::Duration};
+
+use serenity::model::channel::{Attachment, Message};
+use tokio::task::JoinSet;
+
+use crate::util;
+
+// The kinds of file whose text is included, by extension
+const TEXT_EXTENSIONS: [&str; 2] = ["txt", "md"];
+
+// How long the files can take to download, all together. The interaction has to be answered
+// within three seconds, and nothing is posted until the prompt is ready
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_millis(2000);
+
+// function to get the text of a message's text attachments, each under its file name, in at
+// most `limit` bytes. The files are downloaded at the same time
+pub async fn text(message: &Message, limit: usize) -> anyhow::Result<String> {
+    let client = reqwest::Client::new();
+    let files: Vec<_> = message.attachments.iter().filter(|a| is_text(a)).collect();
+    // Downloads still going when this returns are dropped with the set
+    let mut downloads = JoinSet::new();
+    for (index, attachment) in files.iter().enumerate() {
+        let (client, url) = (client.clone(), attachment.url.clone());
+        // No one file can take more than the whole limit
+        downloads.spawn(async move { (index, download(&client, &url, limit).await) });
+    }
+
+    let mut contents: Vec<_> = files.iter().map(|_| None).collect();
+    let all = async {
+        while let Some(finished) = downloads.join_next().await {
+            let (index, content) = finished.expect("downloads don't panic");
+            contents[index] = Some(content);
+        }
+    };
+    if tokio::time::timeout(DOWNLOAD_TIMEOUT, all).await.is_err() {
+        return Err(util::user_error(
+            "The attached files took too long to download.",
+        ));
+    }
+
+    let mut text = String::new();
+    for (attachment, content) in files.iter().zip(contents) {
+        let content = content.expect("every download finished").map_err(|e| {
+            util::user_error(format!("Couldn't download {}: {e}", attachment.filename))
+        })?;
+        let separator = if text.is_empty() { "" } else { "\n\n" };
+        let header = format!("{}:\n", attachment.filename);
+        let room = limit.saturating_sub(text.len() + separator.len() + header.len());
+        if room == 0 {
+            info!(
+                "Leaving out {} and any later attachments, which don't fit in max_attachment_bytes",
+                attachment.filename
+            );
+            break;
+        }
+        text += separator;
+        text += &header;
+        text += &fit(content.as_bytes(), room);
+    }
+    Ok(text)
+}
+
+// Whether or not an attachment is a file whose text is included
+fn is_text(attachment: &Attachment) -> bool {
+    Path::new(&attachment.filename)
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .is_some_and(|extension| {
+            TEXT_EXTENSIONS
+                .iter()
+                .any(|e| extension.eq_ignore_ascii_case(e))
+        })
+}
+
+// function to download the start of a file as text, stopping once there's `limit` bytes of it
+async fn download(client: &reqwest::Client, url: &str, limit: usize) -> reqwest::Result<String> {
+    let mut response = client.get(url).send().await?.error_for_status()?;
+    let mut bytes = vec![];
+    while let Some(chunk) = response.chunk().await? {
+        bytes.extend_from_slice(&chunk);
+        if bytes.len() >= limit {
+            break;
+        }
+    }
+    Ok(fit(&bytes, limit))
+}
+
+// function to turn bytes into text of at most `limit` bytes, without leaving half a
+// character at the end where it's cut off
+fn fit(bytes: &[u8], limit: usize) -> String {
+    let mut text = String::from_utf8_lossy(&bytes[..bytes.len().min(limit)]).into_owned();
+    while text.len() > limit {
+        text.pop();
+    }
+    // A character cut in half comes out as a replacement character
+    if bytes.len() > limit && text.ends_with(char::REPLACEMENT_CHARACTER) {
+        text.pop();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cuts_text_off_between_characters() {
+        assert_eq!(fit(b"short", 100), "short");
+        assert_eq!(fit("naïve".as_bytes(), 3), "na");
+        assert_eq!(fit("naïve".as_bytes(), 4), "naï");
+    }
+}
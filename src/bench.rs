@@ -0,0 +1,234 @@
+// This file holds the owner-only `/bench` command.
+// It runs a fixed benchmark (the same prompt, seed and token count every time) through the
+// normal generation queue, so that quantizations and thread counts can be compared with numbers
+// rather than by feel. Each run is queued like any other request, so benchmarking never jumps
+// ahead of users, and the benchmark can be cancelled between or during runs.
+use serde_json::json;
+use serenity::{
+    http::Http,
+    model::prelude::interaction::{
+        application_command::ApplicationCommandInteraction, InteractionResponseType,
+    },
+};
+
+use crate::{
+    config::Configuration,
+    generation::{self, InferenceError, Token},
+    handler, util,
+};
+
+// The measurements from one run of the benchmark
+struct RunResult {
+    prompt_tokens: usize,
+    prompt_tokens_per_second: f64,
+    generated_tokens: usize,
+    generation_tokens_per_second: f64,
+}
+
+// function to handle `/bench`
+pub async fn bench(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    request_tx: &flume::Sender<generation::Request>,
+    config: &Configuration,
+    shard_id: u64,
+) -> anyhow::Result<()> {
+    if !is_owner(http, cmd).await? {
+        return Err(util::user_error("Only the bot's owner can use /bench."));
+    }
+
+    let settings = &config.bench;
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|m| {
+                m.content(format!("Running the benchmark ({} runs)...", settings.runs))
+            })
+    })
+    .await?;
+
+    // The cancel button sends the response's ID to the generation thread,
+    // which cancels whichever run is using it
+    let mut message = cmd.get_interaction_response(http).await?;
+    let message_id = message.id;
+    handler::add_cancel_button(http, message_id, &mut message, cmd.user.id).await?;
+
+    let peak_rss_before = peak_rss_kb();
+    let mut runs = vec![];
+    for run in 0..settings.runs {
+        let (token_tx, token_rx) = flume::unbounded();
+        let (completion_tx, completion_rx) = flume::bounded(1);
+        request_tx.send(generation::Request {
+            prompt: settings.prompt.clone(),
+            batch_size: config.inference.batch_size,
+            batch_decode: false,
+            token_buffer_size: 1,
+            token_tx,
+            message_id,
+            seed: Some(settings.seed),
+            maximum_token_count: Some(settings.generated_tokens),
+            n_sequences: 1,
+            sampling: Default::default(),
+            low_priority: false,
+            echo_prompt: false,
+            context: generation::RequestContext {
+                guild_id: cmd.guild_id.map(|id| id.0),
+                channel_id: cmd.channel_id.0,
+                user_id: cmd.user.id.0,
+                command_name: cmd.data.name.clone(),
+                shard_id: Some(shard_id),
+            },
+            progress_tx: None,
+            completion_tx: Some(completion_tx),
+            trace: None,
+            queued_at: std::time::Instant::now(),
+        })?;
+
+        // The output itself doesn't matter; wait for the run to finish
+        while let Ok(token) = token_rx.recv_async().await {
+            match token {
+                Token::Token(_) | Token::Metadata(_) | Token::SequenceStart(_) => {}
+                Token::Error(InferenceError::Cancelled) => {
+                    message
+                        .edit(http, |m| {
+                            m.content(format!(
+                                "The benchmark was cancelled during run {}.",
+                                run + 1
+                            ))
+                            .set_components(Default::default())
+                        })
+                        .await?;
+                    return Ok(());
+                }
+                Token::Error(err) => return Err(err.into()),
+            }
+        }
+
+        let stats = completion_rx.try_recv()?.stats;
+        runs.push(RunResult {
+            prompt_tokens: stats.prompt_tokens,
+            prompt_tokens_per_second: per_second(stats.prompt_tokens, stats.feed_prompt_duration),
+            generated_tokens: stats.predict_tokens,
+            generation_tokens_per_second: per_second(stats.predict_tokens, stats.predict_duration),
+        });
+    }
+    let peak_rss_delta_kb = peak_rss_kb()
+        .zip(peak_rss_before)
+        .map(|(after, before)| after.saturating_sub(before));
+
+    let (prompt_mean, prompt_stddev) =
+        mean_and_stddev(runs.iter().map(|r| r.prompt_tokens_per_second));
+    let (generation_mean, generation_stddev) =
+        mean_and_stddev(runs.iter().map(|r| r.generation_tokens_per_second));
+    let prompt_tokens = runs.first().map_or(0, |r| r.prompt_tokens);
+    let generated_tokens = runs.first().map_or(0, |r| r.generated_tokens);
+
+    // Log the results as a single line of JSON, so that runs across configs can be compared
+    // with a script
+    info!(
+        "bench_result {}",
+        json!({
+            "model": config.model.path,
+            "architecture": config.model.architecture,
+            "thread_count": config.inference.thread_count,
+            "batch_size": config.inference.batch_size,
+            "f16_kv": config.inference.f16_kv,
+            "runs": runs.len(),
+            "seed": settings.seed,
+            "prompt_tokens": prompt_tokens,
+            "generated_tokens": generated_tokens,
+            "prompt_tokens_per_second": prompt_mean,
+            "prompt_tokens_per_second_stddev": prompt_stddev,
+            "generation_tokens_per_second": generation_mean,
+            "generation_tokens_per_second_stddev": generation_stddev,
+            "per_run_generation_tokens_per_second": runs.iter().map(|r| r.generation_tokens_per_second).collect::<Vec<_>>(),
+            "peak_rss_delta_kb": peak_rss_delta_kb,
+        })
+    );
+
+    message
+        .edit(http, |m| {
+            m.content("")
+                .set_components(Default::default())
+                .embed(|e| {
+                    e.title("Benchmark results")
+                        .field(
+                            "Prompt",
+                            format!(
+                                "{prompt_tokens} tokens at {prompt_mean:.2} ± {prompt_stddev:.2} tok/s"
+                            ),
+                            false,
+                        )
+                        .field(
+                            "Generation",
+                            format!(
+                                "{generated_tokens} tokens at {generation_mean:.2} ± {generation_stddev:.2} tok/s"
+                            ),
+                            false,
+                        )
+                        .field(
+                            "Per run",
+                            runs.iter()
+                                .map(|r| format!("{:.2}", r.generation_tokens_per_second))
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                                + " tok/s",
+                            false,
+                        )
+                        .field(
+                            "Peak RSS increase",
+                            peak_rss_delta_kb
+                                .map_or_else(|| "unknown".into(), |kb| format!("{:.1} MB", kb as f64 / 1024.0)),
+                            false,
+                        )
+                        .footer(|f| {
+                            f.text(format!(
+                                "{} runs, seed {}, {} threads, batch size {}",
+                                runs.len(),
+                                settings.seed,
+                                config.inference.thread_count,
+                                config.inference.batch_size
+                            ))
+                        })
+                })
+        })
+        .await?;
+
+    Ok(())
+}
+
+// function to check whether the user is the bot's owner (or a member of the team that owns it)
+pub async fn is_owner(http: &Http, cmd: &ApplicationCommandInteraction) -> anyhow::Result<bool> {
+    let info = http.get_current_application_info().await?;
+    let user_id = cmd.user.id;
+    Ok(info.owner.id == user_id
+        || info
+            .team
+            .is_some_and(|t| t.members.iter().any(|m| m.user.id == user_id)))
+}
+
+// function to turn a token count and duration into tokens per second
+pub fn per_second(tokens: usize, duration: std::time::Duration) -> f64 {
+    tokens as f64 / duration.as_secs_f64().max(f64::EPSILON)
+}
+
+// function to get the mean and (population) standard deviation of some measurements
+pub fn mean_and_stddev(values: impl Iterator<Item = f64> + Clone) -> (f64, f64) {
+    let count = values.clone().count().max(1) as f64;
+    let mean = values.clone().sum::<f64>() / count;
+    let variance = values.map(|v| (v - mean).powi(2)).sum::<f64>() / count;
+    (mean, variance.sqrt())
+}
+
+// function to read the process's peak resident memory so far, in kilobytes.
+// This is read from /proc, so it's only available on Linux
+fn peak_rss_kb() -> Option<u64> {
+    std::fs::read_to_string("/proc/self/status")
+        .ok()?
+        .lines()
+        .find_map(|l| l.strip_prefix("VmHWM:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse()
+        .ok()
+}
@@ -0,0 +1,315 @@
+// This file holds the writing out of conversations as a single prompt, with the turn
+// markers of the configured chat format, and the stop sequences that end an answer in
+// that format. Anything that builds a prompt from several turns should go through here,
+// so that every model gets the markers it was tuned on.
+use std::str::FromStr;
+
+use crate::config;
+
+// Who a turn of a conversation is from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+impl FromStr for Role {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "system" => Ok(Role::System),
+            "user" => Ok(Role::User),
+            "assistant" => Ok(Role::Assistant),
+            other => anyhow::bail!("unknown role `{other}`"),
+        }
+    }
+}
+
+impl Role {
+    // The name of the role, as it's written in requests
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+        }
+    }
+}
+
+// The line put in place of turns that were left out to keep a conversation short
+pub const OMITTED_MARKER: &str = "[earlier conversation omitted]";
+
+// The limits on how much of a conversation's history goes into a prompt
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Window {
+    // The most turns (other than system prompts) that are kept
+    pub max_turns: Option<usize>,
+    // The most tokens the kept turns can add up to
+    pub max_tokens: Option<usize>,
+}
+
+impl Window {
+    // function to keep as much of the end of a conversation as fits in the window. System
+    // prompts and the latest turn are always kept; the oldest of the rest are left out first.
+    // If any are left out, a marker says so, and `true` is returned with the turns
+    pub fn apply<'a>(
+        &self,
+        turns: &[(Role, &'a str)],
+        count_tokens: impl Fn(&str) -> usize,
+    ) -> (Vec<(Role, &'a str)>, bool) {
+        match self.split(turns, count_tokens) {
+            Some(oldest_kept) => (replace_before(turns, oldest_kept, OMITTED_MARKER), true),
+            None => (turns.to_vec(), false),
+        }
+    }
+
+    // function to find where the turns that fit in the window start, if any before them
+    // (other than system prompts) don't fit
+    pub fn split(
+        &self,
+        turns: &[(Role, &str)],
+        count_tokens: impl Fn(&str) -> usize,
+    ) -> Option<usize> {
+        let latest = turns.iter().rposition(|(role, _)| *role != Role::System)?;
+
+        let mut used_turns = 1;
+        let mut used_tokens: usize = turns
+            .iter()
+            .enumerate()
+            .filter(|&(i, (role, _))| i == latest || *role == Role::System)
+            .map(|(_, (_, content))| count_tokens(content))
+            .sum();
+
+        // Walking back from the latest turn, find the oldest turn that still fits
+        let mut oldest_kept = latest;
+        for (i, (role, content)) in turns[..latest].iter().enumerate().rev() {
+            if *role == Role::System {
+                continue;
+            }
+            let tokens = count_tokens(content);
+            let over_turns = self.max_turns.is_some_and(|max| used_turns >= max);
+            let over_tokens = self
+                .max_tokens
+                .is_some_and(|max| used_tokens + tokens > max);
+            if over_turns || over_tokens {
+                break;
+            }
+            used_turns += 1;
+            used_tokens += tokens;
+            oldest_kept = i;
+        }
+
+        turns[..oldest_kept]
+            .iter()
+            .any(|(role, _)| *role != Role::System)
+            .then_some(oldest_kept)
+    }
+}
+
+// function to replace the turns before `index` (other than system prompts) with a single
+// system turn. The system prompts stay where they were, and the replacement goes after them
+pub fn replace_before<'a>(
+    turns: &[(Role, &'a str)],
+    index: usize,
+    replacement: &'a str,
+) -> Vec<(Role, &'a str)> {
+    let mut kept: Vec<_> = turns[..index]
+        .iter()
+        .filter(|(role, _)| *role == Role::System)
+        .copied()
+        .collect();
+    kept.push((Role::System, replacement));
+    kept.extend_from_slice(&turns[index..]);
+    kept
+}
+
+// function to write out a conversation, ending where the assistant's answer starts
+pub fn render(template: &config::ChatTemplate, turns: &[(Role, &str)]) -> String {
+    let mut prompt = String::new();
+    // The system prompt, while it waits for the user turn it goes inside
+    let mut pending_system = String::new();
+    let mut seen_user = false;
+
+    for &(role, content) in turns {
+        match role {
+            Role::System if template.system_in_first_user && !seen_user => {
+                pending_system += &wrap(&template.system, content);
+            }
+            Role::System => prompt += &wrap(&template.system, content),
+            Role::User => {
+                let content = std::mem::take(&mut pending_system) + content;
+                prompt += &wrap(&template.user, &content);
+                seen_user = true;
+            }
+            Role::Assistant => prompt += &wrap(&template.assistant, content),
+        }
+    }
+
+    // A system prompt with no user turn to go inside gets an empty one
+    if !pending_system.is_empty() {
+        prompt += &wrap(&template.user, &pending_system);
+    }
+
+    prompt + &template.assistant_start
+}
+
+// function to write out a system prompt on its own, for putting in front of a prompt
+// that isn't a conversation
+pub fn render_system(template: &config::ChatTemplate, system_prompt: &str) -> String {
+    wrap(&template.system, system_prompt)
+}
+
+// function to work out where an answer in this format ends: at the configured stop
+// sequences, or else where the next user turn would start and where the assistant's
+// turn ends (whichever of the two isn't just whitespace)
+pub fn stop_sequences(template: &config::ChatTemplate) -> Vec<String> {
+    if let Some(stop_sequences) = &template.stop_sequences {
+        return stop_sequences.clone();
+    }
+
+    let user_start = template.user.split("{{CONTENT}}").next();
+    let assistant_end = template.assistant.rsplit("{{CONTENT}}").next();
+
+    let mut stop_sequences: Vec<String> = vec![];
+    for marker in [assistant_end, user_start].into_iter().flatten() {
+        let marker = marker.trim();
+        if !marker.is_empty() && !stop_sequences.iter().any(|s| s == marker) {
+            stop_sequences.push(marker.to_string());
+        }
+    }
+    stop_sequences
+}
+
+// function to put a turn's text into its part of the template
+fn wrap(wrapper: &str, content: &str) -> String {
+    // Only the template is searched, so text that contains `{{CONTENT}}` is left as it is
+    wrapper.replacen("{{CONTENT}}", content, 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::ChatFormat;
+
+    const CONVERSATION: &[(Role, &str)] = &[
+        (Role::System, "Be brief."),
+        (Role::User, "Hi"),
+        (Role::Assistant, "Hello!"),
+        (Role::User, "Bye"),
+    ];
+
+    #[test]
+    fn renders_each_preset() {
+        let cases = [
+            (
+                ChatFormat::Plain,
+                "system: Be brief.\nuser: Hi\nassistant: Hello!\nuser: Bye\nassistant:",
+                vec!["user:"],
+            ),
+            (
+                ChatFormat::ChatMl,
+                "<|im_start|>system\nBe brief.<|im_end|>\n\
+                 <|im_start|>user\nHi<|im_end|>\n\
+                 <|im_start|>assistant\nHello!<|im_end|>\n\
+                 <|im_start|>user\nBye<|im_end|>\n\
+                 <|im_start|>assistant\n",
+                vec!["<|im_end|>", "<|im_start|>user"],
+            ),
+            (
+                ChatFormat::Llama2,
+                "[INST] <<SYS>>\nBe brief.\n<</SYS>>\n\nHi [/INST] Hello! </s>[INST] Bye [/INST]",
+                vec!["</s>", "[INST]"],
+            ),
+            (
+                ChatFormat::Vicuna,
+                "Be brief.\n\nUSER: Hi\nASSISTANT: Hello!</s>\nUSER: Bye\nASSISTANT:",
+                vec!["</s>", "USER:"],
+            ),
+        ];
+        for (format, prompt, stops) in cases {
+            let template = format.template();
+            assert_eq!(render(&template, CONVERSATION), prompt, "{format:?}");
+            assert_eq!(stop_sequences(&template), stops, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn configured_stop_sequences_replace_the_derived_ones() {
+        let mut template = ChatFormat::ChatMl.template();
+        template.stop_sequences = Some(vec!["###".into()]);
+        assert_eq!(stop_sequences(&template), vec!["###"]);
+    }
+
+    // A tokenizer that counts words, so that the budgets are easy to follow
+    fn words(text: &str) -> usize {
+        text.split_whitespace().count()
+    }
+
+    const HISTORY: &[(Role, &str)] = &[
+        (Role::System, "You are a helpful assistant."),
+        (Role::User, "one"),
+        (Role::Assistant, "two two"),
+        (Role::User, "three three three"),
+        (Role::Assistant, "four four four four"),
+        (Role::User, "five five five five five"),
+    ];
+
+    #[test]
+    fn a_conversation_that_fits_is_kept_whole() {
+        let window = Window {
+            max_turns: Some(10),
+            max_tokens: Some(100),
+        };
+        assert_eq!(window.apply(HISTORY, words), (HISTORY.to_vec(), false));
+    }
+
+    #[test]
+    fn the_oldest_turns_are_left_out_first() {
+        // The system prompt and the latest turn take 10 words, which leaves room for the 4
+        // and 3 before them, but not the 2 before those
+        let window = Window {
+            max_turns: None,
+            max_tokens: Some(17),
+        };
+        let (turns, omitted) = window.apply(HISTORY, words);
+        assert!(omitted);
+        assert_eq!(
+            turns,
+            vec![
+                (Role::System, "You are a helpful assistant."),
+                (Role::System, OMITTED_MARKER),
+                (Role::User, "three three three"),
+                (Role::Assistant, "four four four four"),
+                (Role::User, "five five five five five"),
+            ]
+        );
+
+        // Limiting the turns instead gives the same result
+        let window = Window {
+            max_turns: Some(3),
+            max_tokens: None,
+        };
+        assert_eq!(window.apply(HISTORY, words), (turns, true));
+    }
+
+    #[test]
+    fn the_system_prompt_and_latest_turn_are_always_kept() {
+        // Even when they don't fit themselves
+        let window = Window {
+            max_turns: Some(1),
+            max_tokens: Some(1),
+        };
+        let (turns, omitted) = window.apply(HISTORY, words);
+        assert!(omitted);
+        assert_eq!(
+            turns,
+            vec![
+                (Role::System, "You are a helpful assistant."),
+                (Role::System, OMITTED_MARKER),
+                (Role::User, "five five five five five"),
+            ]
+        );
+    }
+}
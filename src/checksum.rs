@@ -0,0 +1,163 @@
+// This file holds the check of model files against `sha256` under `[model]`, at startup.
+// A file truncated by an interrupted copy often still loads, and just generates worse text,
+// so the bot refuses to load a file that doesn't match. Hashing a large model takes a while,
+// so the hash is kept in a `<model>.sha256` file next to it, and only worked out again once
+// the model file's size or modification time changes. `--skip-checksum` skips the check, and
+// the `hash-model` subcommand prints the hash to put in the config.
+use std::{
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use anyhow::Context as AnyhowContext;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config;
+
+// How often hashing progress is logged, at most
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+// A file's hash, with the size and modification time it was worked out for
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Cached {
+    size: u64,
+    modified_ns: u64,
+    sha256: String,
+}
+
+// function to check a model file against its configured checksum, if it has one
+pub fn verify(model: &config::Model) -> anyhow::Result<()> {
+    let Some(expected) = &model.sha256 else {
+        return Ok(());
+    };
+    if model.is_mock() {
+        return Ok(());
+    }
+
+    let actual = hash(&model.path)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        anyhow::bail!(
+            "{} doesn't match model.sha256, so it may be truncated or corrupted\n  \
+             expected: {}\n  actual:   {actual}\n\
+             Replace the file, or run with --skip-checksum to load it anyway",
+            model.path.display(),
+            expected.to_lowercase()
+        );
+    }
+    info!("{} matches model.sha256", model.path.display());
+    Ok(())
+}
+
+// function to get a file's SHA-256 hash, as lowercase hex. The hash from an earlier run is
+// used if the file hasn't changed since
+pub fn hash(path: &Path) -> anyhow::Result<String> {
+    let metadata =
+        fs::metadata(path).with_context(|| format!("failed to read {}", path.display()))?;
+    if let Some(cached) = read_cache(path, &metadata) {
+        debug!("Using the cached hash of {}", path.display());
+        return Ok(cached);
+    }
+
+    let sha256 = hash_file(path).with_context(|| format!("failed to hash {}", path.display()))?;
+    remember(path, &sha256);
+    Ok(sha256)
+}
+
+// function to hash a file with SHA-256, as lowercase hex, logging the progress on large files
+pub fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let total = file.metadata()?.len();
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 1 << 20];
+    let mut hashed = 0;
+    let mut last_logged = Instant::now();
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        hashed += read as u64;
+
+        if last_logged.elapsed() >= PROGRESS_INTERVAL {
+            last_logged = Instant::now();
+            info!(
+                "Hashing {}: {:.0}%",
+                path.display(),
+                hashed as f64 / total.max(1) as f64 * 100.0
+            );
+        }
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// function to keep a file's hash for later runs, e.g. once a download has been verified.
+// Failing to (say, next to a read-only model) only means it's worked out again next time
+pub fn remember(path: &Path, sha256: &str) {
+    let cached = fs::metadata(path).map(|metadata| Cached {
+        size: metadata.len(),
+        modified_ns: modified_ns(&metadata),
+        sha256: sha256.to_lowercase(),
+    });
+    let written = cached.and_then(|cached| {
+        fs::write(
+            cache_path(path),
+            serde_json::to_string(&cached).map_err(io::Error::from)?,
+        )
+    });
+    if let Err(err) = written {
+        debug!("Couldn't cache the hash of {}: {err}", path.display());
+    }
+}
+
+// function to read a file's cached hash, if there is one for its current size and
+// modification time
+fn read_cache(path: &Path, metadata: &fs::Metadata) -> Option<String> {
+    let cached: Cached = serde_json::from_str(&fs::read_to_string(cache_path(path)).ok()?).ok()?;
+    (cached.size == metadata.len() && cached.modified_ns == modified_ns(metadata))
+        .then_some(cached.sha256)
+}
+
+// The file a model file's hash is cached in
+fn cache_path(path: &Path) -> PathBuf {
+    let mut cache = path.as_os_str().to_owned();
+    cache.push(".sha256");
+    PathBuf::from(cache)
+}
+
+// A file's modification time, in nanoseconds since the Unix epoch (0 if it isn't known)
+fn modified_ns(metadata: &fs::Metadata) -> u64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |since| since.as_nanos() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cached_hash_is_only_used_for_the_same_file() {
+        let path =
+            std::env::temp_dir().join(format!("llmcord-checksum-{}.bin", std::process::id()));
+        fs::write(&path, b"pretend these are model weights").unwrap();
+        let sha256 = hash(&path).unwrap();
+        assert_eq!(
+            sha256,
+            format!("{:x}", Sha256::digest(b"pretend these are model weights"))
+        );
+        assert!(cache_path(&path).exists());
+
+        // A truncated copy has another size, so it's hashed again
+        fs::write(&path, b"pretend these").unwrap();
+        assert_ne!(hash(&path).unwrap(), sha256);
+
+        fs::remove_file(cache_path(&path)).ok();
+        fs::remove_file(path).ok();
+    }
+}
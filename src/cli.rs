@@ -0,0 +1,309 @@
+// This file holds the command-line interface.
+// Without a subcommand, the Discord bot is started as usual; the subcommands
+// are offline tools that reuse the bot's code paths without going through Discord.
+use std::{collections::HashMap, io::Write};
+
+use anyhow::Context as AnyhowContext;
+use clap::{Parser, Subcommand};
+use serenity::model::prelude::MessageId;
+
+use crate::{
+    bench, checksum,
+    config::Configuration,
+    generation::{self, Token},
+    persona, store, system_prompt,
+};
+
+/// A Discord bot that generates responses using any language model supported by `llm`.
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Print the JSON Schema of config.toml and exit, without loading the configuration.
+    #[arg(long)]
+    pub schema: bool,
+    /// Download the model file if it's missing (see `model.download_url`) and exit.
+    #[arg(long)]
+    pub download_only: bool,
+    /// Load the model file even if it doesn't match `model.sha256`.
+    #[arg(long)]
+    pub skip_checksum: bool,
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+pub enum CliCommand {
+    /// Run a command's prompt through the model locally and print the output, without Discord.
+    Generate {
+        /// The name of the command (from config.toml) whose template to use.
+        #[arg(long)]
+        command: String,
+        /// The prompt to substitute into the template.
+        #[arg(long)]
+        prompt: String,
+        /// The text of the message the command is used on, for templates with `{{REPLY}}`.
+        #[arg(long)]
+        reply: Option<String>,
+        /// Text the output starts with, which the model continues.
+        #[arg(long)]
+        prefix: Option<String>,
+        /// The ID of the server to generate as if in, for its system prompt.
+        #[arg(long)]
+        guild: Option<u64>,
+        /// The ID of the channel to generate as if in, for its persona.
+        #[arg(long)]
+        channel: Option<u64>,
+        /// A value for one of the command's own options, as `name=value`. Can be repeated.
+        #[arg(long = "option", value_parser = parse_option)]
+        options: Vec<(String, String)>,
+        /// The seed to use for sampling.
+        #[arg(long)]
+        seed: Option<u64>,
+        /// The maximum number of tokens to generate.
+        #[arg(long)]
+        max_tokens: Option<usize>,
+    },
+    /// Print the SHA-256 hash of the configured model file, for `model.sha256`.
+    HashModel,
+    /// Compare how fast tokens are sent with and without `batch_decode`, at several batch
+    /// sizes, using the prompt, seed, token count and number of runs from `[bench]`.
+    BenchDecode {
+        /// A batch size to try with batch decoding. Can be repeated.
+        #[arg(long = "batch-size", default_values_t = [2, 4, 8, 16])]
+        batch_sizes: Vec<usize>,
+    },
+}
+
+// Runs the given subcommand
+pub fn run(config: &Configuration, command: CliCommand) -> anyhow::Result<()> {
+    match command {
+        CliCommand::Generate {
+            command,
+            prompt,
+            reply,
+            prefix,
+            guild,
+            channel,
+            options,
+            seed,
+            max_tokens,
+        } => generate(
+            config,
+            &command,
+            prompt,
+            reply.as_deref(),
+            prefix.as_deref().unwrap_or_default(),
+            (guild, channel),
+            &options.into_iter().collect(),
+            seed,
+            max_tokens,
+        ),
+        CliCommand::HashModel => {
+            let sha256 = checksum::hash(&config.model.path)?;
+            println!("sha256 = \"{sha256}\"");
+            Ok(())
+        }
+        CliCommand::BenchDecode { batch_sizes } => bench_decode(config, &batch_sizes),
+    }
+}
+
+// Parses a `--option name=value` argument
+fn parse_option(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected `name=value`, got `{arg}`"))
+}
+
+// Renders the command's template exactly like `hallucinate` does, runs it through
+// the same generation code as the bot, and prints the tokens to stdout as they arrive.
+// The system prompt is the one the command would get in the given guild and channel,
+// including those set with `/system` and `/persona` if they're persisted
+#[allow(clippy::too_many_arguments)] // One for each of the subcommand's arguments
+fn generate(
+    config: &Configuration,
+    command_name: &str,
+    user_prompt: String,
+    reply: Option<&str>,
+    response_prefix: &str,
+    (guild_id, channel_id): (Option<u64>, Option<u64>),
+    option_values: &HashMap<String, String>,
+    seed: Option<u64>,
+    maximum_token_count: Option<usize>,
+) -> anyhow::Result<()> {
+    let command = config
+        .commands
+        .get(command_name)
+        .with_context(|| format!("no command named `{command_name}` in the config"))?;
+
+    // Assemble the prompt through the same functions the bot uses
+    let user_prompt = config.inference.preprocess_user_prompt(user_prompt);
+    let store = store::Store::open(&config.persistence)?;
+    let (persona, system_prompt) = system_prompt::for_channel(
+        config,
+        command,
+        &persona::ChannelPersonas::load(store.clone()),
+        &system_prompt::SystemPrompts::load(store),
+        guild_id,
+        channel_id.unwrap_or_default(),
+    );
+    let prompt = command
+        .assemble_prompt(
+            config,
+            system_prompt.as_deref(),
+            &user_prompt,
+            reply,
+            option_values,
+        )?
+        .prompt
+        + response_prefix;
+
+    let model = crate::load_model(config)?;
+
+    let (token_tx, token_rx) = flume::unbounded();
+    let request = generation::Request {
+        prompt,
+        batch_size: config.inference.batch_size,
+        batch_decode: config.inference.batch_decode,
+        token_buffer_size: config.inference.token_buffer_size,
+        token_tx,
+        // There is no Discord message to cancel from the CLI
+        message_id: MessageId(0),
+        seed,
+        maximum_token_count,
+        n_sequences: 1,
+        sampling: persona.map(|(_, p)| p.sampling).unwrap_or_default(),
+        low_priority: false,
+        echo_prompt: true,
+        context: generation::RequestContext {
+            guild_id,
+            channel_id: channel_id.unwrap_or_default(),
+            user_id: 0,
+            command_name: command_name.to_string(),
+            shard_id: None,
+        },
+        progress_tx: None,
+        completion_tx: None,
+        trace: None,
+        queued_at: std::time::Instant::now(),
+    };
+
+    // Print tokens on a separate thread so that they stream out while the model runs
+    // (it also keeps the seed, which is reported with the stats)
+    let printer = std::thread::spawn(move || {
+        let mut stdout = std::io::stdout();
+        let mut seed = None;
+        for token in token_rx.iter() {
+            match token {
+                Token::Token(t) => {
+                    print!("{t}");
+                    stdout.flush().ok();
+                }
+                Token::Metadata(metadata) => seed = Some(metadata.seed),
+                Token::SequenceStart(index) => println!("\n--- Alternative {} ---", index + 1),
+                Token::Error(_) => {}
+            }
+        }
+        println!();
+        seed
+    });
+
+    let context = generation::GenerationContext::new(config)?;
+    let result = generation::process_incoming_request(&request, &model, &context);
+
+    // Dropping the request closes the token channel, which lets the printer finish
+    drop(request);
+    let seed = printer.join().ok().flatten();
+
+    // Returning the error makes the process exit with a non-zero status
+    let completion = result?;
+    let stats = completion.stats;
+    eprintln!("Stop reason: {}", completion.stop_reason);
+    if let Some(seed) = seed {
+        eprintln!("Seed: {seed}");
+    }
+    eprintln!(
+        "Prompt: {} tokens in {:.2}s",
+        stats.prompt_tokens,
+        stats.feed_prompt_duration.as_secs_f64()
+    );
+    eprintln!(
+        "Generation: {} tokens in {:.2}s ({:.2} tokens/s)",
+        stats.predict_tokens,
+        stats.predict_duration.as_secs_f64(),
+        stats.predict_tokens as f64 / stats.predict_duration.as_secs_f64().max(f64::EPSILON)
+    );
+
+    Ok(())
+}
+
+// Runs the `[bench]` prompt without batch decoding, then with it at each of the batch sizes,
+// and prints how many messages each sent over the token channel and how fast it generated.
+// Every run uses the same seed, so they all generate the same text
+fn bench_decode(config: &Configuration, batch_sizes: &[usize]) -> anyhow::Result<()> {
+    let settings = &config.bench;
+    let model = crate::load_model(config)?;
+    let context = generation::GenerationContext::new(config)?;
+
+    println!(
+        "{:<12} {:>8} {:>8} {:>12} {:>8}",
+        "batching", "tokens", "messages", "tokens/s", "stddev"
+    );
+    // `None` is the run without batch decoding, which sends every token on its own
+    for batch_size in std::iter::once(None).chain(batch_sizes.iter().copied().map(Some)) {
+        let mut tokens = 0;
+        let mut messages = 0;
+        let mut tokens_per_second = vec![];
+        for _ in 0..settings.runs.max(1) {
+            let (token_tx, token_rx) = flume::unbounded();
+            let request = generation::Request {
+                prompt: settings.prompt.clone(),
+                batch_size: batch_size.unwrap_or(1),
+                batch_decode: batch_size.is_some(),
+                token_buffer_size: 1,
+                token_tx,
+                message_id: MessageId(0),
+                seed: Some(settings.seed),
+                maximum_token_count: Some(settings.generated_tokens),
+                n_sequences: 1,
+                sampling: Default::default(),
+                low_priority: false,
+                echo_prompt: false,
+                context: generation::RequestContext {
+                    guild_id: None,
+                    channel_id: 0,
+                    user_id: 0,
+                    command_name: "bench-decode".to_string(),
+                    shard_id: None,
+                },
+                progress_tx: None,
+                completion_tx: None,
+                trace: None,
+                queued_at: std::time::Instant::now(),
+            };
+
+            // Receive on another thread, like the bot does, so that the channel is really used
+            let receiver = std::thread::spawn(move || {
+                token_rx
+                    .iter()
+                    .filter(|t| matches!(t, Token::Token(_)))
+                    .count()
+            });
+            let result = generation::process_incoming_request(&request, &model, &context);
+            drop(request);
+            messages = receiver.join().unwrap_or_default();
+
+            let stats = result?.stats;
+            tokens = stats.predict_tokens;
+            tokens_per_second.push(bench::per_second(
+                stats.predict_tokens,
+                stats.predict_duration,
+            ));
+        }
+
+        let (mean, stddev) = bench::mean_and_stddev(tokens_per_second.into_iter());
+        let batching = batch_size.map_or_else(|| "off".to_string(), |size| format!("{size}"));
+        println!("{batching:<12} {tokens:>8} {messages:>8} {mean:>12.2} {stddev:>8.2}");
+    }
+
+    Ok(())
+}
@@ -1,10 +1,14 @@
 use anyhow::Context;
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use serenity::model::Permissions;
 use std::{collections::HashMap, path::PathBuf};
 
+use crate::{chat, constant, logging, tokenizer_config, util};
+
 // Define the main configuration struct, serializable and deserializable
 // Define a structure called Configuration, which holds various configuration settings.
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Configuration {
     // Configuration component for authentication settings.
     pub authentication: Authentication,
@@ -17,6 +21,76 @@ pub struct Configuration {
 
     // Configuration component for storing commands using a HashMap.
     pub commands: HashMap<String, Command>,
+
+    // Configuration component for the optional OpenAI-compatible HTTP API.
+    // The API is only started if this section is present.
+    pub http_api: Option<HttpApi>,
+
+    // Configuration component for the optional health/readiness endpoint.
+    // The endpoint is only started if this section is present.
+    pub health: Option<Health>,
+
+    // Configuration component for persistent storage (usage stats, quotas, conversations).
+    // If this section is missing, nothing is persisted.
+    #[serde(default)]
+    pub persistence: Persistence,
+
+    // A Discord webhook that internal errors are posted to, so that the operator
+    // hears about failures. Errors are only logged if this isn't set.
+    pub error_webhook_url: Option<String>,
+
+    // The channel that responses reported with their "Report" button are posted to, for the
+    // moderators. Responses have no such button if this isn't set.
+    pub report_channel_id: Option<u64>,
+
+    // Whether to post a message listing the commands in a guild's system channel when the
+    // bot joins it.
+    #[serde(default)]
+    pub announce_on_join: bool,
+
+    // Configuration component for the optional fallback backend, which requests
+    // overflow to when the local model's queue is saturated.
+    pub fallback: Option<Fallback>,
+
+    // Configuration component for the Discord gateway connection.
+    #[serde(default)]
+    pub gateway: Gateway,
+
+    // Configuration component for the bot's status, which shows whether it is busy.
+    // The status is only updated if this section is present.
+    pub presence: Option<Presence>,
+
+    // Configuration component for the owner-only `/bench` command.
+    #[serde(default)]
+    pub bench: Bench,
+
+    // Configuration component for the "Export" button on finished responses.
+    #[serde(default)]
+    pub export: Export,
+
+    // Configuration component for logging, beyond the console.
+    #[serde(default)]
+    pub logging: Logging,
+
+    // Settings for individual guilds, by guild ID.
+    #[serde(default)]
+    pub guilds: HashMap<String, Guild>,
+
+    // Personas that `/persona` can switch a channel to, by name.
+    #[serde(default)]
+    pub personas: HashMap<String, Persona>,
+
+    // Configuration component for summarizing long conversations' oldest turns.
+    // They are only summarized (instead of left out) if this section is present.
+    pub summarization: Option<Summarization>,
+
+    // Prompts that are generated and posted to a channel every day.
+    #[serde(default)]
+    pub schedules: Vec<Schedule>,
+
+    // Configuration component for stopping output that shouldn't be shown.
+    #[serde(default)]
+    pub moderation: Moderation,
 }
 
 // Implement the Default trait for Configuration to provide default values.
@@ -27,6 +101,8 @@ impl Default for Configuration {
             // Default settings for authentication.
             authentication: Authentication {
                 discord_token: None,
+                allowed_guilds: vec![],
+                block_on_leave: false,
             },
 
             // Default settings for the model, including file path, 
@@ -38,6 +114,14 @@ impl Default for Configuration {
                 prefer_mmap: true,
                 use_gpu: true,
                 gpu_layers: None,
+                mock: None,
+                rope_context_scaling: None,
+                chat_format: ChatFormat::default(),
+                idle_unload_minutes: None,
+                tokenizer_config_file: None,
+                ignore_memory_check: false,
+                download_url: None,
+                sha256: None,
             },
 
             // Default settings for inference, specifying thread count, 
@@ -45,9 +129,43 @@ impl Default for Configuration {
             inference: Inference {
                 thread_count: 8,
                 batch_size: 8,
+                batch_decode: false,
+                token_buffer_size: default_token_buffer_size(),
+                f16_kv: true,
                 discord_message_update_interval_ms: 250,
+                max_discord_edits_per_minute: default_max_discord_edits_per_minute(),
                 replace_newlines: true,
                 show_prompt_template: true,
+                seed_display: false,
+                show_generation_metadata: false,
+                max_prompt_chars: None,
+                dedup_window_seconds: default_dedup_window_seconds(),
+                system_prompt: None,
+                n_predict_per_token_ms: 0,
+                enable_reaction_feedback: false,
+                max_history_turns: None,
+                max_history_tokens: None,
+                stream_to_file: None,
+                stream_file_max_mb: default_stream_file_max_mb(),
+                enable_persistent_cache: false,
+                cache_ttl_hours: default_cache_ttl_hours(),
+                cache_replay_delay_ms: default_cache_replay_delay_ms(),
+                max_rerolls: 0,
+                reroll_temperature_delta: default_reroll_temperature_delta(),
+                max_variations: default_max_variations(),
+                enable_feedback_buttons: false,
+                feedback_voting_hours: default_feedback_voting_hours(),
+                loop_ngram_tokens: default_loop_ngram_tokens(),
+                loop_max_repeats: default_loop_max_repeats(),
+                max_generation_seconds: None,
+                max_queue_wait_seconds: None,
+                sampler_order: default_sampler_order(),
+                fast_lane_tokens: default_fast_lane_tokens(),
+                fast_lane_streak: default_fast_lane_streak(),
+                max_attachment_bytes: default_max_attachment_bytes(),
+                enable_speculative_decoding: false,
+                draft_model: None,
+                speculative_tokens: default_speculative_tokens(),
             },
 
             // Default settings for commands using a HashMap, including two predefined commands.
@@ -59,6 +177,8 @@ impl Default for Configuration {
                         enabled: true,
                         description: "Hallucinates some text.".into(),
                         prompt: "{{PROMPT}}".into(),
+                        n_sequences: 1,
+                        ..Default::default()
                     },
                 ),
                 (
@@ -80,15 +200,73 @@ impl Default for Configuration {
                             "
                         }
                         .into(),
+                        n_sequences: 1,
+                        ..Default::default()
                     },
                 ),
             ]),
+
+            // The HTTP API is disabled by default.
+            http_api: None,
+
+            // The health endpoint is disabled by default.
+            health: None,
+
+            // Persistence is disabled by default.
+            persistence: Persistence::default(),
+
+            // No error webhook by default.
+            error_webhook_url: None,
+
+            // No reporting by default.
+            report_channel_id: None,
+            announce_on_join: false,
+
+            // No fallback backend by default.
+            fallback: None,
+
+            // A single gateway connection by default.
+            gateway: Gateway::default(),
+
+            // The status isn't updated by default.
+            presence: None,
+
+            // The standard benchmark.
+            bench: Bench::default(),
+
+            // Exports are only shown to whoever asked for them.
+            export: Export::default(),
+
+            // Logs only go to the console by default.
+            logging: Logging::default(),
+
+            // No guild has settings of its own by default.
+            guilds: HashMap::new(),
+
+            // No personas by default.
+            personas: HashMap::new(),
+            schedules: vec![],
+
+            // Old turns are left out rather than summarized by default.
+            summarization: None,
+
+            // Nothing is withheld by default.
+            moderation: Moderation::default(),
         }
     }
 }
 
 // Implement additional methods for the Configuration structure
 impl Configuration {
+    // The system prompt configured for a guild (`None` for DMs), falling back to the global one.
+    // This doesn't include any set at runtime with `/system`
+    pub fn system_prompt(&self, guild_id: Option<u64>) -> Option<&str> {
+        guild_id
+            .and_then(|id| self.guilds.get(&id.to_string()))
+            .and_then(|g| g.system_prompt.as_deref())
+            .or(self.inference.system_prompt.as_deref())
+    }
+
     // A constant representing the filename for the configuration file
     const FILENAME: &str = "config.toml";
 
@@ -97,7 +275,9 @@ impl Configuration {
         // check if reading the file is successful
         let config = if let Ok(file) = std::fs::read_to_string(Self::FILENAME) {
             // If successful, deserialize the file content using the toml crate
-            toml::from_str(&file).context("failed to load config")?
+            let config: Self = toml::from_str(&file).context("failed to load config")?;
+            config.validate()?;
+            config
         } else {
             // If the file reading fails, create a default configuration, save it, and use it
             let config = Self::default();
@@ -109,6 +289,201 @@ impl Configuration {
         Ok(config)
     }
 
+    // function to check `config.toml` as it is on disk, without loading it or creating it.
+    // Returns every problem found, which is empty if the file is valid
+    pub fn check_file() -> Vec<anyhow::Error> {
+        let file = match std::fs::read_to_string(Self::FILENAME) {
+            Ok(file) => file,
+            Err(err) => return vec![anyhow::Error::new(err).context("failed to read config")],
+        };
+        match toml::from_str::<Self>(&file) {
+            Ok(config) => config.problems(),
+            Err(err) => vec![anyhow::Error::new(err).context("failed to load config")],
+        }
+    }
+
+    // function to check the parts of the configuration that deserializing can't
+    fn validate(&self) -> anyhow::Result<()> {
+        match self.problems().into_iter().next() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    // function to find the problems with the speculative decoding settings, when it's on
+    fn speculative_problems(&self) -> Vec<anyhow::Error> {
+        let mut problems = vec![];
+        if !cfg!(feature = "speculative") {
+            problems.push(anyhow::anyhow!(
+                "inference.enable_speculative_decoding needs the bot to be built with \
+                 `--features speculative`"
+            ));
+        }
+        match &self.inference.draft_model {
+            None => problems.push(anyhow::anyhow!(
+                "inference.enable_speculative_decoding needs an inference.draft_model"
+            )),
+            Some(draft) if draft.is_mock() || self.model.is_mock() => problems.push(
+                anyhow::anyhow!("speculative decoding doesn't work with the mock model"),
+            ),
+            Some(_) => {}
+        }
+        if self.inference.speculative_tokens == 0
+            || self.inference.speculative_tokens > self.inference.batch_size
+        {
+            problems.push(anyhow::anyhow!(
+                "inference.speculative_tokens must be between 1 and inference.batch_size"
+            ));
+        }
+        problems
+    }
+
+    // function to find every problem with the configuration that deserializing can't
+    fn problems(&self) -> Vec<anyhow::Error> {
+        let mut problems = vec![];
+
+        if let Some(scaling) = &self.model.rope_context_scaling {
+            // Scaling can only extend the context
+            if scaling.factor().is_nan() || scaling.factor() < 1.0 {
+                problems.push(anyhow::anyhow!(
+                    "the RoPE scaling factor must be at least 1.0, not {}",
+                    scaling.factor()
+                ));
+            }
+        }
+
+        if let Err(err) = self
+            .model
+            .chat_format
+            .template()
+            .validate()
+            .context("invalid model.chat_format")
+        {
+            problems.push(err);
+        }
+
+        if let Err(err) = self
+            .model
+            .tokenizer_config()
+            .context("invalid model.tokenizer_config_file")
+        {
+            problems.push(err);
+        }
+
+        if self.inference.reroll_temperature_delta.is_nan()
+            || self.inference.reroll_temperature_delta <= 0.0
+        {
+            problems.push(anyhow::anyhow!(
+                "inference.reroll_temperature_delta must be greater than 0"
+            ));
+        }
+
+        let sampler_order = &self.inference.sampler_order;
+        for (i, sampler) in sampler_order.iter().enumerate() {
+            if sampler_order[..i].contains(sampler) {
+                let name = serde_json::to_string(sampler).unwrap_or_default();
+                problems.push(anyhow::anyhow!(
+                    "inference.sampler_order lists {name} more than once"
+                ));
+            }
+        }
+
+        if let Some(sha256) = &self.model.sha256 {
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                problems.push(anyhow::anyhow!(
+                    "model.sha256 must be 64 hexadecimal digits"
+                ));
+            }
+        }
+
+        if self.model.idle_unload_minutes == Some(0) {
+            problems.push(anyhow::anyhow!(
+                "model.idle_unload_minutes must be at least 1"
+            ));
+        }
+
+        if self.inference.max_generation_seconds == Some(0) {
+            problems.push(anyhow::anyhow!(
+                "inference.max_generation_seconds must be at least 1"
+            ));
+        }
+        if self.inference.max_queue_wait_seconds == Some(0) {
+            problems.push(anyhow::anyhow!(
+                "inference.max_queue_wait_seconds must be at least 1"
+            ));
+        }
+
+        if self.inference.enable_speculative_decoding {
+            problems.extend(self.speculative_problems());
+        }
+
+        if self.inference.fast_lane_streak == 0 {
+            problems.push(anyhow::anyhow!(
+                "inference.fast_lane_streak must be at least 1"
+            ));
+        }
+
+        if self.inference.loop_max_repeats > 0 && self.inference.loop_ngram_tokens == 0 {
+            problems.push(anyhow::anyhow!(
+                "inference.loop_ngram_tokens must be at least 1"
+            ));
+        }
+
+        // The variations go in one embed, like a command's alternatives
+        if !(1..=Command::MAX_SEQUENCES).contains(&self.inference.max_variations) {
+            problems.push(anyhow::anyhow!(
+                "inference.max_variations must be from 1 to {}",
+                Command::MAX_SEQUENCES
+            ));
+        }
+
+        if let Err(err) = self
+            .moderation
+            .halt_patterns()
+            .context("invalid moderation.halt_on_output")
+        {
+            problems.push(err);
+        }
+
+        for (name, persona) in &self.personas {
+            if let Err(err) = persona
+                .sampling
+                .validate()
+                .with_context(|| format!("invalid config for persona `{name}`"))
+            {
+                problems.push(err);
+            }
+        }
+
+        for (name, command) in &self.commands {
+            // The built-in command would be used instead, and Discord refuses two commands
+            // with the same name
+            if constant::command::ALL.contains(&name.as_str()) {
+                problems.push(anyhow::anyhow!(
+                    "the command `{name}` has the same name as a built-in command; \
+                     it needs a different name"
+                ));
+            }
+            if let Err(err) = command
+                .validate(&self.inference)
+                .with_context(|| format!("invalid config for command `{name}`"))
+            {
+                problems.push(err);
+            }
+        }
+
+        for schedule in &self.schedules {
+            if let Err(err) = schedule
+                .validate(&self.commands)
+                .with_context(|| format!("invalid config for schedule `{}`", schedule.name))
+            {
+                problems.push(err);
+            }
+        }
+
+        problems
+    }
+
     // A function to save the current configuration to a file
     fn save(&self) -> anyhow::Result<()> {
         // Write the configuration to the specified file
@@ -117,17 +492,97 @@ impl Configuration {
             toml::to_string_pretty(self)?, // Serialize the configuration to a TOML-formatted string
         )?)
     }
+
+    // function to write out the JSON Schema of the configuration file, for editors to
+    // validate `config.toml` against
+    pub fn schema() -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(&schemars::schema_for!(Self))?)
+    }
+}
+
+// The structure to hold the settings of a single guild
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct Guild {
+    // The guild's system prompt, instead of `inference.system_prompt`
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+}
+
+// The structure to hold a persona, which `/persona` can make a channel's system prompt
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Persona {
+    // The system prompt, instead of the guild's
+    pub system_prompt: String,
+    // Changes to how tokens are sampled while the persona is active
+    #[serde(flatten)]
+    pub sampling: Sampling,
+}
+
+// The structure to hold changes to how tokens are sampled. Anything that isn't set keeps
+// the default
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Sampling {
+    #[serde(default)]
+    pub temperature: Option<f32>,
+    #[serde(default)]
+    pub top_k: Option<usize>,
+    #[serde(default)]
+    pub top_p: Option<f32>,
+    #[serde(default)]
+    pub repeat_penalty: Option<f32>,
+}
+
+impl Sampling {
+    // Whether or not anything is changed
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    // function to check that the values are ones the samplers can use
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.temperature.is_some_and(|t| t.is_nan() || t <= 0.0) {
+            anyhow::bail!("temperature must be greater than 0");
+        }
+        if self.top_k == Some(0) {
+            anyhow::bail!("top_k must be at least 1");
+        }
+        if self
+            .top_p
+            .is_some_and(|p| p.is_nan() || p <= 0.0 || p > 1.0)
+        {
+            anyhow::bail!("top_p must be greater than 0 and at most 1");
+        }
+        if self.repeat_penalty.is_some_and(|p| p.is_nan() || p <= 0.0) {
+            anyhow::bail!("repeat_penalty must be greater than 0");
+        }
+        Ok(())
+    }
 }
 
 // Define a structure to hold authentication settings
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Authentication {
     // Discord token for authentication
     pub discord_token: Option<String>,
+    // The only guilds the bot will serve; empty means every guild is allowed
+    #[serde(default)]
+    pub allowed_guilds: Vec<u64>,
+    // Whether to leave guilds that aren't allowed as soon as the bot is added to them
+    // (and warn when the bot is removed from an allowed guild)
+    #[serde(default)]
+    pub block_on_leave: bool,
+}
+
+impl Authentication {
+    // function to check whether the bot may serve a guild (`None` for DMs)
+    pub fn is_guild_allowed(&self, guild_id: Option<u64>) -> bool {
+        self.allowed_guilds.is_empty()
+            || guild_id.is_some_and(|id| self.allowed_guilds.contains(&id))
+    }
 }
 
 // Define a structure to hold model-related settings
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Model {
     // Path to the model file
     pub path: PathBuf,
@@ -143,17 +598,284 @@ pub struct Model {
     // The number of layers to offload to the GPU (if `use_gpu` is on).
     // If not set, all layers will be offloaded.
     pub gpu_layers: Option<usize>,
+    // Settings for the built-in mock model, used when `architecture` is "mock".
+    // If not set, the mock's defaults are used.
+    pub mock: Option<Mock>,
+    // How the model's RoPE positions are scaled, for models fine-tuned to a longer
+    // context than they were trained with. If not set, they aren't scaled.
+    #[serde(default)]
+    pub rope_context_scaling: Option<RopeScalingConfig>,
+    // The turn markers that conversations are written out with for the model
+    // (e.g. by the HTTP API's chat completions). It should match what the model was tuned on
+    #[serde(default)]
+    pub chat_format: ChatFormat,
+    // How many minutes the model can go unused before it's unloaded to free its memory. It's
+    // loaded again when the next request comes in. If not set, it's never unloaded
+    #[serde(default)]
+    pub idle_unload_minutes: Option<u64>,
+    // The `tokenizer_config.json` that came with the model, for its end-of-text marker (see
+    // `tokenizer_config`). If not set, only the model's own end-of-text token ends answers
+    #[serde(default)]
+    pub tokenizer_config_file: Option<PathBuf>,
+    // Whether or not to load the model even if it looks like it won't fit in memory (see
+    // `memory_check`)
+    #[serde(default)]
+    pub ignore_memory_check: bool,
+    // Where to download the model file from if it isn't at `path` (see `download`)
+    #[serde(default)]
+    pub download_url: Option<String>,
+    // The SHA-256 checksum the model file must have, as hex, checked when it's downloaded and
+    // at startup (see `checksum`). If not set, it isn't checked
+    #[serde(default)]
+    pub sha256: Option<String>,
 }
 // Implementing the additional methods for the Model structure
 impl Model {
+    // function to read `tokenizer_config_file`, if it's set
+    pub fn tokenizer_config(&self) -> anyhow::Result<Option<tokenizer_config::TokenizerConfig>> {
+        self.tokenizer_config_file
+            .as_deref()
+            .map(tokenizer_config::TokenizerConfig::load)
+            .transpose()
+    }
+
+    // The RoPE base frequency that LLaMA-family models are trained with
+    const ROPE_FREQUENCY_BASE: f32 = 10_000.0;
+
+    // The size of each attention head in LLaMA-family models, which NTK scaling depends on
+    const ROPE_HEAD_DIMENSION: f32 = 128.0;
+
+    // function to turn the RoPE scaling settings into the overrides `llm` loads the model with
+    pub fn rope_overrides(&self) -> anyhow::Result<Option<llm::RoPEOverrides>> {
+        let Some(scaling) = &self.rope_context_scaling else {
+            return Ok(None);
+        };
+
+        let overrides = match *scaling {
+            // Positions are squeezed together, so the trained range covers the longer context
+            RopeScalingConfig::Linear { factor } => llm::RoPEOverrides {
+                frequency_scale: 1.0 / factor,
+                ..Default::default()
+            },
+            // The base frequency is raised instead, which keeps nearby positions distinct
+            RopeScalingConfig::Ntk { factor } => {
+                let exponent = Self::ROPE_HEAD_DIMENSION / (Self::ROPE_HEAD_DIMENSION - 2.0);
+                llm::RoPEOverrides {
+                    frequency_base: (Self::ROPE_FREQUENCY_BASE * factor.powf(exponent)) as usize,
+                    ..Default::default()
+                }
+            }
+            RopeScalingConfig::Yarn { .. } => anyhow::bail!(
+                "YaRN RoPE scaling isn't supported by the version of `llm` the bot uses; \
+                 use linear or NTK scaling instead"
+            ),
+        };
+        Ok(Some(overrides))
+    }
+
+    // The context length the model can make use of, once RoPE scaling is taken into account
+    pub fn effective_context_length(&self) -> usize {
+        let factor = self
+            .rope_context_scaling
+            .as_ref()
+            .map_or(1.0, |s| s.factor());
+        (self.context_token_length as f32 * factor) as usize
+    }
     // function to parse the model architecture from a string
     pub fn architecture(&self) -> Option<llm::ModelArchitecture> {
         self.architecture.parse().ok()
     }
+
+    // function to check if the built-in mock model should be used instead of a real one
+    pub fn is_mock(&self) -> bool {
+        self.architecture.eq_ignore_ascii_case("mock")
+    }
+}
+
+// The ways a model's RoPE positions can be scaled to extend its context
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum RopeScalingConfig {
+    // Divides every position by `factor`
+    Linear {
+        factor: f32,
+    },
+    // Raises the base frequency, as NTK-aware scaling does
+    Ntk {
+        factor: f32,
+    },
+    // YaRN, for models fine-tuned with it from `original_max_position` tokens
+    Yarn {
+        factor: f32,
+        original_max_position: u32,
+    },
+}
+
+impl RopeScalingConfig {
+    // How many times longer than the trained context the scaled context is
+    pub fn factor(&self) -> f32 {
+        match *self {
+            Self::Linear { factor } | Self::Ntk { factor } | Self::Yarn { factor, .. } => factor,
+        }
+    }
+}
+
+// The turn formats that conversations can be written out with
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ChatFormat {
+    // `role: content` lines, which any model can make some sense of
+    #[default]
+    Plain,
+    // `<|im_start|>role ... <|im_end|>`, as used by many recent fine-tunes
+    ChatMl,
+    // `[INST] ... [/INST]`, with the system prompt inside the first instruction
+    Llama2,
+    // `USER: ... ASSISTANT: ...`, as used by Vicuna v1.1 and its descendants
+    Vicuna,
+    // A format described in the config
+    Custom(ChatTemplate),
+}
+
+impl ChatFormat {
+    // The template for the format, written out for the built-in presets
+    pub fn template(&self) -> ChatTemplate {
+        let preset =
+            |system: &str, user: &str, assistant: &str, assistant_start: &str| ChatTemplate {
+                system: system.to_string(),
+                user: user.to_string(),
+                assistant: assistant.to_string(),
+                assistant_start: assistant_start.to_string(),
+                system_in_first_user: false,
+                stop_sequences: None,
+            };
+
+        match self {
+            ChatFormat::Plain => preset(
+                "system: {{CONTENT}}\n",
+                "user: {{CONTENT}}\n",
+                "assistant: {{CONTENT}}\n",
+                "assistant:",
+            ),
+            ChatFormat::ChatMl => preset(
+                "<|im_start|>system\n{{CONTENT}}<|im_end|>\n",
+                "<|im_start|>user\n{{CONTENT}}<|im_end|>\n",
+                "<|im_start|>assistant\n{{CONTENT}}<|im_end|>\n",
+                "<|im_start|>assistant\n",
+            ),
+            ChatFormat::Llama2 => ChatTemplate {
+                system_in_first_user: true,
+                ..preset(
+                    "<<SYS>>\n{{CONTENT}}\n<</SYS>>\n\n",
+                    "[INST] {{CONTENT}} [/INST]",
+                    " {{CONTENT}} </s>",
+                    "",
+                )
+            },
+            ChatFormat::Vicuna => preset(
+                "{{CONTENT}}\n\n",
+                "USER: {{CONTENT}}\n",
+                "ASSISTANT: {{CONTENT}}</s>\n",
+                "ASSISTANT:",
+            ),
+            ChatFormat::Custom(template) => template.clone(),
+        }
+    }
+}
+
+// How each turn of a conversation is written out. Each turn's text replaces `{{CONTENT}}`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct ChatTemplate {
+    // How the system prompt is written
+    pub system: String,
+    // How a user's turn is written
+    pub user: String,
+    // How an earlier answer is written
+    pub assistant: String,
+    // What comes after the conversation, for the model to continue with its answer
+    pub assistant_start: String,
+    // Whether or not the system prompt goes inside the first user turn instead of
+    // before it, as Llama 2 expects
+    #[serde(default)]
+    pub system_in_first_user: bool,
+    // Where answers end. If not set, they end where the next user turn would start,
+    // or at the end of the assistant's turn
+    #[serde(default)]
+    pub stop_sequences: Option<Vec<String>>,
+}
+
+impl ChatTemplate {
+    // function to check that each kind of turn has a place for its text
+    pub fn validate(&self) -> anyhow::Result<()> {
+        for (name, wrapper) in [
+            ("system", &self.system),
+            ("user", &self.user),
+            ("assistant", &self.assistant),
+        ] {
+            if !wrapper.contains("{{CONTENT}}") {
+                anyhow::bail!("the {name} format has no {{{{CONTENT}}}}");
+            }
+        }
+        Ok(())
+    }
+}
+
+// The structure to hold the settings for the built-in mock model.
+// The mock lets the bot be run and tested without downloading any model weights.
+// Any setting that's left out takes its default
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+#[serde(default)]
+pub struct Mock {
+    // What the mock generates
+    pub mode: MockMode,
+    // How many tokens the mock emits per second
+    pub tokens_per_second: f32,
+    // The number of tokens to generate in `lorem` mode
+    pub max_tokens: usize,
+    // The file to replay in `script` mode, with one token per line
+    pub script_path: Option<PathBuf>,
+}
+
+// Implement the Default trait for Mock to provide default values.
+impl Default for Mock {
+    fn default() -> Self {
+        Self {
+            mode: MockMode::Echo,
+            tokens_per_second: 20.0,
+            max_tokens: 64,
+            script_path: None,
+        }
+    }
+}
+
+// The kinds of output the mock model can generate
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MockMode {
+    // Repeats the prompt back, word by word
+    Echo,
+    // Generates random lorem ipsum, chosen deterministically from the seed
+    Lorem,
+    // Replays the tokens from `script_path`
+    Script,
+}
+
+// The samplers tokens can go through, each with its setting in `Sampling`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SamplerType {
+    // Penalizes tokens that came up recently (`repeat_penalty`)
+    RepetitionPenalty,
+    // Keeps the most likely tokens (`top_k`)
+    TopK,
+    // Keeps the most likely tokens that make up a share of the probability (`top_p`)
+    TopP,
+    // Flattens or sharpens the probabilities (`temperature`)
+    Temperature,
 }
 
 // The structure to hold inference-related settings
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Inference {
     // The number of threads to use
     pub thread_count: usize,
@@ -161,17 +883,632 @@ pub struct Inference {
     // controls the size of that batch. Larger values will result in
     // faster inference, but will use more memory.
     pub batch_size: usize,
+    // Whether or not to send generated tokens to Discord in groups of `batch_size`
+    // instead of one at a time. This reduces overhead for fast models.
+    #[serde(default)]
+    pub batch_decode: bool,
+    // The number of generated tokens to send to Discord together, when `batch_decode` is off.
+    // Raising this cuts down on tiny edits for models with very short (e.g. single-character)
+    // tokens, without waiting on a timer
+    #[serde(default = "default_token_buffer_size")]
+    pub token_buffer_size: usize,
+    // Whether or not to store the KV cache in half precision (f16) instead of f32.
+    // This halves the memory the cache takes, at a negligible cost to quality.
+    #[serde(default = "default_f16_kv")]
+    pub f16_kv: bool,
     // Low values will result in you getting throttled by Discord
     pub discord_message_update_interval_ms: u64,
+    // The most times a response's messages are edited per minute while it's generating,
+    // on top of the update interval, so that fast models don't make the message flicker.
+    // Edits that would go over are skipped, and their text is sent with the next one.
+    // 0 means there's no limit
+    #[serde(default = "default_max_discord_edits_per_minute")]
+    pub max_discord_edits_per_minute: u32,
     // Whether or not to replace '\n' with newlines
     pub replace_newlines: bool,
     // Whether or not to show the entire prompt template, or just
     // what the user specified
     pub show_prompt_template: bool,
+    // Whether or not to show the seed a response was generated with at the end of it,
+    // so that it can be reproduced (even if the seed was picked at random)
+    #[serde(default)]
+    pub seed_display: bool,
+    // Whether or not to show how many tokens a response took, how long it took and how
+    // fast it was generated at the end of it
+    #[serde(default)]
+    pub show_generation_metadata: bool,
+    // The longest prompt, in characters once the command's template is filled in, that
+    // will be generated from. Longer prompts are refused before anything is posted.
+    // Commands can set their own limit. There's no limit if this isn't set
+    #[serde(default)]
+    pub max_prompt_chars: Option<usize>,
+    // How long, in seconds, a prompt is remembered after a user submits it, so that the same
+    // prompt from the same user (e.g. from a double-click) isn't generated twice. 0 turns it off
+    #[serde(default = "default_dedup_window_seconds")]
+    pub dedup_window_seconds: u64,
+    // The system prompt (e.g. the bot's persona) for conversations, and for commands that
+    // use one. Guilds can have their own instead
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    // How long generating a token is expected to take, in milliseconds, until requests have
+    // finished to measure it from. Waiting requests are run shortest first by these estimates
+    #[serde(default)]
+    pub n_predict_per_token_ms: u64,
+    // Whether or not to add thumbs up and thumbs down reactions to finished responses,
+    // which users can click to vote on them. Votes are kept in the store
+    #[serde(default)]
+    pub enable_reaction_feedback: bool,
+    // The most turns, and the most tokens, of a conversation's history that go into a prompt.
+    // The oldest turns are left out first; the system prompt and the latest message never are
+    #[serde(default)]
+    pub max_history_turns: Option<usize>,
+    #[serde(default)]
+    pub max_history_tokens: Option<usize>,
+    // A file that every completed generation is also written to, as a line of JSON with
+    // its prompt and response. It's rotated once it's over `stream_file_max_mb` megabytes
+    #[serde(default)]
+    pub stream_to_file: Option<PathBuf>,
+    #[serde(default = "default_stream_file_max_mb")]
+    pub stream_file_max_mb: u32,
+    // Whether or not to remember completed generations in the store, so that a request with
+    // exactly the same prompt and settings gets the remembered output instead of running the
+    // model. This needs `[persistence]` to be enabled
+    #[serde(default)]
+    pub enable_persistent_cache: bool,
+    // How long a remembered generation is reused for, in hours
+    #[serde(default = "default_cache_ttl_hours")]
+    pub cache_ttl_hours: u64,
+    // The pause between the pieces of a remembered generation as it's sent, in milliseconds,
+    // so that it comes in the way a live one would
+    #[serde(default = "default_cache_replay_delay_ms")]
+    pub cache_replay_delay_ms: u64,
+    // How many times a finished response can be rerolled at a higher or lower temperature with
+    // its "Make it weirder" and "More focused" buttons (0 for no buttons)
+    #[serde(default)]
+    pub max_rerolls: usize,
+    // How much each reroll raises or lowers the temperature by
+    #[serde(default = "default_reroll_temperature_delta")]
+    pub reroll_temperature_delta: f32,
+    // The most variations a single use of a command can ask for with its `count` option, each
+    // generated from its own seed (1 to leave the option out)
+    #[serde(default = "default_max_variations")]
+    pub max_variations: usize,
+    // Whether or not to put 👍 and 👎 buttons under finished responses. Anyone can vote once
+    // on each response, for `feedback_voting_hours` after it finishes. Votes are kept in the
+    // store, and `/stats` sums them up
+    #[serde(default)]
+    pub enable_feedback_buttons: bool,
+    #[serde(default = "default_feedback_voting_hours")]
+    pub feedback_voting_hours: u64,
+    // Generation stops once a stretch of `loop_ngram_tokens` tokens has come back more than
+    // `loop_max_repeats` times in a row, at the same spacing, since the model is then stuck
+    // repeating itself (0 repeats to never stop it)
+    #[serde(default = "default_loop_ngram_tokens")]
+    pub loop_ngram_tokens: usize,
+    #[serde(default = "default_loop_max_repeats")]
+    pub loop_max_repeats: usize,
+    // The longest a request can run for, in seconds, however few tokens it has generated.
+    // It's stopped where it is once it has run this long
+    #[serde(default)]
+    pub max_generation_seconds: Option<u64>,
+    // The longest a request can wait for the model, in seconds, before it's dropped and the
+    // user is told to try again. Reminders and scheduled posts wait as long as they need to
+    #[serde(default)]
+    pub max_queue_wait_seconds: Option<u64>,
+    // The order tokens go through the samplers in, before one is picked. A sampler that isn't
+    // listed isn't used
+    #[serde(default = "default_sampler_order")]
+    pub sampler_order: Vec<SamplerType>,
+    // Requests that can generate fewer tokens than this (going by their token limit and what
+    // their prompt leaves of the context) wait in a fast lane that runs first (0 for one lane)
+    #[serde(default = "default_fast_lane_tokens")]
+    pub fast_lane_tokens: usize,
+    // How many fast lane requests run in a row before a waiting slow lane request gets a turn
+    #[serde(default = "default_fast_lane_streak")]
+    pub fast_lane_streak: usize,
+    // Whether or not a small draft model proposes tokens for the model to check, several at a
+    // time, which is faster when the draft usually guesses right. Needs the `speculative`
+    // feature, and a `draft_model` that shares the model's tokenizer
+    // The most text that's taken from the files attached to a message for `{{ATTACHMENTS}}`,
+    // in bytes. Anything past it is cut off
+    #[serde(default = "default_max_attachment_bytes")]
+    pub max_attachment_bytes: usize,
+    #[serde(default)]
+    pub enable_speculative_decoding: bool,
+    // The draft model, loaded alongside the model when speculative decoding is on
+    #[serde(default)]
+    pub draft_model: Option<Model>,
+    // How many tokens the draft model proposes at a time
+    #[serde(default = "default_speculative_tokens")]
+    pub speculative_tokens: usize,
+}
+
+// The default for `Inference::f16_kv`, for configs written before it existed
+fn default_f16_kv() -> bool {
+    true
+}
+
+// The default for `Inference::token_buffer_size`, which sends each token as it comes
+fn default_token_buffer_size() -> usize {
+    1
+}
+
+// The default for `Inference::max_discord_edits_per_minute`
+fn default_max_discord_edits_per_minute() -> u32 {
+    20
+}
+
+// The default for `Inference::stream_file_max_mb`
+fn default_stream_file_max_mb() -> u32 {
+    100
+}
+
+// The default for `Inference::cache_ttl_hours`
+fn default_cache_ttl_hours() -> u64 {
+    24
+}
+
+// The default for `Inference::cache_replay_delay_ms`
+fn default_cache_replay_delay_ms() -> u64 {
+    20
+}
+
+// The default for `Inference::reroll_temperature_delta`
+fn default_reroll_temperature_delta() -> f32 {
+    0.3
+}
+
+// The default for `Inference::max_variations`
+fn default_max_variations() -> usize {
+    3
+}
+
+// The default for `Inference::feedback_voting_hours`
+fn default_feedback_voting_hours() -> u64 {
+    24
+}
+
+// The default for `Inference::loop_ngram_tokens`
+fn default_loop_ngram_tokens() -> usize {
+    8
+}
+
+// The default for `Inference::loop_max_repeats`
+fn default_loop_max_repeats() -> usize {
+    5
+}
+
+// The default for `Inference::fast_lane_tokens`
+fn default_fast_lane_tokens() -> usize {
+    512
+}
+
+// The default for `Inference::fast_lane_streak`
+fn default_fast_lane_streak() -> usize {
+    4
+}
+
+// The default for `Inference::speculative_tokens`
+fn default_speculative_tokens() -> usize {
+    4
+}
+
+// The default for `Inference::max_attachment_bytes`
+fn default_max_attachment_bytes() -> usize {
+    8192
+}
+
+// The default for `Inference::sampler_order`, which is the order `llm` samples in by default
+pub fn default_sampler_order() -> Vec<SamplerType> {
+    vec![
+        SamplerType::RepetitionPenalty,
+        SamplerType::TopK,
+        SamplerType::TopP,
+        SamplerType::Temperature,
+    ]
+}
+
+// The default for `Inference::dedup_window_seconds`
+fn default_dedup_window_seconds() -> u64 {
+    5
+}
+
+// Implementing the additional methods for the Inference structure
+impl Inference {
+    // function to apply the prompt preprocessing settings to a user's prompt.
+    // This is shared between the bot and the offline CLI, so both see the same prompt
+    pub fn preprocess_user_prompt(&self, user_prompt: String) -> String {
+        // Replace '\n' with newlines if specified
+        if self.replace_newlines {
+            user_prompt.replace("\\n", "\n")
+        } else {
+            user_prompt
+        }
+    }
+
+    // function to build the `llm` session settings from the inference settings
+    pub fn session_config(&self) -> llm::InferenceSessionConfig {
+        let memory_type = if self.f16_kv {
+            llm::ModelKVMemoryType::Float16
+        } else {
+            llm::ModelKVMemoryType::Float32
+        };
+
+        llm::InferenceSessionConfig {
+            memory_k_type: memory_type,
+            memory_v_type: memory_type,
+            n_batch: self.batch_size,
+            n_threads: self.thread_count,
+        }
+    }
+
+    // The limits on how much of a conversation's history goes into a prompt
+    pub fn history_window(&self) -> chat::Window {
+        chat::Window {
+            max_turns: self.max_history_turns,
+            max_tokens: self.max_history_tokens,
+        }
+    }
+}
+
+// The structure to hold the settings for the OpenAI-compatible HTTP API
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct HttpApi {
+    // The address the server listens on, e.g. "127.0.0.1:8080"
+    pub bind_address: String,
+    // If set, requests must carry an `Authorization: Bearer <token>` header
+    pub bearer_token: Option<String>,
+    // How long, in seconds, clients have to wait between requests to each endpoint (0 for no
+    // wait). Clients aren't told apart, so they all share it, like one Discord user would
+    #[serde(default)]
+    pub cooldown_seconds: u64,
+}
+
+// The structure to hold the settings for the health/readiness endpoint
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Health {
+    // The address the endpoint listens on, e.g. "0.0.0.0:9090"
+    pub bind_address: String,
+}
+
+// The structure to hold the settings for the fallback backend.
+// This is any server with an OpenAI-compatible `/v1/completions` endpoint
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Fallback {
+    // The base URL of the server, e.g. "https://api.example.com"
+    pub url: String,
+    // The API key to send as a bearer token, if the server needs one
+    pub api_key: Option<String>,
+    // The name of the model to ask the server for
+    pub model: String,
+    // The maximum number of tokens to ask the server to generate
+    pub max_tokens: usize,
+    // Requests go to the fallback once this many are already waiting for the local model
+    pub queue_depth_threshold: usize,
+    // Requests also go to the fallback if they are estimated to wait longer than this
+    pub max_wait_seconds: u64,
+}
+
+// The structure to hold the settings for summarizing conversations. When a conversation's
+// history is over `trigger_tokens`, the turns that don't fit are summarized by the model
+// (behind every other request) and the summary goes into the prompt in their place
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Summarization {
+    // The prompt the summary is generated from. `{{CONVERSATION}}` is replaced by the turns
+    // being summarized, one `role: text` line each
+    #[serde(default = "default_summarization_prompt")]
+    pub prompt: String,
+    // How many tokens of history a conversation can have before it's summarized
+    pub trigger_tokens: usize,
+    // The most tokens a summary can be
+    #[serde(default = "default_summary_tokens")]
+    pub max_tokens: usize,
+}
+
+// The default for `Summarization::prompt`
+fn default_summarization_prompt() -> String {
+    indoc::indoc! {
+        "Summarize the following conversation in a few sentences, keeping any names, facts and decisions.
+
+        {{CONVERSATION}}
+
+        Summary:"
+    }
+    .to_string()
+}
+
+// The default for `Summarization::max_tokens`
+fn default_summary_tokens() -> usize {
+    200
+}
+
+// The structure to hold the settings for the Discord gateway connection
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Gateway {
+    // The number of shards (gateway connections) to run, or "auto" to use as many as
+    // Discord recommends. Discord requires sharding once a bot is in 2,500 guilds
+    pub shards: ShardCount,
+}
+
+// Implement the Default trait for Gateway to provide default values.
+impl Default for Gateway {
+    fn default() -> Self {
+        Self {
+            shards: ShardCount::Fixed(1),
+        }
+    }
+}
+
+// The number of shards to run, written in the config as a number or as "auto"
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum ShardCount {
+    // A fixed number of shards
+    Fixed(u64),
+    // As many shards as Discord recommends
+    Auto(AutoShardCount),
+}
+
+// The "auto" keyword for `ShardCount`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AutoShardCount {
+    Auto,
+}
+
+// The structure to hold the settings for the bot's status.
+// `{active}` is replaced with the number of requests generating, and `{queued}`
+// with the number waiting for the model
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Presence {
+    // The status shown while requests are generating or waiting
+    pub busy: String,
+    // The status shown while there's nothing to do
+    pub idle: String,
+    // The status shown while the model is unloaded for being idle (see `idle_unload_minutes`)
+    #[serde(default = "default_unloaded_presence")]
+    pub unloaded: String,
+}
+
+// Implement the Default trait for Presence to provide default values.
+impl Default for Presence {
+    fn default() -> Self {
+        Self {
+            busy: "Generating for {active} users ({queued} queued)".into(),
+            idle: "Idle — /hallucinate to start".into(),
+            unloaded: default_unloaded_presence(),
+        }
+    }
+}
+
+// The default for `Presence::unloaded`
+fn default_unloaded_presence() -> String {
+    "Asleep — the first request takes a little longer".into()
+}
+
+// Implementing the additional methods for the Presence structure
+impl Presence {
+    // function to fill in the status text for the given number of active and queued requests,
+    // and whether the model is unloaded
+    pub fn render(&self, active: usize, queued: usize, unloaded: bool) -> String {
+        let template = if active == 0 && queued == 0 && unloaded {
+            &self.unloaded
+        } else if active == 0 && queued == 0 {
+            &self.idle
+        } else {
+            &self.busy
+        };
+        template
+            .replace("{active}", &active.to_string())
+            .replace("{queued}", &queued.to_string())
+    }
+}
+
+// The structure to hold the settings for the `/bench` benchmark.
+// Keep these the same between runs whose results are being compared
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Bench {
+    // The prompt to feed the model (the default is roughly 128 tokens long)
+    pub prompt: String,
+    // The number of tokens to generate in each run
+    pub generated_tokens: usize,
+    // The number of times to run the benchmark
+    pub runs: usize,
+    // The seed to sample with, so that every run generates the same text
+    pub seed: u64,
+}
+
+// Implement the Default trait for Bench to provide default values.
+impl Default for Bench {
+    fn default() -> Self {
+        Self {
+            prompt: indoc::indoc! {"
+                The lighthouse keeper had lived alone on the island for eleven years. Every
+                evening he climbed the spiral staircase, trimmed the wick, polished the great
+                lens and wrote the same line in his logbook: all is well. Ships passed in the
+                distance, their lights blinking like slow stars, and none of them ever stopped.
+                Then, one grey morning in late autumn, a small wooden boat drifted onto the
+                rocks below the tower. It carried no crew, no cargo and no name, only a sealed
+                glass jar containing a folded letter addressed to him. He carried the jar up
+                to the kitchen, set it on the table and"
+            }
+            .into(),
+            generated_tokens: 128,
+            runs: 3,
+            seed: 42,
+        }
+    }
+}
+
+// The structure to hold a prompt that's generated and posted to a channel every day
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Schedule {
+    // The name it's listed under by `/schedules`
+    pub name: String,
+    // The time of day to post at, as "HH:MM" in UTC
+    pub at: String,
+    // The channel to post in
+    pub channel_id: u64,
+    // The command whose template the prompt goes into
+    pub command: String,
+    // The prompts to use, one a day in turn
+    pub prompts: Vec<String>,
+}
+
+impl Schedule {
+    // The time of day to post at, in seconds since midnight (UTC)
+    pub fn time_of_day(&self) -> anyhow::Result<u64> {
+        let (hours, minutes) = self
+            .at
+            .split_once(':')
+            .context("`at` must be a time of day like \"08:30\"")?;
+        let (hours, minutes): (u64, u64) = (hours.parse()?, minutes.parse()?);
+        if hours >= 24 || minutes >= 60 {
+            anyhow::bail!("`at` must be a time of day from 00:00 to 23:59");
+        }
+        Ok(hours * 60 * 60 + minutes * 60)
+    }
+
+    // function to check the parts of the schedule that deserializing can't
+    fn validate(&self, commands: &HashMap<String, Command>) -> anyhow::Result<()> {
+        self.time_of_day()?;
+        if self.prompts.is_empty() {
+            anyhow::bail!("there must be at least one prompt");
+        }
+        if !commands.get(&self.command).is_some_and(|c| c.enabled) {
+            anyhow::bail!("there is no enabled command named `{}`", self.command);
+        }
+        Ok(())
+    }
+}
+
+// The structure to hold the settings for exporting responses as files
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Export {
+    // Whether or not exported files are only shown to the user who pressed "Export"
+    pub ephemeral: bool,
+}
+
+// Implement the Default trait for Export to provide default values.
+impl Default for Export {
+    fn default() -> Self {
+        Self { ephemeral: true }
+    }
+}
+
+// The structure to hold the settings for stopping output that shouldn't be shown
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Moderation {
+    // Phrases that stop the generation as soon as the output contains them. Plain phrases
+    // match regardless of case; ones written as `/pattern/` are regular expressions
+    #[serde(default)]
+    pub halt_on_output: Vec<String>,
+    // What the response is replaced with when it's stopped
+    #[serde(default = "default_withheld_notice")]
+    pub withheld_notice: String,
+}
+
+// Implement the Default trait for Moderation to provide default values.
+impl Default for Moderation {
+    fn default() -> Self {
+        Self {
+            halt_on_output: vec![],
+            withheld_notice: default_withheld_notice(),
+        }
+    }
+}
+
+// The default for `Moderation::withheld_notice`
+fn default_withheld_notice() -> String {
+    "*This response was withheld.*".to_string()
+}
+
+impl Moderation {
+    // function to turn `halt_on_output` into regular expressions
+    pub fn halt_patterns(&self) -> anyhow::Result<Vec<regex::Regex>> {
+        self.halt_on_output
+            .iter()
+            .map(|phrase| {
+                let pattern = match phrase.strip_prefix('/').and_then(|p| p.strip_suffix('/')) {
+                    Some(pattern) => pattern.to_string(),
+                    None => format!("(?i){}", regex::escape(phrase)),
+                };
+                regex::Regex::new(&pattern).with_context(|| format!("invalid pattern `{phrase}`"))
+            })
+            .collect()
+    }
+}
+
+// The structure to hold the settings for logging
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct Logging {
+    // The log file that everything is also written to, if any
+    pub file: Option<LogFile>,
+}
+
+// The structure to hold the settings for the rotated log file
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct LogFile {
+    // The path to the log file; rotated files get `.1`, `.2`, ... appended to it
+    pub path: PathBuf,
+    // The size at which the log file is rotated, in bytes
+    #[serde(default = "default_log_max_size_bytes")]
+    pub max_size_bytes: u64,
+    // The number of rotated files to keep, besides the current one
+    #[serde(default = "default_log_max_files")]
+    pub max_files: usize,
+    // The least important level that is written to the file.
+    // This can be changed without restarting, by editing the config and sending SIGHUP
+    #[serde(default)]
+    pub level: logging::Level,
+}
+
+// The default for `LogFile::max_size_bytes`: 10 MiB
+fn default_log_max_size_bytes() -> u64 {
+    10 * 1024 * 1024
+}
+
+// The default for `LogFile::max_files`
+fn default_log_max_files() -> usize {
+    5
+}
+
+// The structure to hold the settings for persistent storage
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Persistence {
+    // Whether or not to keep data in a SQLite database on disk.
+    // If disabled, everything is kept in memory and lost when the bot stops
+    pub enabled: bool,
+    // The path to the SQLite database, created if it doesn't exist
+    pub path: PathBuf,
+}
+
+// Implement the Default trait for Persistence to provide default values.
+impl Default for Persistence {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: "llmcord.sqlite3".into(),
+        }
+    }
+}
+
+// A rough number of characters per token, for estimating the length of a prompt
+// without the model's tokenizer
+pub const CHARS_PER_TOKEN: usize = 4;
+
+// A command's prompt, put together by `Command::assemble_prompt`
+pub struct AssembledPrompt {
+    // The whole prompt, with the system prompt in front
+    pub prompt: String,
+    // The parts of the prompt before and after the user's prompt, so that it can be told
+    // apart from the rest of the output (the system prompt and the examples come before it)
+    pub prefix: String,
+    pub suffix: String,
 }
 
 // The structure to hold command-related settings
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
 pub struct Command {
     // The flag indicating whether the command is enabled or disabled
     pub enabled: bool,
@@ -179,4 +1516,695 @@ pub struct Command {
     pub description: String,
     // This holds the prompts associated with the command
     pub prompt: String,
+    // The Discord permission a member needs to use this command, e.g. "MANAGE_MESSAGES".
+    // Multiple permissions can be required with "MANAGE_MESSAGES | KICK_MEMBERS".
+    #[serde(default, with = "permission_names")]
+    #[schemars(with = "Option<String>")]
+    pub require_permission: Option<Permissions>,
+    // Whether or not to log progress updates (tokens generated, context used)
+    // while this command is generating
+    #[serde(default)]
+    pub log_progress: bool,
+    // Whether or not this command must always run on the local model,
+    // even if the queue is saturated (for prompts that must never leave the box)
+    #[serde(default)]
+    pub local_only: bool,
+    // Whether or not it is an error to use this command without a message to fill in
+    // `{{REPLY}}` (or `{{ATTACHMENTS}}`). If not, they're replaced with nothing
+    #[serde(default)]
+    pub require_reply: bool,
+    // The fewest tokens the model should generate. If it ends the output before then,
+    // the generation is retried with a new random seed, up to `max_retries` times
+    #[serde(default)]
+    pub min_generation_tokens: Option<usize>,
+    // The number of retries for output that's shorter than `min_generation_tokens`
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    // The longest prompt this command will generate from, overriding
+    // `inference.max_prompt_chars`
+    #[serde(default)]
+    pub max_prompt_chars: Option<usize>,
+    // How the finished output is trimmed before it's shown for the last time
+    #[serde(default)]
+    pub trim: OutputTrim,
+    // Whether or not the guild's system prompt goes in front of this command's prompt,
+    // written with the model's chat format
+    #[serde(default)]
+    pub use_system_prompt: bool,
+    // Find-and-replace rules for the output, applied in order, e.g. to remove a preamble
+    // the model always writes. They're applied to everything generated so far every time
+    // the response is shown, so a match split across tokens still gets replaced
+    #[serde(default)]
+    pub output_replacements: Vec<OutputReplacement>,
+    // Whether or not to keep the `<think>...</think>` blocks that chain-of-thought models (like
+    // DeepSeek-R1) write before their answer out of the response, and whether to show them as
+    // a spoiler in front of it instead of leaving them out
+    #[serde(default)]
+    pub strip_thinking_tags: bool,
+    #[serde(default)]
+    pub show_thinking_as_spoiler: bool,
+    // Extra options for the command, each of which fills in a placeholder in the template
+    // (e.g. an option named `tone` fills in `{{TONE}}`)
+    #[serde(default)]
+    pub options: Vec<CommandOption>,
+    // Example inputs and outputs shown to the model before the user's prompt. They go where
+    // the template has `{{EXAMPLES}}`, or right before `{{PROMPT}}` if it doesn't
+    #[serde(default)]
+    pub examples: Vec<CommandExample>,
+    // How each example is written, with `{{INPUT}}` and `{{OUTPUT}}` for its two halves
+    #[serde(default = "default_example_format")]
+    pub example_format: String,
+    // How the output is shown in Discord
+    #[serde(default)]
+    pub response_format: ResponseFormat,
+    // Whether or not to keep the template out of `/prompt`, which then only lists the
+    // command's options
+    #[serde(default)]
+    pub hide_template: bool,
+    // How many different responses to generate for each use of the command (each with its
+    // own seed), which are shown as numbered alternatives
+    #[serde(default = "default_n_sequences")]
+    pub n_sequences: usize,
+    // How long a user has to wait after using the command successfully before they can use it
+    // again, in seconds (0 for no wait)
+    #[serde(default)]
+    pub cooldown_seconds: u64,
+}
+
+// The default for `Command::n_sequences`
+fn default_n_sequences() -> usize {
+    1
+}
+
+// The default for `Command::max_retries`, for commands that only set `min_generation_tokens`
+fn default_max_retries() -> u32 {
+    2
+}
+
+// The default for `Command::example_format`
+fn default_example_format() -> String {
+    "Input: {{INPUT}}\nOutput: {{OUTPUT}}\n\n".to_string()
+}
+// Implementing the additional methods for the Command structure
+impl Command {
+    // The most options a Discord command can have
+    const MAX_DISCORD_OPTIONS: usize = 25;
+
+    // The most alternatives a command can generate, which all have to fit in one embed
+    const MAX_SEQUENCES: usize = 5;
+
+    // Option names that the bot already uses for every command
+    const RESERVED_OPTION_NAMES: [&'static str; 7] = [
+        "prompt",
+        "seed",
+        "prefix",
+        "count",
+        "reply",
+        "examples",
+        "attachments",
+    ];
+
+    // function to put together the prompt that's generated from when this command is used,
+    // the same way wherever it's used from: the system prompt (if the command uses one, and
+    // there is one) written with the model's chat format, then the command's template with
+    // the user's prompt, the content of the message the command was used on (if any), the
+    // command's options and its examples substituted in. `option_values` holds the options
+    // the user gave, by name; the rest use their defaults. It also holds the text of the
+    // message's attachments, under `ATTACHMENTS`. The system prompt is always kept whole, so
+    // it comes out of the room left in the context for the examples
+    pub fn assemble_prompt(
+        &self,
+        config: &Configuration,
+        system_prompt: Option<&str>,
+        user_prompt: &str,
+        reply: Option<&str>,
+        option_values: &HashMap<String, String>,
+    ) -> anyhow::Result<AssembledPrompt> {
+        let system_block = system_prompt
+            .filter(|_| self.use_system_prompt)
+            .map_or_else(String::new, |s| {
+                chat::render_system(&config.model.chat_format.template(), s)
+            });
+        let context_tokens = config
+            .model
+            .effective_context_length()
+            .saturating_sub(system_block.len().div_ceil(CHARS_PER_TOKEN));
+
+        let (values, left_out) =
+            self.placeholder_values(user_prompt, reply, option_values, context_tokens)?;
+        if left_out > 0 {
+            info!(
+                "Leaving out {left_out} of {} examples so that the prompt fits in the context",
+                self.examples.len()
+            );
+        }
+
+        // The parts before and after the user's prompt are both empty if the template
+        // doesn't include it
+        let template = self.template();
+        let (prefix, suffix) = match template.split_once("{{PROMPT}}") {
+            Some((prefix, suffix)) => (
+                system_block.clone() + &render_template(prefix, &values)?,
+                render_template(suffix, &values)?,
+            ),
+            None => Default::default(),
+        };
+
+        Ok(AssembledPrompt {
+            prompt: system_block + &render_template(&template, &values)?,
+            prefix,
+            suffix,
+        })
+    }
+
+    // The names of the placeholders the command's template uses, each once, in order
+    pub fn placeholder_names(&self) -> Vec<&str> {
+        let mut names = vec![];
+        for (_, name) in placeholders(&self.prompt) {
+            if !names.contains(&name) {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    // Whether or not the command gets a `count` option for generating several variations,
+    // which commands with alternatives of their own don't
+    pub fn has_count_option(&self, inference: &Inference) -> bool {
+        inference.max_variations > 1 && self.n_sequences == 1
+    }
+
+    // Whether or not this command's template includes the message it was used on
+    pub fn uses_reply(&self) -> bool {
+        self.prompt.contains("{{REPLY}}")
+    }
+
+    // Whether or not this command's template includes the text files attached to the message
+    // it was used on
+    pub fn uses_attachments(&self) -> bool {
+        self.prompt.contains("{{ATTACHMENTS}}")
+    }
+
+    // The template, with a place for the examples right before the user's prompt
+    // if it doesn't say where they go
+    fn template(&self) -> std::borrow::Cow<'_, str> {
+        if self.examples.is_empty() || self.prompt.contains("{{EXAMPLES}}") {
+            return self.prompt.as_str().into();
+        }
+        self.prompt
+            .replacen("{{PROMPT}}", "{{EXAMPLES}}{{PROMPT}}", 1)
+            .into()
+    }
+
+    // function to write out each of the command's examples in its example format
+    fn render_examples(&self) -> anyhow::Result<Vec<String>> {
+        self.examples
+            .iter()
+            .enumerate()
+            .map(|(index, example)| {
+                let values = HashMap::from([
+                    ("INPUT".to_string(), example.input.clone()),
+                    ("OUTPUT".to_string(), example.output.clone()),
+                ]);
+                render_template(&self.example_format, &values)
+                    .with_context(|| format!("example {index} couldn't be rendered"))
+            })
+            .collect()
+    }
+
+    // function to check that the command's options and template placeholders match up,
+    // and that Discord will accept the options
+    pub fn validate(&self, inference: &Inference) -> anyhow::Result<()> {
+        let mut names = std::collections::HashSet::new();
+        for option in &self.options {
+            let name = option.name.as_str();
+            let valid = (1..=32).contains(&name.len())
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+            if !valid {
+                anyhow::bail!(
+                    "option `{name}` must be 1 to 32 lowercase letters, digits or underscores"
+                );
+            }
+            if Self::RESERVED_OPTION_NAMES.contains(&name) {
+                anyhow::bail!("option `{name}` has a name the bot already uses");
+            }
+            if !names.insert(name) {
+                anyhow::bail!("option `{name}` is declared more than once");
+            }
+            option.default_value()?;
+
+            let placeholder = option.placeholder();
+            if !placeholders(&self.prompt).any(|(_, p)| p == placeholder) {
+                anyhow::bail!(
+                    "option `{name}` is declared, but the prompt has no {{{{{placeholder}}}}}"
+                );
+            }
+        }
+
+        // The prompt, seed and prefix options (and count, if it has one) come on top of the
+        // declared ones
+        let built_in = if self.has_count_option(inference) {
+            4
+        } else {
+            3
+        };
+        if self.options.len() + built_in > Self::MAX_DISCORD_OPTIONS {
+            anyhow::bail!(
+                "a command can have at most {} options, as the prompt, seed, prefix{} options \
+                 take up the rest",
+                Self::MAX_DISCORD_OPTIONS - built_in,
+                if built_in == 4 { " and count" } else { "" }
+            );
+        }
+
+        if !self.examples.is_empty() {
+            let has_place =
+                placeholders(&self.prompt).any(|(_, p)| p == "PROMPT" || p == "EXAMPLES");
+            if !has_place {
+                anyhow::bail!("the command has examples, but the prompt has no {{{{PROMPT}}}} or {{{{EXAMPLES}}}} to put them before");
+            }
+            self.render_examples()?;
+        }
+
+        if !(1..=Self::MAX_SEQUENCES).contains(&self.n_sequences) {
+            anyhow::bail!("n_sequences must be from 1 to {}", Self::MAX_SEQUENCES);
+        }
+
+        for (_, placeholder) in placeholders(&self.prompt) {
+            let declared = matches!(placeholder, "PROMPT" | "REPLY" | "EXAMPLES" | "ATTACHMENTS")
+                || self.options.iter().any(|o| o.placeholder() == placeholder);
+            if !declared {
+                anyhow::bail!(
+                    "the prompt has {{{{{placeholder}}}}}, but there is no option named `{}`",
+                    placeholder.to_lowercase()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    // function to work out what each placeholder in the template is replaced with,
+    // and how many examples were left out to make the prompt fit
+    fn placeholder_values(
+        &self,
+        user_prompt: &str,
+        reply: Option<&str>,
+        option_values: &HashMap<String, String>,
+        context_tokens: usize,
+    ) -> anyhow::Result<(HashMap<String, String>, usize)> {
+        if reply.is_none() && self.require_reply && (self.uses_reply() || self.uses_attachments()) {
+            // The user's mistake, so it isn't reported to the operator
+            return Err(util::user_error(
+                "This command has to be used on a message (from the message's Apps menu).",
+            ));
+        }
+
+        let mut values = HashMap::from([
+            ("PROMPT".to_string(), user_prompt.to_string()),
+            ("REPLY".to_string(), reply.unwrap_or_default().to_string()),
+            (
+                "ATTACHMENTS".to_string(),
+                option_values
+                    .get(constant::value::ATTACHMENTS)
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+        ]);
+        for option in &self.options {
+            let value = match option_values.get(&option.name) {
+                Some(value) => value.clone(),
+                None => match option.default_value()? {
+                    Some(default) => default,
+                    None if option.required => {
+                        return Err(util::user_error(format!(
+                            "The `{}` option is required.",
+                            option.name
+                        )))
+                    }
+                    None => String::new(),
+                },
+            };
+            values.insert(option.placeholder(), value);
+        }
+
+        let (examples, included) = self.fitting_examples(&values, context_tokens)?;
+        values.insert("EXAMPLES".to_string(), examples);
+        Ok((values, self.examples.len() - included))
+    }
+
+    // function to write out as many examples as fit in the context alongside the rest of
+    // the prompt, in order, leaving a quarter of the context (or `min_generation_tokens`,
+    // if that's more) for the response. Later examples are the ones left out.
+    // Returns the examples, and how many of them there are
+    fn fitting_examples(
+        &self,
+        values: &HashMap<String, String>,
+        context_tokens: usize,
+    ) -> anyhow::Result<(String, usize)> {
+        if self.examples.is_empty() {
+            return Ok((String::new(), 0));
+        }
+
+        let mut values = values.clone();
+        values.insert("EXAMPLES".to_string(), String::new());
+        let base_length = render_template(&self.template(), &values)?.len();
+
+        let reserved = (context_tokens / 4).max(self.min_generation_tokens.unwrap_or(0));
+        let budget = context_tokens.saturating_sub(reserved) * CHARS_PER_TOKEN;
+
+        let mut examples = String::new();
+        let mut included = 0;
+        for example in self.render_examples()? {
+            if base_length + examples.len() + example.len() > budget {
+                break;
+            }
+            examples.push_str(&example);
+            included += 1;
+        }
+        Ok((examples, included))
+    }
+}
+
+// An example input and the output the model should give for it
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct CommandExample {
+    pub input: String,
+    pub output: String,
+}
+
+// The structure to hold how a command's finished output is trimmed
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct OutputTrim {
+    // Text that ends the output. The output is cut at the first of these, or at the start
+    // of one that it ends partway through
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    // Whether or not to cut output that ran out of tokens back to its last complete sentence
+    #[serde(default)]
+    pub to_sentence_end: bool,
+}
+
+impl OutputTrim {
+    // Whether or not any trimming is configured. Trailing whitespace is
+    // only removed if it is, so that output is otherwise shown as generated
+    pub fn is_enabled(&self) -> bool {
+        !self.stop_sequences.is_empty() || self.to_sentence_end
+    }
+}
+
+// The structure to hold a find-and-replace rule for a command's output
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct OutputReplacement {
+    // The regular expression to find. `^` matches the start of the output, not the prompt
+    #[serde(with = "regex_pattern")]
+    #[schemars(with = "String")]
+    pub pattern: regex::Regex,
+    // The text to replace each match with, which can refer to groups as `$1` or `${name}`
+    #[serde(default)]
+    pub replacement: String,
+}
+
+// How a command's output is shown in Discord
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq, Eq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseFormat {
+    // As Discord markdown, like the prompt
+    #[default]
+    PlainText,
+    // In a code block, highlighted as the given language (if any)
+    CodeBlock {
+        #[serde(default)]
+        language: String,
+    },
+    // In a JSON code block. The finished output has to be valid JSON, or it's an error
+    Json,
+}
+
+impl ResponseFormat {
+    // The language of the code block the output is shown in, if it's shown in one
+    pub fn code_block_language(&self) -> Option<&str> {
+        match self {
+            ResponseFormat::PlainText => None,
+            ResponseFormat::CodeBlock { language } => Some(language),
+            ResponseFormat::Json => Some("json"),
+        }
+    }
+}
+
+// The type of value a command option takes
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandOptionKind {
+    #[default]
+    String,
+    Integer,
+    Number,
+    Boolean,
+}
+
+// An extra option of a command, declared in the config
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct CommandOption {
+    // The option's name in Discord. The placeholder it fills in is this in upper case
+    pub name: String,
+    // The description shown in Discord; the name is used if this isn't set
+    #[serde(default)]
+    pub description: Option<String>,
+    // The type of value the option takes
+    #[serde(rename = "type", default)]
+    pub kind: CommandOptionKind,
+    // The value used when the option isn't given. Options without a default
+    // are replaced with nothing, unless they're required
+    #[serde(default)]
+    #[schemars(with = "Option<serde_json::Value>")]
+    pub default: Option<toml::Value>,
+    // Whether or not the option has to be given
+    #[serde(default)]
+    pub required: bool,
+}
+
+impl CommandOption {
+    // The placeholder this option fills in, without the braces
+    pub fn placeholder(&self) -> String {
+        self.name.to_uppercase()
+    }
+
+    // function to get the option's default as text, checking that it matches the option's type
+    fn default_value(&self) -> anyhow::Result<Option<String>> {
+        use toml::Value;
+
+        let Some(default) = &self.default else {
+            return Ok(None);
+        };
+        let value = match (self.kind, default) {
+            (CommandOptionKind::String, Value::String(s)) => s.clone(),
+            (CommandOptionKind::Integer, Value::Integer(i)) => i.to_string(),
+            (CommandOptionKind::Number, Value::Float(f)) => f.to_string(),
+            (CommandOptionKind::Number, Value::Integer(i)) => i.to_string(),
+            (CommandOptionKind::Boolean, Value::Boolean(b)) => b.to_string(),
+            (kind, _) => anyhow::bail!("the default of option `{}` isn't a {kind:?}", self.name),
+        };
+        Ok(Some(value))
+    }
+}
+
+// function to find the placeholders in a template, with their positions.
+// A placeholder is an upper-case name in double braces (e.g. `{{PROMPT}}`); anything else
+// in braces is left alone, so that templates can still contain braces of their own
+fn placeholders(template: &str) -> impl Iterator<Item = (std::ops::Range<usize>, &str)> {
+    let mut position = 0;
+    std::iter::from_fn(move || loop {
+        let start = position + template[position..].find("{{")?;
+        let name_start = start + 2;
+        let name_end = name_start + template[name_start..].find("}}")?;
+        let name = &template[name_start..name_end];
+
+        let is_placeholder = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+        if is_placeholder {
+            position = name_end + 2;
+            return Some((start..position, name));
+        }
+        position = start + 1;
+    })
+}
+
+// function to replace each placeholder in a template with its value, in a single pass,
+// so that values containing placeholders (e.g. a prompt with `{{REPLY}}` in it) stay as
+// they are. It's an error for a placeholder to have no value
+fn render_template(template: &str, values: &HashMap<String, String>) -> anyhow::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut position = 0;
+    for (range, name) in placeholders(template) {
+        let value = values
+            .get(name)
+            .with_context(|| format!("the prompt has an unresolved placeholder {{{{{name}}}}}"))?;
+        rendered.push_str(&template[position..range.start]);
+        rendered.push_str(value);
+        position = range.end;
+    }
+    rendered.push_str(&template[position..]);
+    Ok(rendered)
+}
+
+// Serialization of permissions by name (e.g. "MANAGE_MESSAGES"), rather than
+// the raw bitfield number that serenity uses, so that the config is readable
+mod permission_names {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use serenity::model::Permissions;
+
+    // function to get the config name of a single permission, e.g. "MANAGE_MESSAGES"
+    fn name_of(permission: Permissions) -> Option<String> {
+        permission
+            .get_permission_names()
+            .first()
+            .map(|name| name.to_uppercase().replace(' ', "_"))
+    }
+
+    // function to parse permission names separated by `|` into a set of permissions
+    fn parse(names: &str) -> Result<Permissions, String> {
+        let mut permissions = Permissions::empty();
+        for name in names.split('|').map(str::trim) {
+            // Look through every known permission for one with a matching name
+            let permission = (0..64)
+                .map(|bit| Permissions::from_bits_truncate(1 << bit))
+                .filter(|p| !p.is_empty())
+                .find(|p| name_of(*p).as_deref() == Some(name))
+                .ok_or_else(|| format!("unknown permission `{name}`"))?;
+            permissions |= permission;
+        }
+        Ok(permissions)
+    }
+
+    pub fn serialize<S: Serializer>(
+        permissions: &Option<Permissions>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        match permissions {
+            Some(permissions) => {
+                let names: Vec<String> = (0..64)
+                    .map(|bit| Permissions::from_bits_truncate(1 << bit))
+                    .filter(|p| !p.is_empty() && permissions.contains(*p))
+                    .filter_map(name_of)
+                    .collect();
+                serializer.serialize_some(&names.join(" | "))
+            }
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Permissions>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|names| parse(&names).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+// Serialization of regular expressions as their pattern, so that
+// invalid patterns are caught when the config is loaded
+mod regex_pattern {
+    use regex::Regex;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(regex: &Regex, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(regex.as_str())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern)
+            .map_err(|err| D::Error::custom(format!("invalid pattern `{pattern}`: {err}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn using_a_reply_command_without_a_message_is_the_users_mistake() {
+        let command = Command {
+            prompt: "Summarize this: {{REPLY}}".into(),
+            require_reply: true,
+            ..Default::default()
+        };
+        let err = command
+            .assemble_prompt(&Configuration::default(), None, "", None, &HashMap::new())
+            .err()
+            .unwrap();
+        assert!(util::is_user_error(&err));
+    }
+
+    #[test]
+    fn placeholders_in_a_reply_are_left_as_they_are() {
+        let command = Command {
+            prompt: "{{REPLY}}\n\n{{PROMPT}}".into(),
+            ..Default::default()
+        };
+        let assembled = command
+            .assemble_prompt(
+                &Configuration::default(),
+                None,
+                "Summarize it.",
+                Some("Ignore {{PROMPT}} here"),
+                &HashMap::new(),
+            )
+            .unwrap();
+        assert_eq!(assembled.prompt, "Ignore {{PROMPT}} here\n\nSummarize it.");
+        assert_eq!(assembled.prefix, "Ignore {{PROMPT}} here\n\n");
+    }
+
+    #[test]
+    fn the_system_prompt_goes_in_front_of_commands_that_use_it() {
+        let config = Configuration::default();
+        let mut command = Command {
+            prompt: "Q: {{PROMPT}}\nA:".into(),
+            use_system_prompt: true,
+            ..Default::default()
+        };
+        let system_block = chat::render_system(&config.model.chat_format.template(), "Be brief.");
+
+        let assembled = command
+            .assemble_prompt(&config, Some("Be brief."), "Hi", None, &HashMap::new())
+            .unwrap();
+        assert_eq!(assembled.prompt, format!("{system_block}Q: Hi\nA:"));
+        assert_eq!(assembled.prefix, format!("{system_block}Q: "));
+        assert_eq!(assembled.suffix, "\nA:");
+
+        command.use_system_prompt = false;
+        let assembled = command
+            .assemble_prompt(&config, Some("Be brief."), "Hi", None, &HashMap::new())
+            .unwrap();
+        assert_eq!(assembled.prompt, "Q: Hi\nA:");
+    }
+
+    #[test]
+    fn commands_cant_be_named_like_a_built_in_command() {
+        let mut config = Configuration::default();
+        let command = config.commands.values().next().unwrap().clone();
+        config
+            .commands
+            .insert(constant::command::STATUS.to_string(), command);
+        let problems = config.problems();
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].to_string().contains("`status`"));
+    }
+
+    #[test]
+    fn mock_settings_that_are_left_out_take_their_defaults() {
+        let mock: Mock = toml::from_str("mode = \"lorem\"").unwrap();
+        assert_eq!(mock.mode, MockMode::Lorem);
+        assert_eq!(mock.max_tokens, Mock::default().max_tokens);
+        assert!(mock.script_path.is_none());
+
+        let mock: Mock = toml::from_str("").unwrap();
+        assert_eq!(mock.mode, MockMode::Echo);
+    }
 }
@@ -0,0 +1,66 @@
+// This file holds the owner-only `/config-validate` command.
+// It checks `config.toml` as it is on disk right now, the same way it's checked when the bot
+// starts, so that an edited config can be checked before restarting with it. Nothing is
+// applied; the running bot keeps the config it started with.
+use serenity::{
+    builder::CreateApplicationCommand,
+    http::Http,
+    model::{
+        prelude::interaction::{
+            application_command::ApplicationCommandInteraction, InteractionResponseType,
+        },
+        Permissions,
+    },
+};
+
+use crate::{bench, config::Configuration, constant, util};
+
+// The most characters of problems listed, so that the reply fits in one message
+const MAX_REPLY_CHARS: usize = 1900;
+
+// function to handle `/config-validate`
+pub async fn config_validate(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+) -> anyhow::Result<()> {
+    if !bench::is_owner(http, cmd).await? {
+        return Err(util::user_error(
+            "Only the bot's owner can use /config-validate.",
+        ));
+    }
+
+    let problems = Configuration::check_file();
+    let content = if problems.is_empty() {
+        "✅ Config is valid".to_string()
+    } else {
+        let mut content = format!("❌ Config has {} problem(s):", problems.len());
+        for (i, problem) in problems.iter().enumerate() {
+            let line = format!("\n- {problem:#}");
+            if content.len() + line.len() > MAX_REPLY_CHARS {
+                content += &format!("\n…and {} more", problems.len() - i);
+                break;
+            }
+            content += &line;
+        }
+        content
+    };
+
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| d.content(content).ephemeral(true))
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to build the `/config-validate` command, for registering with Discord
+pub fn command() -> CreateApplicationCommand {
+    let mut config_validate = CreateApplicationCommand::default();
+    config_validate
+        .name(constant::command::CONFIG_VALIDATE)
+        .description("Checks config.toml on disk without applying it (owner only).")
+        // Hidden from everyone but administrators; the owner check happens when it's used
+        .default_member_permissions(Permissions::ADMINISTRATOR);
+    config_validate
+}
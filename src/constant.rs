@@ -5,4 +5,107 @@ pub mod value {
 
     // This constant represents the key used for seeds in interactions
     pub const SEED: &str = "seed";
+
+    // This constant represents the key used for text that the response starts with
+    pub const PREFIX: &str = "prefix";
+
+    // This constant represents the key used for the command to run in `/remind`
+    pub const COMMAND: &str = "command";
+
+    // This constant represents the key used for the delay in `/remind`
+    pub const IN_MINUTES: &str = "in_minutes";
+
+    // These constants represent the keys used for the two texts compared by `/similar`
+    pub const FIRST: &str = "first";
+    pub const SECOND: &str = "second";
+
+    // These constants represent the subcommands of `/embed`
+    pub const STORE: &str = "store";
+    pub const QUERY: &str = "query";
+
+    // These constants represent the keys used for the options of `/embed`. `COUNT` is also
+    // the number of variations the configured commands generate
+    pub const NAME: &str = "name";
+    pub const TEXT: &str = "text";
+    pub const COUNT: &str = "count";
+
+    // These constants represent the subcommands of `/system` and `/persona`
+    pub const SET: &str = "set";
+    pub const SHOW: &str = "show";
+    pub const CLEAR: &str = "clear";
+
+    // This constant represents the key used for the sample prompt in `/prompt`
+    pub const SAMPLE: &str = "sample";
+
+    // These constants represent the keys used for the options of `/debug_generate`
+    pub const MAX_TOKENS: &str = "max_tokens";
+    pub const CANDIDATES: &str = "candidates";
+
+    // This constant represents the key that the text of a message's attachments is rendered
+    // into templates under, alongside the options (so no option can have it as its name)
+    pub const ATTACHMENTS: &str = "attachments";
+}
+
+// names of the built-in commands, which exist alongside the ones in the config
+pub mod command {
+    // This constant is the name of the command that schedules a generation for later
+    pub const REMIND: &str = "remind";
+
+    // This constant is the name of the owner-only command that benchmarks the model
+    pub const BENCH: &str = "bench";
+
+    // This constant is the name of the owner-only command that checks `config.toml` on disk
+    pub const CONFIG_VALIDATE: &str = "config-validate";
+
+    // This constant is the name of the owner-only command that lists the scheduled posts
+    pub const SCHEDULES: &str = "schedules";
+
+    // This constant is the name of the command that compares two pieces of text
+    pub const SIMILAR: &str = "similar";
+
+    // This constant is the name of the command that stores and searches named snippets
+    pub const EMBED: &str = "embed";
+
+    // This constant is the name of the command that shows how busy the bot is
+    pub const STATUS: &str = "status";
+
+    // This constant is the name of the command that gives a link for adding the bot to a server
+    pub const INVITE: &str = "invite";
+
+    // This constant is the name of the command that shows how responses have been voted on
+    pub const STATS: &str = "stats";
+
+    // This constant is the name of the admin-only command that sets the guild's system prompt
+    pub const SYSTEM: &str = "system";
+
+    // This constant is the name of the command that chooses the channel's persona
+    pub const PERSONA: &str = "persona";
+
+    // This constant is the name of the command that shows a command's template
+    pub const PROMPT: &str = "prompt";
+
+    // This constant is the name of the command that lists the running and waiting requests
+    pub const QUEUE: &str = "queue";
+
+    // This constant is the name of the owner-only command that traces the likeliest tokens
+    pub const DEBUG_GENERATE: &str = "debug_generate";
+
+    // This constant holds the names of all of the built-in commands, which the commands in
+    // the config can't have
+    pub const ALL: [&str; 14] = [
+        REMIND,
+        BENCH,
+        CONFIG_VALIDATE,
+        SCHEDULES,
+        SIMILAR,
+        EMBED,
+        STATUS,
+        INVITE,
+        STATS,
+        SYSTEM,
+        PERSONA,
+        PROMPT,
+        QUEUE,
+        DEBUG_GENERATE,
+    ];
 }
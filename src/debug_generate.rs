@@ -0,0 +1,351 @@
+// This file holds the owner-only `/debug_generate` command, for tuning the samplers.
+// It runs a short generation from a prompt (as it is, without a command's template) through
+// the normal generation queue, recording the most likely tokens at each step and how likely
+// the model thought each was, and replies with the trace as a JSON file. The probabilities are
+// the model's own, before the samplers (temperature, top-k and so on) changed them, so they
+// show what it almost said. Recording them means generating token by token outside of
+// `llm`'s own loop, which only requests with a trace do.
+use std::{borrow::Cow, time::Instant};
+
+use serde::Serialize;
+use serde_json::json;
+use serenity::{
+    builder::CreateApplicationCommand,
+    http::Http,
+    model::{
+        prelude::{
+            command::CommandOptionType,
+            interaction::{
+                application_command::ApplicationCommandInteraction, InteractionResponseType,
+            },
+            AttachmentType,
+        },
+        Permissions,
+    },
+};
+
+use crate::{
+    bench,
+    config::Configuration,
+    constant,
+    generation::{self, InferenceError, Token},
+    util,
+};
+
+// The most tokens a trace can have, so that the file stays readable
+const MAX_TRACE_TOKENS: usize = 200;
+
+// How many tokens a trace has, and how many candidates each step has, unless asked otherwise
+const DEFAULT_TRACE_TOKENS: usize = 32;
+const DEFAULT_CANDIDATES: usize = 5;
+
+// The most candidates each step can have
+const MAX_CANDIDATES: usize = 20;
+
+// What a request's trace records, and where each step goes
+#[derive(Clone)]
+pub struct Trace {
+    // How many of the most likely tokens are recorded at each step
+    pub candidates: usize,
+    pub step_tx: flume::Sender<Step>,
+}
+
+// One generated token, with the tokens the model thought most likely in its place
+#[derive(Serialize, Debug)]
+pub struct Step {
+    pub token: String,
+    pub probability: f32,
+    // Where the token ranked among every token, from 1 for the most likely
+    pub rank: usize,
+    pub candidates: Vec<Candidate>,
+}
+
+// A token the model could have generated, and how likely it thought it was
+#[derive(Serialize, Debug)]
+pub struct Candidate {
+    pub token: String,
+    pub probability: f32,
+}
+
+// function to handle `/debug_generate`
+pub async fn debug_generate(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    request_tx: &flume::Sender<generation::Request>,
+    config: &Configuration,
+    shard_id: u64,
+) -> anyhow::Result<()> {
+    if !bench::is_owner(http, cmd).await? {
+        return Err(util::user_error(
+            "Only the bot's owner can use /debug_generate.",
+        ));
+    }
+    if config.model.is_mock() {
+        return Err(util::user_error(
+            "The mock model has no probabilities to show.",
+        ));
+    }
+
+    let options = &cmd.data.options;
+    let prompt = util::get_value(options, constant::value::PROMPT)
+        .and_then(util::value_to_string)
+        .ok_or_else(|| util::user_error("A prompt is required."))?;
+    let option = |name, default: usize, maximum: usize| {
+        util::get_value(options, name)
+            .and_then(util::value_to_integer)
+            .map_or(default, |v| (v.max(1) as usize).min(maximum))
+    };
+    let tokens = option(
+        constant::value::MAX_TOKENS,
+        DEFAULT_TRACE_TOKENS,
+        MAX_TRACE_TOKENS,
+    );
+    let candidates = option(
+        constant::value::CANDIDATES,
+        DEFAULT_CANDIDATES,
+        MAX_CANDIDATES,
+    );
+    let seed = util::get_value(options, constant::value::SEED)
+        .and_then(util::value_to_integer)
+        .map(|seed| seed as u64);
+
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|m| {
+                m.content(format!("Tracing up to {tokens} tokens..."))
+                    .ephemeral(true)
+            })
+    })
+    .await?;
+    let message_id = cmd.get_interaction_response(http).await?.id;
+
+    let (token_tx, token_rx) = flume::unbounded();
+    let (completion_tx, completion_rx) = flume::bounded(1);
+    let (step_tx, step_rx) = flume::unbounded();
+    request_tx.send(generation::Request {
+        prompt: prompt.clone(),
+        batch_size: config.inference.batch_size,
+        batch_decode: false,
+        token_buffer_size: 1,
+        token_tx,
+        message_id,
+        seed,
+        maximum_token_count: Some(tokens),
+        n_sequences: 1,
+        sampling: Default::default(),
+        low_priority: false,
+        echo_prompt: false,
+        context: generation::RequestContext {
+            guild_id: cmd.guild_id.map(|id| id.0),
+            channel_id: cmd.channel_id.0,
+            user_id: cmd.user.id.0,
+            command_name: cmd.data.name.clone(),
+            shard_id: Some(shard_id),
+        },
+        progress_tx: None,
+        completion_tx: Some(completion_tx),
+        trace: Some(Trace {
+            candidates,
+            step_tx,
+        }),
+        queued_at: Instant::now(),
+    })?;
+
+    // The trace is only sent once it's whole, rather than streamed
+    while let Ok(token) = token_rx.recv_async().await {
+        if let Token::Error(err) = token {
+            return Err(err.into());
+        }
+    }
+    let completion = completion_rx.try_recv()?;
+    let steps: Vec<Step> = step_rx.try_iter().collect();
+
+    let trace = json!({
+        "prompt": prompt,
+        "seed": completion.seed,
+        "sampler_order": config.inference.sampler_order,
+        "stop_reason": completion.stop_reason.to_string(),
+        "text": completion.text,
+        "steps": steps,
+    });
+    let data = serde_json::to_string_pretty(&trace)?;
+    cmd.create_followup_message(http, |m| {
+        m.content(format!("Traced {} tokens.", steps.len()))
+            .add_file(AttachmentType::Bytes {
+                data: Cow::Owned(data.into_bytes()),
+                filename: format!("trace-{message_id}.json"),
+            })
+            .ephemeral(true)
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to feed a request's prompt and generate from it, like
+// `llm::InferenceSession::infer`, but a token at a time, recording each step of the trace
+pub fn infer(
+    model: &dyn llm::Model,
+    session: &mut llm::InferenceSession,
+    rng: &mut impl rand::Rng,
+    request: &generation::Request,
+    params: &llm::InferenceParameters,
+    trace: &Trace,
+    mut callback: impl FnMut(llm::InferenceResponse) -> Result<llm::InferenceFeedback, InferenceError>,
+) -> Result<llm::InferenceStats, llm::InferenceError> {
+    let callback_error = |e| llm::InferenceError::UserCallback(Box::new(e));
+    let vocabulary = model.tokenizer().len();
+    let mut stats = llm::InferenceStats::default();
+    let mut text = generation::Utf8Buffer::default();
+
+    let started = Instant::now();
+    let mut halted = false;
+    let mut output = llm::OutputRequest {
+        all_logits: Some(vec![]),
+        ..Default::default()
+    };
+    session.feed_prompt(model, &request.prompt, &mut output, |bytes| {
+        let Some(token) = text.push(bytes) else {
+            return Ok::<_, InferenceError>(llm::InferenceFeedback::Continue);
+        };
+        let feedback = callback(llm::InferenceResponse::PromptToken(token))?;
+        halted = matches!(feedback, llm::InferenceFeedback::Halt);
+        Ok(feedback)
+    })?;
+    stats.prompt_tokens = session.tokens().len();
+    stats.feed_prompt_duration = started.elapsed();
+    if halted {
+        return Ok(stats);
+    }
+
+    // The logits the next token is picked from, which the model gives after reading the token
+    // before it
+    let mut logits = last_row(output.all_logits.unwrap_or_default(), vocabulary);
+    let maximum = request
+        .maximum_token_count
+        .unwrap_or(MAX_TRACE_TOKENS)
+        .min(MAX_TRACE_TOKENS);
+    let started = Instant::now();
+    while stats.predict_tokens < maximum {
+        let mut output = llm::OutputRequest {
+            all_logits: Some(vec![]),
+            ..Default::default()
+        };
+        let bytes = match session.infer_next_token(model, params, &mut output, rng) {
+            Ok(bytes) => Some(bytes),
+            Err(llm::InferenceError::EndOfText) => None,
+            Err(e) => return Err(e),
+        };
+        let token = match bytes {
+            Some(_) => *session.tokens().last().expect("the token was just added"),
+            None => model.eot_token_id(),
+        };
+        stats.predict_tokens += 1;
+        trace
+            .step_tx
+            .send(step(model, &logits, token, trace.candidates))
+            .ok();
+
+        let Some(bytes) = bytes else {
+            callback(llm::InferenceResponse::EotToken).map_err(callback_error)?;
+            break;
+        };
+        if let Some(text) = text.push(&bytes) {
+            let feedback =
+                callback(llm::InferenceResponse::InferredToken(text)).map_err(callback_error)?;
+            if matches!(feedback, llm::InferenceFeedback::Halt) {
+                break;
+            }
+        }
+        logits = last_row(output.all_logits.unwrap_or_default(), vocabulary);
+    }
+    stats.predict_duration = started.elapsed();
+
+    Ok(stats)
+}
+
+// function to take the logits after the last token read, out of the logits after every token
+fn last_row(mut logits: Vec<f32>, vocabulary: usize) -> Vec<f32> {
+    logits.split_off(logits.len().saturating_sub(vocabulary))
+}
+
+// function to record a step of the trace: the token picked, and the most likely tokens by the
+// model's own probabilities
+fn step(model: &dyn llm::Model, logits: &[f32], token: llm::TokenId, candidates: usize) -> Step {
+    let probabilities = softmax(logits);
+    let text = |id: usize| String::from_utf8_lossy(&model.tokenizer().token(id)).into_owned();
+
+    let mut ranked: Vec<_> = probabilities.iter().copied().enumerate().collect();
+    ranked.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+    let probability = probabilities.get(token as usize).copied().unwrap_or(0.0);
+    Step {
+        token: text(token as usize),
+        probability,
+        rank: ranked.iter().filter(|(_, p)| *p > probability).count() + 1,
+        candidates: ranked
+            .into_iter()
+            .take(candidates)
+            .map(|(id, probability)| Candidate {
+                token: text(id),
+                probability,
+            })
+            .collect(),
+    }
+}
+
+// function to turn logits into probabilities
+fn softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exps: Vec<f32> = logits.iter().map(|l| (l - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+// function to build the `/debug_generate` command, for registering with Discord
+pub fn command() -> CreateApplicationCommand {
+    let mut debug_generate = CreateApplicationCommand::default();
+    debug_generate
+        .name(constant::command::DEBUG_GENERATE)
+        .description("Traces the most likely tokens at each step of a generation (owner only).")
+        // Hidden from everyone but administrators; the owner check happens when it's used
+        .default_member_permissions(Permissions::ADMINISTRATOR)
+        .create_option(|opt| {
+            opt.name(constant::value::PROMPT)
+                .description("The prompt, as it's given to the model.")
+                .kind(CommandOptionType::String)
+                .required(true)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::MAX_TOKENS)
+                .description("How many tokens to generate.")
+                .kind(CommandOptionType::Integer)
+                .min_int_value(1)
+                .max_int_value(MAX_TRACE_TOKENS)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::CANDIDATES)
+                .description("How many of the most likely tokens to show at each step.")
+                .kind(CommandOptionType::Integer)
+                .min_int_value(1)
+                .max_int_value(MAX_CANDIDATES)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::SEED)
+                .description("The seed to sample with.")
+                .kind(CommandOptionType::Integer)
+        });
+    debug_generate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn softmax_gives_probabilities() {
+        let probabilities = softmax(&[2.0, 1.0, 0.0]);
+        assert!((probabilities.iter().sum::<f32>() - 1.0).abs() < 1e-6);
+        assert!(probabilities[0] > probabilities[1] && probabilities[1] > probabilities[2]);
+        assert!((probabilities[0] / probabilities[1] - std::f32::consts::E).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,173 @@
+// This file holds the "ⓘ" button on finished responses.
+// The footer only has room for a little, so pressing it shows everything the response was
+// generated with: the command and its template, the model, the sampler settings, the seed,
+// the token counts, why it stopped and how long it took. It's shown only to whoever pressed
+// it, who can be anyone. The details are remembered with the response for exporting, so
+// they're only available while it is.
+use std::time::Duration;
+
+use serenity::{
+    builder::CreateActionRow,
+    http::Http,
+    model::prelude::{
+        component::ButtonStyle,
+        interaction::{message_component::MessageComponentInteraction, InteractionResponseType},
+        MessageId,
+    },
+};
+use sha2::{Digest, Sha256};
+
+use crate::{config, export, generation};
+
+// The prefix of the button's custom ID; the first message's ID follows it
+pub const BUTTON_PREFIX: &str = "details";
+
+// How many hex digits of the template's hash are shown, which is plenty to tell them apart
+const TEMPLATE_HASH_DIGITS: usize = 12;
+
+// What a finished response was generated with, and how the generation went
+#[derive(Clone)]
+pub struct GenerationRecord {
+    // The command that was used
+    pub command_name: String,
+    // A hash of the command's template, to tell whether it has changed since
+    pub template_hash: String,
+    // The model's file and architecture
+    pub model: String,
+    // The sampler settings, with the defaults filled in
+    pub sampling: config::Sampling,
+    // The seed the response was generated with, if it's known
+    pub seed: Option<u64>,
+    // Why the generation stopped and its statistics. `None` if the fallback backend served
+    // it, which doesn't report them
+    pub outcome: Option<(generation::StopReason, llm::InferenceStats)>,
+}
+
+impl GenerationRecord {
+    // function to record how a response was generated
+    pub fn new(
+        command_name: &str,
+        command: &config::Command,
+        model: &config::Model,
+        sampling: config::Sampling,
+        seed: Option<u64>,
+        completion: Option<&generation::Completion>,
+    ) -> Self {
+        let template_hash = format!("{:x}", Sha256::digest(command.prompt.as_bytes()));
+        let model_file = model.path.file_name().map_or_else(
+            || model.path.display().to_string(),
+            |f| f.to_string_lossy().into(),
+        );
+
+        Self {
+            command_name: command_name.to_string(),
+            template_hash: template_hash[..TEMPLATE_HASH_DIGITS].to_string(),
+            model: format!("{model_file} ({})", model.architecture),
+            sampling: config::Sampling {
+                temperature: Some(
+                    sampling
+                        .temperature
+                        .unwrap_or(generation::DEFAULT_TEMPERATURE),
+                ),
+                top_k: Some(sampling.top_k.unwrap_or(generation::DEFAULT_TOP_K)),
+                top_p: Some(sampling.top_p.unwrap_or(generation::DEFAULT_TOP_P)),
+                repeat_penalty: Some(
+                    sampling
+                        .repeat_penalty
+                        .unwrap_or(generation::DEFAULT_REPEAT_PENALTY),
+                ),
+            },
+            seed,
+            outcome: completion.map(|c| (c.stop_reason, c.stats)),
+        }
+    }
+
+    // function to write out the details for Discord
+    fn describe(&self) -> String {
+        let sampling = &self.sampling;
+        let mut lines = vec![
+            "**Generation details**".to_string(),
+            format!(
+                "**Command:** `/{}` · template `{}`",
+                self.command_name, self.template_hash
+            ),
+            format!("**Model:** `{}`", self.model),
+            format!(
+                "**Sampling:** temperature {:.2} · top-k {} · top-p {:.2} · repeat penalty {:.2}",
+                sampling.temperature.unwrap_or_default(),
+                sampling.top_k.unwrap_or_default(),
+                sampling.top_p.unwrap_or_default(),
+                sampling.repeat_penalty.unwrap_or_default()
+            ),
+            format!(
+                "**Seed:** {}",
+                self.seed.map_or("unknown".to_string(), |s| s.to_string())
+            ),
+        ];
+
+        match &self.outcome {
+            Some((stop_reason, stats)) => {
+                let predict_seconds = stats.predict_duration.as_secs_f32();
+                lines.push(format!(
+                    "**Tokens:** {} in the prompt, {} generated · stopped on {stop_reason}",
+                    stats.prompt_tokens, stats.predict_tokens
+                ));
+                lines.push(format!(
+                    "**Timing:** {} feeding the prompt, {} generating ({:.1} tok/s)",
+                    format_seconds(stats.feed_prompt_duration),
+                    format_seconds(stats.predict_duration),
+                    if predict_seconds > 0.0 {
+                        stats.predict_tokens as f32 / predict_seconds
+                    } else {
+                        0.0
+                    }
+                ));
+            }
+            None => lines.push(
+                "Served by the fallback backend, which doesn't report token counts or timing."
+                    .to_string(),
+            ),
+        }
+
+        lines.join("\n")
+    }
+}
+
+// function to write out a duration in seconds, e.g. "1.5s"
+fn format_seconds(duration: Duration) -> String {
+    format!("{:.1}s", duration.as_secs_f32())
+}
+
+// function to add the button to the row of buttons under a finished response
+pub fn create_button(row: &mut CreateActionRow, first_id: MessageId) {
+    row.create_button(|b| {
+        b.custom_id(format!("{BUTTON_PREFIX}#{first_id}"))
+            .style(ButtonStyle::Secondary)
+            .label("ⓘ")
+    });
+}
+
+// function to handle a press of the button, by showing the details to whoever pressed it
+pub async fn show(
+    cmp: &MessageComponentInteraction,
+    http: &Http,
+    transcripts: &export::Transcripts,
+    first_id: MessageId,
+) -> anyhow::Result<()> {
+    let content = transcripts.record(first_id).map_or_else(
+        || "The details of this response are no longer available.".to_string(),
+        |record| record.describe(),
+    );
+
+    cmp.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| {
+                d.content(content)
+                    .ephemeral(true)
+                    .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse())
+            })
+    })
+    .await?;
+
+    Ok(())
+}
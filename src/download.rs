@@ -0,0 +1,284 @@
+// This file holds the model download, set with `download_url` (and `sha256`) under `[model]`.
+// If the model file isn't where the config says, it's downloaded from the URL first, logging
+// its progress. It goes to a `.part` file next to it, which is only renamed into place once
+// its checksum has been verified, so a file at the configured path is always a whole one. An
+// interrupted download is resumed from the `.part` file if the server supports it, and started
+// over if it doesn't. `--download-only` fetches the file and exits, for container builds.
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use tokio::io::AsyncWriteExt;
+
+use crate::{checksum, config};
+
+// How often progress is logged, at most
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+// Why a download failed, kept apart so that it's clear what to do about it
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    // The server couldn't be reached, or the connection dropped
+    #[error("network error while downloading {url}: {source}")]
+    Network { url: String, source: reqwest::Error },
+    // The server answered, but not with the file
+    #[error("the server answered {status} for {url}")]
+    Status {
+        url: String,
+        status: reqwest::StatusCode,
+    },
+    // The disk filled up
+    #[error(
+        "ran out of disk space writing {}; free some space and run again to resume",
+        path.display()
+    )]
+    DiskFull { path: PathBuf },
+    // Anything else that went wrong writing the file
+    #[error("failed to write {}: {source}", path.display())]
+    Disk { path: PathBuf, source: io::Error },
+    // The whole file arrived, but isn't the one that was expected
+    #[error(
+        "the download from {url} doesn't match model.sha256 (expected {expected}, got {actual}); \
+         it has been deleted"
+    )]
+    ChecksumMismatch {
+        url: String,
+        expected: String,
+        actual: String,
+    },
+}
+
+// function to download the model file if it's missing and there's somewhere to get it from
+pub async fn ensure_model(model: &config::Model) -> anyhow::Result<()> {
+    let Some(url) = &model.download_url else {
+        return Ok(());
+    };
+    if model.is_mock() || model.path.exists() {
+        return Ok(());
+    }
+
+    info!(
+        "{} is missing; downloading it from {url}",
+        model.path.display()
+    );
+    download(url, &model.path, model.sha256.as_deref()).await?;
+    info!("Downloaded {}", model.path.display());
+    Ok(())
+}
+
+// function to download a file to `path`, by way of a `.part` file that's only renamed into
+// place once it's complete and, if a checksum is given, verified
+pub async fn download(url: &str, path: &Path, sha256: Option<&str>) -> Result<(), DownloadError> {
+    let part_path = part_path(path);
+    let write_error = |source: io::Error| disk_error(&part_path, source);
+    let network_error = |source| DownloadError::Network {
+        url: url.to_string(),
+        source,
+    };
+
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(write_error)?;
+    }
+
+    // Picks up where an earlier attempt left off, if there was one
+    let resume_from = match tokio::fs::metadata(&part_path).await {
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    };
+    let client = reqwest::Client::new();
+    let mut request = client.get(url);
+    if resume_from > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={resume_from}-"));
+    }
+    let mut response = request.send().await.map_err(network_error)?;
+
+    let status = response.status();
+    let resuming = resume_from > 0 && status == reqwest::StatusCode::PARTIAL_CONTENT;
+    if status == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // The `.part` file is already whole, which the checksum confirms if there is one
+        drop(response);
+        return finish(url, path, &part_path, sha256).await;
+    }
+    if !status.is_success() {
+        return Err(DownloadError::Status {
+            url: url.to_string(),
+            status,
+        });
+    }
+    if resume_from > 0 && !resuming {
+        info!("The server can't resume the download, so it's starting over");
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(resuming)
+        .truncate(!resuming)
+        .open(&part_path)
+        .await
+        .map_err(write_error)?;
+
+    let already = if resuming { resume_from } else { 0 };
+    let total = response.content_length().map(|length| length + already);
+    let mut downloaded = already;
+    let mut last_logged = Instant::now();
+    while let Some(chunk) = response.chunk().await.map_err(network_error)? {
+        file.write_all(&chunk).await.map_err(write_error)?;
+        downloaded += chunk.len() as u64;
+
+        if last_logged.elapsed() >= PROGRESS_INTERVAL {
+            last_logged = Instant::now();
+            match total {
+                Some(total) => info!(
+                    "Downloaded {:.1} of {:.1} MB ({:.0}%)",
+                    downloaded as f64 / 1e6,
+                    total as f64 / 1e6,
+                    downloaded as f64 / total as f64 * 100.0
+                ),
+                None => info!("Downloaded {:.1} MB", downloaded as f64 / 1e6),
+            }
+        }
+    }
+    file.flush().await.map_err(write_error)?;
+    file.sync_all().await.map_err(write_error)?;
+    drop(file);
+
+    finish(url, path, &part_path, sha256).await
+}
+
+// function to verify a finished `.part` file and rename it into place. A file that doesn't
+// match the checksum is deleted, so that the next attempt starts over
+async fn finish(
+    url: &str,
+    path: &Path,
+    part_path: &Path,
+    sha256: Option<&str>,
+) -> Result<(), DownloadError> {
+    let mut verified = None;
+    if let Some(expected) = sha256 {
+        let hashed = part_path.to_path_buf();
+        let actual = tokio::task::spawn_blocking(move || checksum::hash_file(&hashed))
+            .await
+            .expect("hashing doesn't panic")
+            .map_err(|e| disk_error(part_path, e))?;
+        if !actual.eq_ignore_ascii_case(expected) {
+            tokio::fs::remove_file(part_path).await.ok();
+            return Err(DownloadError::ChecksumMismatch {
+                url: url.to_string(),
+                expected: expected.to_lowercase(),
+                actual,
+            });
+        }
+        verified = Some(actual);
+    }
+
+    tokio::fs::rename(part_path, path)
+        .await
+        .map_err(|e| disk_error(path, e))?;
+    // Saves hashing the file again when it's checked at startup
+    if let Some(sha256) = verified {
+        checksum::remember(path, &sha256);
+    }
+    Ok(())
+}
+
+// The file a download is written to until it's complete
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+// function to tell a full disk apart from other problems writing the file
+fn disk_error(path: &Path, source: io::Error) -> DownloadError {
+    if source.kind() == io::ErrorKind::StorageFull {
+        DownloadError::DiskFull {
+            path: path.to_path_buf(),
+        }
+    } else {
+        DownloadError::Disk {
+            path: path.to_path_buf(),
+            source,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{
+        http::{header, HeaderMap, StatusCode},
+        routing::get,
+        Router,
+    };
+
+    use sha2::{Digest, Sha256};
+
+    use super::*;
+
+    const CONTENT: &[u8] = b"pretend these are model weights";
+
+    // function to serve `CONTENT` on a local port, honouring `Range` requests like a real
+    // file server would. Returns the file's URL
+    fn serve() -> String {
+        async fn file(headers: HeaderMap) -> (StatusCode, Vec<u8>) {
+            let start = headers
+                .get(header::RANGE)
+                .and_then(|r| r.to_str().ok()?.strip_prefix("bytes=")?.strip_suffix('-'))
+                .and_then(|s| s.parse::<usize>().ok());
+            match start {
+                Some(start) => (StatusCode::PARTIAL_CONTENT, CONTENT[start..].to_vec()),
+                None => (StatusCode::OK, CONTENT.to_vec()),
+            }
+        }
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address: SocketAddr = listener.local_addr().unwrap();
+        let app = Router::new().route("/model.bin", get(file));
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(app.into_make_service()),
+        );
+        format!("http://{address}/model.bin")
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("llmcord-download-{}-{name}", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn resumes_and_verifies_the_download() {
+        let url = serve();
+        let path = temp_path("resume.bin");
+        // An earlier attempt got partway
+        std::fs::write(part_path(&path), &CONTENT[..10]).unwrap();
+
+        let sha256 = format!("{:x}", Sha256::digest(CONTENT));
+        download(&url, &path, Some(&sha256)).await.unwrap();
+
+        assert_eq!(std::fs::read(&path).unwrap(), CONTENT);
+        assert!(!part_path(&path).exists());
+        std::fs::remove_file(format!("{}.sha256", path.display())).ok();
+        std::fs::remove_file(path).ok();
+    }
+
+    #[tokio::test]
+    async fn deletes_a_download_that_doesnt_match() {
+        let url = serve();
+        let path = temp_path("mismatch.bin");
+
+        let err = download(&url, &path, Some(&"0".repeat(64)))
+            .await
+            .unwrap_err();
+
+        assert!(matches!(err, DownloadError::ChecksumMismatch { .. }));
+        assert!(!path.exists());
+        assert!(!part_path(&path).exists());
+    }
+}
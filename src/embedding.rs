@@ -0,0 +1,403 @@
+// This file holds the `/similar` and `/embed` commands, and the named embeddings they use.
+// Embeddings come from the generation thread (see `generation::EmbeddingRequest`), so they
+// share the loaded model with generation instead of competing for it. Named embeddings are
+// kept in memory, and also in the store so that they survive restarts when persistence is on.
+// Names are scoped to the guild they were stored in (or to the user, in DMs).
+use std::{
+    collections::HashMap,
+    sync::{atomic::Ordering, Mutex},
+};
+
+use anyhow::Context as AnyhowContext;
+use serenity::{
+    builder::CreateApplicationCommand,
+    http::Http,
+    model::prelude::{
+        command::CommandOptionType,
+        interaction::{
+            application_command::ApplicationCommandInteraction, InteractionResponseType,
+        },
+    },
+};
+
+use crate::{
+    constant,
+    generation::{self, InferenceError},
+    health, store, util,
+};
+
+// The number of matches `/embed query` shows, unless asked for another number
+const DEFAULT_MATCH_COUNT: usize = 3;
+
+// The most matches `/embed query` can show
+const MAX_MATCH_COUNT: usize = 10;
+
+// The longest text that can be embedded, in characters
+const MAX_TEXT_LENGTH: usize = 2000;
+
+// A piece of text stored under a name, along with its embedding
+struct Snippet {
+    name: String,
+    text: String,
+    vector: Vec<f32>,
+}
+
+// The named embeddings, by scope
+pub struct Snippets {
+    // The snippets in each scope
+    scopes: Mutex<HashMap<String, Vec<Snippet>>>,
+    // The store that snippets are persisted to
+    store: store::Store,
+}
+
+impl Snippets {
+    // function to load the snippets that were persisted by earlier runs, if any.
+    // They're only a convenience, so the bot starts without them if they can't be read
+    pub fn load(store: store::Store) -> Self {
+        let records = store.load_embeddings().unwrap_or_else(|err| {
+            warn!("Failed to load stored embeddings: {err:?}");
+            vec![]
+        });
+
+        let mut scopes: HashMap<String, Vec<Snippet>> = HashMap::new();
+        for record in records {
+            scopes.entry(record.scope).or_default().push(Snippet {
+                name: record.name,
+                text: record.text,
+                vector: record.vector,
+            });
+        }
+
+        Self {
+            scopes: Mutex::new(scopes),
+            store,
+        }
+    }
+
+    // function to store a snippet, replacing any with the same name in the scope
+    fn insert(&self, scope: &str, name: &str, text: &str, vector: Vec<f32>) {
+        self.store.save_embedding(store::EmbeddingRecord {
+            scope: scope.to_string(),
+            name: name.to_string(),
+            text: text.to_string(),
+            vector: vector.clone(),
+        });
+
+        let mut scopes = self.scopes.lock().unwrap();
+        let snippets = scopes.entry(scope.to_string()).or_default();
+        snippets.retain(|s| s.name != name);
+        snippets.push(Snippet {
+            name: name.to_string(),
+            text: text.to_string(),
+            vector,
+        });
+    }
+
+    // function to find the snippets in a scope most similar to an embedding,
+    // returning their names, texts and similarities, most similar first
+    fn nearest(&self, scope: &str, vector: &[f32], count: usize) -> Vec<(String, String, f32)> {
+        let scopes = self.scopes.lock().unwrap();
+        let mut matches: Vec<_> = scopes
+            .get(scope)
+            .into_iter()
+            .flatten()
+            .filter_map(|s| {
+                // Snippets embedded by a different model can't be compared, so they're skipped
+                let similarity = cosine_similarity(vector, &s.vector)?;
+                Some((s.name.clone(), s.text.clone(), similarity))
+            })
+            .collect();
+        matches.sort_by(|a, b| b.2.total_cmp(&a.2));
+        matches.truncate(count);
+        matches
+    }
+}
+
+// function to compute the cosine similarity of two embeddings, from -1 (opposite) to 1 (the same).
+// Returns `None` if they have different lengths (or are empty), as they can't be compared
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> Option<f32> {
+    if a.len() != b.len() || a.is_empty() {
+        return None;
+    }
+
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return Some(0.0); // A zero vector isn't similar to anything
+    }
+
+    Some((dot / (norm_a * norm_b)).clamp(-1.0, 1.0))
+}
+
+// function to handle `/similar`, which compares two pieces of text
+pub async fn similar(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    embedding_tx: &flume::Sender<generation::EmbeddingRequest>,
+    readiness: &health::Readiness,
+) -> anyhow::Result<()> {
+    use constant::value as v;
+    use util::value_to_string;
+
+    check_supported(readiness)?;
+
+    let options = &cmd.data.options;
+    let first = util::get_value(options, v::FIRST)
+        .and_then(value_to_string)
+        .context("no first text specified")?;
+    let second = util::get_value(options, v::SECOND)
+        .and_then(value_to_string)
+        .context("no second text specified")?;
+    check_length(&first)?;
+    check_length(&second)?;
+
+    // The embeddings wait in the queue behind any generation, so answer later
+    defer(cmd, http).await?;
+    let first_vector = embed(embedding_tx, &first).await?;
+    let second_vector = embed(embedding_tx, &second).await?;
+    let similarity = cosine_similarity(&first_vector, &second_vector)
+        .context("the embeddings have different lengths")?;
+
+    cmd.edit_original_interaction_response(http, |r| {
+        r.content(format!("Cosine similarity: **{similarity:.4}**"))
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to handle `/embed`, which stores named snippets and finds the closest ones
+pub async fn embed_command(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    embedding_tx: &flume::Sender<generation::EmbeddingRequest>,
+    readiness: &health::Readiness,
+    snippets: &Snippets,
+) -> anyhow::Result<()> {
+    use constant::value as v;
+    use util::{value_to_integer, value_to_string};
+
+    check_supported(readiness)?;
+
+    let subcommand = cmd
+        .data
+        .options
+        .first()
+        .context("no subcommand specified")?;
+    let options = &subcommand.options;
+    let text = util::get_value(options, v::TEXT)
+        .and_then(value_to_string)
+        .context("no text specified")?;
+    check_length(&text)?;
+
+    // Names are shared by everyone in a guild, and private to the user in DMs
+    let scope = match cmd.guild_id {
+        Some(guild_id) => format!("guild:{guild_id}"),
+        None => format!("user:{}", cmd.user.id),
+    };
+
+    defer(cmd, http).await?;
+    let vector = embed(embedding_tx, &text).await?;
+
+    let content = match subcommand.name.as_str() {
+        v::STORE => {
+            let name = util::get_value(options, v::NAME)
+                .and_then(value_to_string)
+                .context("no name specified")?;
+            snippets.insert(&scope, &name, &text, vector);
+            format!("Stored `{name}`.")
+        }
+        v::QUERY => {
+            let count = util::get_value(options, v::COUNT)
+                .and_then(value_to_integer)
+                .map_or(DEFAULT_MATCH_COUNT, |c| {
+                    c.clamp(1, MAX_MATCH_COUNT as i64) as usize
+                });
+            let matches = snippets.nearest(&scope, &vector, count);
+            if matches.is_empty() {
+                "There are no stored snippets to compare with. Add some with `/embed store`."
+                    .to_string()
+            } else {
+                matches
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (name, text, similarity))| {
+                        format!("{}. **{name}** ({similarity:.4}): {}", i + 1, preview(text))
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            }
+        }
+        other => anyhow::bail!("unknown subcommand `{other}`"),
+    };
+
+    cmd.edit_original_interaction_response(http, |r| {
+        r.content(content)
+            .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse())
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to build the `/similar` and `/embed` commands, for registering with Discord
+pub fn commands() -> Vec<CreateApplicationCommand> {
+    use constant::value as v;
+
+    let mut similar = CreateApplicationCommand::default();
+    similar
+        .name(constant::command::SIMILAR)
+        .description("Compares the meaning of two pieces of text.")
+        .create_option(|opt| {
+            opt.name(v::FIRST)
+                .description("The first text.")
+                .kind(CommandOptionType::String)
+                .required(true)
+        })
+        .create_option(|opt| {
+            opt.name(v::SECOND)
+                .description("The second text.")
+                .kind(CommandOptionType::String)
+                .required(true)
+        });
+
+    let mut embed = CreateApplicationCommand::default();
+    embed
+        .name(constant::command::EMBED)
+        .description("Stores named snippets, and finds the ones closest in meaning to a text.")
+        .create_option(|sub| {
+            sub.name(v::STORE)
+                .description("Stores a snippet under a name.")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|opt| {
+                    opt.name(v::NAME)
+                        .description("The name to store the snippet under.")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+                .create_sub_option(|opt| {
+                    opt.name(v::TEXT)
+                        .description("The snippet.")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_option(|sub| {
+            sub.name(v::QUERY)
+                .description("Finds the stored snippets closest in meaning to a text.")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|opt| {
+                    opt.name(v::TEXT)
+                        .description("The text to compare with.")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+                .create_sub_option(|opt| {
+                    opt.name(v::COUNT)
+                        .description("How many matches to show.")
+                        .kind(CommandOptionType::Integer)
+                        .min_int_value(1)
+                        .max_int_value(MAX_MATCH_COUNT)
+                        .required(false)
+                })
+        });
+
+    vec![similar, embed]
+}
+
+// function to refuse the commands up front if the model can't produce embeddings
+fn check_supported(readiness: &health::Readiness) -> anyhow::Result<()> {
+    if readiness.embeddings_supported.load(Ordering::SeqCst) {
+        Ok(())
+    } else {
+        Err(util::user_error(
+            "The loaded model can't produce embeddings, so this command isn't available.",
+        ))
+    }
+}
+
+// function to refuse text that's too long to embed
+fn check_length(text: &str) -> anyhow::Result<()> {
+    if text.chars().count() > MAX_TEXT_LENGTH {
+        return Err(util::user_error(format!(
+            "The text can be at most {MAX_TEXT_LENGTH} characters long."
+        )));
+    }
+    Ok(())
+}
+
+// function to acknowledge the command, so that it can be answered once the embeddings are ready
+async fn defer(cmd: &ApplicationCommandInteraction, http: &Http) -> anyhow::Result<()> {
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+    })
+    .await?;
+    Ok(())
+}
+
+// function to embed text on the generation thread
+async fn embed(
+    embedding_tx: &flume::Sender<generation::EmbeddingRequest>,
+    text: &str,
+) -> anyhow::Result<Vec<f32>> {
+    let (result_tx, result_rx) = flume::bounded(1);
+    embedding_tx.send(generation::EmbeddingRequest {
+        text: text.to_string(),
+        result_tx,
+    })?;
+
+    match result_rx.recv_async().await? {
+        Ok(vector) => Ok(vector),
+        // This can only happen if the model changed its mind since the check at startup
+        Err(InferenceError::EmbeddingsUnsupported) => Err(util::user_error(
+            InferenceError::EmbeddingsUnsupported.to_string(),
+        )),
+        Err(err) => Err(err.into()),
+    }
+}
+
+// function to shorten a snippet for display
+fn preview(text: &str) -> String {
+    const PREVIEW_LENGTH: usize = 100;
+    let mut chars = text.chars();
+    let preview: String = chars.by_ref().take(PREVIEW_LENGTH).collect();
+    if chars.next().is_some() {
+        format!("{preview}…")
+    } else {
+        preview
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: Option<f32>, b: f32) -> bool {
+        a.is_some_and(|a| (a - b).abs() < 1e-6)
+    }
+
+    #[test]
+    fn cosine_similarity_of_related_vectors() {
+        // The same direction counts as identical, whatever the length
+        assert!(close(
+            cosine_similarity(&[1.0, 2.0, 3.0], &[1.0, 2.0, 3.0]),
+            1.0
+        ));
+        assert!(close(
+            cosine_similarity(&[1.0, 2.0, 3.0], &[2.0, 4.0, 6.0]),
+            1.0
+        ));
+        assert!(close(cosine_similarity(&[1.0, 0.0], &[0.0, 5.0]), 0.0));
+        assert!(close(cosine_similarity(&[1.0, -2.0], &[-1.0, 2.0]), -1.0));
+    }
+
+    #[test]
+    fn cosine_similarity_of_vectors_that_cant_be_compared() {
+        // A zero vector has no direction, so it isn't similar to anything
+        assert_eq!(cosine_similarity(&[0.0, 0.0], &[1.0, 1.0]), Some(0.0));
+        // Empty vectors and vectors of different lengths aren't compared at all
+        assert_eq!(cosine_similarity(&[], &[]), None);
+        assert_eq!(cosine_similarity(&[1.0, 2.0], &[1.0, 2.0, 3.0]), None);
+    }
+}
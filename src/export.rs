@@ -0,0 +1,242 @@
+// This file holds the "Export" button on finished responses.
+// Pressing it uploads the whole response (prompt and output) as Markdown file attachments,
+// which is easier to keep than a reply spread over several Discord messages.
+// Recently finished responses are kept in memory, so that the export has the exact prompt,
+// output and seed; older ones (or ones from before a restart) are rebuilt from the messages
+// the bot sent, which is all that Discord still has.
+use std::{
+    borrow::Cow,
+    sync::{Arc, Mutex},
+};
+
+use serenity::{
+    builder::CreateActionRow,
+    http::Http,
+    model::prelude::{
+        component::ButtonStyle,
+        interaction::{message_component::MessageComponentInteraction, InteractionResponseType},
+        AttachmentType, Message, MessageId, UserId,
+    },
+};
+
+use crate::{config, details, util};
+
+// The prefix of the export button's custom ID; the first message's ID follows it
+pub const BUTTON_PREFIX: &str = "export";
+
+// How many finished responses are remembered for exporting
+const MAX_TRANSCRIPTS: usize = 256;
+
+// The largest file uploaded, leaving room under the 8 MiB limit of servers without boosts
+const MAX_FILE_BYTES: usize = 8 * 1024 * 1024 - 64 * 1024;
+
+// The most files Discord accepts in a single message
+const MAX_FILES_PER_MESSAGE: usize = 10;
+
+// A finished response, as it was generated
+pub struct Transcript {
+    // How it was generated: the command, the settings, the seed and the model's statistics
+    pub record: details::GenerationRecord,
+    // The prompt shown to the user (their prompt, or the whole template if it's shown)
+    pub prompt: String,
+    // The generated output, without the prompt
+    pub response: String,
+    // The persona the response was generated with, if any
+    pub persona: Option<String>,
+    // The user who asked for the response
+    pub requester: UserId,
+}
+
+// The most recently finished responses, keyed by the ID of their first message.
+// Cheap to clone; every clone shares the same list
+#[derive(Clone, Default)]
+pub struct Transcripts(Arc<Mutex<util::BoundedMap<MessageId, Transcript, MAX_TRANSCRIPTS>>>);
+
+impl Transcripts {
+    // function to remember a finished response, forgetting the oldest one if there are too many.
+    // A response that's generated again (e.g. rerolled) replaces what was remembered of it
+    pub fn insert(&self, first_id: MessageId, transcript: Transcript) {
+        self.0.lock().unwrap().insert(first_id, transcript);
+    }
+
+    // function to find how a remembered response was generated, if it's still remembered
+    pub fn record(&self, first_id: MessageId) -> Option<details::GenerationRecord> {
+        self.inspect(first_id, |t| t.record.clone())
+    }
+
+    // function to look at a remembered response, if it's still remembered
+    pub fn inspect<T>(&self, first_id: MessageId, f: impl FnOnce(&Transcript) -> T) -> Option<T> {
+        self.0.lock().unwrap().get(&first_id).map(f)
+    }
+
+    // function to render a remembered response as Markdown, if it's still remembered
+    fn render(&self, first_id: MessageId) -> Option<(String, Option<u64>)> {
+        let transcripts = self.0.lock().unwrap();
+        let t = transcripts.get(&first_id)?;
+        let persona = t
+            .persona
+            .as_ref()
+            .map_or_else(String::new, |p| format!("*Persona: {p}*\n\n"));
+        Some((
+            format!(
+                "# /{}\n\n{persona}## Prompt\n\n{}\n\n## Response\n\n{}\n",
+                t.record.command_name, t.prompt, t.response
+            ),
+            t.record.seed,
+        ))
+    }
+}
+
+// function to add the export button to the row of buttons under a finished response
+pub fn create_button(row: &mut CreateActionRow, first_id: MessageId) {
+    row.create_button(|b| {
+        b.custom_id(format!("{BUTTON_PREFIX}#{first_id}"))
+            .style(ButtonStyle::Secondary)
+            .label("Export")
+    });
+}
+
+// function to handle a press of the export button
+pub async fn export(
+    cmp: &MessageComponentInteraction,
+    http: &Http,
+    transcripts: &Transcripts,
+    config: &config::Configuration,
+    first_id: MessageId,
+) -> anyhow::Result<()> {
+    let ephemeral = config.export.ephemeral;
+
+    // Walking the message chain can take a while, so acknowledge the press first
+    cmp.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::DeferredChannelMessageWithSource)
+            .interaction_response_data(|d| d.ephemeral(ephemeral))
+    })
+    .await?;
+
+    let (mut text, seed) = match transcripts.render(first_id) {
+        Some(rendered) => rendered,
+        // Responses from before later parts were sent as followups are chains of replies
+        None if cmp.message.message_reference.is_some() => (
+            walk_message_chain(http, &cmp.message, first_id).await?,
+            None,
+        ),
+        None => (collect_followups(http, &cmp.message, first_id).await?, None),
+    };
+
+    // The metadata goes at the end of the transcript
+    let model = config
+        .model
+        .path
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned())
+        .unwrap_or_else(|| config.model.architecture.clone());
+    let seed = seed.map_or_else(|| "random".to_string(), |s| s.to_string());
+    text += &format!("\n---\n\n*Model: {model} · Seed: {seed}*\n");
+
+    // Upload the transcript in as many files (and messages) as it takes
+    let files = split_into_files(&text);
+    let file_count = files.len();
+    for (group_index, group) in files.chunks(MAX_FILES_PER_MESSAGE).enumerate() {
+        let attachments = group.iter().enumerate().map(|(i, data)| {
+            let filename = if file_count == 1 {
+                format!("transcript-{first_id}.md")
+            } else {
+                let part = group_index * MAX_FILES_PER_MESSAGE + i + 1;
+                format!("transcript-{first_id}-part{part}-of-{file_count}.md")
+            };
+            AttachmentType::Bytes {
+                data: Cow::Borrowed(data.as_bytes()),
+                filename,
+            }
+        });
+        cmp.create_followup_message(http, |m| m.add_files(attachments).ephemeral(ephemeral))
+            .await?;
+    }
+
+    Ok(())
+}
+
+// function to rebuild a response from the messages the bot sent for it.
+// Each message after the first is a followup to the same interaction, so they're found among
+// the channel's messages between the first message and the one with the button
+async fn collect_followups(
+    http: &Http,
+    last: &Message,
+    first_id: MessageId,
+) -> anyhow::Result<String> {
+    let interaction_id = last.interaction.as_ref().map(|i| i.id);
+    let mut messages = vec![last.channel_id.message(http, first_id).await?];
+    if last.id != first_id {
+        let mut between = last
+            .channel_id
+            .messages(http, |r| r.after(first_id).limit(100))
+            .await?;
+        between.retain(|m| {
+            m.id <= last.id
+                && m.author.id == last.author.id
+                && m.interaction.as_ref().map(|i| i.id) == interaction_id
+        });
+        messages.extend(between);
+    }
+    messages.sort_by_key(|m| m.id);
+
+    // The response was split between messages at spaces, so it's rejoined with them
+    let contents: Vec<_> = messages.into_iter().map(|m| m.content).collect();
+    Ok(format!("# Response\n\n{}\n", contents.join(" ")))
+}
+
+// function to rebuild a response from the messages the bot sent for it, as responses used
+// to be sent: each message after the first is a reply to the one before it, so the chain is
+// followed backwards from the message with the button until the first message is reached
+async fn walk_message_chain(
+    http: &Http,
+    last: &Message,
+    first_id: MessageId,
+) -> anyhow::Result<String> {
+    let mut contents = vec![last.content.clone()];
+    let mut current = last.clone();
+    while current.id != first_id {
+        let Some(previous_id) = current
+            .message_reference
+            .as_ref()
+            .and_then(|r| r.message_id)
+        else {
+            break; // The chain was broken (e.g. a message was deleted)
+        };
+        current = current.channel_id.message(http, previous_id).await?;
+        contents.push(current.content.clone());
+    }
+    contents.reverse();
+
+    // The response was split between messages at spaces, so it's rejoined with them
+    Ok(format!("# Response\n\n{}\n", contents.join(" ")))
+}
+
+// function to split text into files that are each under the upload limit,
+// preferring to split between lines
+fn split_into_files(text: &str) -> Vec<String> {
+    let mut files = vec![String::new()];
+    for line in text.split_inclusive('\n') {
+        let mut line = line;
+        while !line.is_empty() {
+            let current = files.last_mut().unwrap();
+            let space = MAX_FILE_BYTES - current.len();
+            if line.len() <= space {
+                current.push_str(line);
+                break;
+            }
+
+            // Only split inside a line if it doesn't fit in a file of its own
+            if current.is_empty() {
+                let mut split = space;
+                while !line.is_char_boundary(split) {
+                    split -= 1;
+                }
+                current.push_str(&line[..split]);
+                line = &line[split..];
+            }
+            files.push(String::new());
+        }
+    }
+    files
+}
@@ -0,0 +1,199 @@
+// This file holds the fallback backend.
+// When the local model's queue is saturated, requests can overflow to a remote server with
+// an OpenAI-compatible `/v1/completions` endpoint instead of waiting. The remote server's
+// output is streamed into the request's token channel, so the Discord side treats it exactly
+// like output from the local generation thread.
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::{
+    config,
+    generation::{self, InferenceError, Token},
+};
+
+// function to decide whether a new request should go to the fallback backend,
+// given how many requests are already waiting for the local model and how long they should take
+pub fn should_use_fallback(
+    fallback: &config::Fallback,
+    queue_depth: usize,
+    estimated_wait: Duration,
+) -> bool {
+    queue_depth >= fallback.queue_depth_threshold
+        || estimated_wait > Duration::from_secs(fallback.max_wait_seconds)
+}
+
+// How often a request on the fallback backend checks whether it has been cancelled
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+// function to run a request on the fallback backend, sending its output through the
+// request's token channel. Errors are sent through the channel too, like the local thread does.
+// Cancelling the request (with its Cancel button) drops the connection to the remote server
+pub async fn generate(
+    http: &reqwest::Client,
+    fallback: &config::Fallback,
+    request: generation::Request,
+    cancellations: &generation::Cancellations,
+) {
+    let message_id = request.message_id;
+    let cancelled = async {
+        while !cancellations.is_cancelled(message_id) {
+            tokio::time::sleep(CANCEL_CHECK_INTERVAL).await;
+        }
+    };
+
+    let error = tokio::select! {
+        result = stream_completion(http, fallback, &request) => result.err().map(|err| {
+            InferenceError::custom(format!("The fallback backend failed: {err}"))
+        }),
+        _ = cancelled => Some(InferenceError::Cancelled),
+    };
+    // A cancellation that came in as the request ended has nothing left to stop
+    cancellations.clear(message_id);
+    if let Some(error) = error {
+        request.token_tx.send(Token::Error(error)).ok();
+    }
+}
+
+// function to request a streamed completion and forward each piece of text as it arrives
+async fn stream_completion(
+    http: &reqwest::Client,
+    fallback: &config::Fallback,
+    request: &generation::Request,
+) -> anyhow::Result<()> {
+    let mut body = json!({
+        "model": fallback.model,
+        "prompt": request.prompt,
+        "max_tokens": request.maximum_token_count.unwrap_or(fallback.max_tokens),
+        "stream": true,
+    });
+    if let Some(seed) = request.seed {
+        body["seed"] = json!(seed);
+    }
+
+    let mut builder = http
+        .post(format!(
+            "{}/v1/completions",
+            fallback.url.trim_end_matches('/')
+        ))
+        .json(&body);
+    if let Some(api_key) = &fallback.api_key {
+        builder = builder.bearer_auth(api_key);
+    }
+    let mut response = builder.send().await?.error_for_status()?;
+
+    // The remote server doesn't send the prompt back, so echo it ourselves
+    if request.echo_prompt {
+        send_text(request, request.prompt.clone())?;
+    }
+
+    // The response is a stream of server-sent events, each a line of `data: {json}`.
+    // Chunks can end partway through a line, so only complete lines are parsed
+    let mut buffer = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+
+        while let Some(newline) = buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = buffer.drain(..=newline).collect();
+            let line = String::from_utf8_lossy(&line);
+            let Some(data) = line.trim().strip_prefix("data:") else {
+                continue; // Blank lines and comments separate events
+            };
+
+            let data = data.trim();
+            if data == "[DONE]" {
+                return Ok(());
+            }
+
+            let event: serde_json::Value = serde_json::from_str(data)?;
+            if let Some(text) = event["choices"][0]["text"].as_str() {
+                send_text(request, text.to_string())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// function to send a piece of text back to the requester
+fn send_text(request: &generation::Request, text: String) -> anyhow::Result<()> {
+    request
+        .token_tx
+        .send(Token::Token(text))
+        .map_err(|_| anyhow::anyhow!("the requester stopped listening"))
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::model::prelude::MessageId;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn cancelling_a_request_stops_waiting_for_the_fallback() {
+        // A server that takes the request and never answers
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut connections = vec![];
+            while let Ok((connection, _)) = listener.accept().await {
+                connections.push(connection);
+            }
+        });
+
+        let (token_tx, token_rx) = flume::unbounded();
+        let request = generation::Request {
+            prompt: "Hello".to_string(),
+            batch_size: 1,
+            batch_decode: false,
+            token_buffer_size: 1,
+            token_tx,
+            message_id: MessageId(1),
+            seed: None,
+            maximum_token_count: None,
+            n_sequences: 1,
+            sampling: Default::default(),
+            low_priority: false,
+            echo_prompt: false,
+            context: generation::RequestContext {
+                guild_id: None,
+                channel_id: 0,
+                user_id: 0,
+                command_name: "hallucinate".to_string(),
+                shard_id: None,
+            },
+            progress_tx: None,
+            completion_tx: None,
+            trace: None,
+            queued_at: std::time::Instant::now(),
+        };
+        let fallback = config::Fallback {
+            url,
+            api_key: None,
+            model: "remote".to_string(),
+            max_tokens: 16,
+            queue_depth_threshold: 1,
+            max_wait_seconds: 0,
+        };
+
+        let cancellations = generation::Cancellations::default();
+        let generating = {
+            let cancellations = cancellations.clone();
+            tokio::spawn(async move {
+                generate(&reqwest::Client::new(), &fallback, request, &cancellations).await
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        cancellations.cancel(MessageId(1));
+
+        tokio::time::timeout(Duration::from_secs(5), generating)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(matches!(
+            token_rx.try_recv(),
+            Ok(Token::Error(InferenceError::Cancelled))
+        ));
+        assert!(!cancellations.is_cancelled(MessageId(1)));
+    }
+}
@@ -0,0 +1,369 @@
+// This file holds the feedback on finished responses, and the `/stats` command that sums it up.
+// With reaction feedback, the bot reacts to the last message of each response with a thumbs up
+// and a thumbs down, and users vote by clicking them. A user flipping between the two only has
+// their final vote written, once they've stopped for a moment.
+// With feedback buttons, the response gets 👍 and 👎 buttons instead, and each user can press
+// one of them once, while voting on the response is open.
+// Either way, votes are recorded in the store against the response's first message, along
+// with what it was generated with.
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use serenity::{
+    builder::{CreateActionRow, CreateApplicationCommand},
+    http::Http,
+    model::prelude::{
+        component::ButtonStyle,
+        interaction::{
+            application_command::ApplicationCommandInteraction,
+            message_component::MessageComponentInteraction, InteractionResponseType,
+        },
+        Message, MessageId, Reaction, ReactionType, UserId,
+    },
+};
+
+use crate::{
+    constant, details, store,
+    util::{self, DiscordInteraction},
+};
+
+// The reactions that vote a response up and down
+const UP: &str = "👍";
+const DOWN: &str = "👎";
+
+// How many finished responses can be voted on; older ones are forgotten
+const MAX_RESPONSES: usize = 256;
+
+// How long a user's vote has to stay the same before it's recorded
+const DEBOUNCE: Duration = Duration::from_secs(3);
+
+// The prefixes of the vote buttons' custom IDs; the first message's ID follows them
+pub const UP_PREFIX: &str = "vote_up";
+pub const DOWN_PREFIX: &str = "vote_down";
+
+// A user's opinion of a response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vote {
+    Up,
+    Down,
+}
+
+impl Vote {
+    // function to find the vote a reaction stands for, if it's one of the voting reactions
+    fn from_reaction(emoji: &ReactionType) -> Option<Self> {
+        match emoji {
+            ReactionType::Unicode(s) if s == UP => Some(Vote::Up),
+            ReactionType::Unicode(s) if s == DOWN => Some(Vote::Down),
+            _ => None,
+        }
+    }
+
+    // The value the vote is stored as
+    pub fn value(self) -> i64 {
+        match self {
+            Vote::Up => 1,
+            Vote::Down => -1,
+        }
+    }
+}
+
+// What a voted-on response was generated with, which is recorded with each vote
+#[derive(Clone)]
+struct Subject {
+    // The command the response is from
+    command_name: String,
+    // The hash of the command's template, and the model
+    template_hash: String,
+    model: String,
+}
+
+impl Subject {
+    fn new(record: &details::GenerationRecord) -> Self {
+        Self {
+            command_name: record.command_name.clone(),
+            template_hash: record.template_hash.clone(),
+            model: record.model.clone(),
+        }
+    }
+}
+
+// A finished response that can be voted on with reactions, keyed by the message the
+// reactions are on
+struct Response {
+    // The response's first message, which identifies it
+    first_id: MessageId,
+    subject: Subject,
+}
+
+// A finished response that can be voted on with the buttons, keyed by its first message
+struct Ballot {
+    subject: Subject,
+    // When voting opened, to close it after the configured time
+    opened_at: Instant,
+    // The users who have voted, who can't vote again
+    voters: HashSet<UserId>,
+}
+
+// A user's latest vote on a response, while it waits to be recorded
+struct PendingVote {
+    vote: Option<Vote>,
+    // Bumped on every change, so that only the last change's timer writes it
+    revision: u64,
+}
+
+#[derive(Default)]
+struct State {
+    responses: util::BoundedMap<MessageId, Response, MAX_RESPONSES>,
+    votes: HashMap<(MessageId, UserId), PendingVote>,
+    ballots: util::BoundedMap<MessageId, Ballot, MAX_RESPONSES>,
+}
+
+// The responses that can be voted on, and the votes waiting to be recorded.
+// Cheap to clone; every clone shares the same state
+#[derive(Clone)]
+pub struct Feedback {
+    state: Arc<Mutex<State>>,
+    // The bot's own user, whose reactions aren't votes
+    bot_id: Arc<OnceLock<UserId>>,
+    // How long voting with the buttons stays open on a response
+    voting_time: Duration,
+    store: store::Store,
+}
+
+impl Feedback {
+    pub fn new(store: store::Store, voting_hours: u64) -> Self {
+        Self {
+            state: Default::default(),
+            bot_id: Default::default(),
+            voting_time: Duration::from_secs(voting_hours * 60 * 60),
+            store,
+        }
+    }
+
+    // function to remember the bot's own user, once it's known
+    pub fn set_bot_id(&self, bot_id: UserId) {
+        self.bot_id.set(bot_id).ok();
+    }
+
+    // function to add the voting reactions to the last message of a finished response,
+    // and start accepting votes on it
+    pub async fn add_reactions(
+        &self,
+        http: &Http,
+        first_id: MessageId,
+        last: &Message,
+        record: &details::GenerationRecord,
+    ) -> anyhow::Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            let response = Response {
+                first_id,
+                subject: Subject::new(record),
+            };
+            if let Some((_, oldest)) = state.responses.insert(last.id, response) {
+                state.votes.retain(|(id, _), _| *id != oldest.first_id);
+            }
+        }
+
+        last.react(http, ReactionType::Unicode(UP.into())).await?;
+        last.react(http, ReactionType::Unicode(DOWN.into())).await?;
+        Ok(())
+    }
+
+    // function to handle a reaction being added to or removed from a message.
+    // Reactions that aren't votes on a response are ignored
+    pub fn handle_reaction(&self, reaction: &Reaction, added: bool) {
+        let Some(vote) = Vote::from_reaction(&reaction.emoji) else {
+            return;
+        };
+        let Some(user_id) = reaction.user_id else {
+            return;
+        };
+        if self.bot_id.get() == Some(&user_id) {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let Some(response) = state.responses.get(&reaction.message_id) else {
+            return;
+        };
+        let (first_id, subject) = (response.first_id, response.subject.clone());
+
+        // The latest reaction added is the vote; removing it takes the vote back
+        let pending = state
+            .votes
+            .entry((first_id, user_id))
+            .or_insert(PendingVote {
+                vote: None,
+                revision: 0,
+            });
+        if added {
+            pending.vote = Some(vote);
+        } else if pending.vote == Some(vote) {
+            pending.vote = None;
+        } else {
+            return;
+        }
+        pending.revision += 1;
+        let revision = pending.revision;
+        drop(state);
+
+        // Record the vote once it has stayed the same for a moment
+        let feedback = self.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(DEBOUNCE).await;
+
+            let vote = {
+                let mut state = feedback.state.lock().unwrap();
+                match state.votes.get(&(first_id, user_id)) {
+                    Some(pending) if pending.revision == revision => {
+                        let vote = pending.vote;
+                        // Nothing needs remembering once a vote has been taken back
+                        if vote.is_none() {
+                            state.votes.remove(&(first_id, user_id));
+                        }
+                        vote
+                    }
+                    // The vote has changed since; the later change records it
+                    _ => return,
+                }
+            };
+
+            feedback.save(first_id, user_id, subject, vote);
+        });
+    }
+
+    // function to start accepting votes on a finished response with the buttons, forgetting
+    // the responses that voting has closed on. If it was already open (it has been rerolled),
+    // it's opened again, for the new output
+    pub fn open_ballot(&self, first_id: MessageId, record: &details::GenerationRecord) {
+        let mut state = self.state.lock().unwrap();
+        state
+            .ballots
+            .retain(|_, b| b.opened_at.elapsed() < self.voting_time);
+        state.ballots.insert(
+            first_id,
+            Ballot {
+                subject: Subject::new(record),
+                opened_at: Instant::now(),
+                voters: HashSet::new(),
+            },
+        );
+    }
+
+    // function to handle a press of one of the vote buttons. Each user's first press counts;
+    // the reply is only shown to them
+    pub async fn vote_button(
+        &self,
+        cmp: &MessageComponentInteraction,
+        http: &Http,
+        first_id: MessageId,
+        vote: Vote,
+    ) -> anyhow::Result<()> {
+        let subject = {
+            let mut state = self.state.lock().unwrap();
+            match state.ballots.get_mut(&first_id) {
+                Some(ballot) if ballot.opened_at.elapsed() < self.voting_time => ballot
+                    .voters
+                    .insert(cmp.user.id)
+                    .then(|| ballot.subject.clone())
+                    .ok_or("You've already voted on this response."),
+                _ => Err("Voting is closed for this response."),
+            }
+        };
+
+        let reply = match subject {
+            Ok(subject) => {
+                self.save(first_id, cmp.user.id, subject, Some(vote));
+                "Thanks for your feedback!"
+            }
+            Err(reply) => reply,
+        };
+        cmp.create_ephemeral(http, reply).await
+    }
+
+    // function to record a user's vote (or, with `None`, take it back) in the store
+    fn save(&self, first_id: MessageId, user_id: UserId, subject: Subject, vote: Option<Vote>) {
+        self.store.save_feedback(store::FeedbackRecord {
+            response_id: first_id.0,
+            user_id: user_id.0,
+            command_name: subject.command_name,
+            template_hash: subject.template_hash,
+            model: subject.model,
+            vote,
+        });
+    }
+}
+
+// function to add the vote buttons to a row of buttons under a finished response
+pub fn create_buttons(row: &mut CreateActionRow, first_id: MessageId) {
+    row.create_button(|b| {
+        b.custom_id(format!("{UP_PREFIX}#{first_id}"))
+            .style(ButtonStyle::Secondary)
+            .label(UP)
+    })
+    .create_button(|b| {
+        b.custom_id(format!("{DOWN_PREFIX}#{first_id}"))
+            .style(ButtonStyle::Secondary)
+            .label(DOWN)
+    });
+}
+
+// function to handle `/stats`, which shows how each command's responses have been voted on
+// and how often they've been reported
+pub async fn stats_command(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    feedback: &Feedback,
+) -> anyhow::Result<()> {
+    let stats = feedback.store.load_feedback_stats()?;
+    let content = if stats.is_empty() {
+        "No responses have been voted on or reported yet.".to_string()
+    } else {
+        stats
+            .iter()
+            .map(|s| {
+                format!(
+                    "**/{}** · {UP} {} · {DOWN} {} · {:.0}% approval · {} reported of {} ({:.1}%)",
+                    s.command_name,
+                    s.up,
+                    s.down,
+                    percentage(s.up, s.up + s.down),
+                    s.reported,
+                    s.requests,
+                    percentage(s.reported, s.requests)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| d.content(content).ephemeral(true))
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to work out what percentage of a total a count is, or 0 if there's nothing
+fn percentage(count: u64, total: u64) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+// function to build the `/stats` command, for registering with Discord
+pub fn command() -> CreateApplicationCommand {
+    let mut stats = CreateApplicationCommand::default();
+    stats
+        .name(constant::command::STATS)
+        .description("Shows how each command's responses have been voted on and reported.");
+    stats
+}
@@ -1,12 +1,27 @@
 // This file has all the custom error types and other sturcts
 // for working with the actual running for llm
 // Also holds the function to make new threads to handle multiple requests
-use std::{collections::HashSet, thread::JoinHandle};
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
 
 use rand::SeedableRng;
 use serenity::model::prelude::MessageId;
 use thiserror::Error;
 
+#[cfg(feature = "speculative")]
+use crate::speculative;
+use crate::{
+    config, debug_generate, generation_log, health, idle_unload, mock, moderation, postprocess,
+    prompt_cache, repetition, schedule, store,
+};
+
 // This enum Defines the custom error type InferenceError using the Error, Debug, and Clone traits
 #[derive(Debug, Error, Clone)]
 pub enum InferenceError {
@@ -14,6 +29,30 @@ pub enum InferenceError {
     #[error("The generation was cancelled.")]
     Cancelled,
 
+    // Variant indicating that the model ran out of GPU memory while generating
+    #[error("The model ran out of GPU memory. Try a shorter prompt or reduce batch size.")]
+    OomError,
+
+    // Variant indicating that the output matched `moderation.halt_on_output`, and was stopped
+    #[error("The response was withheld.")]
+    Withheld,
+
+    // Variant indicating that the request waited longer than `max_queue_wait_seconds`
+    #[error(
+        "Sorry, your request waited too long in the queue and was dropped. \
+         Please try again in a little while."
+    )]
+    QueueTimeout,
+
+    // Variant indicating that the loaded model (or its backend) can't produce embeddings
+    #[error("The loaded model can't produce embeddings.")]
+    EmbeddingsUnsupported,
+
+    // Variant indicating that the model was unloaded for being idle, and couldn't be loaded
+    // again for the request
+    #[error("The model couldn't be loaded: {0}. Please try again in a moment.")]
+    ReloadFailed(String),
+
     // Variant allowing for a custom error message with a placeholder ({0})
     #[error("{0}")]
     Custom(String),
@@ -34,6 +73,10 @@ pub struct Request {
     pub prompt: String,
     // The size of the text generation batch
     pub batch_size: usize,
+    // Whether or not to send tokens in groups of `batch_size` instead of one at a time
+    pub batch_decode: bool,
+    // The number of tokens to send together when `batch_decode` is off (at least one)
+    pub token_buffer_size: usize,
     // A channel sender for transmitting generated tokens
     // (In the realm of concurrent programming in Rust,
     // Flume channels provide a reliable means of communication
@@ -44,127 +87,1650 @@ pub struct Request {
     pub message_id: MessageId,
     // An optional seed for the random number generator
     pub seed: Option<u64>,
+    // An optional limit on the number of tokens to generate
+    pub maximum_token_count: Option<usize>,
+    // The number of alternative completions to generate, one after another (at least one)
+    pub n_sequences: usize,
+    // Changes to how tokens are sampled, e.g. from the channel's persona
+    pub sampling: config::Sampling,
+    // Whether or not the request should wait until nothing else is (e.g. internal requests
+    // that nobody is watching)
+    pub low_priority: bool,
+    // Whether or not the prompt should be sent back through `token_tx`
+    // before the generated tokens (Discord displays it, the HTTP API doesn't)
+    pub echo_prompt: bool,
+    // The Discord metadata surrounding the request (who, where and which command)
+    pub context: RequestContext,
+    // An optional channel for progress updates during generation.
+    // This is `None` for requests that don't want the overhead
+    pub progress_tx: Option<flume::Sender<GenerationProgress>>,
+    // An optional channel that the completion (stop reason and statistics) is sent
+    // through once generation succeeds, for requesters that want the numbers
+    pub completion_tx: Option<flume::Sender<Completion>>,
+    // An optional trace of the most likely tokens at each step, for `/debug_generate`.
+    // Generating with one is slower, so it's `None` for everything else
+    pub trace: Option<debug_generate::Trace>,
+    // When the request was sent, which `max_queue_wait_seconds` counts from. The generation
+    // thread only takes requests off the channel between generations, so this can be well
+    // before it sees them
+    pub queued_at: Instant,
+}
+
+impl Request {
+    // function to copy the request with another seed and its own token channel, e.g. to try it
+    // again. The progress and completion channels are shared with this request
+    pub fn clone_with_seed(&self, seed: u64) -> (Request, flume::Receiver<Token>) {
+        let (token_tx, token_rx) = flume::unbounded();
+        let request = Request {
+            prompt: self.prompt.clone(),
+            batch_size: self.batch_size,
+            batch_decode: self.batch_decode,
+            token_buffer_size: self.token_buffer_size,
+            token_tx,
+            message_id: self.message_id,
+            seed: Some(seed),
+            maximum_token_count: self.maximum_token_count,
+            n_sequences: self.n_sequences,
+            sampling: self.sampling,
+            low_priority: self.low_priority,
+            echo_prompt: self.echo_prompt,
+            context: self.context.clone(),
+            progress_tx: self.progress_tx.clone(),
+            completion_tx: self.completion_tx.clone(),
+            trace: self.trace.clone(),
+            queued_at: Instant::now(),
+        };
+        (request, token_rx)
+    }
+}
+
+// This struct represents a request for the embedding of some text.
+// Embeddings run on the generation thread, so that they never compete with generations
+// for the model
+pub struct EmbeddingRequest {
+    // The text to embed
+    pub text: String,
+    // A channel sender for the embedding (or the reason there isn't one)
+    pub result_tx: flume::Sender<Result<Vec<f32>, InferenceError>>,
+}
+
+// This struct is a progress update, sent every few tokens during generation
+#[derive(Debug, Clone, Copy)]
+pub struct GenerationProgress {
+    // The number of tokens generated so far (not counting the prompt)
+    pub tokens_generated: usize,
+    // The fraction of the model's context (and so its KV cache) that is in use
+    pub kv_cache_usage: f32,
+}
+
+// This struct holds the ambient Discord metadata for a request.
+// The IDs are stored as plain integers so that anything consuming the request
+// (logging, stats, post-processing) does not need to know about serenity types
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    // The guild (server) the request came from, if it wasn't sent in a DM
+    pub guild_id: Option<u64>,
+    // The channel the request was made in
+    pub channel_id: u64,
+    // The user who made the request
+    pub user_id: u64,
+    // The name of the command that was invoked
+    pub command_name: String,
+    // The gateway shard the request arrived on, if it came from Discord
+    pub shard_id: Option<u64>,
 }
 
 // Definition of the Token enum, representing the result of text generation
+#[allow(clippy::enum_variant_names)] // `Token::Token` is used throughout, so it keeps its name
 pub enum Token {
     // Variant for a successfully generated token containing text
     Token(String),
     // Variant for an error during text generation, holding an InferenceError
     Error(InferenceError),
+    // Variant for information about the generation, sent once it has finished successfully
+    Metadata(GenerationMetadata),
+    // Variant marking the start of one of several alternative completions, by its index.
+    // These are only sent for requests with more than one sequence
+    SequenceStart(u8),
+}
+
+// This struct holds information about how a generation was run
+#[derive(Debug, Clone, Copy)]
+pub struct GenerationMetadata {
+    // The number of tokens generated
+    pub tokens_generated: usize,
+    // The number of tokens in the prompt that was fed to the model
+    pub prompt_tokens: usize,
+    // How long the generation took, feeding the prompt included
+    pub duration_ms: u64,
+    // How fast tokens were generated, not counting the time spent feeding the prompt
+    pub tokens_per_second: f32,
+    // The seed the random number generator was initialised with; the requested one,
+    // or the one picked at random if none was requested
+    pub seed: u64,
+}
+
+// The reason a generation stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // The model produced an end-of-text token
+    EndOfText,
+    // The maximum number of tokens was generated
+    TokenLimit,
+    // The output was repeating itself (see `repetition::LoopDetector`)
+    RepetitionLoop,
+    // The generation ran for longer than `max_generation_seconds`
+    TimeLimit,
+}
+
+// Implementation of Display, so that the stop reason can be shown to users
+impl std::fmt::Display for StopReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopReason::EndOfText => write!(f, "end of text"),
+            StopReason::TokenLimit => write!(f, "token limit reached"),
+            StopReason::RepetitionLoop => write!(f, "output was repeating"),
+            StopReason::TimeLimit => write!(f, "time limit reached"),
+        }
+    }
+}
+
+// This struct holds the outcome of a successful generation
+pub struct Completion {
+    // Why the generation stopped
+    pub stop_reason: StopReason,
+    // Timing and token count statistics reported by `llm`
+    pub stats: llm::InferenceStats,
+    // The generated text, without the prompt
+    pub text: String,
+    // The seed the random number generator was initialised with
+    pub seed: u64,
+}
+
+impl Completion {
+    // function to combine the completions of two alternatives of the same request: the
+    // statistics are added up, the texts are kept one after the other, and the seed is the
+    // first alternative's
+    fn merge(self, next: Completion) -> Completion {
+        Completion {
+            // An earlier alternative that was stopped for looping is worth knowing about
+            stop_reason: if self.stop_reason == StopReason::RepetitionLoop {
+                self.stop_reason
+            } else {
+                next.stop_reason
+            },
+            stats: llm::InferenceStats {
+                feed_prompt_duration: self.stats.feed_prompt_duration
+                    + next.stats.feed_prompt_duration,
+                prompt_tokens: self.stats.prompt_tokens + next.stats.prompt_tokens,
+                predict_duration: self.stats.predict_duration + next.stats.predict_duration,
+                predict_tokens: self.stats.predict_tokens + next.stats.predict_tokens,
+            },
+            text: self.text + "\n\n" + &next.text,
+            seed: self.seed,
+        }
+    }
+}
+
+// The limits on how long requests can wait and run for, and when they're stopped for
+// repeating themselves or at an end-of-text marker. Taken from the config once, when the
+// generation thread starts
+#[derive(Debug, Clone, Default)]
+pub struct GenerationLimits {
+    // When a generation that's repeating itself is stopped
+    pub loops: repetition::LoopLimits,
+    // The longest a generation can run for, if there's a limit
+    pub max_duration: Option<Duration>,
+    // The longest a request can wait in the queue, if there's a limit
+    pub max_queue_wait: Option<Duration>,
+    // Text that ends a generation as if the model had produced its end-of-text token, for
+    // models that only produce their end-of-text marker as text (see `tokenizer_config`)
+    pub end_of_text: Option<String>,
+}
+
+impl GenerationLimits {
+    // function to take the limits from the config, with the end-of-text marker from the
+    // model's tokenizer config, if it has one
+    pub fn new(inference: &config::Inference, end_of_text: Option<String>) -> Self {
+        Self {
+            loops: repetition::LoopLimits::new(inference),
+            max_duration: inference.max_generation_seconds.map(Duration::from_secs),
+            max_queue_wait: inference.max_queue_wait_seconds.map(Duration::from_secs),
+            end_of_text,
+        }
+    }
+}
+
+// What requests are generated with, other than the model: the settings taken from the config,
+// and the state shared with the rest of the bot. The generation thread, the CLI and the tests
+// each make one; clones share the state
+#[derive(Clone)]
+pub struct GenerationContext {
+    // The settings for the `llm` sessions that requests are run in
+    pub session_config: llm::InferenceSessionConfig,
+    // The order tokens go through the samplers in
+    pub sampler_order: Vec<config::SamplerType>,
+    // The phrases that stop a generation when they're output
+    pub output_filter: moderation::OutputFilter,
+    // How long requests can wait and run for, and when they're stopped for repeating themselves
+    pub limits: GenerationLimits,
+    // The requests that have been asked to stop
+    pub cancellations: Cancellations,
+    // The count of requests being generated, shared with the `/status` command
+    pub active_requests: ActiveRequests,
+    // Where the order requests will run in is published, with the running request's progress,
+    // for waiting users
+    pub board: schedule::Board,
+}
+
+// The default has the default config's session settings and sampler order, no filter or
+// limits, and state that isn't shared with anything
+impl Default for GenerationContext {
+    fn default() -> Self {
+        Self {
+            session_config: config::Configuration::default().inference.session_config(),
+            sampler_order: config::default_sampler_order(),
+            output_filter: Default::default(),
+            limits: Default::default(),
+            cancellations: Default::default(),
+            active_requests: Default::default(),
+            board: Default::default(),
+        }
+    }
+}
+
+impl GenerationContext {
+    // function to take the settings from the config, with state that isn't shared with anything
+    // yet. This fails if the moderation patterns or the tokenizer config are invalid
+    pub fn new(config: &config::Configuration) -> anyhow::Result<Self> {
+        Ok(Self {
+            session_config: config.inference.session_config(),
+            sampler_order: config.inference.sampler_order.clone(),
+            output_filter: moderation::OutputFilter::new(&config.moderation)?,
+            limits: GenerationLimits::new(
+                &config.inference,
+                config.model.tokenizer_config()?.and_then(|t| t.eos_token),
+            ),
+            ..Default::default()
+        })
+    }
+}
+
+// What the generation thread keeps to itself: how it orders the requests waiting for it, and
+// where it keeps what they generated
+pub struct ThreadState {
+    // The estimate of how long requests take, which decides the order they're run in
+    pub estimator: schedule::Estimator,
+    // Where requests wait until they're run, split into the fast and slow lanes
+    pub queue: schedule::Queue,
+    // The file that completed generations are written to, if there is one
+    pub generation_log: Option<generation_log::GenerationLog>,
+    // The cache that completed generations are remembered in and replayed from, if it's on
+    pub prompt_cache: Option<prompt_cache::PromptCache>,
+}
+
+impl ThreadState {
+    // function to set up the thread's state as the config asks
+    pub fn new(config: &config::Configuration, store: store::Store) -> Self {
+        Self {
+            estimator: schedule::Estimator::new(&config.inference),
+            queue: schedule::Queue::new(schedule::Lanes::new(
+                &config.inference,
+                config.model.effective_context_length(),
+            )),
+            generation_log: generation_log::GenerationLog::open(&config.inference),
+            prompt_cache: prompt_cache::PromptCache::new(&config.inference, store),
+        }
+    }
+}
+
+// The number of requests that are being generated right now (as opposed to waiting in the
+// queue). Cheap to clone; every clone shares the same count
+#[derive(Clone, Default)]
+pub struct ActiveRequests(Arc<AtomicUsize>);
+
+impl ActiveRequests {
+    // The number of requests being generated right now
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    // function to count a request as active until the returned guard is dropped
+    fn start(&self) -> ActiveRequestGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        ActiveRequestGuard(self.clone())
+    }
+}
+
+// The requests that have been asked to stop, shared by the handler (which cancels them) and
+// the generation thread (which stops them). A request's ID is only ever taken out by the
+// thread when it deals with that request, so cancelling one request can't lose the
+// cancellation of another. Cheap to clone; every clone shares the same set
+#[derive(Clone, Default)]
+pub struct Cancellations(Arc<Mutex<HashSet<MessageId>>>);
+
+impl Cancellations {
+    // function to ask for a request to be stopped, whether it's running or hasn't started yet
+    pub fn cancel(&self, message_id: MessageId) {
+        self.0.lock().unwrap().insert(message_id);
+    }
+
+    // function to check whether a request has been asked to stop, leaving the request marked
+    pub fn is_cancelled(&self, message_id: MessageId) -> bool {
+        self.0.lock().unwrap().contains(&message_id)
+    }
+
+    // function to unmark a request once it has been dealt with. Returns whether it was marked
+    pub fn clear(&self, message_id: MessageId) -> bool {
+        self.0.lock().unwrap().remove(&message_id)
+    }
+}
+
+// Guard that counts a request in `ActiveRequests` until dropped. Dropping also happens when
+// generation panics and unwinds, so a panic can't leave the count too high
+pub struct ActiveRequestGuard(ActiveRequests);
+
+impl Drop for ActiveRequestGuard {
+    fn drop(&mut self) {
+        self.0 .0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// The model that text generation runs on
+pub enum Model {
+    // A real model loaded through `llm`
+    Llm(Box<dyn llm::Model>),
+    // The built-in mock model, for developing and testing without model weights
+    Mock(mock::MockModel),
+    // A real model, with a draft model proposing tokens for it (see `speculative`)
+    #[cfg(feature = "speculative")]
+    Speculative(speculative::Models),
+}
+
+// Implementation of the methods for the Model enum
+impl Model {
+    // The number of tokens that fit in the model's context
+    pub fn context_size(&self) -> usize {
+        match self {
+            Model::Llm(model) => model.context_size(),
+            Model::Mock(mock) => mock.context_size(),
+            #[cfg(feature = "speculative")]
+            Model::Speculative(models) => models.main.context_size(),
+        }
+    }
+
+    // The `llm` model that generates, unless it's the mock
+    pub fn llm(&self) -> Option<&dyn llm::Model> {
+        match self {
+            Model::Llm(model) => Some(model.as_ref()),
+            Model::Mock(_) => None,
+            #[cfg(feature = "speculative")]
+            Model::Speculative(models) => Some(models.main.as_ref()),
+        }
+    }
+
+    // The embedding of some text, taken from the model's final hidden state.
+    // Models whose `llm` implementation doesn't extract embeddings (and the mock)
+    // report that they're unsupported rather than returning an empty vector
+    pub fn embed(
+        &self,
+        text: &str,
+        session_config: llm::InferenceSessionConfig,
+    ) -> Result<Vec<f32>, InferenceError> {
+        let Some(model) = self.llm() else {
+            return Err(InferenceError::EmbeddingsUnsupported);
+        };
+
+        // Anything past the context wouldn't fit in the session
+        let tokens: Vec<llm::TokenId> = model
+            .tokenizer()
+            .tokenize(text, true)
+            .map_err(|e| InferenceError::custom(e.to_string()))?
+            .into_iter()
+            .map(|(_, id)| id)
+            .take(model.context_size())
+            .collect();
+
+        let mut session = model.start_session(session_config);
+        let mut output = llm::OutputRequest {
+            all_logits: None,
+            embeddings: Some(vec![]),
+        };
+        model.evaluate(&mut session, &tokens, &mut output);
+
+        match output.embeddings {
+            Some(embedding) if !embedding.is_empty() => Ok(embedding),
+            _ => Err(InferenceError::EmbeddingsUnsupported),
+        }
+    }
 }
 
 // This function is responsible for creating a new thread to handle text generation requests
 pub fn make_thread(
-    // Takes a model implementing the llm::Model trait
-    model: Box<dyn llm::Model>,
+    // Takes the model to generate with, which may be unloaded while it's idle
+    mut model: idle_unload::ModelSlot,
     // Receives requests through a channel
     request_rx: flume::Receiver<Request>,
-    // Listens for cancellation signals associated with Discord messages
-    cancel_rx: flume::Receiver<MessageId>,
+    // Receives embedding requests through a channel
+    embedding_rx: flume::Receiver<EmbeddingRequest>,
+    // The shared readiness state, updated with the thread's liveness and queue depth
+    readiness: Arc<health::Readiness>,
+    // The store that completed requests are recorded in
+    store: store::Store,
+    // What requests are generated with, shared with the rest of the bot
+    context: GenerationContext,
+    // How the thread orders requests, and where it keeps what they generated
+    state: ThreadState,
 ) -> JoinHandle<()> {
     // Spawns a new thread to continuously process incoming requests
-    std::thread::spawn(move || loop {
-        // Attempts to receive a text generation request from the channel
-        if let Ok(request) = request_rx.try_recv() {
-            // Processes the received request using the provided model
-            match process_incoming_request(&request, model.as_ref(), &cancel_rx) {
-                // Do nothing if processing is successful
-                Ok(_) => {}
-                Err(e) => {
-                    // Sends an error token back through the communication channel if an error occurs
-                    if let Err(err) = request.token_tx.send(Token::Error(e)) {
-                        eprintln!("Failed to send error: {err:?}");
+    std::thread::spawn(move || {
+        let ThreadState {
+            mut estimator,
+            mut queue,
+            mut generation_log,
+            prompt_cache,
+        } = state;
+        let GenerationContext {
+            session_config,
+            cancellations,
+            board,
+            limits,
+            ..
+        } = &context;
+        let session_config = *session_config;
+
+        // Marks the thread as alive until it exits (or panics)
+        let _alive = health::AliveGuard::new(readiness.clone());
+
+        // Checks once whether the model can produce embeddings, so that the commands
+        // that need them can say so up front instead of failing partway through
+        let embeddings_supported = model
+            .get()
+            .is_ok_and(|model| model.embed("Hello", session_config).is_ok());
+        readiness
+            .embeddings_supported
+            .store(embeddings_supported, Ordering::SeqCst);
+
+        // Whether the board needs publishing again
+        let mut changed = true;
+
+        loop {
+            // Takes every text generation request that has arrived, drops the waiting ones that
+            // have been cancelled, and publishes how many are waiting and how long they should take
+            changed |= queue.extend(request_rx.try_iter(), &estimator);
+            for request in queue.remove(&board.take_cancelled()) {
+                request
+                    .token_tx
+                    .send(Token::Error(InferenceError::Cancelled))
+                    .ok();
+                changed = true;
+            }
+            // Drops the requests that have waited too long; their users are told to try again
+            if let Some(max_wait) = limits.max_queue_wait {
+                for request in queue.remove_expired(max_wait) {
+                    info!("Dropping expired request request_id={}", request.message_id);
+                    readiness.expired_requests.fetch_add(1, Ordering::SeqCst);
+                    request
+                        .token_tx
+                        .send(Token::Error(InferenceError::QueueTimeout))
+                        .ok();
+                    changed = true;
+                }
+            }
+            // Frees the model's memory if it hasn't been used in a while
+            if model.unload_if_idle() {
+                info!("Unloaded the model after it went unused");
+                readiness.model_unloaded.store(true, Ordering::SeqCst);
+            }
+            readiness.queue_depth.store(queue.depth(), Ordering::SeqCst);
+            let (fast, slow) = queue.lane_depths();
+            readiness.fast_lane_depth.store(fast, Ordering::SeqCst);
+            readiness.slow_lane_depth.store(slow, Ordering::SeqCst);
+            readiness
+                .estimated_queue_ms
+                .store(queue.estimated_ms(), Ordering::SeqCst);
+            if changed {
+                board.publish(&queue, &estimator);
+                changed = false;
+            }
+
+            // Runs the waiting request that should finish soonest, unless it was cancelled
+            // just as it came up
+            if let Some((request, estimate_ms)) = queue.pop() {
+                changed = true;
+                if cancellations.clear(request.message_id)
+                    || !board.start(&request, estimate_ms, &estimator)
+                {
+                    request
+                        .token_tx
+                        .send(Token::Error(InferenceError::Cancelled))
+                        .ok();
+                    continue;
+                }
+
+                // Logs who the request is for, so that generations can be traced back to Discord.
+                // The fields are `key=value` pairs, so that log files can be searched by them
+                let request_context = &request.context;
+                info!(
+                    "Processing request request_id={} command={} user_id={} channel_id={} guild_id={} shard_id={}",
+                    request.message_id,
+                    request_context.command_name,
+                    request_context.user_id,
+                    request_context.channel_id,
+                    request_context.guild_id.map_or("none".to_string(), |id| id.to_string()),
+                    request_context.shard_id.map_or("none".to_string(), |id| id.to_string())
+                );
+
+                // Loads the model again if it was unloaded, which the request waits for. If that
+                // fails, the next request tries again
+                let was_unloaded = !model.is_loaded();
+                let load_timer = std::time::Instant::now();
+                let loaded = match model.get() {
+                    Ok(loaded) => loaded,
+                    Err(err) => {
+                        error!("Failed to load the model again: {err:?}");
+                        board.finish();
+                        request
+                            .token_tx
+                            .send(Token::Error(InferenceError::ReloadFailed(err.to_string())))
+                            .ok();
+                        continue;
+                    }
+                };
+                if was_unloaded {
+                    info!(
+                        "Loaded the model again in {}ms",
+                        load_timer.elapsed().as_millis()
+                    );
+                    readiness
+                        .model_load_ms
+                        .store(load_timer.elapsed().as_millis() as u64, Ordering::SeqCst);
+                    readiness.model_unloaded.store(false, Ordering::SeqCst);
+                }
+
+                // Replays the request's remembered output if the cache has one, and otherwise
+                // processes the request using the provided model
+                let started_at = store::now();
+                let timer = std::time::Instant::now();
+                let cached = prompt_cache
+                    .as_ref()
+                    .and_then(|cache| Some((cache, cache.lookup(&request)?)));
+                let replayed = cached.is_some();
+                let result = match cached {
+                    Some((cache, cached)) => {
+                        info!(
+                            "Replaying cached generation request_id={}",
+                            request.message_id
+                        );
+                        cache.replay(&request, cached, cancellations, board)
+                    }
+                    None => process_incoming_request(&request, loaded, &context),
+                };
+                // Replays say nothing about how fast the model is, so they're left out of
+                // the estimates
+                if !replayed {
+                    update_average_generation_ms(&readiness, timer.elapsed());
+                }
+                board.finish();
+                model.touch();
+                // A cancellation that came in as the request ended has nothing left to stop
+                cancellations.clear(request.message_id);
+
+                match result {
+                    Ok(completion) => {
+                        if completion.stop_reason == StopReason::TimeLimit {
+                            readiness
+                                .timed_out_generations
+                                .fetch_add(1, Ordering::SeqCst);
+                        }
+                        if !replayed {
+                            estimator.record(&completion.stats, request.n_sequences);
+                            estimator.record_duration(estimate_ms, timer.elapsed());
+                            if let Some(prompt_cache) = &prompt_cache {
+                                prompt_cache.save(&request, &completion);
+                            }
+                        }
+                        info!(
+                            "Finished request request_id={} prompt_tokens={} generated_tokens={} duration_ms={}",
+                            request.message_id,
+                            completion.stats.prompt_tokens,
+                            completion.stats.predict_tokens,
+                            timer.elapsed().as_millis()
+                        );
+
+                        // Records the completed request; the write happens off this thread
+                        store.record_request(store::RequestRecord {
+                            context: request.context.clone(),
+                            prompt: request.prompt.clone(),
+                            prompt_tokens: completion.stats.prompt_tokens,
+                            generated_tokens: completion.stats.predict_tokens,
+                            started_at,
+                            finished_at: store::now(),
+                        });
+
+                        if let Some(generation_log) = &mut generation_log {
+                            generation_log.record(&request, &completion);
+                        }
+
+                        // Hands the completion to the requester, if they asked for it
+                        if let Some(completion_tx) = &request.completion_tx {
+                            completion_tx.send(completion).ok();
+                        }
+                    }
+                    Err(e) => {
+                        // Sends an error token back through the communication channel if an error occurs
+                        if let Err(err) = request.token_tx.send(Token::Error(e)) {
+                            warn!("Failed to send error: {err:?}");
+                        }
                     }
                 }
             }
-        }
 
-        // Pauses the thread, to avoid excessive processing
-        std::thread::sleep(std::time::Duration::from_millis(5));
+            // Embeds text for whoever asked; these are quick, so they're done between generations
+            if let Ok(request) = embedding_rx.try_recv() {
+                let result = match model.get() {
+                    Ok(model) => model.embed(&request.text, session_config),
+                    Err(err) => Err(InferenceError::ReloadFailed(err.to_string())),
+                };
+                request.result_tx.send(result).ok();
+            }
+
+            // Pauses the thread, to avoid excessive processing
+            std::thread::sleep(std::time::Duration::from_millis(5));
+        }
     })
 }
 
-// Function to process incoming text generation requests
-fn process_incoming_request(
+// Function to fold a request's duration into the running average, which the
+// handler uses to estimate how long new requests would wait for the local model
+fn update_average_generation_ms(readiness: &health::Readiness, duration: std::time::Duration) {
+    let sample = duration.as_millis() as u64;
+    let average = readiness.average_generation_ms.load(Ordering::SeqCst);
+    let average = if average == 0 {
+        sample
+    } else {
+        // An exponential moving average, weighted towards recent requests
+        (average * 4 + sample) / 5
+    };
+    readiness
+        .average_generation_ms
+        .store(average, Ordering::SeqCst);
+}
+
+// The settings tokens are sampled with, unless the request asks for others (`llm`'s defaults)
+pub const DEFAULT_TEMPERATURE: f32 = 0.80;
+pub const DEFAULT_TOP_K: usize = 40;
+pub const DEFAULT_TOP_P: f32 = 0.95;
+pub const DEFAULT_REPEAT_PENALTY: f32 = 1.30;
+
+// The number of generated tokens between progress updates
+const PROGRESS_INTERVAL_TOKENS: usize = 10;
+
+// Function to process incoming text generation requests.
+// This is also used directly by the offline CLI, so that it runs exactly what the bot runs
+pub fn process_incoming_request(
     // This holds all the information about the request
     request: &Request,
     // The model responsible for text/response generation
-    model: &dyn llm::Model,
-    // A channel for receiving cancellation signals
-    cancel_rx: &flume::Receiver<MessageId>,
-) -> Result<(), InferenceError> {
-    // Creating a random number generator with an optional seed
-    // This variable will be used to hold a random number generator
-    let mut rng = if let Some(seed) = request.seed {
-        rand::rngs::StdRng::seed_from_u64(seed)
-    } else {
-        rand::rngs::StdRng::from_entropy()
-    };
+    model: &Model,
+    // What the request is generated with. It counts as being generated in the context's
+    // active requests until this returns
+    context: &GenerationContext,
+) -> Result<Completion, InferenceError> {
+    let _active = context.active_requests.start();
 
-    // Starting a new session with the language model
-    let mut session = model.start_session(Default::default());
+    // The time limit covers every alternative together
+    let deadline = context.limits.max_duration.map(|d| Instant::now() + d);
 
-    // Defining parameters for text generation
-    let params = llm::InferenceParameters {
-        sampler: llm::samplers::default_samplers(),
-    };
+    // Alternatives are generated one after another, each after a marker with its index.
+    // Each gets its own seed: the request's seed counted up, or a random one
+    let sequences = request.n_sequences.max(1);
+    let mut completion: Option<Completion> = None;
+    for index in 0..sequences {
+        if sequences > 1 {
+            request
+                .token_tx
+                .send(Token::SequenceStart(index as u8))
+                .map_err(|_| InferenceError::custom("Failed to send token to channel."))?;
+        }
 
-    // Initiating the text generation process
-    session
-        .infer(
+        let seed = request.seed.map(|seed| seed.wrapping_add(index as u64));
+        // The prompt is only echoed once, in front of the first alternative
+        let echo_prompt = request.echo_prompt && index == 0;
+        let session = Session::start(
             model,
-            &mut rng,
-            &llm::InferenceRequest {
-                // Converting the request prompt to the necessary format
-                prompt: (&request.prompt).into(),
-                parameters: &params,
-                play_back_previous_tokens: false,
-                maximum_token_count: None,
-            },
-            &mut Default::default(),
-            // Callback function for handling each generated token
-            move |t| {
-                // Handling cancellation requests
-                let cancellation_requests: HashSet<_> = cancel_rx.drain().collect();
-                if cancellation_requests.contains(&request.message_id) {
-                    // Signaling that the text generation is cancelled
-                    return Err(InferenceError::Cancelled);
+            request,
+            seed,
+            context.session_config,
+            &context.sampler_order,
+        );
+        let sequence = run_sequence(request, session, context, deadline, echo_prompt)?;
+        let timed_out = sequence.stop_reason == StopReason::TimeLimit;
+        completion = Some(match completion {
+            Some(completion) => completion.merge(sequence),
+            None => sequence,
+        });
+        // There's no time left for the rest of the alternatives
+        if timed_out {
+            break;
+        }
+    }
+
+    completion.ok_or_else(|| InferenceError::custom("No sequences were generated."))
+}
+
+// Function to generate a single completion for a request, sending its tokens through the
+// request's channel
+fn run_sequence(
+    request: &Request,
+    // The session to generate in
+    mut session: Session<'_>,
+    context: &GenerationContext,
+    // When the generation is stopped for running too long, if ever
+    deadline: Option<Instant>,
+    // Whether or not the prompt should be sent back before the generated tokens
+    echo_prompt: bool,
+) -> Result<Completion, InferenceError> {
+    let GenerationContext {
+        cancellations,
+        board,
+        output_filter,
+        limits,
+        ..
+    } = context;
+
+    // Collecting tokens into batches (of `token_buffer_size` tokens, unless batch decoding
+    // is enabled)
+    let mut batcher = TokenBatcher::new(if request.batch_decode {
+        request.batch_size
+    } else {
+        request.token_buffer_size
+    });
+    let batcher_ref = &mut batcher;
+
+    // Tracking whether the model finished on its own, to tell why the generation stopped
+    let mut reached_end_of_text = false;
+    let reached_end_of_text_ref = &mut reached_end_of_text;
+
+    // Collecting the generated text, for the generation log
+    let mut generated_text = String::new();
+    let generated_text_ref = &mut generated_text;
+
+    // Watching the output for phrases that stop it, and for it repeating itself
+    let mut watch = output_filter.watch();
+    let mut loop_detector = repetition::LoopDetector::new(limits.loops);
+
+    // Watching the output for the end-of-text marker, which can be split over several tokens.
+    // Text that could be the start of it is held back until it's clear whether it is
+    let mut end_of_text =
+        postprocess::StopFilter::new(limits.end_of_text.iter().cloned().collect());
+    let end_of_text_ref = &mut end_of_text;
+    let mut stopped_repeating = false;
+    let stopped_repeating_ref = &mut stopped_repeating;
+    let mut timed_out = false;
+    let timed_out_ref = &mut timed_out;
+
+    // Tracking how many tokens are in the context, and how many have been generated,
+    // for progress updates
+    let context_size = session.context_size();
+    let mut tokens_in_context = 0;
+    let mut tokens_generated = 0;
+
+    // Callback function for handling each generated token.
+    // This is shared by every kind of model, so they all behave the same way
+    let mut callback = move |t: llm::InferenceResponse| {
+        // Handling cancellation requests (only this request's; others are left for theirs)
+        if cancellations.is_cancelled(request.message_id) {
+            // Signaling that the text generation is cancelled
+            return Err(InferenceError::Cancelled);
+        }
+
+        // Stopping where it is once the time limit has passed, keeping what was generated
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            *timed_out_ref = true;
+            return Ok(llm::InferenceFeedback::Halt);
+        }
+
+        // Counting the token, watching generated ones for where the generation should stop,
+        // and finding the text to send for it, if any
+        let text = match t {
+            llm::InferenceResponse::PromptToken(text) => {
+                tokens_in_context += 1;
+                // Prompt tokens are skipped if the requester doesn't want them echoed back
+                echo_prompt.then_some(text)
+            }
+            llm::InferenceResponse::InferredToken(text) => {
+                tokens_in_context += 1;
+                tokens_generated += 1;
+                board.count_token();
+
+                // The matching token is never sent. Earlier tokens of a phrase split over
+                // several may have been, so the requester has to take back what they've shown
+                if watch.push(&text) {
+                    return Err(InferenceError::Withheld);
+                }
+                if loop_detector.push(&text) {
+                    *stopped_repeating_ref = true;
+                    return Ok(llm::InferenceFeedback::Halt);
                 }
 
-                // Processing different types of generated tokens
-                match t {
-                    // For snapshot, prompt, and inferred tokens
-                    llm::InferenceResponse::SnapshotToken(t)
-                    | llm::InferenceResponse::PromptToken(t)
-                    | llm::InferenceResponse::InferredToken(t) => {
-                        // Sending the generated token through the channel
-                        request
-                            .token_tx
-                            .send(Token::Token(t))
-                            // Handling potential errors during token transmission
-                            .map_err(|_| {
-                                InferenceError::custom("Failed to send token to channel.")
-                            })?;
+                // Sending a progress update every few generated tokens, if one was asked for
+                if let Some(progress_tx) = &request.progress_tx {
+                    if tokens_generated % PROGRESS_INTERVAL_TOKENS == 0 {
+                        // The receiver going away only means nobody is watching anymore
+                        progress_tx
+                            .send(GenerationProgress {
+                                tokens_generated,
+                                kv_cache_usage: tokens_in_context as f32 / context_size as f32,
+                            })
+                            .ok();
                     }
-                    // For end-of-text tokens
-                    llm::InferenceResponse::EotToken => {}
                 }
 
-                // Indicating that the text generation process should continue
-                Ok(llm::InferenceFeedback::Continue)
+                // The end-of-text marker is never sent, so the generation ends just as if the
+                // model had produced its end-of-text token. The text before it is kept
+                let shown = end_of_text_ref.push(&text);
+                generated_text_ref.push_str(&shown);
+                if end_of_text_ref.is_stopped() {
+                    *reached_end_of_text_ref = true;
+                }
+                Some(shown)
+            }
+            llm::InferenceResponse::SnapshotToken(text) => Some(text),
+            // For end-of-text tokens
+            llm::InferenceResponse::EotToken => {
+                *reached_end_of_text_ref = true;
+                None
+            }
+        };
+
+        // Sending the batch through the channel once it's full
+        if let Some(batch) = text
+            .filter(|text| !text.is_empty())
+            .and_then(|text| batcher_ref.push(text))
+        {
+            send_token(request, batch)?;
+        }
+        if end_of_text_ref.is_stopped() {
+            return Ok(llm::InferenceFeedback::Halt);
+        }
+
+        // Indicating that the text generation process should continue
+        Ok(llm::InferenceFeedback::Continue)
+    };
+
+    // Initiating the text generation process
+    let stats = session.run(&mut callback)?;
+
+    // Sending what was held back in case it was the start of the end-of-text marker, and
+    // whatever is left over in the last, partially-filled batch
+    let held_back = end_of_text.finish();
+    generated_text.push_str(&held_back);
+    if let Some(batch) = Some(held_back)
+        .filter(|text| !text.is_empty())
+        .and_then(|text| batcher.push(text))
+    {
+        send_token(request, batch)?;
+    }
+    if let Some(batch) = batcher.flush() {
+        send_token(request, batch)?;
+    }
+
+    // Reporting how the generation went, now that all of its text has been sent
+    let predict_seconds = stats.predict_duration.as_secs_f32();
+    request
+        .token_tx
+        .send(Token::Metadata(GenerationMetadata {
+            tokens_generated: stats.predict_tokens,
+            prompt_tokens: stats.prompt_tokens,
+            duration_ms: (stats.feed_prompt_duration + stats.predict_duration).as_millis() as u64,
+            tokens_per_second: if predict_seconds > 0.0 {
+                stats.predict_tokens as f32 / predict_seconds
+            } else {
+                0.0
             },
-        )
-        // Ignoring the result, as only interested in potential errors
-        .map(|_| ())
+            seed: session.seed(),
+        }))
+        .map_err(|_| InferenceError::custom("Failed to send token to channel."))?;
+
+    Ok(Completion {
+        stop_reason: if timed_out {
+            StopReason::TimeLimit
+        } else if stopped_repeating {
+            StopReason::RepetitionLoop
+        } else if reached_end_of_text {
+            StopReason::EndOfText
+        } else {
+            StopReason::TokenLimit
+        },
+        stats,
+        text: generated_text,
+        seed: session.seed(),
+    })
+}
+
+// A callback that's passed every token of a generation. It can stop the generation by
+// returning an error
+pub type TokenCallback<'a> =
+    dyn FnMut(llm::InferenceResponse) -> Result<llm::InferenceFeedback, InferenceError> + 'a;
+
+// What a session generates with: it feeds a request's prompt and generates from it. Models
+// loaded through `llm` do this in their inference session, and the mock model by itself
+pub trait Inference {
+    // The number of tokens that fit in the context
+    fn context_size(&self) -> usize;
+
+    // function to feed the request's prompt and generate from it, passing every token to
+    // the callback
+    fn infer(
+        &mut self,
+        request: &Request,
+        rng: &mut rand::rngs::StdRng,
+        callback: &mut TokenCallback<'_>,
+    ) -> Result<llm::InferenceStats, InferenceError>;
+}
+
+// A generation session for one request: what it generates with, and the random number
+// generator it samples with. Keeping them together gives one place for session-level
+// concerns, like picking the seed
+pub struct Session<'a> {
+    // The request the session is for
+    request: &'a Request,
+    // The model's side of the session
+    inference: Box<dyn Inference + 'a>,
+    // The random number generator used for sampling
+    rng: rand::rngs::StdRng,
+    // The seed the random number generator was created with
+    seed: u64,
+}
+
+impl<'a> Session<'a> {
+    // function to start a session for a request with the given model
+    pub fn start(
+        model: &'a Model,
+        request: &'a Request,
+        seed: Option<u64>,
+        session_config: llm::InferenceSessionConfig,
+        sampler_order: &'a [config::SamplerType],
+    ) -> Self {
+        let inference: Box<dyn Inference + 'a> = match model {
+            Model::Mock(mock) => Box::new(mock),
+            model => Box::new(LlmSession {
+                model,
+                session: model
+                    .llm()
+                    .expect("the mock model was handled above")
+                    .start_session(session_config),
+                sampler_order,
+                session_config,
+            }),
+        };
+        Self::new(request, inference, seed)
+    }
+
+    // function to start a session for a request that generates with `inference`.
+    // If there's no seed, one is picked at random, so that it can still be reported
+    // (and the generation reproduced later)
+    pub fn new(
+        request: &'a Request,
+        inference: Box<dyn Inference + 'a>,
+        seed: Option<u64>,
+    ) -> Self {
+        let seed = seed.unwrap_or_else(rand::random);
+        Self {
+            request,
+            inference,
+            rng: rand::rngs::StdRng::seed_from_u64(seed),
+            seed,
+        }
+    }
+
+    // The seed the session samples with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // The number of tokens that fit in the session's context
+    pub fn context_size(&self) -> usize {
+        self.inference.context_size()
+    }
+
+    // function to feed the request's prompt and generate from it, passing every token to
+    // the callback. The callback can stop the generation by returning an error
+    pub fn run(
+        &mut self,
+        callback: &mut TokenCallback<'_>,
+    ) -> Result<llm::InferenceStats, InferenceError> {
+        self.inference.infer(self.request, &mut self.rng, callback)
+    }
+}
+
+// The session of a model loaded through `llm` (with its draft model, if it has one)
+struct LlmSession<'a> {
+    // The model the session generates with
+    model: &'a Model,
+    // The `llm` session
+    session: llm::InferenceSession,
+    // The order tokens go through the samplers in
+    sampler_order: &'a [config::SamplerType],
+    // The settings the `llm` session was started with, for the draft model's session
+    #[cfg_attr(not(feature = "speculative"), allow(dead_code))]
+    session_config: llm::InferenceSessionConfig,
+}
+
+impl Inference for LlmSession<'_> {
+    fn context_size(&self) -> usize {
+        self.model.context_size()
+    }
+
+    fn infer(
+        &mut self,
+        request: &Request,
+        rng: &mut rand::rngs::StdRng,
+        callback: &mut TokenCallback<'_>,
+    ) -> Result<llm::InferenceStats, InferenceError> {
+        let session = &mut self.session;
+
+        // Defining parameters for text generation
+        let params = make_inference_parameters(&request.sampling, self.sampler_order);
+
+        let result = match (self.model, &request.trace) {
+            // Traced requests are generated a token at a time by the model alone, so that the
+            // likeliest tokens at each step can be recorded
+            (model, Some(trace)) => debug_generate::infer(
+                model
+                    .llm()
+                    .expect("sessions are only started for `llm` models"),
+                session,
+                rng,
+                request,
+                &params,
+                trace,
+                callback,
+            ),
+            #[cfg(feature = "speculative")]
+            (Model::Speculative(models), None) => speculative::infer(
+                models,
+                session,
+                self.session_config,
+                rng,
+                request,
+                &params,
+                callback,
+            ),
+            (model, None) => session.infer(
+                model
+                    .llm()
+                    .expect("sessions are only started for `llm` models"),
+                rng,
+                &llm::InferenceRequest {
+                    // Converting the request prompt to the necessary format
+                    prompt: (&request.prompt).into(),
+                    parameters: &params,
+                    play_back_previous_tokens: false,
+                    maximum_token_count: request.maximum_token_count,
+                },
+                &mut Default::default(),
+                callback,
+            ),
+        };
+
         // Converting specific types of errors into the custom InferenceError type for clarity
-        .map_err(|e| match e {
+        result.map_err(|e| match e {
             // If the error is due to a user callback
             llm::InferenceError::UserCallback(e) => {
                 // Extracting and cloning the InferenceError from the user callback
                 e.downcast::<InferenceError>().unwrap().as_ref().clone()
             }
             // For other types of errors
-            e => InferenceError::custom(e.to_string()),
+            e => {
+                let message = e.to_string();
+                if is_out_of_memory(&message) {
+                    error!(
+                        "Out of GPU memory (prompt length: {} bytes, batch size: {}): {message}",
+                        request.prompt.len(),
+                        request.batch_size
+                    );
+                    InferenceError::OomError
+                } else {
+                    InferenceError::custom(message)
+                }
+            }
         })
+    }
+}
+
+// The bytes of tokens that don't make whole characters yet, e.g. half of an emoji. For
+// generating outside of `llm`'s own loop, which does this itself (see `speculative` and
+// `debug_generate`)
+#[derive(Default)]
+pub struct Utf8Buffer(Vec<u8>);
+
+impl Utf8Buffer {
+    // function to add a token's bytes, returning the text once the characters are whole
+    pub fn push(&mut self, bytes: &[u8]) -> Option<String> {
+        self.0.extend_from_slice(bytes);
+        match std::str::from_utf8(&self.0) {
+            Ok(text) => {
+                let text = text.to_string();
+                self.0.clear();
+                Some(text)
+            }
+            // The rest of the character is still to come
+            Err(e) if e.error_len().is_none() => None,
+            // Bytes that will never be a character are passed on as they are
+            Err(_) => Some(String::from_utf8_lossy(&std::mem::take(&mut self.0)).into_owned()),
+        }
+    }
+}
+
+// The messages that GPU backends use when they run out of memory.
+// `llm` doesn't have a specific error for this, so it's detected from the error text
+const OUT_OF_MEMORY_MESSAGES: &[&str] = &[
+    "CUDA out of memory",
+    "cudaErrorMemoryAllocation",
+    "Metal out of memory",
+    "CL_MEM_OBJECT_ALLOCATION_FAILURE",
+    "CL_OUT_OF_RESOURCES",
+];
+
+// Function to check whether an error from `llm` is the GPU running out of memory
+fn is_out_of_memory(message: &str) -> bool {
+    let message = message.to_lowercase();
+    OUT_OF_MEMORY_MESSAGES
+        .iter()
+        .any(|m| message.contains(&m.to_lowercase()))
+}
+
+// Function to build the sampling parameters used for generation.
+// This is shared between the bot and the offline CLI
+pub fn make_inference_parameters(
+    sampling: &config::Sampling,
+    sampler_order: &[config::SamplerType],
+) -> llm::InferenceParameters {
+    use config::SamplerType;
+    use llm::samplers::llm_samplers::{samplers::*, types::SamplerChain};
+
+    if sampling.is_default() && sampler_order == config::default_sampler_order() {
+        return llm::InferenceParameters {
+            sampler: llm::samplers::default_samplers(),
+        };
+    }
+
+    // The samplers `llm` uses by default, in the configured order and with the requested
+    // values in place of its own
+    let mut chain = SamplerChain::<llm::TokenId, f32>::new();
+    for sampler in sampler_order {
+        match sampler {
+            SamplerType::RepetitionPenalty => chain.push_sampler(SampleRepetition::new(
+                sampling.repeat_penalty.unwrap_or(DEFAULT_REPEAT_PENALTY),
+                64,
+            )),
+            SamplerType::TopK => {
+                chain.push_sampler(SampleTopK::new(sampling.top_k.unwrap_or(DEFAULT_TOP_K), 1))
+            }
+            SamplerType::TopP => {
+                chain.push_sampler(SampleTopP::new(sampling.top_p.unwrap_or(DEFAULT_TOP_P), 1))
+            }
+            SamplerType::Temperature => chain.push_sampler(SampleTemperature::new(
+                sampling.temperature.unwrap_or(DEFAULT_TEMPERATURE),
+            )),
+        };
+    }
+    // The token is picked from whatever is left
+    chain.push_sampler(SampleRandDistrib::new());
+    llm::InferenceParameters {
+        sampler: std::sync::Arc::new(std::sync::Mutex::new(chain)),
+    }
+}
+
+// Function to send a token (or a batch of tokens) back to the requester
+fn send_token(request: &Request, token: String) -> Result<(), InferenceError> {
+    request
+        .token_tx
+        .send(Token::Token(token))
+        // Handling potential errors during token transmission
+        .map_err(|_| InferenceError::custom("Failed to send token to channel."))
+}
+
+// This struct collects tokens into groups before they're sent, so that fast models
+// don't flood the channel (and in turn, Discord) with one update per token
+struct TokenBatcher {
+    // The joined text of the tokens collected so far
+    text: String,
+    // The number of tokens collected so far
+    count: usize,
+    // The number of tokens to collect before the batch is sent
+    size: usize,
+}
+
+impl TokenBatcher {
+    // Creates a batcher that groups `size` tokens together (at least one)
+    fn new(size: usize) -> Self {
+        Self {
+            text: String::new(),
+            count: 0,
+            size: size.max(1),
+        }
+    }
+
+    // Adds a token to the batch, returning the joined batch if it is now full
+    fn push(&mut self, token: String) -> Option<String> {
+        self.text += &token;
+        self.count += 1;
+        if self.count >= self.size {
+            self.flush()
+        } else {
+            None
+        }
+    }
+
+    // Takes whatever has been collected so far, if anything
+    fn flush(&mut self) -> Option<String> {
+        self.count = 0;
+        if self.text.is_empty() {
+            None
+        } else {
+            Some(std::mem::take(&mut self.text))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    // function to build a request for the test, with its token channel
+    fn request(message_id: u64) -> (Request, flume::Receiver<Token>) {
+        let (token_tx, token_rx) = flume::unbounded();
+        let request = Request {
+            prompt: "Hello".to_string(),
+            batch_size: 1,
+            batch_decode: false,
+            token_buffer_size: 1,
+            token_tx,
+            message_id: MessageId(message_id),
+            seed: None,
+            maximum_token_count: None,
+            n_sequences: 1,
+            sampling: Default::default(),
+            low_priority: false,
+            echo_prompt: false,
+            context: RequestContext {
+                guild_id: None,
+                channel_id: 1,
+                user_id: 1,
+                command_name: "test".to_string(),
+                shard_id: None,
+            },
+            progress_tx: None,
+            completion_tx: None,
+            trace: None,
+            queued_at: Instant::now(),
+        };
+        (request, token_rx)
+    }
+
+    #[test]
+    fn characters_split_over_tokens_are_put_back_together() {
+        let mut buffer = Utf8Buffer::default();
+        let emoji = "🦀".as_bytes();
+        assert_eq!(buffer.push(&emoji[..2]), None);
+        assert_eq!(buffer.push(&emoji[2..]).as_deref(), Some("🦀"));
+        assert_eq!(buffer.push(b"ok").as_deref(), Some("ok"));
+    }
+
+    // function to set up the generation thread's state for a test: a single lane, and nowhere
+    // to keep what's generated
+    fn thread_state(config: &config::Configuration) -> ThreadState {
+        ThreadState {
+            estimator: schedule::Estimator::new(&config.inference),
+            queue: Default::default(),
+            generation_log: None,
+            prompt_cache: None,
+        }
+    }
+
+    // Cancelling a queued request while another is generating used to be lost, because the
+    // running request's callback drained (and dropped) every cancellation it saw
+    #[test]
+    fn cancelling_a_queued_request_while_another_runs() {
+        let script_path =
+            std::env::temp_dir().join(format!("llmcord-cancel-test-{}.txt", std::process::id()));
+        std::fs::write(&script_path, " token\n".repeat(20)).unwrap();
+
+        let config = config::Configuration::default();
+        let model = Model::Mock(
+            mock::MockModel::load(
+                &config::Mock {
+                    mode: config::MockMode::Script,
+                    tokens_per_second: 100.0,
+                    max_tokens: 20,
+                    script_path: Some(script_path.clone()),
+                },
+                2048,
+            )
+            .unwrap(),
+        );
+
+        let (request_tx, request_rx) = flume::unbounded();
+        let (_embedding_tx, embedding_rx) = flume::unbounded();
+        let context = GenerationContext::default();
+        let cancellations = context.cancellations.clone();
+        let _thread = make_thread(
+            idle_unload::ModelSlot::new(model, None),
+            request_rx,
+            embedding_rx,
+            Default::default(),
+            store::Store::open(&Default::default()).unwrap(),
+            context,
+            thread_state(&config),
+        );
+
+        // The first request starts streaming, then the second is queued and cancelled
+        let (first, first_rx) = request(1);
+        request_tx.send(first).unwrap();
+        assert!(matches!(
+            first_rx.recv_timeout(Duration::from_secs(5)),
+            Ok(Token::Token(_))
+        ));
+
+        let (second, second_rx) = request(2);
+        request_tx.send(second).unwrap();
+        cancellations.cancel(MessageId(2));
+
+        // The first request finishes, and the second never produces any output
+        while let Ok(token) = first_rx.recv_timeout(Duration::from_secs(5)) {
+            assert!(!matches!(token, Token::Error(_)));
+        }
+        let mut second_tokens = vec![];
+        while let Ok(token) = second_rx.recv_timeout(Duration::from_secs(5)) {
+            second_tokens.push(token);
+        }
+        std::fs::remove_file(script_path).ok();
+        assert!(matches!(
+            second_tokens[..],
+            [Token::Error(InferenceError::Cancelled)]
+        ));
+    }
+
+    // function to load a `lorem` mock model that generates `max_tokens` tokens at the given rate
+    fn lorem_model(tokens_per_second: f32, max_tokens: usize) -> Model {
+        Model::Mock(
+            mock::MockModel::load(
+                &config::Mock {
+                    mode: config::MockMode::Lorem,
+                    tokens_per_second,
+                    max_tokens,
+                    script_path: None,
+                },
+                2048,
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn slow_generation_stops_at_the_time_limit() {
+        let model = lorem_model(50.0, 1000);
+        let (request, _token_rx) = request(1);
+        let context = GenerationContext {
+            limits: GenerationLimits {
+                max_duration: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let completion = process_incoming_request(&request, &model, &context).unwrap();
+
+        assert_eq!(completion.stop_reason, StopReason::TimeLimit);
+        assert!(completion.stats.predict_tokens < 1000);
+    }
+
+    #[test]
+    fn generation_ends_at_the_end_of_text_marker() {
+        let script_path =
+            std::env::temp_dir().join(format!("llmcord-eot-test-{}.txt", std::process::id()));
+        std::fs::write(&script_path, " Hello\n world\n<|im_end|>\n more\n").unwrap();
+        let model = Model::Mock(
+            mock::MockModel::load(
+                &config::Mock {
+                    mode: config::MockMode::Script,
+                    tokens_per_second: 1000.0,
+                    max_tokens: 20,
+                    script_path: Some(script_path.clone()),
+                },
+                2048,
+            )
+            .unwrap(),
+        );
+        let (request, _token_rx) = request(1);
+        let context = GenerationContext {
+            limits: GenerationLimits {
+                end_of_text: Some("<|im_end|>".to_string()),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let completion = process_incoming_request(&request, &model, &context);
+        std::fs::remove_file(script_path).ok();
+
+        let completion = completion.unwrap();
+        assert_eq!(completion.stop_reason, StopReason::EndOfText);
+        assert_eq!(completion.text, " Hello world");
+    }
+
+    #[test]
+    fn request_waiting_too_long_is_dropped() {
+        let config = config::Configuration::default();
+        let (request_tx, request_rx) = flume::unbounded();
+        let (_embedding_tx, embedding_rx) = flume::unbounded();
+        let _thread = make_thread(
+            idle_unload::ModelSlot::new(lorem_model(50.0, 50), None),
+            request_rx,
+            embedding_rx,
+            Default::default(),
+            store::Store::open(&Default::default()).unwrap(),
+            GenerationContext {
+                limits: GenerationLimits {
+                    max_queue_wait: Some(Duration::from_millis(100)),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            thread_state(&config),
+        );
+
+        // The first request takes about a second, so the second waits too long behind it
+        let (first, first_rx) = request(1);
+        request_tx.send(first).unwrap();
+        assert!(matches!(
+            first_rx.recv_timeout(Duration::from_secs(5)),
+            Ok(Token::Token(_))
+        ));
+        let (second, second_rx) = request(2);
+        request_tx.send(second).unwrap();
+
+        let mut second_tokens = vec![];
+        while let Ok(token) = second_rx.recv_timeout(Duration::from_secs(5)) {
+            second_tokens.push(token);
+        }
+        assert!(matches!(
+            second_tokens[..],
+            [Token::Error(InferenceError::QueueTimeout)]
+        ));
+        while let Ok(token) = first_rx.recv_timeout(Duration::from_secs(5)) {
+            assert!(!matches!(token, Token::Error(_)));
+        }
+    }
+
+    // A session that "generates" the given tokens, to run the token loop without a model.
+    // It can cancel its request after some of them, as a user pressing Cancel would
+    struct Scripted {
+        tokens: &'static [&'static str],
+        cancel_after: Option<(usize, Cancellations)>,
+    }
+
+    impl Inference for Scripted {
+        fn context_size(&self) -> usize {
+            64
+        }
+
+        fn infer(
+            &mut self,
+            request: &Request,
+            _rng: &mut rand::rngs::StdRng,
+            callback: &mut TokenCallback<'_>,
+        ) -> Result<llm::InferenceStats, InferenceError> {
+            let mut stats = llm::InferenceStats {
+                prompt_tokens: 1,
+                ..Default::default()
+            };
+            callback(llm::InferenceResponse::PromptToken(request.prompt.clone()))?;
+            for (index, token) in self.tokens.iter().enumerate() {
+                if let Some((after, cancellations)) = &self.cancel_after {
+                    if index == *after {
+                        cancellations.cancel(request.message_id);
+                    }
+                }
+                stats.predict_tokens += 1;
+                let response = llm::InferenceResponse::InferredToken(token.to_string());
+                if let llm::InferenceFeedback::Halt = callback(response)? {
+                    return Ok(stats);
+                }
+            }
+            callback(llm::InferenceResponse::EotToken)?;
+            Ok(stats)
+        }
+    }
+
+    // function to run a request's token loop in a scripted session, with seed 7
+    fn run_scripted(
+        request: &Request,
+        session: Scripted,
+        cancellations: &Cancellations,
+        limits: &GenerationLimits,
+    ) -> Result<Completion, InferenceError> {
+        let context = GenerationContext {
+            cancellations: cancellations.clone(),
+            limits: limits.clone(),
+            ..Default::default()
+        };
+        run_sequence(
+            request,
+            Session::new(request, Box::new(session), Some(7)),
+            &context,
+            None,
+            request.echo_prompt,
+        )
+    }
+
+    #[test]
+    fn tokens_are_sent_in_batches_with_the_metadata_last() {
+        let (mut request, token_rx) = request(1);
+        request.token_buffer_size = 2;
+        request.echo_prompt = true;
+        let session = Scripted {
+            tokens: &["a", "b", "c"],
+            cancel_after: None,
+        };
+        let completion =
+            run_scripted(&request, session, &Default::default(), &Default::default()).unwrap();
+
+        assert_eq!(completion.stop_reason, StopReason::EndOfText);
+        assert_eq!(completion.text, "abc");
+        assert_eq!(completion.seed, 7);
+        let tokens: Vec<_> = token_rx.drain().collect();
+        assert!(matches!(
+            &tokens[..],
+            [Token::Token(first), Token::Token(second), Token::Metadata(metadata)]
+                if first == "Helloa" && second == "bc" && metadata.tokens_generated == 3
+        ));
+    }
+
+    #[test]
+    fn cancelling_mid_stream_stops_the_session() {
+        let (request, token_rx) = request(1);
+        let cancellations = Cancellations::default();
+        let session = Scripted {
+            tokens: &["a", "b", "c", "d"],
+            cancel_after: Some((2, cancellations.clone())),
+        };
+        let result = run_scripted(&request, session, &cancellations, &Default::default());
+
+        assert!(matches!(result, Err(InferenceError::Cancelled)));
+        let tokens: Vec<_> = token_rx.drain().collect();
+        assert!(matches!(
+            &tokens[..],
+            [Token::Token(a), Token::Token(b)] if a == "a" && b == "b"
+        ));
+    }
+
+    #[test]
+    fn the_end_of_text_marker_halts_the_session() {
+        let (request, _token_rx) = request(1);
+        let session = Scripted {
+            tokens: &[" Hi", "<|im_end|>", " more"],
+            cancel_after: None,
+        };
+        let limits = GenerationLimits {
+            end_of_text: Some("<|im_end|>".to_string()),
+            ..Default::default()
+        };
+        let completion = run_scripted(&request, session, &Default::default(), &limits).unwrap();
+
+        assert_eq!(completion.stop_reason, StopReason::EndOfText);
+        assert_eq!(completion.text, " Hi");
+        assert_eq!(completion.stats.predict_tokens, 2);
+    }
+
+    #[test]
+    fn an_end_of_text_marker_split_over_tokens_is_never_sent() {
+        let (request, token_rx) = request(1);
+        let session = Scripted {
+            tokens: &[" Hi", " there<|im", "_end|>", " more"],
+            cancel_after: None,
+        };
+        let limits = GenerationLimits {
+            end_of_text: Some("<|im_end|>".to_string()),
+            ..Default::default()
+        };
+        let completion = run_scripted(&request, session, &Default::default(), &limits).unwrap();
+
+        assert_eq!(completion.stop_reason, StopReason::EndOfText);
+        assert_eq!(completion.text, " Hi there");
+        assert_eq!(completion.stats.predict_tokens, 3);
+        let sent: String = token_rx
+            .drain()
+            .filter_map(|token| match token {
+                Token::Token(t) => Some(t),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(sent, " Hi there");
+    }
+
+    #[test]
+    fn the_mock_model_generates_through_its_session() {
+        let model = lorem_model(1000.0, 3);
+        let (request, _token_rx) = request(1);
+        let config = config::Configuration::default().inference.session_config();
+        let sampler_order = config::default_sampler_order();
+        let mut session = Session::start(&model, &request, Some(7), config, &sampler_order);
+        assert_eq!(session.context_size(), 2048);
+
+        let mut tokens = vec![];
+        let stats = session
+            .run(&mut |response| {
+                tokens.push(response);
+                Ok(llm::InferenceFeedback::Continue)
+            })
+            .unwrap();
+        assert_eq!(stats.predict_tokens, 3);
+        // The prompt, the three words, and the end of the text
+        assert_eq!(tokens.len(), 5);
+        assert!(matches!(tokens[4], llm::InferenceResponse::EotToken));
+    }
 }
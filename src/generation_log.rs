@@ -0,0 +1,50 @@
+// This file holds the generation log, which (if `stream_to_file` is set) records every
+// completed generation as a line of JSON, for debugging or for collecting a dataset.
+// It's written by the generation thread as each request finishes, so it has the whole
+// output no matter what happens to the Discord messages showing it.
+use serde_json::json;
+
+use crate::{config, generation, logging};
+
+// The number of rotated files kept, besides the current one
+const MAX_ROTATED_FILES: usize = 5;
+
+// The generation log, rotated by size like the log file
+pub struct GenerationLog(logging::RollingFile);
+
+impl GenerationLog {
+    // function to open the generation log described by the configuration, if there is one.
+    // If it can't be opened, the bot runs without it
+    pub fn open(inference: &config::Inference) -> Option<Self> {
+        let path = inference.stream_to_file.as_deref()?;
+        let max_size = u64::from(inference.stream_file_max_mb) * 1024 * 1024;
+
+        match logging::RollingFile::open(path, max_size, MAX_ROTATED_FILES) {
+            Ok(file) => Some(Self(file)),
+            Err(err) => {
+                warn!(
+                    "Failed to open the generation log {}: {err:?}",
+                    path.display()
+                );
+                None
+            }
+        }
+    }
+
+    // function to record a completed generation
+    pub fn record(&mut self, request: &generation::Request, completion: &generation::Completion) {
+        let line = json!({
+            "timestamp": logging::timestamp(),
+            "user_id": request.context.user_id,
+            "command": request.context.command_name,
+            "prompt": request.prompt,
+            "response": completion.text,
+        })
+        .to_string()
+            + "\n";
+
+        if let Err(err) = self.0.write_line(&line).and_then(|_| self.0.flush()) {
+            warn!("Failed to write to the generation log: {err:?}");
+        }
+    }
+}
@@ -1,71 +1,288 @@
 use crate::{
+    alert, attachments, bench,
     config::{self, Configuration},
-    constant,
+    config_validate, constant, debug_generate, details, embedding, export, fallback, feedback,
     generation::{self, Token},
+    health, idle_unload, inspect, invite, limits, notice, persona, postprocess, presence,
+    prompts::Prompts,
+    queue, recurring, registration, reminder, report, reroll, schedule, store, system_prompt,
     util::{self, run_and_report_error, DiscordInteraction},
 };
 use anyhow::Context as AnyhowContext;
 use serenity::{
     async_trait,
     builder::CreateComponents,
-    client::{Context, EventHandler},
+    client::{bridge::gateway::event::ShardStageUpdateEvent, Context, EventHandler},
     futures::StreamExt,
-    http::Http,
+    gateway::ConnectionStage,
+    http::{ratelimiting::Route, Http, LightMethod},
     model::{
         application::interaction::Interaction,
         prelude::{
-            command::{Command, CommandOptionType},
             interaction::{
-                application_command::ApplicationCommandInteraction, InteractionResponseType,
+                application_command::{ApplicationCommandInteraction, CommandDataOption},
+                message_component::MessageComponentInteraction,
+                InteractionResponseType,
             },
             *,
         },
     },
 };
-use std::collections::HashSet;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 pub struct Handler {
     // Import necessary dependencies from external crates and modules
     _model_thread: std::thread::JoinHandle<()>, // A handle to the background thread responsible for model generation
     config: Configuration,                      // Holds the configuration settings for the handler
     request_tx: flume::Sender<generation::Request>, // Channel sender for sending requests to the background thread
-    cancel_tx: flume::Sender<MessageId>, // Channel sender for canceling a specific message generation
+    embedding_tx: flume::Sender<generation::EmbeddingRequest>, // Channel sender for embedding requests to the background thread
+    cancellations: generation::Cancellations, // The requests that have been asked to stop, shared with the background thread
+    readiness: Arc<health::Readiness>, // Shared readiness state, updated with the gateway status
+    alerter: alert::Alerter,           // Reports internal errors to the operator's webhook
+    reminders: reminder::Reminders, // Reminders scheduled with `/remind`, shared with the reminder task
+    schedules: recurring::Schedules, // The daily posts, for `/schedules`; shared with their task
+    presence: Arc<presence::CurrentPresence>, // The bot's current status, restored when shards reconnect
+    fallback_http: reqwest::Client, // HTTP client for the fallback backend, if one is configured
+    commands_registered: AtomicBool, // Set once a shard has started registering the commands; cleared again if that fails
+    known_guilds: Mutex<HashSet<GuildId>>, // The guilds the shards were in when they connected, and those joined since
+    started_at: Timestamp, // When the bot started, to tell guilds joined since apart from those it was already in
+    transcripts: export::Transcripts, // Recently finished responses, for the "Export" button
+    rerolls: reroll::Rerolls, // Recently finished responses, for the reroll buttons
+    snippets: embedding::Snippets, // Named embeddings stored with `/embed`
+    active_requests: generation::ActiveRequests, // The requests being generated right now, for `/status`
+    board: schedule::Board, // Where waiting requests stand, as published by the generation thread
+    recent_prompts: RecentPrompts, // Prompts submitted in the last few seconds, to catch duplicates
+    cooldowns: limits::Cooldowns, // When each user last used each command successfully, for `cooldown_seconds`
+    system_prompts: system_prompt::SystemPrompts, // Guilds' system prompts set with `/system`
+    personas: persona::ChannelPersonas, // Channels' personas chosen with `/persona`
+    feedback: feedback::Feedback, // Votes on responses, from the feedback reactions and buttons
+    reports: report::Reports,     // Responses reported to the moderators, with their posts
 }
 // Definition of the Handler struct
 impl Handler {
     // Constructor method to create a new Handler instance
-    pub fn new(config: Configuration, model: Box<dyn llm::Model>) -> Self {
-        // Create unbounded channels for sending requests and cancel messages
+    pub fn new(
+        config: Configuration,
+        model: idle_unload::ModelSlot,
+        readiness: Arc<health::Readiness>,
+        store: store::Store,
+        reminders: reminder::Reminders,
+        schedules: recurring::Schedules,
+        presence: Arc<presence::CurrentPresence>,
+    ) -> Self {
+        // Create unbounded channels for sending requests, and the set of cancelled requests
         let (request_tx, request_rx) = flume::unbounded::<generation::Request>();
-        let (cancel_tx, cancel_rx) = flume::unbounded::<MessageId>();
+        let (embedding_tx, embedding_rx) = flume::unbounded::<generation::EmbeddingRequest>();
+
+        // Load the embeddings stored with `/embed` by earlier runs
+        let snippets = embedding::Snippets::load(store.clone());
+        let system_prompts = system_prompt::SystemPrompts::load(store.clone());
+        let personas = persona::ChannelPersonas::load(store.clone());
+        let feedback =
+            feedback::Feedback::new(store.clone(), config.inference.feedback_voting_hours);
+        let reports = report::Reports::new(store.clone());
+
+        // The moderation patterns and the tokenizer config were checked when the config was
+        // loaded
+        let context = generation::GenerationContext::new(&config)
+            .expect("the moderation patterns and tokenizer config are valid");
+        let cancellations = context.cancellations.clone();
+        let active_requests = context.active_requests.clone();
+        let board = context.board.clone();
 
         // Start a background thread for model generation
-        let _model_thread = generation::make_thread(model, request_rx, cancel_rx);
+        let _model_thread = generation::make_thread(
+            model,
+            request_rx,
+            embedding_rx,
+            readiness.clone(),
+            store.clone(),
+            context,
+            generation::ThreadState::new(&config, store),
+        );
+
+        // Report internal errors to the operator, if they've configured a webhook
+        let alerter = alert::Alerter::new(config.error_webhook_url.clone());
 
         // Initialize and return a new Handler instance
         Self {
             _model_thread,
             config,
             request_tx,
-            cancel_tx,
+            embedding_tx,
+            cancellations,
+            readiness,
+            alerter,
+            reminders,
+            schedules,
+            presence,
+            fallback_http: reqwest::Client::new(),
+            commands_registered: AtomicBool::new(false),
+            known_guilds: Default::default(),
+            started_at: Timestamp::now(),
+            transcripts: Default::default(),
+            rerolls: Default::default(),
+            snippets,
+            active_requests,
+            board,
+            recent_prompts: Default::default(),
+            cooldowns: Default::default(),
+            system_prompts,
+            personas,
+            feedback,
+            reports,
         }
     }
+
+    // Returns a sender for the generation queue, so that other frontends
+    // (like the HTTP API) can share the same model thread as Discord
+    pub fn request_tx(&self) -> flume::Sender<generation::Request> {
+        self.request_tx.clone()
+    }
+
+    // Returns the count of requests the generation thread is running, which leaves out the
+    // ones still waiting and the ones the fallback backend serves
+    pub fn active_requests(&self) -> generation::ActiveRequests {
+        self.active_requests.clone()
+    }
 }
 
 // Implementation of the EventHandler trait for the Handler struct
 #[async_trait]
 impl EventHandler for Handler {
     //  method called when the bot is ready
+    // This is called once for every shard, and again whenever a shard reconnects from scratch
     async fn ready(&self, ctx: Context, ready: Ready) {
-        println!("{} is connected; registering commands...", ready.user.name);
+        let shard_id = ctx.shard_id;
+        info!("[shard {shard_id}] {} is connected", ready.user.name);
+
+        // The bot's own feedback reactions aren't votes
+        self.feedback.set_bot_id(ready.user.id);
+
+        // The guilds the shard is already in, so that their `guild_create`s aren't taken for joins
+        self.known_guilds
+            .lock()
+            .unwrap()
+            .extend(ready.guilds.iter().map(|g| g.id));
+
+        // Commands are global, so they only need registering once, by whichever shard is first
+        if !self.commands_registered.swap(true, Ordering::SeqCst) {
+            info!("[shard {shard_id}] Registering commands...");
+
+            // A failure isn't fatal: the commands already registered keep working, and the
+            // next shard to connect (or this one, on reconnecting) tries again
+            if let Err(err) = registration::register_commands(&ctx.http, &self.config).await {
+                error!(
+                    "Error while registering commands, so they may be out of date until the \
+                     next reconnect: {err:?}"
+                );
+                self.commands_registered.store(false, Ordering::SeqCst);
+            }
+        }
+
+        info!("[shard {shard_id}] {} is good to go!", ready.user.name);
+        self.readiness.set_shard_connected(shard_id, true);
+
+        // A shard that connected from scratch has no status, so give it the current one
+        self.presence.restore(&ctx).await;
+    }
+
+    // method called when the gateway connection is resumed after a drop
+    async fn resume(&self, ctx: Context, _: ResumedEvent) {
+        info!("[shard {}] Connection resumed", ctx.shard_id);
+        self.readiness.set_shard_connected(ctx.shard_id, true);
+    }
 
-        // Attempt to register commands, exit with an error if unsuccessful
-        if let Err(err) = ready_handler(&ctx.http, &self.config).await {
-            println!("Error while registering commands: `{err}`");
-            std::process::exit(1);
+    // method called when a shard's connection stage changes (e.g. it disconnects).
+    // Responses that are already generating carry on regardless, because they're sent
+    // through Discord's HTTP API, which doesn't depend on the gateway connection
+    async fn shard_stage_update(&self, _ctx: Context, event: ShardStageUpdateEvent) {
+        let shard_id = event.shard_id.0;
+        let connected = event.new == ConnectionStage::Connected;
+        if event.old == ConnectionStage::Connected && !connected {
+            info!("[shard {shard_id}] Disconnected ({:?})", event.new);
         }
+        self.readiness.set_shard_connected(shard_id, connected);
+    }
 
-        println!("{} is good to go!", ready.user.name);
+    // method called when the bot joins a guild, or a guild's data is sent on connecting.
+    // Guilds that aren't allowed are left straight away, if the config asks for that
+    async fn guild_create(&self, ctx: Context, guild: Guild) {
+        // A guild that wasn't in the shard's `Ready` is a new join. Events are handled
+        // concurrently, so one that was might still be on its way in; it joined before the bot
+        // started, though
+        let is_new = self.known_guilds.lock().unwrap().insert(guild.id)
+            && guild.joined_at.unix_timestamp() >= self.started_at.unix_timestamp();
+
+        let authentication = &self.config.authentication;
+        if authentication.block_on_leave && !authentication.is_guild_allowed(Some(guild.id.0)) {
+            info!(
+                "[shard {}] Leaving guild {} ({}), which is not in allowed_guilds",
+                ctx.shard_id, guild.name, guild.id
+            );
+            if let Err(err) = guild.leave(&ctx.http).await {
+                warn!("Failed to leave guild {}: {err:?}", guild.id);
+            }
+            return;
+        }
+        if !is_new {
+            return;
+        }
+
+        // Commands are registered globally, so the new guild has them already
+        info!(
+            "[shard {}] Joined guild {} ({})",
+            ctx.shard_id, guild.name, guild.id
+        );
+        if let (true, Some(channel_id)) = (self.config.announce_on_join, guild.system_channel_id) {
+            let welcome = registration::welcome_message(&self.config);
+            if let Err(err) = channel_id.say(&ctx.http, welcome).await {
+                warn!(
+                    "Failed to post the welcome message in guild {}: {err:?}",
+                    guild.id
+                );
+            }
+        }
+    }
+
+    // method called when the bot is removed from a guild (or the guild becomes unavailable)
+    async fn guild_delete(&self, ctx: Context, incomplete: UnavailableGuild) {
+        let authentication = &self.config.authentication;
+        let removed = !incomplete.unavailable;
+        // Being added back is a new join
+        if removed {
+            self.known_guilds.lock().unwrap().remove(&incomplete.id);
+        }
+        if removed
+            && authentication.block_on_leave
+            && authentication.allowed_guilds.contains(&incomplete.id.0)
+        {
+            warn!(
+                "[shard {}] The bot was removed from allowed guild {}",
+                ctx.shard_id, incomplete.id
+            );
+        }
+    }
+
+    // methods called when a reaction is added to or removed from a message, which
+    // counts as a vote if it's one of the feedback reactions on a response
+    async fn reaction_add(&self, _ctx: Context, reaction: Reaction) {
+        if self.config.inference.enable_reaction_feedback {
+            self.feedback.handle_reaction(&reaction, true);
+        }
+    }
+
+    async fn reaction_remove(&self, _ctx: Context, reaction: Reaction) {
+        if self.config.inference.enable_reaction_feedback {
+            self.feedback.handle_reaction(&reaction, false);
+        }
     }
 
     //  method called when a user interacts with the bot
@@ -73,6 +290,22 @@ impl EventHandler for Handler {
         // Reference to the HTTP context for making HTTP requests
         let http = &ctx.http;
 
+        // Count every interaction, so that types that are ignored below still show up
+        self.readiness
+            .count_interaction(&format!("{:?}", interaction.kind()));
+
+        // Refuse to serve guilds (and DMs) that aren't on the allow list, if there is one. Every
+        // kind of interaction is checked, so that the buttons under responses posted before a
+        // guild was taken off the list stop working too
+        if !self
+            .config
+            .authentication
+            .is_guild_allowed(interaction_guild_id(&interaction).map(|id| id.0))
+        {
+            refuse_unauthorized(&interaction, http).await;
+            return;
+        }
+
         // Match the type of interaction
         match interaction {
             // Handle application command interactions
@@ -80,21 +313,187 @@ impl EventHandler for Handler {
                 let name = cmd.data.name.as_str();
                 let commands = &self.config.commands;
 
-                // Check if the command exists in the configuration
-                if let Some(command) = commands.get(name) {
-                    // Run the command and report any errors
+                // Handle the built-in `/remind` command
+                if name == constant::command::REMIND {
+                    run_and_report_error(&cmd, http, remind(self, &cmd, http, ctx.shard_id)).await;
+                    return;
+                }
+
+                // Handle the built-in, owner-only `/bench` command
+                if name == constant::command::BENCH {
+                    run_and_report_error(
+                        &cmd,
+                        http,
+                        bench::bench(&cmd, http, &self.request_tx, &self.config, ctx.shard_id),
+                    )
+                    .await;
+                    return;
+                }
+
+                // Handle the built-in, owner-only `/debug_generate` command
+                if name == constant::command::DEBUG_GENERATE {
+                    run_and_report_error(
+                        &cmd,
+                        http,
+                        debug_generate::debug_generate(
+                            &cmd,
+                            http,
+                            &self.request_tx,
+                            &self.config,
+                            ctx.shard_id,
+                        ),
+                    )
+                    .await;
+                    return;
+                }
+
+                // Handle the built-in, owner-only `/config-validate` command
+                if name == constant::command::CONFIG_VALIDATE {
+                    run_and_report_error(&cmd, http, config_validate::config_validate(&cmd, http))
+                        .await;
+                    return;
+                }
+
+                // Handle the built-in, owner-only `/schedules` command
+                if name == constant::command::SCHEDULES {
+                    run_and_report_error(
+                        &cmd,
+                        http,
+                        recurring::schedules_command(&cmd, http, &self.schedules),
+                    )
+                    .await;
+                    return;
+                }
+
+                // Handle the built-in `/status` command
+                if name == constant::command::STATUS {
+                    run_and_report_error(&cmd, http, status(self, &cmd, http)).await;
+                    return;
+                }
+
+                // Handle the built-in `/invite` command
+                if name == constant::command::INVITE {
+                    run_and_report_error(&cmd, http, invite::invite(&cmd, http, &self.config))
+                        .await;
+                    return;
+                }
+
+                // Handle the built-in `/stats` command
+                if name == constant::command::STATS {
                     run_and_report_error(
                         &cmd,
                         http,
-                        hallucinate(
+                        feedback::stats_command(&cmd, http, &self.feedback),
+                    )
+                    .await;
+                    return;
+                }
+
+                // Handle the built-in `/queue` command
+                if name == constant::command::QUEUE {
+                    run_and_report_error(
+                        &cmd,
+                        http,
+                        queue::queue_command(&cmd, http, &self.board, self.request_tx.len()),
+                    )
+                    .await;
+                    return;
+                }
+
+                // Handle the built-in embedding commands
+                if name == constant::command::SIMILAR {
+                    run_and_report_error(
+                        &cmd,
+                        http,
+                        embedding::similar(&cmd, http, &self.embedding_tx, &self.readiness),
+                    )
+                    .await;
+                    return;
+                }
+                if name == constant::command::EMBED {
+                    run_and_report_error(
+                        &cmd,
+                        http,
+                        embedding::embed_command(
                             &cmd,
                             http,
-                            self.request_tx.clone(),
-                            &self.config.inference,
-                            command,
+                            &self.embedding_tx,
+                            &self.readiness,
+                            &self.snippets,
                         ),
                     )
                     .await;
+                    return;
+                }
+
+                // Handle the built-in, admin-only `/system` command
+                if name == constant::command::SYSTEM {
+                    run_and_report_error(
+                        &cmd,
+                        http,
+                        system_prompt::system_command(
+                            &cmd,
+                            http,
+                            &self.config,
+                            &self.system_prompts,
+                        ),
+                    )
+                    .await;
+                    return;
+                }
+
+                // Handle the built-in `/persona` command
+                if name == constant::command::PERSONA {
+                    run_and_report_error(
+                        &cmd,
+                        http,
+                        persona::persona_command(&cmd, http, &self.config, &self.personas),
+                    )
+                    .await;
+                    return;
+                }
+
+                // Handle the built-in `/prompt` command
+                if name == constant::command::PROMPT {
+                    run_and_report_error(
+                        &cmd,
+                        http,
+                        inspect::prompt_command(&cmd, http, &self.config, |command| {
+                            channel_system_prompt(self, &cmd, command).1
+                        }),
+                    )
+                    .await;
+                    return;
+                }
+
+                // Check if the command exists in the configuration
+                if let Some(command) = commands.get(name) {
+                    // Refuse the command if the member lacks the permission it requires
+                    if !has_required_permission(&cmd, command) {
+                        notice::send(&cmd, http, &permission_rejection(name, command)).await;
+                        return;
+                    }
+
+                    // Run the command and report any errors
+                    run_and_report_error(&cmd, http, async {
+                        let result = hallucinate(self, &cmd, http, command, ctx.shard_id).await;
+
+                        // Errors that aren't the user's mistake are our fault,
+                        // so the operator is told about them as well
+                        if let Some(err) = result.as_ref().err().filter(|e| !util::is_user_error(e))
+                        {
+                            self.alerter.report(
+                                err,
+                                alert::AlertContext {
+                                    command_name: name,
+                                    guild_id: cmd.guild_id.map(|id| id.0),
+                                },
+                            );
+                        }
+
+                        result
+                    })
+                    .await;
                 }
             }
             // Handle message component interactions
@@ -108,8 +507,12 @@ impl EventHandler for Handler {
                     {
                         // Check if the interaction is initiated by the same user
                         if cmp.user.id == user_id {
-                            // Send a cancel message to the background thread
-                            self.cancel_tx.send(MessageId(message_id)).ok();
+                            // A waiting request is dropped from the queue (and its handler
+                            // updates the placeholder); a running one is stopped by the
+                            // background thread
+                            if !self.board.cancel(MessageId(message_id)) {
+                                self.cancellations.cancel(MessageId(message_id));
+                            }
 
                             // Respond with a deferred update to the original message
                             cmp.create_interaction_response(http, |r| {
@@ -120,102 +523,442 @@ impl EventHandler for Handler {
                         }
                     }
                 }
+
+                // Admins can cancel requests from `/queue`
+                if let [queue::CANCEL_BUTTON_PREFIX, message_id] =
+                    cmp.data.custom_id.split('#').collect::<Vec<_>>()[..]
+                {
+                    if let Ok(message_id) = message_id.parse::<u64>() {
+                        run_and_report_error(
+                            &cmp,
+                            http,
+                            queue::cancel_button(
+                                &cmp,
+                                http,
+                                &self.board,
+                                &self.cancellations,
+                                MessageId(message_id),
+                            ),
+                        )
+                        .await;
+                    }
+                }
+
+                // The user who asked for a response can reroll it at another temperature
+                if let [prefix @ (reroll::WEIRDER_PREFIX | reroll::FOCUSED_PREFIX), first_id] =
+                    cmp.data.custom_id.split('#').collect::<Vec<_>>()[..]
+                {
+                    if let Ok(first_id) = first_id.parse::<u64>() {
+                        run_and_report_error(
+                            &cmp,
+                            http,
+                            reroll(
+                                self,
+                                &cmp,
+                                http,
+                                MessageId(first_id),
+                                prefix == reroll::WEIRDER_PREFIX,
+                            ),
+                        )
+                        .await;
+                    }
+                }
+
+                // Anyone can vote on a finished response, once
+                if let [prefix @ (feedback::UP_PREFIX | feedback::DOWN_PREFIX), first_id] =
+                    cmp.data.custom_id.split('#').collect::<Vec<_>>()[..]
+                {
+                    if let Ok(first_id) = first_id.parse::<u64>() {
+                        let vote = if prefix == feedback::UP_PREFIX {
+                            feedback::Vote::Up
+                        } else {
+                            feedback::Vote::Down
+                        };
+                        run_and_report_error(
+                            &cmp,
+                            http,
+                            self.feedback
+                                .vote_button(&cmp, http, MessageId(first_id), vote),
+                        )
+                        .await;
+                    }
+                }
+
+                // Anyone can report a finished response to the moderators
+                if let [report::BUTTON_PREFIX, first_id] =
+                    cmp.data.custom_id.split('#').collect::<Vec<_>>()[..]
+                {
+                    if let (Ok(first_id), Some(channel_id)) =
+                        (first_id.parse::<u64>(), self.config.report_channel_id)
+                    {
+                        run_and_report_error(
+                            &cmp,
+                            http,
+                            report::report(
+                                &cmp,
+                                http,
+                                &self.reports,
+                                &self.transcripts,
+                                channel_id,
+                                MessageId(first_id),
+                            ),
+                        )
+                        .await;
+                    }
+                }
+
+                // Anyone can look up how a finished response was generated
+                if let [details::BUTTON_PREFIX, first_id] =
+                    cmp.data.custom_id.split('#').collect::<Vec<_>>()[..]
+                {
+                    if let Ok(first_id) = first_id.parse::<u64>() {
+                        run_and_report_error(
+                            &cmp,
+                            http,
+                            details::show(&cmp, http, &self.transcripts, MessageId(first_id)),
+                        )
+                        .await;
+                    }
+                }
+
+                // Anyone can export a finished response
+                if let [export::BUTTON_PREFIX, first_id] =
+                    cmp.data.custom_id.split('#').collect::<Vec<_>>()[..]
+                {
+                    if let Ok(first_id) = first_id.parse::<u64>() {
+                        run_and_report_error(
+                            &cmp,
+                            http,
+                            export::export(
+                                &cmp,
+                                http,
+                                &self.transcripts,
+                                &self.config,
+                                MessageId(first_id),
+                            ),
+                        )
+                        .await;
+                    }
+                }
             }
-            _ => {} // Ignore other types of interactions
+            // Suggest values for autocompleted options as they're typed
+            Interaction::Autocomplete(ac) if ac.data.name == constant::command::PERSONA => {
+                if let Err(err) = persona::autocomplete(&ac, http, &self.config).await {
+                    warn!("Failed to autocomplete personas: {err:?}");
+                }
+            }
+            Interaction::Autocomplete(ac) if ac.data.name == constant::command::PROMPT => {
+                if let Err(err) = inspect::autocomplete(&ac, http, &self.config).await {
+                    warn!("Failed to autocomplete commands: {err:?}");
+                }
+            }
+            // Ignore other types of interactions
+            other => debug!("Ignoring a {:?} interaction", other.kind()),
         };
     }
 }
 
-//  function to handle the bot's readiness and command registration
-async fn ready_handler(http: &Http, config: &Configuration) -> anyhow::Result<()> {
-    // Retrieve the globally registered commands from Discord
-    let registered_commands = Command::get_global_application_commands(http).await?;
+// function to find the guild an interaction came from (`None` for DMs and pings)
+fn interaction_guild_id(interaction: &Interaction) -> Option<GuildId> {
+    match interaction {
+        Interaction::ApplicationCommand(cmd) => cmd.guild_id,
+        Interaction::MessageComponent(cmp) => cmp.guild_id,
+        Interaction::Autocomplete(ac) => ac.guild_id,
+        Interaction::ModalSubmit(modal) => modal.guild_id,
+        Interaction::Ping(_) => None,
+    }
+}
 
-    // Create a HashSet of names from the registered commands
-    let registered_commands: HashSet<_> = registered_commands
-        .iter()
-        .map(|c| c.name.as_str())
-        .collect();
+// function to tell the user of an interaction from a guild that isn't allowed that the bot
+// won't serve it. Autocomplete can't show a message, so it gets no suggestions instead
+async fn refuse_unauthorized(interaction: &Interaction, http: &Http) {
+    const REFUSAL: &str = "This bot is not authorized for your server.";
+    let result = match interaction {
+        Interaction::ApplicationCommand(cmd) => cmd.create_ephemeral_followup(http, REFUSAL).await,
+        Interaction::MessageComponent(cmp) => cmp.create_ephemeral(http, REFUSAL).await,
+        Interaction::ModalSubmit(modal) => modal.create_ephemeral(http, REFUSAL).await,
+        Interaction::Autocomplete(ac) => ac
+            .create_autocomplete_response(http, |r| r)
+            .await
+            .map_err(Into::into),
+        Interaction::Ping(_) => Ok(()),
+    };
+    if let Err(err) = result {
+        warn!("Failed to refuse an unauthorized guild: {err:?}");
+    }
+}
 
-    // Create a HashSet of names from the enabled commands in the bot's configuration
-    let our_commands: HashSet<_> = config
-        .commands
-        .iter()
-        .filter(|(_, v)| v.enabled)
-        .map(|(k, _)| k.as_str())
-        .collect();
+// function to check if the member using a command has the permission it requires.
+// Discord includes the member's resolved permissions in guild interactions, which is
+// more robust than checking role IDs (which differ between servers)
+fn has_required_permission(cmd: &ApplicationCommandInteraction, command: &config::Command) -> bool {
+    let Some(required) = command.require_permission else {
+        return true; // The command doesn't require anything
+    };
 
-    // Check if the registered commands match the configured commands
-    if registered_commands != our_commands {
-        // If there's a mismatch, reset the globally registered commands
-        Command::set_global_application_commands(http, |c| c.set_application_commands(vec![]))
-            .await?;
+    // Outside of a guild there is no member, and so no permissions
+    let permissions = cmd
+        .member
+        .as_ref()
+        .and_then(|m| m.permissions)
+        .unwrap_or_default();
+
+    permissions.administrator() || permissions.contains(required)
+}
+
+// function to build the notice shown to a member who lacks the permission a command requires
+fn permission_rejection(name: &str, command: &config::Command) -> notice::Rejection {
+    notice::Rejection::MissingPermission {
+        command: name.to_string(),
+        permissions: command
+            .require_permission
+            .unwrap_or_default()
+            .get_permission_names()
+            .join(", "),
     }
+}
 
-    // Iterate over the enabled commands in the bot's configuration
-    for (name, command) in config.commands.iter().filter(|(_, v)| v.enabled) {
-        // Create a global application command for each configured command
-        Command::create_global_application_command(http, |cmd| {
-            cmd.name(name)
-                .description(command.description.as_str())
-                .create_option(|opt| {
-                    // Create an option for the prompt parameter
-                    opt.name(constant::value::PROMPT)
-                        .description("The prompt.")
-                        .kind(CommandOptionType::String)
-                        .required(true)
-                });
-
-            // Create additional parameters for the command
-            create_parameters(cmd)
-        })
-        .await?;
+// function to handle `/remind`, which schedules one of the configured commands to run later
+async fn remind(
+    handler: &Handler,
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    shard_id: u64,
+) -> anyhow::Result<()> {
+    // Take what's needed from the handler
+    let config = &handler.config;
+
+    // Import constants and utility functions
+    use constant::value as v;
+    use util::{value_to_integer, value_to_string};
+
+    // Extract the options from the command interaction
+    let options = &cmd.data.options;
+    let command_name = util::get_value(options, v::COMMAND)
+        .and_then(value_to_string)
+        .context("no command specified")?;
+    let user_prompt = util::get_value(options, v::PROMPT)
+        .and_then(value_to_string)
+        .context("no prompt specified")?;
+    let in_minutes = util::get_value(options, v::IN_MINUTES)
+        .and_then(value_to_integer)
+        .context("no delay specified")?
+        .max(1) as u64;
+    if in_minutes > reminder::MAX_MINUTES {
+        return Err(util::user_error(format!(
+            "Reminders can be at most {} days away.",
+            reminder::MAX_MINUTES / (24 * 60)
+        )));
+    }
+
+    // The reminder runs with the permissions the command needs now
+    let command = config
+        .commands
+        .get(&command_name)
+        .filter(|c| c.enabled)
+        .with_context(|| format!("there is no command named `{command_name}`"))?;
+    if !has_required_permission(cmd, command) {
+        return Err(permission_rejection(&command_name, command).into());
     }
 
-    Ok(()) // Return Ok if the command registration is successful
+    // Render the prompt now, exactly as the command itself would
+    let user_prompt = config.inference.preprocess_user_prompt(user_prompt);
+    // `/remind` has no way to give the command's own options, so they take their defaults
+    let (_, system_prompt) = channel_system_prompt(handler, cmd, command);
+    let prompt = command
+        .assemble_prompt(
+            config,
+            system_prompt.as_deref(),
+            &user_prompt,
+            None,
+            &HashMap::new(),
+        )?
+        .prompt;
+    limits::check_prompt_length(
+        command
+            .max_prompt_chars
+            .or(config.inference.max_prompt_chars),
+        &prompt,
+    )?;
+    handler.reminders.add(reminder::ScheduledReminder {
+        due_at: store::now() + in_minutes * 60,
+        prompt,
+        context: generation::RequestContext {
+            guild_id: cmd.guild_id.map(|id| id.0),
+            channel_id: cmd.channel_id.0,
+            user_id: cmd.user.id.0,
+            command_name: command_name.clone(),
+            shard_id: Some(shard_id),
+        },
+    });
+
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|m| {
+                m.content(format!(
+                    "I'll run /{command_name} in {in_minutes} minute(s) and post the result here. \
+                     Reminders are only kept in memory, so this one is lost if I restart before then."
+                ))
+                .ephemeral(true)
+            })
+    })
+    .await?;
+
+    Ok(())
 }
 
-// Function to create additional parameters for an application command
-fn create_parameters(
-    command: &mut serenity::builder::CreateApplicationCommand,
-) -> &mut serenity::builder::CreateApplicationCommand {
-    // Create an option for the seed parameter
-    command.create_option(|opt| {
-        opt.name(constant::value::SEED)
-            .kind(CommandOptionType::Integer)
-            .description("The seed to use for sampling.")
-            .min_int_value(0)
-            .required(false)
+// function to handle `/status`, which shows how busy the bot is
+async fn status(
+    handler: &Handler,
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+) -> anyhow::Result<()> {
+    let generating = handler.active_requests.count();
+    let queued = schedule::queue_depth(&handler.readiness, &handler.request_tx);
+    let average_ms = handler
+        .readiness
+        .average_generation_ms
+        .load(Ordering::SeqCst);
+
+    let mut content = format!("Generating: {generating}\nQueued: {queued}");
+    if queued > 0 && handler.config.inference.fast_lane_tokens > 0 {
+        // Requests the generation thread hasn't taken yet haven't been put in a lane
+        let fast = handler.readiness.fast_lane_depth.load(Ordering::SeqCst);
+        let slow = handler.readiness.slow_lane_depth.load(Ordering::SeqCst);
+        content += &format!(" (fast lane {fast} · slow lane {slow})");
+    }
+    if average_ms > 0 {
+        content += &format!(
+            "\nAverage generation time: {:.1}s",
+            average_ms as f64 / 1000.0
+        );
+    }
+    if queued > 0 {
+        let wait = schedule::estimated_wait(&handler.readiness, &handler.request_tx);
+        content += &format!("\nEstimated wait: {:.0}s", wait.as_secs_f64());
+    }
+    let interactions = handler.readiness.interaction_counts();
+    if !interactions.is_empty() {
+        let counts: Vec<_> = interactions
+            .iter()
+            .map(|(kind, count)| format!("{kind} {count}"))
+            .collect();
+        content += &format!("\nInteractions received: {}", counts.join(" · "));
+    }
+    if handler.config.model.idle_unload_minutes.is_some() {
+        let unloaded = handler.readiness.model_unloaded.load(Ordering::SeqCst);
+        content += if unloaded {
+            "\nModel: unloaded (idle)"
+        } else {
+            "\nModel: loaded"
+        };
+    }
+    let inference = &handler.config.inference;
+    if let Some(seconds) = inference.max_generation_seconds {
+        let stopped = handler
+            .readiness
+            .timed_out_generations
+            .load(Ordering::SeqCst);
+        content += &format!("\nTime limit: {seconds}s per generation ({stopped} stopped)");
+    }
+    if let Some(seconds) = inference.max_queue_wait_seconds {
+        let dropped = handler.readiness.expired_requests.load(Ordering::SeqCst);
+        content += &format!("\nQueue limit: {seconds}s of waiting ({dropped} dropped)");
+    }
+    if handler.config.fallback.is_some() {
+        let local = handler.readiness.routed_local.load(Ordering::SeqCst);
+        let fallback = handler.readiness.routed_fallback.load(Ordering::SeqCst);
+        content += &format!("\nRouted: {local} local · {fallback} fallback");
+    }
+
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|m| m.content(content).ephemeral(true))
     })
+    .await?;
+
+    Ok(())
 }
 
 //  function to handle the hallucination process
 async fn hallucinate(
+    handler: &Handler,
     cmd: &ApplicationCommandInteraction,
     http: &Http,
-    request_tx: flume::Sender<generation::Request>,
-    inference: &config::Inference,
     command: &config::Command,
+    shard_id: u64,
 ) -> anyhow::Result<()> {
+    // Take what's needed from the handler
+    let inference = &handler.config.inference;
+    let readiness = &handler.readiness;
+
     // Import constants and utility functions
     use constant::value as v;
-    use util::{value_to_integer, value_to_string};
+    use util::value_to_string;
 
     // Extract options from the command interaction
     let options = &cmd.data.options;
 
-    // Retrieve user prompt from options, converting it to a string
-    let user_prompt = util::get_value(options, v::PROMPT)
-        .and_then(value_to_string)
-        .context("no prompt specified")?;
-    println!("user_prompt - {:?}", user_prompt);
+    // The message the command was used on, if it was used from a message's context menu
+    let replied_to = cmd.data.resolved.messages.values().next();
+    let reply = replied_to.map(|m| m.content.as_str());
 
-    // Replace newlines in the user prompt if specified in the inference configuration
-    let user_prompt = if inference.replace_newlines {
-        user_prompt.replace("\\n", "\n")
-    } else {
-        user_prompt
-    };
+    // Retrieve user prompt from options, with newlines replaced if that's turned on
+    let user_prompt = user_prompt(options, reply, inference)?;
+    debug!("user_prompt - {:?}", user_prompt);
+
+    // The values of the command's own options, by name
+    let mut option_values: HashMap<_, _> = command
+        .options
+        .iter()
+        .filter_map(|o| {
+            let value = util::get_value(options, &o.name).and_then(util::value_to_text)?;
+            Some((o.name.clone(), value))
+        })
+        .collect();
+
+    // The text files attached to the message, which are only downloaded if they're used
+    if let Some(message) = replied_to.filter(|_| command.uses_attachments()) {
+        let text = attachments::text(message, inference.max_attachment_bytes).await?;
+        option_values.insert(v::ATTACHMENTS.to_string(), text);
+    }
+
+    // The system prompt goes in front of the command's prompt, if the command uses one
+    let (persona, system_prompt) = channel_system_prompt(handler, cmd, command);
+    let assembled = command.assemble_prompt(
+        &handler.config,
+        system_prompt.as_deref(),
+        &user_prompt,
+        reply,
+        &option_values,
+    )?;
+
+    // Text the response should start with, which goes after the prompt so that the model
+    // continues it. It's shown as the start of the response, not as part of the prompt
+    let response_prefix = util::get_value(options, v::PREFIX)
+        .and_then(value_to_string)
+        .map(|p| inference.preprocess_user_prompt(p))
+        .unwrap_or_default();
+    let generation_prompt = format!("{}{response_prefix}", assembled.prompt);
+
+    // Refuse users who used the command a moment ago, and prompts that are too long, before
+    // posting anything, so that they leave no trace
+    limits::check(
+        &handler.cooldowns,
+        cmd.user.id.0,
+        &cmd.data.name,
+        command.max_prompt_chars.or(inference.max_prompt_chars),
+        &generation_prompt,
+    )?;
+
+    // The same prompt twice in quick succession is almost always a double submission
+    let dedup_window = Duration::from_secs(inference.dedup_window_seconds);
+    if !handler
+        .recent_prompts
+        .check_and_insert(cmd.user.id, &generation_prompt, dedup_window)
+    {
+        return Err(notice::Rejection::DuplicatePrompt.into());
+    }
 
     // Create an Outputter to manage outputting tokens and messages
     let mut outputter = Outputter::new(
@@ -223,135 +966,790 @@ async fn hallucinate(
         cmd,
         Prompts {
             show_prompt_template: inference.show_prompt_template,
-            processed: command.prompt.replace("{{PROMPT}}", &user_prompt),
+            processed: assembled.prompt,
             user: user_prompt,
-            template: command.prompt.clone(),
+            prefix: assembled.prefix,
+            suffix: assembled.suffix,
         },
         std::time::Duration::from_millis(inference.discord_message_update_interval_ms),
+        inference.max_discord_edits_per_minute,
     )
     .await?;
+    outputter.output_replacements = command.output_replacements.clone();
+    outputter.thinking = postprocess::Thinking::of(command);
+    outputter.response_format = command.response_format.clone();
+    outputter.persona = persona.map(|(name, _)| name.to_string());
 
     // Get the interaction message and its ID
     let message = cmd.get_interaction_message(http).await?;
     let message_id = message.id;
 
-    // Retrieve the seed from options, converting it to a u64
-    let seed = util::get_value(options, v::SEED)
-        .and_then(value_to_integer)
-        .map(|i| i as u64);
-    println!(" seed - {:?}", seed);
+    // Retrieve the seed from options
+    let seed = requested_seed(options);
+    debug!(" seed - {:?}", seed);
 
-    // Create a channel for communication of tokens
-    let (token_tx, token_rx) = flume::unbounded();
+    // And how many variations to generate, which commands with alternatives of their own
+    // don't offer
+    if command.n_sequences == 1 {
+        outputter.variations = variation_count(options, inference);
+    }
+
+    // If the command wants progress updates, log them as they come in
+    let progress_tx = command.log_progress.then(|| {
+        let (progress_tx, progress_rx) = flume::unbounded::<generation::GenerationProgress>();
+        tokio::spawn(async move {
+            while let Ok(progress) = progress_rx.recv_async().await {
+                info!(
+                    "{message_id}: {} tokens generated, {:.0}% of context used",
+                    progress.tokens_generated,
+                    progress.kv_cache_usage * 100.0
+                );
+            }
+        });
+        progress_tx
+    });
+
+    // What the request is generated with, and who it's for
+    let sampling = persona.map(|(_, p)| p.sampling).unwrap_or_default();
+    let context = generation::RequestContext {
+        guild_id: cmd.guild_id.map(|id| id.0),
+        channel_id: cmd.channel_id.0,
+        user_id: cmd.user.id.0,
+        command_name: cmd.data.name.clone(),
+        shard_id: Some(shard_id),
+    };
+
+    // Generate each variation the user asked for (usually just one) from its own seed,
+    // retrying with a new random seed if the model stops too early
+    let mut resolved_seed = seed;
+    let mut completion = None;
+    for variation in 0..outputter.variations {
+        // The variation before this one is finished, and goes with the others
+        if variation > 0 {
+            let finished = outputter.response().to_string();
+            outputter.alternatives.push(finished);
+            outputter.restart(variation);
+        }
+
+        // The first variation has the seed the user asked for, and the others count up from the
+        // seed it ended up with, so that the same seed gives the same set again
+        let variation_seed = match variation {
+            0 => seed,
+            _ => resolved_seed.map(|s| s.wrapping_add(variation as u64)),
+        };
+
+        // Create channels for the tokens, and for how the generation ended
+        let (token_tx, mut token_rx) = flume::unbounded();
+        let (completion_tx, completion_rx) = flume::bounded(1);
+
+        let mut request = generation::Request {
+            prompt: generation_prompt.clone(),
+            batch_size: inference.batch_size,
+            batch_decode: inference.batch_decode,
+            token_buffer_size: inference.token_buffer_size,
+            token_tx,
+            message_id,
+            seed: variation_seed,
+            maximum_token_count: None,
+            n_sequences: command.n_sequences,
+            sampling,
+            low_priority: false,
+            echo_prompt: true,
+            context: context.clone(),
+            progress_tx: progress_tx.clone(),
+            completion_tx: Some(completion_tx),
+            trace: None,
+            queued_at: Instant::now(),
+        };
+
+        let mut retries = 0;
+        loop {
+            // The same request with a new random seed, in case this one's output is too short.
+            // Retrying with the same seed would only produce the same short output
+            let (retry, retry_rx) = request.clone_with_seed(rand::random());
+
+            // The notice telling the user where their request is in the queue, if it has to wait
+            let mut waiting_notice = None;
+            // Tells the token loop if the request is cancelled before it starts, while it's queued
+            let mut removed_rx = None;
+            match handler.send(request, command.local_only)? {
+                Routed::Fallback => {
+                    outputter.footer = Some("Served by the fallback backend".into());
+                }
+                Routed::Local {
+                    queue_depth,
+                    removed_rx: removed,
+                } => {
+                    removed_rx = Some(removed);
+
+                    // The model was unloaded for being idle, so it has to be loaded again first
+                    if readiness.model_unloaded.load(Ordering::SeqCst) {
+                        let load_ms = readiness.model_load_ms.load(Ordering::SeqCst);
+                        outputter.warming_up(load_ms.div_ceil(1000).max(1)).await?;
+                    }
+
+                    // Let the user know their request is waiting behind others, and for how
+                    // long. They can cancel it while it waits, too
+                    if retries == 0 && queue_depth > 0 {
+                        outputter.add_cancel_button().await?;
+                        waiting_notice = WaitingNotice::send(handler, cmd, http, message_id).await;
+                    }
+                }
+            }
+
+            let streamed = stream_tokens(
+                &mut outputter,
+                token_rx,
+                removed_rx,
+                waiting_notice,
+                retries == 0 && variation == 0,
+                &handler.config.moderation.withheld_notice,
+            )
+            .await?;
+            let Streamed::Finished(metadata) = streamed else {
+                return Ok(());
+            };
+
+            // The first variation's seed and statistics stand for the whole set
+            if let Some(metadata) = metadata.filter(|_| variation == 0) {
+                resolved_seed = Some(metadata.seed);
+                if inference.seed_display {
+                    outputter.seed = Some(metadata.seed);
+                }
+                if inference.show_generation_metadata {
+                    outputter.generation_metadata = Some(metadata);
+                }
+            }
+
+            // Check whether the model ended the output before the command's minimum.
+            // The count comes from the generation thread, so it's right even when tokens are
+            // batched, and it doesn't include the echoed prompt. The fallback backend
+            // doesn't report one, so its output is always kept
+            completion = completion_rx.try_recv().ok();
+            outputter.stopped_repeating |= completion
+                .as_ref()
+                .is_some_and(|c| c.stop_reason == generation::StopReason::RepetitionLoop);
+            if completion
+                .as_ref()
+                .is_some_and(|c| c.stop_reason == generation::StopReason::TimeLimit)
+            {
+                outputter.time_limit = inference.max_generation_seconds;
+            }
+            let too_short = command.min_generation_tokens.is_some_and(|min| {
+                completion.as_ref().is_some_and(|c| {
+                    c.stop_reason == generation::StopReason::EndOfText
+                        && c.stats.predict_tokens < min
+                })
+            });
+            if !too_short {
+                break;
+            }
+            if retries >= command.max_retries {
+                outputter.short_response = true;
+                break;
+            }
+
+            retries += 1;
+            info!(
+                "{message_id}: short response, retrying ({retries}/{})",
+                command.max_retries
+            );
+            outputter.restart(variation);
+            (request, token_rx) = (retry, retry_rx);
+        }
+    }
 
-    // Send a generation request to the processing thread
-    request_tx.send(generation::Request {
-        prompt: outputter.prompts.processed.clone(),
+    // The output being generated last is the final alternative, if there are several
+    if !outputter.alternatives.is_empty() {
+        let last = outputter.response().to_string();
+        outputter.alternatives.push(last);
+    }
+
+    postprocess_output(&mut outputter, command, completion.as_ref()).await?;
+
+    // A single response can be rerolled at another temperature, if that's turned on
+    outputter.rerollable = inference.max_rerolls > 0 && outputter.alternatives.is_empty();
+
+    // What the response was generated with, which is remembered with it and its votes
+    let record = details::GenerationRecord::new(
+        &cmd.data.name,
+        command,
+        &handler.config.model,
+        sampling,
+        resolved_seed,
+        completion.as_ref(),
+    );
+
+    // Voting opens before the buttons are shown, so that they work straight away
+    if inference.enable_feedback_buttons {
+        handler.feedback.open_ballot(message_id, &record);
+        outputter.votable = true;
+    }
+    outputter.reportable = handler.config.report_channel_id.is_some();
+
+    // Finish the outputting process, since no errors occurred
+    outputter.finish().await?;
+
+    // The command was used successfully, so the user has to wait before using it again
+    handler.cooldowns.used(
+        cmd.user.id.0,
+        &cmd.data.name,
+        Duration::from_secs(command.cooldown_seconds),
+    );
+
+    // Let users vote on the response, if that's turned on
+    if inference.enable_reaction_feedback {
+        if let Some(last) = outputter.messages.last() {
+            if let Err(err) = handler
+                .feedback
+                .add_reactions(http, message_id, last, &record)
+                .await
+            {
+                warn!("{message_id}: failed to add the feedback reactions: {err:?}");
+            }
+        }
+    }
+
+    // Remember the response, so that it can be exported exactly as it was generated and its
+    // details looked up
+    handler
+        .transcripts
+        .insert(message_id, outputter.transcript(record));
+
+    // And what it was generated from, so that it can be generated again
+    if outputter.rerollable {
+        handler.rerolls.insert(
+            message_id,
+            reroll::Reroll {
+                user_id: cmd.user.id,
+                command_name: cmd.data.name.clone(),
+                prompt: generation_prompt,
+                prompts: outputter.prompts.clone(),
+                sampling,
+                persona: outputter.persona.clone(),
+                context,
+                messages: outputter.messages.clone(),
+                rerolls: 0,
+            },
+        );
+    }
+
+    Ok(()) // Return Ok if the hallucination process is successful
+}
+
+// function to retrieve the user's prompt from the command's options, with the prompt
+// preprocessing settings applied. Context menu commands have no options, so the prompt of one
+// used on a message is empty
+fn user_prompt(
+    options: &[CommandDataOption],
+    reply: Option<&str>,
+    inference: &config::Inference,
+) -> anyhow::Result<String> {
+    let user_prompt = util::get_value(options, constant::value::PROMPT)
+        .and_then(util::value_to_string)
+        .or_else(|| reply.map(|_| String::new()))
+        .ok_or_else(|| util::user_error("no prompt specified"))?;
+
+    Ok(inference.preprocess_user_prompt(user_prompt))
+}
+
+// function to retrieve the seed the user asked for from the command's options, if any
+fn requested_seed(options: &[CommandDataOption]) -> Option<u64> {
+    util::get_value(options, constant::value::SEED)
+        .and_then(util::value_to_integer)
+        .map(|i| i as u64)
+}
+
+// function to retrieve how many variations the user asked for from the command's options,
+// within the configured limit
+fn variation_count(options: &[CommandDataOption], inference: &config::Inference) -> usize {
+    util::get_value(options, constant::value::COUNT)
+        .and_then(util::value_to_integer)
+        .map_or(1, |count| {
+            count.clamp(1, inference.max_variations as i64) as usize
+        })
+}
+
+// function to handle a press of one of the reroll buttons. The response is generated again
+// from the same prompt at a higher (or lower) temperature than it last was, and written over
+// the messages it's shown in
+async fn reroll(
+    handler: &Handler,
+    cmp: &MessageComponentInteraction,
+    http: &Http,
+    first_id: MessageId,
+    weirder: bool,
+) -> anyhow::Result<()> {
+    let inference = &handler.config.inference;
+
+    // The response is taken out while it's rerolled, so that it can't be rerolled twice at once
+    let Some(mut state) = handler.rerolls.take(first_id) else {
+        return Err(util::user_error(
+            "This response can't be rerolled anymore, or is being rerolled already.",
+        ));
+    };
+    if cmp.user.id != state.user_id {
+        handler.rerolls.insert(first_id, state);
+        return Err(util::user_error(
+            "Only the user who asked for this response can reroll it.",
+        ));
+    }
+    let Some(command) = handler
+        .config
+        .commands
+        .get(&state.command_name)
+        .filter(|c| c.enabled)
+    else {
+        return Err(util::user_error(format!(
+            "`/{}` isn't available anymore.",
+            state.command_name
+        )));
+    };
+
+    // Each reroll carries on from the temperature of the last one
+    state.step_temperature(weirder, inference.reroll_temperature_delta);
+    state.rerolls += 1;
+
+    // Acknowledge the press, and take the buttons away until the new output is done
+    cmp.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::DeferredUpdateMessage)
+    })
+    .await?;
+    for msg in &mut state.messages {
+        msg.edit(http, |m| m.set_components(CreateComponents::default()))
+            .await?;
+    }
+
+    let mut outputter = Outputter::resume(
+        http,
+        cmp,
+        state.messages.clone(),
+        state.prompts.clone(),
+        std::time::Duration::from_millis(inference.discord_message_update_interval_ms),
+        inference.max_discord_edits_per_minute,
+    );
+    outputter.output_replacements = command.output_replacements.clone();
+    outputter.thinking = postprocess::Thinking::of(command);
+    outputter.response_format = command.response_format.clone();
+    outputter.persona = state.persona.clone();
+    outputter.footer = Some(format!(
+        "Rerolled at temperature {:.2}",
+        state.temperature()
+    ));
+
+    // The request is the original one again, apart from the temperature (and the seed)
+    let (token_tx, token_rx) = flume::unbounded();
+    let (completion_tx, completion_rx) = flume::bounded(1);
+    handler.request_tx.send(generation::Request {
+        prompt: state.prompt.clone(),
         batch_size: inference.batch_size,
+        batch_decode: inference.batch_decode,
+        token_buffer_size: inference.token_buffer_size,
         token_tx,
-        message_id,
-        seed,
+        message_id: first_id,
+        seed: None,
+        maximum_token_count: None,
+        n_sequences: 1,
+        sampling: state.sampling,
+        low_priority: false,
+        echo_prompt: true,
+        context: state.context.clone(),
+        progress_tx: None,
+        completion_tx: Some(completion_tx),
+        trace: None,
+        queued_at: Instant::now(),
     })?;
 
-    // Create a stream from the token receiver
+    let mut seed = None;
     let mut stream = token_rx.into_stream();
-
-    let mut errored = false;
-
-    // Process tokens from the stream
     while let Some(token) = stream.next().await {
         match token {
-            Token::Token(t) => {
-                outputter.new_token(&t).await?;
+            Token::Token(t) => outputter.new_token(&t).await?,
+            Token::SequenceStart(_) => {}
+            Token::Metadata(metadata) => {
+                seed = Some(metadata.seed);
+                if inference.seed_display {
+                    outputter.seed = Some(metadata.seed);
+                }
+                if inference.show_generation_metadata {
+                    outputter.generation_metadata = Some(metadata);
+                }
+            }
+            Token::Error(generation::InferenceError::Cancelled) => {
+                return outputter.cancelled().await;
+            }
+            Token::Error(generation::InferenceError::Withheld) => {
+                return outputter
+                    .withheld(&handler.config.moderation.withheld_notice)
+                    .await;
+            }
+            Token::Error(err @ generation::InferenceError::QueueTimeout) => {
+                outputter.error().await?;
+                return Err(util::user_error(err.to_string()));
             }
             Token::Error(err) => {
-                match err {
-                    generation::InferenceError::Cancelled => outputter.cancelled().await?,
-                    generation::InferenceError::Custom(m) => outputter.error(&m).await?,
-                };
-                errored = true;
-                break;
+                outputter.error().await?;
+                return Err(err.into());
             }
         }
     }
 
-    // Finish the outputting process if no errors occurred
-    if !errored {
-        outputter.finish().await?;
+    let completion = completion_rx.try_recv().ok();
+    outputter.stopped_repeating = completion
+        .as_ref()
+        .is_some_and(|c| c.stop_reason == generation::StopReason::RepetitionLoop);
+    outputter.time_limit = inference.max_generation_seconds.filter(|_| {
+        completion
+            .as_ref()
+            .is_some_and(|c| c.stop_reason == generation::StopReason::TimeLimit)
+    });
+    postprocess_output(&mut outputter, command, completion.as_ref()).await?;
+
+    // The buttons go away once the response has been rerolled as many times as it can be
+    outputter.rerollable = state.rerolls < inference.max_rerolls;
+
+    // The new output is voted on afresh
+    let record = details::GenerationRecord::new(
+        &state.command_name,
+        command,
+        &handler.config.model,
+        state.sampling,
+        seed,
+        completion.as_ref(),
+    );
+    if inference.enable_feedback_buttons {
+        handler.feedback.open_ballot(first_id, &record);
+        outputter.votable = true;
+    }
+    outputter.reportable = handler.config.report_channel_id.is_some();
+    outputter.finish().await?;
+
+    handler
+        .transcripts
+        .insert(first_id, outputter.transcript(record));
+    if outputter.rerollable {
+        state.messages = outputter.messages.clone();
+        handler.rerolls.insert(first_id, state);
     }
 
-    Ok(()) // Return Ok if the hallucination process is successful
+    Ok(())
 }
 
-// Definition of the Prompts struct
-struct Prompts {
-    show_prompt_template: bool,
-    processed: String,
-    user: String,
-    template: String,
+// Where a request went to be generated
+enum Routed {
+    // The local model's queue, which had `queue_depth` requests waiting. The receiver is told
+    // if the request is cancelled before it starts
+    Local {
+        queue_depth: usize,
+        removed_rx: flume::Receiver<()>,
+    },
+    // The fallback backend, because the local queue was saturated
+    Fallback,
 }
 
-// Implementation of methods for the Prompts struct
-impl Prompts {
-    // Method to create a markdown message, incorporating user prompt and processed output
-    fn make_markdown_message(&self, message: &str) -> String {
-        // Determine whether to display the prompt template or the user's actual prompt
-        let (message, display_prompt) = if !self.show_prompt_template {
-            (self.decouple_prompt_from_message(message), &self.user)
-        } else {
-            (message.to_string(), &self.processed)
-        };
+// What requests are sent to to be generated. The handler sends them to its generation thread,
+// or to the fallback backend; the tests script the tokens that come back instead
+trait GenerationBackend {
+    // function to send a request off to be generated. Requests for commands whose prompts must
+    // stay on this machine (`local_only`) never go to the fallback backend
+    fn send(&self, request: generation::Request, local_only: bool) -> anyhow::Result<Routed>;
+}
 
-        // Format the message with appropriate markdown styling
-        match message.strip_prefix(display_prompt) {
-            Some(msg) => format!("**{display_prompt}**{msg}"),
-            None => match display_prompt.strip_prefix(&message) {
-                Some(ungenerated) => {
-                    if message.is_empty() {
-                        format!("~~{ungenerated}~~")
-                    } else {
-                        format!("**{message}**~~{ungenerated}~~")
+impl GenerationBackend for Handler {
+    fn send(&self, request: generation::Request, local_only: bool) -> anyhow::Result<Routed> {
+        let readiness = &self.readiness;
+        let message_id = request.message_id;
+
+        // Overflow to the fallback backend if the local queue is saturated
+        let queue_depth = schedule::queue_depth(readiness, &self.request_tx);
+        let estimated_wait = schedule::estimated_wait(readiness, &self.request_tx);
+        let fallback = self.config.fallback.as_ref().filter(|settings| {
+            !local_only && fallback::should_use_fallback(settings, queue_depth, estimated_wait)
+        });
+        if let Some(settings) = fallback {
+            readiness.routed_fallback.fetch_add(1, Ordering::SeqCst);
+            info!("{message_id}: local queue is saturated, using the fallback backend");
+
+            let (client, settings) = (self.fallback_http.clone(), settings.clone());
+            let cancellations = self.cancellations.clone();
+            tokio::spawn(async move {
+                fallback::generate(&client, &settings, request, &cancellations).await
+            });
+            return Ok(Routed::Fallback);
+        }
+
+        // Send a generation request to the processing thread
+        readiness.routed_local.fetch_add(1, Ordering::SeqCst);
+        let removed_rx = self.board.submit(message_id);
+        self.request_tx.send(request)?;
+        Ok(Routed::Local {
+            queue_depth,
+            removed_rx,
+        })
+    }
+}
+
+// How streaming a request's tokens into a response ended
+enum Streamed {
+    // Every token came in, with the generation's metadata if it was sent
+    Finished(Option<generation::GenerationMetadata>),
+    // The response was ended early (e.g. it was cancelled), and has been told so
+    Ended,
+}
+
+// function to show a request's tokens in the response as they come in, keeping the waiting
+// notice up to date until they start. `first_attempt` is whether nothing has been generated
+// for the response yet, so that a request taken off the queue leaves no trace
+async fn stream_tokens(
+    outputter: &mut Outputter<'_>,
+    token_rx: flume::Receiver<Token>,
+    mut removed_rx: Option<flume::Receiver<()>>,
+    mut waiting_notice: Option<WaitingNotice<'_>>,
+    first_attempt: bool,
+    withheld_notice: &str,
+) -> anyhow::Result<Streamed> {
+    let mut stream = token_rx.into_stream();
+    let mut metadata = None;
+    let mut refresh = tokio::time::interval_at(
+        tokio::time::Instant::now() + WaitingNotice::REFRESH_INTERVAL,
+        WaitingNotice::REFRESH_INTERVAL,
+    );
+    loop {
+        let token = tokio::select! {
+            token = stream.next() => token,
+            removed = async { removed_rx.as_ref()?.recv_async().await.ok() }, if removed_rx.is_some() => {
+                // The request was cancelled before it started. Otherwise it has started,
+                // and can only be cancelled through the generation thread
+                if removed.is_none() {
+                    removed_rx = None;
+                    continue;
+                }
+                if let Some(notice) = waiting_notice.take() {
+                    notice.remove().await;
+                }
+                if first_attempt {
+                    outputter.removed_from_queue().await?;
+                } else {
+                    outputter.cancelled().await?;
+                }
+                return Ok(Streamed::Ended);
+            }
+            _ = refresh.tick(), if waiting_notice.is_some() => {
+                if let Some(notice) = &mut waiting_notice {
+                    if !notice.refresh().await {
+                        notice.remove().await;
+                        waiting_notice = None;
                     }
                 }
-                None => message.to_string(),
-            },
+                continue;
+            }
+        };
+        let Some(token) = token else {
+            return Ok(Streamed::Finished(metadata));
+        };
+
+        // The request has started (or failed), so it's no longer waiting
+        if let Some(notice) = waiting_notice.take() {
+            notice.remove().await;
+        }
+
+        match token {
+            Token::Token(t) => {
+                outputter.new_token(&t).await?;
+            }
+            Token::SequenceStart(index) => {
+                // The output so far is a finished alternative; the next one takes its place
+                if index > 0 {
+                    let finished = outputter.response().to_string();
+                    outputter.alternatives.push(finished);
+                    outputter.set_response("");
+                }
+            }
+            Token::Metadata(m) => metadata = Some(m),
+            Token::Error(generation::InferenceError::Cancelled) => {
+                // Cancellation isn't an error, so it's announced in the channel
+                outputter.cancelled().await?;
+                return Ok(Streamed::Ended);
+            }
+            Token::Error(generation::InferenceError::Withheld) => {
+                outputter.withheld(withheld_notice).await?;
+                return Ok(Streamed::Ended);
+            }
+            Token::Error(err @ generation::InferenceError::QueueTimeout) => {
+                // Nothing went wrong, so the operator isn't alerted
+                outputter.error().await?;
+                return Err(util::user_error(err.to_string()));
+            }
+            Token::Error(err) => {
+                // Errors are reported to the user by `run_and_report_error` (an
+                // `OomError`'s message tells them how to avoid running out of memory)
+                outputter.error().await?;
+                return Err(err.into());
+            }
         }
     }
+}
 
-    // Method to decouple the prompt from the generated output in a message
-    fn decouple_prompt_from_message(&self, output: &str) -> String {
-        // Split the template into prefix and suffix based on the {{PROMPT}} placeholder
-        let (prefix, suffix) = self.template.split_once("{{PROMPT}}").unwrap_or_default();
+// function to apply the command's replacements and trimming to a finished output for good,
+// so that what's shown, exported and remembered all match, and to check that output that's
+// meant to be JSON is
+async fn postprocess_output(
+    outputter: &mut Outputter<'_>,
+    command: &config::Command,
+    completion: Option<&generation::Completion>,
+) -> anyhow::Result<()> {
+    // Output from the fallback backend has no stop reason, so it's never cut back to a sentence
+    let thinking = postprocess::Thinking::of(command);
+    if !command.output_replacements.is_empty()
+        || command.trim.is_enabled()
+        || thinking != postprocess::Thinking::Show
+    {
+        // Output cut off by the time limit ends mid-sentence just like output cut off by
+        // the token limit
+        let hit_length_limit = completion.is_some_and(|c| {
+            matches!(
+                c.stop_reason,
+                generation::StopReason::TokenLimit | generation::StopReason::TimeLimit
+            )
+        });
+        let postprocess = |response: &str| {
+            let response = postprocess::strip_thinking(response, thinking);
+            let response = postprocess::replace(&response, &command.output_replacements);
+            if command.trim.is_enabled() {
+                postprocess::trim(&response, &command.trim, hit_length_limit)
+            } else {
+                response.into_owned()
+            }
+        };
+        let response = postprocess(outputter.response());
+        outputter.alternatives = outputter
+            .alternatives
+            .iter()
+            .map(|a| postprocess(a))
+            .collect();
+
+        // The replacements are now part of the output, so they mustn't be applied again
+        outputter.output_replacements.clear();
+        outputter.thinking = postprocess::Thinking::Show;
+        outputter.set_response(&response);
+    }
 
-        // Retrieve the user's prompt
-        let prompt = &self.user;
+    // Output that's meant to be JSON is only useful if it is (every alternative of it)
+    if command.response_format == config::ResponseFormat::Json {
+        let invalid = std::iter::once(outputter.response())
+            .chain(outputter.alternatives.iter().map(String::as_str))
+            .find_map(|r| serde_json::from_str::<serde_json::Value>(r).err());
+        if let Some(err) = invalid {
+            outputter.error().await?;
+            return Err(util::user_error(format!(
+                "The response isn't valid JSON ({err}). Try again, or with a different prompt."
+            )));
+        }
+    }
 
-        // Strip the prefix from the generated output
-        let message = if let Some(msg) = output.strip_prefix(prefix) {
-            msg
-        } else {
-            return String::new();
-        };
+    Ok(())
+}
 
-        // Strip the user prompt from the remaining message
-        let response = if let Some(resp) = message.strip_prefix(prompt) {
-            resp
-        } else {
-            return message.to_string();
-        };
+// function to find the system prompt that goes in front of a command's prompt where it's
+// used, if it uses one. The channel's persona, if it has one, brings its own (and its
+// sampling changes), so it's returned too
+fn channel_system_prompt<'a>(
+    handler: &'a Handler,
+    cmd: &ApplicationCommandInteraction,
+    command: &config::Command,
+) -> (Option<(&'a str, &'a config::Persona)>, Option<String>) {
+    system_prompt::for_channel(
+        &handler.config,
+        command,
+        &handler.personas,
+        &handler.system_prompts,
+        cmd.guild_id.map(|id| id.0),
+        cmd.channel_id.0,
+    )
+}
 
-        // Strip the suffix from the final response
-        let response = if let Some(resp) = response.strip_prefix(suffix) {
-            resp
-        } else {
-            return prompt.to_string();
+// An ephemeral message telling a user where their request is in the queue, and about how
+// long it will wait. It's refreshed as the estimates change, and removed once the request starts
+struct WaitingNotice<'a> {
+    handler: &'a Handler,
+    cmd: &'a ApplicationCommandInteraction,
+    http: &'a Http,
+    // The request it's about
+    request_id: MessageId,
+    // The followup message the notice is in
+    id: MessageId,
+    // What it says, so that edits that wouldn't change anything can be skipped
+    text: String,
+}
+
+impl<'a> WaitingNotice<'a> {
+    // How often the notice is brought up to date
+    const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+    // function to send the notice for a request, if it's waiting
+    async fn send(
+        handler: &'a Handler,
+        cmd: &'a ApplicationCommandInteraction,
+        http: &'a Http,
+        request_id: MessageId,
+    ) -> Option<Self> {
+        let text = Self::text(handler, request_id)?;
+        match cmd
+            .create_followup_message(http, |m| m.content(&text).ephemeral(true))
+            .await
+        {
+            Ok(message) => Some(Self {
+                handler,
+                cmd,
+                http,
+                request_id,
+                id: message.id,
+                text,
+            }),
+            Err(err) => {
+                warn!("{request_id}: failed to send the waiting notice: {err:?}");
+                None
+            }
+        }
+    }
+
+    // function to bring the notice up to date. Returns `false` once the request isn't waiting
+    async fn refresh(&mut self) -> bool {
+        let Some(text) = Self::text(self.handler, self.request_id) else {
+            return false;
         };
+        if text != self.text {
+            if let Err(err) = self
+                .cmd
+                .edit_followup_message(self.http, self.id, |m| m.content(&text))
+                .await
+            {
+                warn!(
+                    "{}: failed to update the waiting notice: {err:?}",
+                    self.request_id
+                );
+            }
+            self.text = text;
+        }
+        true
+    }
 
-        // Add a newline if the suffix ends with a newline character
-        let newline = if suffix.ends_with('\n') { "\n" } else { "" };
+    // function to remove the notice, now that the request isn't waiting
+    async fn remove(&self) {
+        if let Err(err) = self.cmd.delete_followup_message(self.http, self.id).await {
+            warn!("Failed to remove the waiting notice: {err:?}");
+        }
+    }
 
-        // Format the decoupled prompt and response
-        format!("{prompt}{newline}{response}")
+    // function to write out where the request stands, if it's waiting
+    fn text(handler: &Handler, request_id: MessageId) -> Option<String> {
+        let standing = handler
+            .board
+            .standing(request_id, handler.request_tx.len())?;
+        Some(format!(
+            "The bot is busy, so your request is waiting. {}.",
+            standing.describe()
+        ))
     }
 }
 
@@ -364,6 +1762,9 @@ struct Outputter<'a> {
     // Reference to the Http client
     http: &'a Http,
 
+    // The interaction being responded to, which later messages are followups to
+    interaction: &'a dyn DiscordInteraction,
+
     // User ID associated with the Outputter
     user_id: UserId,
 
@@ -387,6 +1788,56 @@ struct Outputter<'a> {
 
     // Duration defining the time between updates
     last_update_duration: std::time::Duration,
+
+    // The configured time between updates, which `last_update_duration`
+    // returns to after being backed off by rate-limiting
+    base_update_duration: std::time::Duration,
+
+    // Limits how many edits are made per minute while generating
+    edit_bucket: EditBucket,
+
+    // The command's find-and-replace rules, applied to the output whenever it's shown
+    output_replacements: Vec<config::OutputReplacement>,
+
+    // How the thinking of chain-of-thought models is shown, which is worked out again from
+    // everything generated so far whenever the output is shown
+    thinking: postprocess::Thinking,
+
+    // How the output is shown
+    response_format: config::ResponseFormat,
+
+    // A note shown in italics at the end of the finished response, if any
+    footer: Option<String>,
+
+    // The seed shown at the end of the finished response, if it should be shown
+    seed: Option<u64>,
+
+    // The persona the response was generated with, shown at the end of it
+    persona: Option<String>,
+
+    // How the generation went, shown at the end of the finished response if it should be shown
+    generation_metadata: Option<generation::GenerationMetadata>,
+
+    // Whether the response is still too short after every retry, which is noted at its end
+    short_response: bool,
+    // Whether the generation was stopped because the output was repeating itself
+    stopped_repeating: bool,
+    // The time limit, in seconds, if the generation was stopped for running past it
+    time_limit: Option<u64>,
+
+    // The finished alternatives, when the command generates several. Once they're all done,
+    // they're shown as numbered fields of an embed instead of in the messages
+    alternatives: Vec<String>,
+    // How many variations the user asked for with the `count` option. When there are several,
+    // each one is generated as a request of its own and they're shown like alternatives
+    variations: usize,
+
+    // Whether the finished response gets the buttons that reroll it at another temperature
+    rerollable: bool,
+    // Whether the finished response gets the vote buttons
+    votable: bool,
+    // Whether the finished response gets the button that reports it to the moderators
+    reportable: bool,
 }
 
 // the <'a> syntax is a lifetime parameter,
@@ -401,9 +1852,10 @@ impl<'a> Outputter<'a> {
     // function to create a new Outputter instance
     async fn new(
         http: &'a Http,                            // Reference to Http with lifetime 'a
-        cmd: &ApplicationCommandInteraction,       // Discord Application Command Interaction
+        cmd: &'a ApplicationCommandInteraction,    // Discord Application Command Interaction
         prompts: Prompts,                          // Struct containing information about prompts
         last_update_duration: std::time::Duration, // Duration for updating messages
+        max_edits_per_minute: u32,                 // The most edits per minute while generating
     ) -> anyhow::Result<Outputter<'a>> {
         // Create an interaction response with Discord using a closure
         cmd.create_interaction_response(http, |response| {
@@ -428,11 +1880,33 @@ impl<'a> Outputter<'a> {
         let starting_message = cmd.get_interaction_response(http).await?;
 
         // Create and return a new Outputter instance
-        Ok(Self {
+        Ok(Self::resume(
+            http,
+            cmd,
+            vec![starting_message],
+            prompts,
+            last_update_duration,
+            max_edits_per_minute,
+        ))
+    }
+
+    // function to create an Outputter that writes over the messages of an earlier response
+    // (e.g. to reroll it), instead of responding to a command. Any further messages it needs
+    // are followups to `interaction`
+    fn resume(
+        http: &'a Http,
+        interaction: &'a dyn DiscordInteraction,
+        messages: Vec<Message>,
+        prompts: Prompts,
+        last_update_duration: std::time::Duration,
+        max_edits_per_minute: u32,
+    ) -> Outputter<'a> {
+        Self {
             http,
 
-            user_id: cmd.user.id,
-            messages: vec![starting_message],
+            interaction,
+            user_id: interaction.user().id,
+            messages,
             chunks: vec![],
 
             message: String::new(),
@@ -442,7 +1916,25 @@ impl<'a> Outputter<'a> {
 
             last_update: std::time::Instant::now(),
             last_update_duration,
-        })
+            base_update_duration: last_update_duration,
+            edit_bucket: EditBucket::new(max_edits_per_minute),
+            output_replacements: vec![],
+            thinking: postprocess::Thinking::default(),
+            response_format: config::ResponseFormat::default(),
+
+            footer: None,
+            seed: None,
+            persona: None,
+            generation_metadata: None,
+            short_response: false,
+            stopped_repeating: false,
+            time_limit: None,
+            alternatives: vec![],
+            variations: 1,
+            rerollable: false,
+            votable: false,
+            reportable: false,
+        }
     }
 
     // function to process a new token and update the Outputter
@@ -456,69 +1948,373 @@ impl<'a> Outputter<'a> {
         // If the accumulated message is empty, add the cancellation button to the first message
         if self.message.is_empty() {
             // Add the cancellation button when we receive the first token
-            if let Some(first) = self.messages.first_mut() {
-                add_cancel_button(self.http, first.id, first, self.user_id).await?;
-            }
+            self.add_cancel_button().await?;
         }
 
         // Accumulate the token to the message
         self.message += token;
+        self.update_chunks();
+
+        // if its time to update messages based on elapsed time, and the edit limit allows it.
+        // Otherwise the text waits for the next update, which sends everything so far
+        if self.last_update.elapsed() > self.last_update_duration && self.edit_bucket.try_take() {
+            self.sync_messages_with_chunks().await?;
+            self.last_update = std::time::Instant::now();
 
-        // Process the message and split it into chunks
-        self.chunks = {
-            let mut chunks: Vec<String> = vec![];
+            // Ease the update interval back towards the configured one after a backoff
+            self.last_update_duration =
+                (self.last_update_duration / 2).max(self.base_update_duration);
+        }
 
-            // Convert the message to markdown and split it into words
-            let markdown = self.prompts.make_markdown_message(&self.message);
-            for word in markdown.split(' ') {
-                // If there is a last chunk and it exceeds the maximum size, start a new chunk
-                if let Some(last) = chunks.last_mut() {
-                    if last.len() > Self::MESSAGE_CHUNK_SIZE {
-                        chunks.push(word.to_string());
+        Ok(())
+    }
+
+    // function to split the message into chunks that each fit in a Discord message
+    fn update_chunks(&mut self) {
+        // Convert the message (with the replacements applied) to markdown
+        let (prompt, response) = self.split_message();
+        let response = postprocess::strip_thinking(response, self.thinking);
+        let response = postprocess::replace(&response, &self.output_replacements);
+        self.chunks = match self.response_format.code_block_language() {
+            // The output goes in code blocks after the prompt, each of which is
+            // opened and closed in the same message
+            Some(language) if !response.is_empty() => {
+                let mut chunks = Self::split_words(&self.prompts.make_markdown_message(prompt));
+                for block in Self::code_blocks(&response, language) {
+                    match chunks.last_mut() {
+                        Some(last) if last.len() + block.len() < Self::MESSAGE_CHUNK_SIZE => {
+                            last.push('\n');
+                            last.push_str(&block);
+                        }
+                        _ => chunks.push(block),
+                    }
+                }
+                chunks
+            }
+            _ => Self::split_words(
+                &self
+                    .prompts
+                    .make_markdown_message(&format!("{prompt}{response}")),
+            ),
+        };
+    }
+
+    // function to split markdown into chunks at spaces. A code block split between two chunks
+    // is closed at the end of the first and opened again (in the same language) at the start
+    // of the second, so that each message renders on its own
+    fn split_words(markdown: &str) -> Vec<String> {
+        let mut chunks: Vec<String> = vec![];
+        for word in markdown.split(' ') {
+            // If there is a last chunk and it exceeds the maximum size, start a new chunk
+            if let Some(last) = chunks.last_mut() {
+                if last.len() > Self::MESSAGE_CHUNK_SIZE {
+                    if Self::is_inside_code_fence(last) {
+                        let language = Self::code_fence_language(last).to_string();
+                        last.push_str("\n```");
+                        chunks.push(format!("```{language}\n{word}"));
                     } else {
-                        last.push(' ');
-                        last.push_str(word);
+                        chunks.push(word.to_string());
                     }
                 } else {
-                    chunks.push(word.to_string());
+                    last.push(' ');
+                    last.push_str(word);
                 }
+            } else {
+                chunks.push(word.to_string());
             }
+        }
+        chunks
+    }
+
+    // function to check whether text ends inside a code block, i.e. it has a ``` that
+    // hasn't been closed
+    fn is_inside_code_fence(text: &str) -> bool {
+        text.matches("```").count() % 2 == 1
+    }
 
-            chunks
+    // The language of the last code block opened in text (empty if it doesn't name one)
+    fn code_fence_language(text: &str) -> &str {
+        let Some((_, after)) = text.rsplit_once("```") else {
+            return "";
         };
+        after.split(char::is_whitespace).next().unwrap_or_default()
+    }
 
-        // if its time to update messages based on elapsed time
-        if self.last_update.elapsed() > self.last_update_duration {
-            self.sync_messages_with_chunks().await?;
-            self.last_update = std::time::Instant::now();
+    // function to split output into code blocks that each fit in a Discord message,
+    // at line breaks where possible
+    fn code_blocks(output: &str, language: &str) -> Vec<String> {
+        let mut pieces: Vec<String> = vec![];
+        for line in output.split_inclusive('\n') {
+            match pieces.last_mut() {
+                Some(last) if last.len() + line.len() <= Self::MESSAGE_CHUNK_SIZE => {
+                    last.push_str(line)
+                }
+                _ => pieces.push(line.to_string()),
+            }
+
+            // Lines too long for a message on their own are split wherever they have to be
+            while pieces
+                .last()
+                .is_some_and(|p| p.len() > Self::MESSAGE_CHUNK_SIZE)
+            {
+                let last = pieces.last_mut().unwrap();
+                let mut split_at = Self::MESSAGE_CHUNK_SIZE;
+                while !last.is_char_boundary(split_at) {
+                    split_at -= 1;
+                }
+                let rest = last.split_off(split_at);
+                pieces.push(rest);
+            }
+        }
+
+        pieces
+            .iter()
+            .filter(|p| !p.is_empty())
+            .map(|p| format!("```{language}\n{}\n```", p.trim_end_matches('\n')))
+            .collect()
+    }
+
+    // The generated output, without the echoed prompt in front of it
+    fn response(&self) -> &str {
+        self.split_message().1
+    }
+
+    // function to split the message into the echoed prompt and the generated output.
+    // While the prompt is still being echoed, there's no output yet. If the echo doesn't
+    // match the prompt, the whole message is treated as output
+    fn split_message(&self) -> (&str, &str) {
+        let processed = self.prompts.processed.as_str();
+        if let Some(response) = self.message.strip_prefix(processed) {
+            (processed, response)
+        } else if processed.starts_with(&self.message) {
+            (&self.message, "")
+        } else {
+            ("", &self.message)
+        }
+    }
+
+    // function to replace the generated output (keeping the prompt in front of it),
+    // for post-processing. The messages are updated on the next sync
+    fn set_response(&mut self, response: &str) {
+        let prompt_length = self.message.len() - self.response().len();
+        self.message.truncate(prompt_length);
+        self.message += response;
+        self.update_chunks();
+    }
+
+    // function to start the output over, for a retry or the next variation. The first `kept`
+    // alternatives are finished variations, which stay.
+    // The messages are kept, and are overwritten as the new output comes in
+    fn restart(&mut self, kept: usize) {
+        self.message.clear();
+        self.chunks.clear();
+        self.alternatives.truncate(kept);
+    }
+
+    // The heading of the alternative at `index`, e.g. "Alternative 2" or "Variation 2/3"
+    fn alternative_label(&self, index: usize) -> String {
+        if self.variations > 1 {
+            format!("Variation {}/{}", index + 1, self.variations)
+        } else {
+            format!("Alternative {}", index + 1)
+        }
+    }
+
+    // The finished response as it's remembered for exporting
+    fn transcript(&self, record: details::GenerationRecord) -> export::Transcript {
+        export::Transcript {
+            record,
+            prompt: if self.prompts.show_prompt_template {
+                self.prompts.processed.clone()
+            } else {
+                self.prompts.user.clone()
+            },
+            response: self.transcript_response(),
+            persona: self.persona.clone(),
+            requester: self.user_id,
+        }
+    }
+
+    // The generated output as it's exported: the response, or every alternative, numbered
+    fn transcript_response(&self) -> String {
+        if self.alternatives.is_empty() {
+            return self.response().to_string();
+        }
+        self.alternatives
+            .iter()
+            .enumerate()
+            .map(|(i, a)| format!("{}:\n{a}", self.alternative_label(i)))
+            .collect::<Vec<_>>()
+            .join("\n\n")
+    }
+
+    // function to replace the response with a notice, when its output was stopped for matching
+    // `moderation.halt_on_output`. The match may straddle tokens, and the messages lag behind
+    // the model, so rather than cutting the match out, every message is rendered again from
+    // the prompt and the notice
+    async fn withheld(&mut self, notice: &str) -> anyhow::Result<()> {
+        self.alternatives.clear();
+        self.output_replacements.clear();
+        self.thinking = postprocess::Thinking::Show;
+        self.response_format = config::ResponseFormat::default();
+        self.set_response(notice);
+
+        while self.messages.len() > self.chunks.len().max(1) {
+            let msg = self.messages.pop().unwrap();
+            msg.delete(self.http).await?;
+        }
+        for (msg, chunk) in self.messages.iter_mut().zip(&self.chunks) {
+            msg.edit(self.http, |m| {
+                m.set_components(CreateComponents::default()).content(chunk)
+            })
+            .await?;
+        }
+        for chunk in self.chunks[self.messages.len().min(self.chunks.len())..].iter() {
+            let msg = self.interaction.followup(self.http, chunk).await?;
+            self.messages.push(msg);
         }
 
+        self.in_terminal_state = true;
         Ok(())
     }
 
-    // function to handle errors and update the Outputter
-    // it handle errors and cancellation, updating the Outputter.
-    async fn error(&mut self, err: &str) -> anyhow::Result<()> {
-        self.on_error(err).await
+    // function to handle errors and update the Outputter.
+    // The error itself is shown to the user separately, through `util::send_ephemeral_error`
+    async fn error(&mut self) -> anyhow::Result<()> {
+        self.on_error(None).await
     }
 
     // function to handle cancellation and update the Outputter
     async fn cancelled(&mut self) -> anyhow::Result<()> {
-        self.on_error("The generation was cancelled.").await
+        self.on_error(Some("The generation was cancelled.")).await
+    }
+
+    // function to show on the placeholder that the model is being loaded again, which takes
+    // about `seconds`. The first tokens replace it
+    async fn warming_up(&mut self, seconds: u64) -> anyhow::Result<()> {
+        if let Some(first) = self.messages.first_mut() {
+            first
+                .edit(self.http, |m| {
+                    m.content(format!("Warming up the model, ~{seconds}s…"))
+                })
+                .await?;
+        }
+        Ok(())
+    }
+
+    // function to add the cancellation button to the first message, unless it already has it
+    async fn add_cancel_button(&mut self) -> anyhow::Result<()> {
+        if let Some(first) = self.messages.first_mut() {
+            if first.components.is_empty() {
+                add_cancel_button(self.http, first.id, first, self.user_id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    // function to handle a request being cancelled while it was queued. Nothing was generated,
+    // so the placeholder just says so, rather than being struck through
+    async fn removed_from_queue(&mut self) -> anyhow::Result<()> {
+        if let Some(first) = self.messages.first_mut() {
+            first
+                .edit(self.http, |m| {
+                    m.set_components(CreateComponents::default())
+                        .content("Removed from queue.")
+                })
+                .await?;
+        }
+        self.in_terminal_state = true;
+        Ok(())
     }
 
     // function to finish processing and update the Outputter
     // finishes processing, removes components from messages, and updates based on remaining chunks.
     async fn finish(&mut self) -> anyhow::Result<()> {
-        // Edit all messages to remove components
-        for msg in &mut self.messages {
-            msg.edit(self.http, |m| m.set_components(CreateComponents::default()))
-                .await?;
+        // The alternatives go in an embed, so the messages are left with just the prompt
+        if !self.alternatives.is_empty() {
+            self.set_response("");
+        }
+
+        // Add the footer (and the seed) to the end of the last chunk
+        let footer = [
+            self.footer.as_ref().map(|f| format!("*{f}*")),
+            self.persona.as_ref().map(|p| format!("[Persona: {p}]")),
+            self.seed.map(|s| format!("[Seed: {s}]")),
+            self.generation_metadata.map(|m| {
+                format!(
+                    "[{} tokens from a {}-token prompt in {:.1}s · {:.1} tok/s]",
+                    m.tokens_generated,
+                    m.prompt_tokens,
+                    m.duration_ms as f64 / 1000.0,
+                    m.tokens_per_second
+                )
+            }),
+            self.short_response
+                .then(|| "[Short response; try a different prompt]".to_string()),
+            self.stopped_repeating
+                .then(|| "[Stopped: output was repeating]".to_string()),
+            self.time_limit
+                .map(|s| format!("[Stopped: time limit of {s}s reached]")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join(" ");
+        if let (Some(last), false) = (self.chunks.last_mut(), footer.is_empty()) {
+            last.push_str(&format!("\n\n{footer}"));
         }
 
         // Update messages based on the remaining chunks
         self.sync_messages_with_chunks().await?;
 
+        let labels: Vec<_> = (0..self.alternatives.len())
+            .map(|i| self.alternative_label(i))
+            .collect();
+        if let (Some(last), false) = (self.messages.last_mut(), self.alternatives.is_empty()) {
+            let alternatives = &self.alternatives;
+            last.edit(self.http, |m| {
+                m.embed(|e| {
+                    for (label, alternative) in labels.into_iter().zip(alternatives) {
+                        e.field(label, embed_field_value(alternative), false);
+                    }
+                    e
+                })
+            })
+            .await?;
+        }
+
+        // Edit all messages to remove components, then put the export and details buttons (and
+        // the reroll and report buttons, if they're wanted) on the last one. The vote buttons
+        // get a row of their own, since a row only fits five
+        let Some(first_id) = self.messages.first().map(|m| m.id) else {
+            return Ok(());
+        };
+        let (last, rest) = self.messages.split_last_mut().unwrap();
+        for msg in rest {
+            msg.edit(self.http, |m| m.set_components(CreateComponents::default()))
+                .await?;
+        }
+        let mut components = CreateComponents::default();
+        components.create_action_row(|r| {
+            export::create_button(r, first_id);
+            details::create_button(r, first_id);
+            if self.rerollable {
+                reroll::create_buttons(r, first_id);
+            }
+            if self.reportable {
+                report::create_button(r, first_id);
+            }
+            r
+        });
+        if self.votable {
+            components.create_action_row(|r| {
+                feedback::create_buttons(r, first_id);
+                r
+            });
+        }
+        last.edit(self.http, |m| m.set_components(components))
+            .await?;
+
         Ok(())
     }
 
@@ -527,9 +2323,18 @@ impl<'a> Outputter<'a> {
     // 2. Removes components from existing messages.
     // 3. Creates new messages for remaining chunks and adds a cancel button to the last message
     async fn sync_messages_with_chunks(&mut self) -> anyhow::Result<()> {
+        // Delete any messages that the output no longer reaches (e.g. after it was trimmed),
+        // always keeping the first, which is the response to the command
+        while self.messages.len() > self.chunks.len().max(1) {
+            let msg = self.messages.pop().unwrap();
+            msg.delete(self.http).await?;
+        }
+
         // Update the last message with its latest state, then insert the remaining chunks in one go
         if let Some((msg, chunk)) = self.messages.iter_mut().zip(self.chunks.iter()).last() {
-            msg.edit(self.http, |m| m.content(chunk)).await?; // Update the content of the last message
+            // Update the content of the last message
+            edit_content_with_backoff(self.http, msg, chunk, &mut self.last_update_duration)
+                .await?;
         }
 
         if self.chunks.len() <= self.messages.len() {
@@ -547,8 +2352,7 @@ impl<'a> Outputter<'a> {
             return Ok(()); // Return if there are no existing messages
         };
         for chunk in self.chunks[self.messages.len()..].iter() {
-            let last = self.messages.last_mut().unwrap();
-            let msg = last.reply(self.http, chunk).await?; // Reply to the last message with new chunk
+            let msg = self.interaction.followup(self.http, chunk).await?; // Send the new chunk as a followup
             self.messages.push(msg); // Store the new message
         }
 
@@ -562,9 +2366,9 @@ impl<'a> Outputter<'a> {
 
     // function to handle errors and update the Outputter
     // Replaces the content of all messages with strikethrough text
-    // Replies to the last message with an error message
     // Sets the terminal state flag to true
-    async fn on_error(&mut self, error_message: &str) -> anyhow::Result<()> {
+    // Replies to the last message with the given reply, if any
+    async fn on_error(&mut self, reply: Option<&str>) -> anyhow::Result<()> {
         // Edit all messages to replace content with strikethrough text
         for msg in &mut self.messages {
             let cut_content = format!("~~{}~~", msg.content);
@@ -575,19 +2379,193 @@ impl<'a> Outputter<'a> {
             .await?;
         }
 
-        let Some(last) = self.messages.last_mut() else {
-            return Ok(()); // Return if there are no messages
-        };
-        last.reply(self.http, error_message).await?; // Reply to the last message with an error message
-
         self.in_terminal_state = true; // Set the terminal state flag
 
+        // Reply to the last message with the reply, if there is one
+        if let (Some(last), Some(reply)) = (self.messages.last_mut(), reply) {
+            last.reply(self.http, reply).await?;
+        }
+
         Ok(())
     }
 }
 
+// function to fit an alternative in an embed field, which can't be empty or longer than
+// 1024 characters
+fn embed_field_value(alternative: &str) -> String {
+    const MAX_FIELD_LENGTH: usize = 1024;
+
+    let alternative = alternative.trim();
+    if alternative.is_empty() {
+        return "*(empty)*".to_string();
+    }
+    if alternative.chars().count() <= MAX_FIELD_LENGTH {
+        return alternative.to_string();
+    }
+    alternative
+        .chars()
+        .take(MAX_FIELD_LENGTH - 1)
+        .collect::<String>()
+        + "…"
+}
+
+// A token bucket that limits how often a response is edited while it's generating.
+// It holds a few edits' worth of tokens, so that the start of a response appears quickly,
+// and refills at the configured rate
+struct EditBucket {
+    // The number of edits that can be made right now (fractions accumulate over time)
+    tokens: f64,
+    // The most tokens the bucket holds
+    capacity: f64,
+    // How many tokens are added per second; `None` if there's no limit
+    refill_per_second: Option<f64>,
+    // When tokens were last added
+    last_refill: std::time::Instant,
+}
+
+impl EditBucket {
+    // The most edits that can be made in a burst
+    const MAX_BURST: u32 = 3;
+
+    // function to create a full bucket allowing `per_minute` edits a minute (0 for no limit)
+    fn new(per_minute: u32) -> Self {
+        let capacity = per_minute.clamp(1, Self::MAX_BURST) as f64;
+        Self {
+            tokens: capacity,
+            capacity,
+            refill_per_second: (per_minute > 0).then(|| per_minute as f64 / 60.0),
+            last_refill: std::time::Instant::now(),
+        }
+    }
+
+    // function to take a token for an edit, returning false if there are none left
+    fn try_take(&mut self) -> bool {
+        let Some(refill_per_second) = self.refill_per_second else {
+            return true;
+        };
+
+        let now = std::time::Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * refill_per_second).min(self.capacity);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// The prompts users have submitted recently, with when they submitted them
+#[derive(Default)]
+struct RecentPrompts(Mutex<HashMap<(UserId, String), Instant>>);
+
+impl RecentPrompts {
+    // function to remember that a user submitted a prompt, returning false if they already
+    // submitted it within `window` (which a zero window never counts)
+    fn check_and_insert(&self, user_id: UserId, prompt: &str, window: Duration) -> bool {
+        if window.is_zero() {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut prompts = self.0.lock().unwrap();
+        prompts.retain(|_, submitted| now.duration_since(*submitted) < window);
+
+        let key = (user_id, prompt.to_string());
+        if prompts.contains_key(&key) {
+            return false;
+        }
+        prompts.insert(key, now);
+        true
+    }
+}
+
+// The longest that the update interval can be backed off to after being rate-limited
+const MAX_UPDATE_DURATION: std::time::Duration = std::time::Duration::from_secs(5);
+
+// The number of times an edit is retried after being rate-limited before giving up
+const MAX_RATE_LIMIT_RETRIES: usize = 3;
+
+// function to edit the content of a message, backing off if Discord rate-limits us
+async fn edit_content_with_backoff(
+    http: &Http,
+    msg: &mut Message,
+    content: &str,
+    update_duration: &mut Duration,
+) -> anyhow::Result<()> {
+    let (channel_id, message_id) = (msg.channel_id, msg.id);
+    *msg = with_backoff(update_duration, || async move {
+        match channel_id
+            .edit_message(http, message_id, |m| m.content(content))
+            .await
+        {
+            Ok(message) => Ok(message),
+            Err(err) => {
+                let retry_after = rate_limit_retry_after(http, channel_id, &err).await;
+                Err((err.into(), retry_after))
+            }
+        }
+    })
+    .await?;
+    Ok(())
+}
+
+// function to run an edit, retrying it if it's rate-limited. A failed edit comes back with
+// how long to wait before retrying it, or `None` if it wasn't rate-limited. Each retry
+// doubles the update interval (up to MAX_UPDATE_DURATION) so that we don't immediately get
+// throttled again, and the edit is given up on after MAX_RATE_LIMIT_RETRIES retries
+async fn with_backoff<T, Edit>(
+    update_duration: &mut Duration,
+    mut edit: impl FnMut() -> Edit,
+) -> anyhow::Result<T>
+where
+    Edit: std::future::Future<Output = Result<T, (anyhow::Error, Option<Duration>)>>,
+{
+    let mut retries = 0;
+    loop {
+        match edit().await {
+            Ok(value) => return Ok(value),
+            Err((_, Some(retry_after))) if retries < MAX_RATE_LIMIT_RETRIES => {
+                retries += 1;
+                *update_duration = (*update_duration * 2).min(MAX_UPDATE_DURATION);
+                tokio::time::sleep(retry_after).await;
+            }
+            Err((err, _)) => return Err(err),
+        }
+    }
+}
+
+// function to check if an error editing a message in the channel is a 429 rate-limit
+// response, returning how long to wait. serenity's ratelimiter already waits out the 429s
+// that come with a `Retry-After` header and retries them itself, so only the ones without
+// one get here. serenity keeps the route's other ratelimit headers, so the wait is the
+// route's `X-RateLimit-Reset-After`, or a second if Discord didn't send that either
+async fn rate_limit_retry_after(
+    http: &Http,
+    channel_id: ChannelId,
+    err: &serenity::Error,
+) -> Option<Duration> {
+    let serenity::Error::Http(err) = err else {
+        return None;
+    };
+    if err.status_code() != Some(serenity::http::StatusCode::TOO_MANY_REQUESTS) {
+        return None;
+    }
+
+    let route = Route::ChannelsIdMessagesId(LightMethod::Patch, channel_id.0);
+    let bucket = http.ratelimiter.routes().read().await.get(&route).cloned();
+    let reset_after = match bucket {
+        Some(bucket) => bucket.lock().await.reset_after(),
+        None => None,
+    };
+    Some(reset_after.unwrap_or(Duration::from_secs(1)))
+}
+
 // function to add a cancel button to a message
-async fn add_cancel_button(
+pub async fn add_cancel_button(
     http: &Http,
     first_id: MessageId,
     msg: &mut Message,
@@ -610,3 +2588,488 @@ async fn add_cancel_button(
         })
         .await?) // Perform the edit operation asynchronously and return the result
 }
+
+#[cfg(test)]
+mod tests {
+    use serenity::{
+        http::Http,
+        model::prelude::{interaction::application_command::CommandDataOptionValue, ChannelId},
+        model::user::User,
+    };
+
+    use super::*;
+    use crate::util::test_interaction::TestInteraction;
+
+    // function to make an option as Discord would send it, already resolved
+    fn option(name: &str, value: CommandDataOptionValue) -> CommandDataOption {
+        let mut option: CommandDataOption =
+            serde_json::from_value(serde_json::json!({ "name": name, "type": 3 })).unwrap();
+        option.resolved = Some(value);
+        option
+    }
+
+    #[test]
+    fn prompt_extraction() {
+        let inference = Configuration::default().inference;
+        let options = [option(
+            constant::value::PROMPT,
+            CommandDataOptionValue::String("Tell me a story".into()),
+        )];
+
+        assert_eq!(
+            user_prompt(&options, None, &inference).unwrap(),
+            "Tell me a story"
+        );
+        // Used on a message, a command without a prompt gets an empty one
+        assert_eq!(user_prompt(&[], Some("a message"), &inference).unwrap(), "");
+        assert!(user_prompt(&[], None, &inference).is_err());
+    }
+
+    #[test]
+    fn replace_newlines() {
+        let mut inference = Configuration::default().inference;
+        let options = [option(
+            constant::value::PROMPT,
+            CommandDataOptionValue::String(r"one\ntwo".into()),
+        )];
+
+        inference.replace_newlines = false;
+        assert_eq!(
+            user_prompt(&options, None, &inference).unwrap(),
+            r"one\ntwo"
+        );
+        inference.replace_newlines = true;
+        assert_eq!(user_prompt(&options, None, &inference).unwrap(), "one\ntwo");
+    }
+
+    #[test]
+    fn seed_resolution() {
+        let options = [option(
+            constant::value::SEED,
+            CommandDataOptionValue::Integer(42),
+        )];
+
+        assert_eq!(requested_seed(&options), Some(42));
+        assert_eq!(requested_seed(&[]), None);
+    }
+
+    #[test]
+    fn variation_count_is_capped() {
+        let mut inference = Configuration::default().inference;
+        inference.max_variations = 3;
+        let count = |n| {
+            [option(
+                constant::value::COUNT,
+                CommandDataOptionValue::Integer(n),
+            )]
+        };
+
+        assert_eq!(variation_count(&[], &inference), 1);
+        assert_eq!(variation_count(&count(2), &inference), 2);
+        assert_eq!(variation_count(&count(10), &inference), 3);
+        assert_eq!(variation_count(&count(0), &inference), 1);
+    }
+
+    #[tokio::test]
+    async fn error_display() {
+        let interaction = TestInteraction {
+            channel_id: ChannelId(1),
+            guild_id: None,
+            user: User::default(),
+            created_messages: Default::default(),
+        };
+        let http = Http::new("");
+
+        // Errors from the generation thread are shown with their own message
+        util::run_and_report_error(&interaction, &http, async {
+            Err(generation::InferenceError::OomError.into())
+        })
+        .await;
+        // Refused commands get the notice, without the "Error:" in front
+        util::run_and_report_error(&interaction, &http, async {
+            Err(notice::Rejection::DuplicatePrompt.into())
+        })
+        .await;
+
+        let messages = interaction.created_messages.lock().unwrap();
+        assert_eq!(
+            messages[0],
+            format!("Error: {}", generation::InferenceError::OomError)
+        );
+        assert_eq!(messages[1], notice::Rejection::DuplicatePrompt.to_string());
+    }
+
+    // function to make a find-and-replace rule
+    fn replacement(pattern: &str, replacement: &str) -> config::OutputReplacement {
+        config::OutputReplacement {
+            pattern: regex::Regex::new(pattern).unwrap(),
+            replacement: replacement.to_string(),
+        }
+    }
+
+    // function to make an Outputter for a prompt that's echoed in front of the output, with
+    // the given rules
+    fn outputter<'a>(
+        http: &'a Http,
+        interaction: &'a TestInteraction,
+        prompt: &str,
+        replacements: &[config::OutputReplacement],
+    ) -> Outputter<'a> {
+        let prompts = Prompts {
+            show_prompt_template: true,
+            processed: prompt.to_string(),
+            user: prompt.to_string(),
+            prefix: String::new(),
+            suffix: String::new(),
+        };
+        let mut outputter = Outputter::resume(
+            http,
+            interaction,
+            vec![],
+            prompts,
+            std::time::Duration::from_secs(1),
+            60,
+        );
+        outputter.output_replacements = replacements.to_vec();
+        outputter
+    }
+
+    // function to add a token to the output as `new_token` does, without touching Discord
+    fn push(outputter: &mut Outputter<'_>, token: &str) -> String {
+        outputter.message += token;
+        outputter.update_chunks();
+        outputter.chunks.concat()
+    }
+
+    // function to make an interaction that only records what would be sent
+    fn test_interaction() -> TestInteraction {
+        TestInteraction {
+            channel_id: ChannelId(1),
+            guild_id: None,
+            user: User::default(),
+            created_messages: Default::default(),
+        }
+    }
+
+    #[test]
+    fn a_match_split_across_tokens_is_replaced_once_it_is_complete() {
+        let (http, interaction) = (Http::new(""), test_interaction());
+        let rules = [replacement("colour", "color")];
+        let mut outputter = outputter(&http, &interaction, "Q:", &rules);
+
+        assert_eq!(push(&mut outputter, "Q:"), "**Q:**");
+        assert_eq!(push(&mut outputter, " The col"), "**Q:** The col");
+        assert_eq!(push(&mut outputter, "our is"), "**Q:** The color is");
+        assert_eq!(push(&mut outputter, " red"), "**Q:** The color is red");
+    }
+
+    #[test]
+    fn start_anchors_match_the_start_of_the_output_not_the_prompt() {
+        let (http, interaction) = (Http::new(""), test_interaction());
+        let rules = [replacement("^Sure", "Certainly")];
+        let mut outputter = outputter(&http, &interaction, "Sure? ", &rules);
+
+        // The echoed prompt starts with the same word, and is left alone
+        assert_eq!(push(&mut outputter, "Sure? "), "**Sure? **");
+        assert_eq!(
+            push(&mut outputter, "Sure, it is."),
+            "**Sure? **Certainly, it is."
+        );
+        // Only the start of the output matches, not every line of it
+        assert_eq!(
+            push(&mut outputter, "\nSure"),
+            "**Sure? **Certainly, it is.\nSure"
+        );
+    }
+
+    #[test]
+    fn long_output_is_split_into_messages_at_spaces() {
+        let (http, interaction) = (Http::new(""), test_interaction());
+        let mut outputter = outputter(&http, &interaction, "Q:", &[]);
+        let mock = crate::mock::MockModel::load(
+            &config::Mock {
+                mode: config::MockMode::Lorem,
+                tokens_per_second: 1_000_000.0,
+                max_tokens: 1000,
+                script_path: None,
+            },
+            2048,
+        )
+        .unwrap();
+
+        push(&mut outputter, "Q:");
+        let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(1);
+        mock.infer("Q:", None, &mut rng, |response| {
+            if let llm::InferenceResponse::InferredToken(t) = response {
+                push(&mut outputter, &t);
+            }
+            Ok(llm::InferenceFeedback::Continue)
+        })
+        .unwrap();
+
+        // Every message but the last is filled up to the limit, and no word is split
+        let chunks = &outputter.chunks;
+        assert!(chunks.len() > 1);
+        for chunk in &chunks[..chunks.len() - 1] {
+            assert!(chunk.len() > Outputter::MESSAGE_CHUNK_SIZE);
+            assert!(chunk.len() < Outputter::MESSAGE_CHUNK_SIZE + 20);
+        }
+        assert_eq!(
+            chunks.join(" "),
+            outputter.prompts.make_markdown_message(&outputter.message)
+        );
+    }
+
+    #[tokio::test]
+    async fn replacements_are_not_applied_again_when_finishing() {
+        let (http, interaction) = (Http::new(""), test_interaction());
+        let rules = [replacement("!", "!!")];
+        let mut outputter = outputter(&http, &interaction, "Q:", &rules);
+        assert_eq!(push(&mut outputter, "Q: Hi!"), "**Q:** Hi!!");
+
+        let command = config::Command {
+            output_replacements: rules.to_vec(),
+            ..Default::default()
+        };
+        postprocess_output(&mut outputter, &command, None)
+            .await
+            .unwrap();
+        assert_eq!(outputter.response(), " Hi!!");
+        assert_eq!(outputter.chunks.concat(), "**Q:** Hi!!");
+
+        // Showing it again (as the last sync does) leaves it as it is
+        outputter.update_chunks();
+        assert_eq!(outputter.chunks.concat(), "**Q:** Hi!!");
+    }
+
+    // A backend that plays the generation thread, from a script
+    enum MockBackend {
+        // It generates these tokens, then the metadata
+        Generates(&'static [&'static str]),
+        // It generates these tokens, then fails
+        Fails(&'static [&'static str], generation::InferenceError),
+        // It takes the request off the queue before it starts, keeping hold of it so that
+        // its tokens never end
+        Removes(std::sync::Mutex<Vec<generation::Request>>),
+    }
+
+    impl GenerationBackend for MockBackend {
+        fn send(&self, request: generation::Request, _local_only: bool) -> anyhow::Result<Routed> {
+            let send_tokens = |tokens: &[&str]| {
+                for token in tokens {
+                    request.token_tx.send(Token::Token(token.to_string())).ok();
+                }
+            };
+            match self {
+                MockBackend::Generates(tokens) => {
+                    send_tokens(tokens);
+                    let metadata = generation::GenerationMetadata {
+                        tokens_generated: tokens.len(),
+                        prompt_tokens: 1,
+                        duration_ms: 10,
+                        tokens_per_second: 100.0,
+                        seed: 7,
+                    };
+                    request.token_tx.send(Token::Metadata(metadata)).ok();
+                    Ok(Routed::Fallback)
+                }
+                MockBackend::Fails(tokens, err) => {
+                    send_tokens(tokens);
+                    request.token_tx.send(Token::Error(err.clone())).ok();
+                    Ok(Routed::Fallback)
+                }
+                MockBackend::Removes(held) => {
+                    let (removed_tx, removed_rx) = flume::bounded(1);
+                    removed_tx.send(()).unwrap();
+                    held.lock().unwrap().push(request);
+                    Ok(Routed::Local {
+                        queue_depth: 1,
+                        removed_rx,
+                    })
+                }
+            }
+        }
+    }
+
+    // function to send a request to the backend and stream its tokens into the outputter,
+    // as `hallucinate` does
+    async fn generate(
+        backend: &impl GenerationBackend,
+        outputter: &mut Outputter<'_>,
+    ) -> anyhow::Result<Streamed> {
+        let (token_tx, token_rx) = flume::unbounded();
+        let request = generation::Request {
+            prompt: "Q:".to_string(),
+            batch_size: 1,
+            batch_decode: false,
+            token_buffer_size: 1,
+            token_tx,
+            message_id: MessageId(1),
+            seed: None,
+            maximum_token_count: None,
+            n_sequences: 1,
+            sampling: Default::default(),
+            low_priority: false,
+            echo_prompt: true,
+            context: generation::RequestContext {
+                guild_id: None,
+                channel_id: 1,
+                user_id: 1,
+                command_name: "test".to_string(),
+                shard_id: None,
+            },
+            progress_tx: None,
+            completion_tx: None,
+            trace: None,
+            queued_at: Instant::now(),
+        };
+        let removed_rx = match backend.send(request, false)? {
+            Routed::Local { removed_rx, .. } => Some(removed_rx),
+            Routed::Fallback => None,
+        };
+        stream_tokens(outputter, token_rx, removed_rx, None, true, "").await
+    }
+
+    #[tokio::test]
+    async fn tokens_stream_into_the_response() {
+        let (http, interaction) = (Http::new(""), test_interaction());
+        let mut outputter = outputter(&http, &interaction, "Q:", &[]);
+        let backend = MockBackend::Generates(&["Q:", " Hi", " there"]);
+
+        let streamed = generate(&backend, &mut outputter).await.unwrap();
+        assert!(matches!(streamed, Streamed::Finished(Some(m)) if m.seed == 7));
+        assert_eq!(outputter.response(), " Hi there");
+        assert_eq!(outputter.chunks.concat(), "**Q:** Hi there");
+    }
+
+    #[tokio::test]
+    async fn a_request_taken_off_the_queue_ends_the_response() {
+        let (http, interaction) = (Http::new(""), test_interaction());
+        let mut outputter = outputter(&http, &interaction, "Q:", &[]);
+        let backend = MockBackend::Removes(Default::default());
+
+        let streamed = generate(&backend, &mut outputter).await.unwrap();
+        assert!(matches!(streamed, Streamed::Ended));
+        assert!(outputter.in_terminal_state);
+        assert_eq!(outputter.response(), "");
+    }
+
+    #[tokio::test]
+    async fn errors_end_the_response() {
+        let (http, interaction) = (Http::new(""), test_interaction());
+
+        // Cancelling keeps what was generated, and isn't an error
+        let mut cancelled = outputter(&http, &interaction, "Q:", &[]);
+        let backend = MockBackend::Fails(&["Q:", " Hi"], generation::InferenceError::Cancelled);
+        let streamed = generate(&backend, &mut cancelled).await.unwrap();
+        assert!(matches!(streamed, Streamed::Ended));
+        assert_eq!(cancelled.response(), " Hi");
+
+        // Waiting too long is the user's to know about, not the operator's
+        let mut timed_out = outputter(&http, &interaction, "Q:", &[]);
+        let backend = MockBackend::Fails(&[], generation::InferenceError::QueueTimeout);
+        let err = generate(&backend, &mut timed_out).await.err().unwrap();
+        assert!(util::is_user_error(&err));
+        assert!(timed_out.in_terminal_state);
+    }
+
+    #[tokio::test]
+    async fn rate_limited_edits_back_off_then_give_up() {
+        let mut update_duration = Duration::from_secs(1);
+        let mut attempts = 0;
+        let result: anyhow::Result<()> = with_backoff(&mut update_duration, || {
+            attempts += 1;
+            std::future::ready(Err((
+                anyhow::anyhow!("rate-limited"),
+                Some(Duration::from_millis(1)),
+            )))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, MAX_RATE_LIMIT_RETRIES + 1);
+        // 1s doubles to 2s and 4s, then stops at the cap
+        assert_eq!(update_duration, MAX_UPDATE_DURATION);
+
+        // An edit that goes through after a retry keeps the interval it backed off to
+        let mut update_duration = Duration::from_secs(1);
+        let mut failed = false;
+        let result = with_backoff(&mut update_duration, || {
+            let first = !std::mem::replace(&mut failed, true);
+            std::future::ready(if first {
+                Err((anyhow::anyhow!("rate-limited"), Some(Duration::ZERO)))
+            } else {
+                Ok(())
+            })
+        })
+        .await;
+        assert!(result.is_ok());
+        assert_eq!(update_duration, Duration::from_secs(2));
+
+        // Other errors aren't retried
+        let mut update_duration = Duration::from_secs(1);
+        let mut attempts = 0;
+        let result: anyhow::Result<()> = with_backoff(&mut update_duration, || {
+            attempts += 1;
+            std::future::ready(Err((anyhow::anyhow!("missing access"), None)))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+        assert_eq!(update_duration, Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn rate_limited_edits_wait_for_the_routes_reset() {
+        // function to make the response Discord sends when an edit is rate-limited without
+        // a `Retry-After` header
+        fn response(reset_after: Option<&str>) -> reqwest::Response {
+            let mut response = axum::http::Response::builder().status(429);
+            if let Some(reset_after) = reset_after {
+                response = response.header("x-ratelimit-reset-after", reset_after);
+            }
+            reqwest::Response::from(response.body("{}").unwrap())
+        }
+        async fn error(response: reqwest::Response) -> serenity::Error {
+            serenity::http::HttpError::UnsuccessfulRequest(
+                serenity::http::error::ErrorResponse::from_response(response).await,
+            )
+            .into()
+        }
+
+        let http = Http::new("");
+        let channel_id = ChannelId(7);
+
+        // Nothing is known about the route yet
+        let err = error(response(None)).await;
+        assert_eq!(
+            rate_limit_retry_after(&http, channel_id, &err).await,
+            Some(Duration::from_secs(1))
+        );
+
+        // serenity has seen the route's headers
+        let route = Route::ChannelsIdMessagesId(LightMethod::Patch, channel_id.0);
+        let bucket = Arc::clone(
+            http.ratelimiter
+                .routes()
+                .write()
+                .await
+                .entry(route)
+                .or_default(),
+        );
+        bucket
+            .lock()
+            .await
+            .post_hook(&response(Some("2.5")), &route)
+            .await
+            .unwrap();
+        let err = error(response(Some("2.5"))).await;
+        assert_eq!(
+            rate_limit_retry_after(&http, channel_id, &err).await,
+            Some(Duration::from_millis(2500))
+        );
+
+        // Other errors aren't rate limits
+        let err = serenity::Error::Other("missing access");
+        assert_eq!(rate_limit_retry_after(&http, channel_id, &err).await, None);
+    }
+}
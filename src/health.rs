@@ -0,0 +1,176 @@
+// This file holds the optional health and readiness endpoint.
+// The readiness state is a set of atomics shared (through an `Arc`) between the model
+// loading code, the Discord event handler, and the generation thread, so that a container
+// orchestrator or load balancer can tell from the outside whether the bot can serve requests.
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::Context as AnyhowContext;
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use serde_json::json;
+
+use crate::config;
+
+// The readiness state of the bot, updated by the parts of the bot that own each piece
+#[derive(Default)]
+pub struct Readiness {
+    // Set once the model has finished loading
+    pub model_loaded: AtomicBool,
+    // Set while the model is unloaded for being idle, until a request loads it again
+    pub model_unloaded: AtomicBool,
+    // How long the model last took to load, in milliseconds
+    pub model_load_ms: AtomicU64,
+    // Whether each Discord gateway shard's connection is up, by shard id
+    shards: Mutex<BTreeMap<u64, bool>>,
+    // Set while the generation thread is running
+    pub generation_thread_alive: AtomicBool,
+    // Set once the generation thread has found that the model can produce embeddings
+    pub embeddings_supported: AtomicBool,
+    // Set when the bot starts shutting down, so that it stops being routed to
+    pub shutting_down: AtomicBool,
+    // The number of requests the generation thread is holding until it can run them
+    pub queue_depth: AtomicUsize,
+    // How many of those are in the fast and slow lanes
+    pub fast_lane_depth: AtomicUsize,
+    pub slow_lane_depth: AtomicUsize,
+    // How long the requests the generation thread is holding should take, in milliseconds
+    pub estimated_queue_ms: AtomicU64,
+    // A running average of how long the local model takes per request, in milliseconds
+    pub average_generation_ms: AtomicU64,
+    // The number of Discord requests routed to the local model and to the fallback backend
+    pub routed_local: AtomicU64,
+    pub routed_fallback: AtomicU64,
+    // The number of requests stopped for running longer than `max_generation_seconds`, and
+    // dropped for waiting longer than `max_queue_wait_seconds`
+    pub timed_out_generations: AtomicU64,
+    pub expired_requests: AtomicU64,
+    // The number of interactions received from Discord, by type, including the ones that
+    // are ignored
+    interactions: Mutex<BTreeMap<String, u64>>,
+}
+
+impl Readiness {
+    // Records whether a gateway shard's connection is up
+    pub fn set_shard_connected(&self, shard_id: u64, connected: bool) {
+        self.shards.lock().unwrap().insert(shard_id, connected);
+    }
+
+    // Counts an interaction of the given type
+    pub fn count_interaction(&self, kind: &str) {
+        *self
+            .interactions
+            .lock()
+            .unwrap()
+            .entry(kind.to_string())
+            .or_default() += 1;
+    }
+
+    // The number of interactions received so far, by type
+    pub fn interaction_counts(&self) -> BTreeMap<String, u64> {
+        self.interactions.lock().unwrap().clone()
+    }
+
+    // Whether or not every gateway shard that has started is connected
+    pub fn gateway_connected(&self) -> bool {
+        let shards = self.shards.lock().unwrap();
+        !shards.is_empty() && shards.values().all(|connected| *connected)
+    }
+
+    // Whether or not the bot is able to serve requests right now
+    pub fn is_ready(&self) -> bool {
+        self.model_loaded.load(Ordering::SeqCst)
+            && self.gateway_connected()
+            && self.generation_thread_alive.load(Ordering::SeqCst)
+            && !self.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+// Guard held by the generation thread; marks the thread as dead when dropped,
+// which also happens if the thread panics and unwinds
+pub struct AliveGuard(Arc<Readiness>);
+
+impl AliveGuard {
+    pub fn new(readiness: Arc<Readiness>) -> Self {
+        readiness
+            .generation_thread_alive
+            .store(true, Ordering::SeqCst);
+        Self(readiness)
+    }
+}
+
+impl Drop for AliveGuard {
+    fn drop(&mut self) {
+        self.0
+            .generation_thread_alive
+            .store(false, Ordering::SeqCst);
+    }
+}
+
+// Starts the health listener and serves requests until the server fails
+pub async fn serve(health: &config::Health, readiness: Arc<Readiness>) -> anyhow::Result<()> {
+    let address: SocketAddr = health
+        .bind_address
+        .parse()
+        .context("Expected health.bind_address to be a valid socket address")?;
+
+    let app = Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/queue", get(queue))
+        .with_state(readiness);
+
+    info!("Health endpoint listening on {address}");
+    axum::Server::bind(&address)
+        .serve(app.into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+// `/healthz`: the process is alive if it can answer at all
+async fn healthz() -> &'static str {
+    "ok"
+}
+
+// `/readyz`: the model is loaded, the gateway is connected and the generation thread is alive
+async fn readyz(State(readiness): State<Arc<Readiness>>) -> (StatusCode, Json<serde_json::Value>) {
+    let status = if readiness.is_ready() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (
+        status,
+        Json(json!({
+            "model_loaded": readiness.model_loaded.load(Ordering::SeqCst),
+            "model_unloaded": readiness.model_unloaded.load(Ordering::SeqCst),
+            "gateway_connected": readiness.gateway_connected(),
+            "shards": *readiness.shards.lock().unwrap(),
+            "generation_thread_alive": readiness.generation_thread_alive.load(Ordering::SeqCst),
+            "shutting_down": readiness.shutting_down.load(Ordering::SeqCst),
+        })),
+    )
+}
+
+// `/queue`: the current depth of the generation queue, and where requests have been routed
+async fn queue(State(readiness): State<Arc<Readiness>>) -> Json<serde_json::Value> {
+    Json(json!({
+        "depth": readiness.queue_depth.load(Ordering::SeqCst),
+        "fast_lane_depth": readiness.fast_lane_depth.load(Ordering::SeqCst),
+        "slow_lane_depth": readiness.slow_lane_depth.load(Ordering::SeqCst),
+        "average_generation_ms": readiness.average_generation_ms.load(Ordering::SeqCst),
+        "estimated_queue_ms": readiness.estimated_queue_ms.load(Ordering::SeqCst),
+        "routed_local": readiness.routed_local.load(Ordering::SeqCst),
+        "routed_fallback": readiness.routed_fallback.load(Ordering::SeqCst),
+        "timed_out_generations": readiness.timed_out_generations.load(Ordering::SeqCst),
+        "expired_requests": readiness.expired_requests.load(Ordering::SeqCst),
+        "interactions": readiness.interaction_counts(),
+    }))
+}
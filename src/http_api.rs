@@ -0,0 +1,875 @@
+// This file holds the optional OpenAI-compatible HTTP API.
+// It translates `/v1/completions` and `/v1/chat/completions` requests into
+// `generation::Request`s on the same queue that the Discord handler uses,
+// so that editors and scripts can share the model that is already loaded.
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as AnyhowContext;
+use axum::{
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    routing::post,
+    Json, Router,
+};
+use serde::Deserialize;
+use serde_json::json;
+use serenity::{
+    futures::{stream, StreamExt},
+    model::prelude::MessageId,
+};
+
+use crate::{
+    chat, config,
+    generation::{self, InferenceError, Token},
+    limits,
+    notice::Rejection,
+    postprocess, summary,
+};
+
+// The ID for the next API request. API requests have no Discord message, but the scheduler
+// keys its queue by message ID, so each one needs an ID of its own. Discord's snowflakes are
+// signed, so they never reach these
+static NEXT_REQUEST_ID: AtomicU64 = AtomicU64::new(1 << 63);
+
+// The state shared between all of the HTTP handlers
+#[derive(Clone)]
+struct ApiState {
+    // Sender for the generation queue shared with the Discord handler
+    request_tx: flume::Sender<generation::Request>,
+    // The token that clients must present, if any
+    bearer_token: Option<String>,
+    // The longest prompt that will be generated from, in characters
+    max_prompt_chars: Option<usize>,
+    // How long clients have to wait between requests to each endpoint, and when they can next
+    cooldown: Duration,
+    cooldowns: Arc<limits::Cooldowns>,
+    // The batch size to use for prompt feeding
+    batch_size: usize,
+    // The name reported back to clients in the `model` field
+    model_name: String,
+    // How chat conversations are written out for the model
+    chat_template: config::ChatTemplate,
+    // The system prompt for conversations that don't bring their own
+    system_prompt: Option<String>,
+    // How much of a conversation's history goes into the prompt
+    history_window: chat::Window,
+    // The settings for summarizing the turns that don't fit, if they're summarized
+    summarization: Option<config::Summarization>,
+    // The summaries generated so far, for reusing in later requests of the same conversation
+    summaries: summary::Summaries,
+}
+
+// Starts the HTTP API and serves requests until the server fails
+pub async fn serve(
+    config: &config::Configuration,
+    http_api: &config::HttpApi,
+    request_tx: flume::Sender<generation::Request>,
+) -> anyhow::Result<()> {
+    let address: SocketAddr = http_api
+        .bind_address
+        .parse()
+        .context("Expected http_api.bind_address to be a valid socket address")?;
+
+    let state = ApiState {
+        request_tx,
+        bearer_token: http_api.bearer_token.clone(),
+        max_prompt_chars: config.inference.max_prompt_chars,
+        cooldown: Duration::from_secs(http_api.cooldown_seconds),
+        cooldowns: Default::default(),
+        batch_size: config.inference.batch_size,
+        model_name: config
+            .model
+            .path
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_else(|| config.model.architecture.clone()),
+        chat_template: config.model.chat_format.template(),
+        system_prompt: config.system_prompt(None).map(str::to_string),
+        history_window: config.inference.history_window(),
+        summarization: config.summarization.clone(),
+        summaries: Default::default(),
+    };
+
+    info!("HTTP API listening on {address}");
+    axum::Server::bind(&address)
+        .serve(router(state).into_make_service())
+        .await?;
+
+    Ok(())
+}
+
+// The routes of the API
+fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/v1/completions", post(completions))
+        .route("/v1/chat/completions", post(chat_completions))
+        .with_state(state)
+}
+
+// The body of a `/v1/completions` request
+#[derive(Deserialize)]
+struct CompletionRequest {
+    prompt: String,
+    #[serde(default)]
+    stream: bool,
+    max_tokens: Option<usize>,
+    seed: Option<u64>,
+}
+
+// The body of a `/v1/chat/completions` request
+#[derive(Deserialize)]
+struct ChatCompletionRequest {
+    messages: Vec<ChatMessage>,
+    #[serde(default)]
+    stream: bool,
+    max_tokens: Option<usize>,
+    seed: Option<u64>,
+}
+
+// A single message in a chat conversation
+#[derive(Deserialize)]
+struct ChatMessage {
+    role: String,
+    content: String,
+}
+
+// Handler for `/v1/completions`
+async fn completions(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(body): Json<CompletionRequest>,
+) -> Result<Response, ApiError> {
+    authorize(&state, &headers)?;
+    admit(&state, "completions", &body.prompt)?;
+
+    let token_rx = start_generation(
+        &state,
+        body.prompt,
+        body.max_tokens,
+        body.seed,
+        "completions",
+        false,
+    )?;
+
+    let id = format!("cmpl-{}", rand::random::<u64>());
+    let created = unix_timestamp();
+    let model = state.model_name.clone();
+
+    // Stream each token back as its own server-sent event
+    if body.stream {
+        let events = token_rx
+            .into_stream()
+            .map(move |token| match token {
+                Token::Token(t) => Event::default().json_data(json!({
+                    "id": id,
+                    "object": "text_completion",
+                    "created": created,
+                    "model": model,
+                    "choices": [{ "index": 0, "text": t, "finish_reason": null }],
+                })),
+                Token::Error(err) => Event::default().json_data(ApiError::from(err).body()),
+                // Clients ignore comments, but the seed is there for anyone who wants it
+                Token::Metadata(metadata) => Ok(seed_comment(metadata)),
+                // API requests only ever have one sequence
+                Token::SequenceStart(_) => Ok(Event::default().comment("")),
+            })
+            .chain(stream::once(async { Ok(Event::default().data("[DONE]")) }));
+
+        return Ok(Sse::new(events)
+            .keep_alive(KeepAlive::default())
+            .into_response());
+    }
+
+    // Otherwise, wait for the whole generation and send it in one go
+    let (text, finish_reason) = collect_generation(token_rx, body.max_tokens, &[]).await?;
+    Ok(Json(json!({
+        "id": id,
+        "object": "text_completion",
+        "created": created,
+        "model": model,
+        "choices": [{ "index": 0, "text": text, "finish_reason": finish_reason }],
+    }))
+    .into_response())
+}
+
+// Handler for `/v1/chat/completions`
+async fn chat_completions(
+    State(state): State<ApiState>,
+    headers: HeaderMap,
+    Json(body): Json<ChatCompletionRequest>,
+) -> Result<Response, ApiError> {
+    authorize(&state, &headers)?;
+
+    let mut turns = body
+        .messages
+        .iter()
+        .map(|m| Ok((m.role.parse()?, m.content.as_str())))
+        .collect::<anyhow::Result<Vec<_>>>()
+        .map_err(|err| {
+            ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                err.to_string(),
+            )
+        })?;
+    if let Some(system_prompt) = &state.system_prompt {
+        if !turns.iter().any(|(role, _)| *role == chat::Role::System) {
+            turns.insert(0, (chat::Role::System, system_prompt.as_str()));
+        }
+    }
+
+    // Summarize (or else leave out) the oldest turns of long conversations. The prompt isn't
+    // tokenized here, so the token counts are estimated from the text's length
+    let summary = summarize_old_turns(&state, &turns).await;
+    let turns = match &summary {
+        Some((oldest_kept, summary)) => chat::replace_before(&turns, *oldest_kept, summary),
+        None => {
+            let (turns, omitted) = state.history_window.apply(&turns, estimate_tokens);
+            if omitted {
+                info!("Left out the oldest turns of a conversation to fit the history window");
+            }
+            turns
+        }
+    };
+    let stop_sequences = chat::stop_sequences(&state.chat_template);
+    let prompt = chat::render(&state.chat_template, &turns);
+    admit(&state, "chat.completions", &prompt)?;
+
+    let token_rx = start_generation(
+        &state,
+        prompt,
+        body.max_tokens,
+        body.seed,
+        "chat.completions",
+        false,
+    )?;
+
+    let id = format!("chatcmpl-{}", rand::random::<u64>());
+    let created = unix_timestamp();
+    let model = state.model_name.clone();
+
+    // Stream each token back as a chat completion chunk
+    if body.stream {
+        let chunk = {
+            let (id, model) = (id.clone(), model.clone());
+            move |delta: serde_json::Value, finish_reason: Option<&str>| {
+                json!({
+                    "id": id,
+                    "object": "chat.completion.chunk",
+                    "created": created,
+                    "model": model,
+                    "choices": [{ "index": 0, "delta": delta, "finish_reason": finish_reason }],
+                })
+            }
+        };
+        // The role comes first, in a chunk of its own; the rest of the chunks only carry content
+        let first_chunk = chunk(json!({ "role": "assistant", "content": "" }), None);
+        let last_chunk = chunk(json!({}), Some("stop"));
+
+        let tokens = stop_at(token_rx, stop_sequences).map(move |token| match token {
+            Token::Token(t) => Event::default().json_data(chunk(json!({ "content": t }), None)),
+            Token::Error(err) => Event::default().json_data(ApiError::from(err).body()),
+            Token::Metadata(metadata) => Ok(seed_comment(metadata)),
+            Token::SequenceStart(_) => Ok(Event::default().comment("")),
+        });
+        let events = stream::once(async { Event::default().json_data(first_chunk) })
+            .chain(tokens)
+            .chain(stream::iter([
+                Event::default().json_data(last_chunk),
+                Ok(Event::default().data("[DONE]")),
+            ]));
+
+        return Ok(Sse::new(events)
+            .keep_alive(KeepAlive::default())
+            .into_response());
+    }
+
+    // Otherwise, wait for the whole generation and send it in one go
+    let (text, finish_reason) =
+        collect_generation(token_rx, body.max_tokens, &stop_sequences).await?;
+    Ok(Json(json!({
+        "id": id,
+        "object": "chat.completion",
+        "created": created,
+        "model": model,
+        "choices": [{
+            "index": 0,
+            "message": { "role": "assistant", "content": text },
+            "finish_reason": finish_reason,
+        }],
+    }))
+    .into_response())
+}
+
+// Checks the `Authorization` header against the configured bearer token
+fn authorize(state: &ApiState, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(expected) = &state.bearer_token else {
+        return Ok(()); // No token configured, so everyone is allowed
+    };
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    if provided == Some(expected.as_str()) {
+        Ok(())
+    } else {
+        Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_request_error",
+            "Missing or invalid bearer token.",
+        ))
+    }
+}
+
+// Checks a client's request against the same limits as the Discord commands, starting the
+// endpoint's cooldown if it passes. API requests have no user, so they share the user ID 0
+fn admit(state: &ApiState, command_name: &str, prompt: &str) -> Result<(), ApiError> {
+    limits::check(
+        &state.cooldowns,
+        0,
+        command_name,
+        state.max_prompt_chars,
+        prompt,
+    )?;
+    state.cooldowns.used(0, command_name, state.cooldown);
+    Ok(())
+}
+
+// Sends a request to the generation thread and returns the receiver for its tokens.
+// If the client disconnects, the receiver is dropped, the generation thread fails to
+// send its next token, and the generation is aborted.
+fn start_generation(
+    state: &ApiState,
+    prompt: String,
+    maximum_token_count: Option<usize>,
+    seed: Option<u64>,
+    command_name: &str,
+    low_priority: bool,
+) -> Result<flume::Receiver<Token>, ApiError> {
+    let (token_tx, token_rx) = flume::unbounded();
+
+    state
+        .request_tx
+        .send(generation::Request {
+            prompt,
+            batch_size: state.batch_size,
+            // Batching only exists to spare Discord from edits, and
+            // token counts (for `finish_reason`) rely on single tokens
+            batch_decode: false,
+            token_buffer_size: 1,
+            token_tx,
+            message_id: MessageId(NEXT_REQUEST_ID.fetch_add(1, Ordering::SeqCst)),
+            seed,
+            maximum_token_count,
+            n_sequences: 1,
+            sampling: Default::default(),
+            low_priority,
+            echo_prompt: false,
+            context: generation::RequestContext {
+                guild_id: None,
+                channel_id: 0,
+                user_id: 0,
+                command_name: command_name.to_string(),
+                shard_id: None,
+            },
+            progress_tx: None,
+            completion_tx: None,
+            trace: None,
+            queued_at: std::time::Instant::now(),
+        })
+        .map_err(|_| {
+            ApiError::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "server_error",
+                "The generation thread is not running.",
+            )
+        })?;
+
+    Ok(token_rx)
+}
+
+// Waits for a generation to finish (or reach a stop sequence), returning the text and the
+// OpenAI finish reason
+async fn collect_generation(
+    token_rx: flume::Receiver<Token>,
+    maximum_token_count: Option<usize>,
+    stop_sequences: &[String],
+) -> Result<(String, &'static str), ApiError> {
+    let mut filter = postprocess::StopFilter::new(stop_sequences.to_vec());
+    let mut text = String::new();
+    let mut token_count = 0;
+
+    while let Ok(token) = token_rx.recv_async().await {
+        match token {
+            Token::Token(t) => {
+                text += &filter.push(&t);
+                token_count += 1;
+                if filter.is_stopped() {
+                    // Returning drops the receiver, which aborts the generation
+                    return Ok((text, "stop"));
+                }
+            }
+            Token::Error(err) => return Err(err.into()),
+            Token::Metadata(_) | Token::SequenceStart(_) => {}
+        }
+    }
+    text += &filter.finish();
+
+    // If we hit the token limit, the model was cut off rather than finishing on its own
+    let finish_reason = match maximum_token_count {
+        Some(max) if token_count >= max => "length",
+        _ => "stop",
+    };
+
+    Ok((text, finish_reason))
+}
+
+// Estimates how many tokens some text is, for fitting conversations in the history window
+fn estimate_tokens(text: &str) -> usize {
+    text.len().div_ceil(config::CHARS_PER_TOKEN)
+}
+
+// Summarizes the turns of a conversation that are over the summarization threshold, if
+// summarization is configured and there are any. Returns where the kept turns start, with
+// the summary to put in place of the ones before them. The summary is generated behind every
+// other request; if it fails, `None` is returned and the turns are left out as usual
+async fn summarize_old_turns(
+    state: &ApiState,
+    turns: &[(chat::Role, &str)],
+) -> Option<(usize, String)> {
+    let settings = state.summarization.as_ref()?;
+    let window = chat::Window {
+        max_turns: state.history_window.max_turns,
+        max_tokens: Some(
+            state
+                .history_window
+                .max_tokens
+                .map_or(settings.trigger_tokens, |max| {
+                    max.min(settings.trigger_tokens)
+                }),
+        ),
+    };
+    let oldest_kept = window.split(turns, estimate_tokens)?;
+
+    // The system prompts are kept as they are, so only the rest are summarized
+    let old_turns: Vec<_> = turns[..oldest_kept]
+        .iter()
+        .filter(|(role, _)| *role != chat::Role::System)
+        .copied()
+        .collect();
+
+    // Start from the summary of as many of the turns as has already been made
+    let previous = state.summaries.find(&old_turns);
+    let summary = match previous {
+        Some((covered, summary)) if covered == old_turns.len() => summary,
+        previous => {
+            let (covered, previous) = previous.unzip();
+            let prompt = summary::prompt(
+                settings,
+                previous.as_deref(),
+                &old_turns[covered.unwrap_or(0)..],
+            );
+            let generated = match start_generation(
+                state,
+                prompt,
+                Some(settings.max_tokens),
+                None,
+                "summarize",
+                true,
+            ) {
+                Ok(token_rx) => collect_generation(token_rx, Some(settings.max_tokens), &[]).await,
+                Err(err) => Err(err),
+            };
+            let summary = match generated {
+                Ok((summary, _)) if !summary.trim().is_empty() => summary.trim().to_string(),
+                Ok(_) => {
+                    warn!(
+                        "A conversation's summary was empty; leaving its oldest turns out instead"
+                    );
+                    return None;
+                }
+                Err(err) => {
+                    warn!(
+                        "Failed to summarize a conversation, leaving its oldest turns out: {}",
+                        err.message
+                    );
+                    return None;
+                }
+            };
+            state.summaries.insert(&old_turns, summary.clone());
+            summary
+        }
+    };
+
+    Some((oldest_kept, format!("{}{summary}", summary::SUMMARY_PREFIX)))
+}
+
+// Turns the tokens of a generation into a stream that ends at the first stop sequence.
+// Ending the stream drops the receiver, which aborts the generation
+fn stop_at(
+    token_rx: flume::Receiver<Token>,
+    stop_sequences: Vec<String>,
+) -> impl stream::Stream<Item = Token> {
+    let filter = postprocess::StopFilter::new(stop_sequences);
+    stream::unfold(Some((token_rx, filter)), |state| async move {
+        let (token_rx, mut filter) = state?;
+        loop {
+            match token_rx.recv_async().await {
+                Ok(Token::Token(t)) => {
+                    let text = filter.push(&t);
+                    if filter.is_stopped() {
+                        return (!text.is_empty()).then_some((Token::Token(text), None));
+                    }
+                    // Text that might be the start of a stop sequence waits for the next token
+                    if !text.is_empty() {
+                        return Some((Token::Token(text), Some((token_rx, filter))));
+                    }
+                }
+                Ok(token) => return Some((token, Some((token_rx, filter)))),
+                Err(_) => {
+                    let rest = filter.finish();
+                    return (!rest.is_empty()).then_some((Token::Token(rest), None));
+                }
+            }
+        }
+    })
+}
+
+// Makes the server-sent event comment that reports the seed a generation used
+fn seed_comment(metadata: generation::GenerationMetadata) -> Event {
+    Event::default().comment(format!("seed: {}", metadata.seed))
+}
+
+// Returns the current time as seconds since the Unix epoch
+fn unix_timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+// An error returned to API clients, in the same shape OpenAI uses
+struct ApiError {
+    status: StatusCode,
+    kind: &'static str,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, kind: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            kind,
+            message: message.into(),
+        }
+    }
+
+    // The JSON body of the error
+    fn body(&self) -> serde_json::Value {
+        json!({ "error": { "message": self.message, "type": self.kind } })
+    }
+}
+
+impl From<InferenceError> for ApiError {
+    fn from(err: InferenceError) -> Self {
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "server_error",
+            err.to_string(),
+        )
+    }
+}
+
+impl From<Rejection> for ApiError {
+    fn from(rejection: Rejection) -> Self {
+        match rejection {
+            Rejection::Cooldown { retry_in, .. } => Self::new(
+                StatusCode::TOO_MANY_REQUESTS,
+                "rate_limit_error",
+                format!(
+                    "Too many requests. Please try again in {} seconds.",
+                    retry_in.as_secs_f32().ceil().max(1.0)
+                ),
+            ),
+            rejection => Self::new(
+                StatusCode::BAD_REQUEST,
+                "invalid_request_error",
+                rejection.to_string(),
+            ),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(self.body())).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{mock, schedule};
+
+    // function to serve the API on a free port, in front of a mock model that replays `tokens`
+    // (one per line). Returns the address it's served on
+    async fn serve_mock(name: &str, tokens: &str, bearer_token: Option<&str>) -> SocketAddr {
+        let script_path = std::env::temp_dir().join(format!(
+            "llmcord-http-api-{name}-{}.txt",
+            std::process::id()
+        ));
+        std::fs::write(&script_path, tokens).unwrap();
+        let model = generation::Model::Mock(
+            mock::MockModel::load(
+                &config::Mock {
+                    mode: config::MockMode::Script,
+                    tokens_per_second: 1000.0,
+                    max_tokens: 20,
+                    script_path: Some(script_path.clone()),
+                },
+                2048,
+            )
+            .unwrap(),
+        );
+        std::fs::remove_file(script_path).ok();
+
+        // Generate each request in turn, as the generation thread does
+        let (request_tx, request_rx) = flume::unbounded::<generation::Request>();
+        std::thread::spawn(move || {
+            let context = generation::GenerationContext::default();
+            while let Ok(request) = request_rx.recv() {
+                let result = generation::process_incoming_request(&request, &model, &context);
+                if let Err(err) = result {
+                    request.token_tx.send(Token::Error(err)).ok();
+                }
+            }
+        });
+
+        let state = state(request_tx, bearer_token);
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(router(state).into_make_service());
+        let address = server.local_addr();
+        tokio::spawn(server);
+        address
+    }
+
+    // function to make the API's state for a test, sending its requests to `request_tx`
+    fn state(
+        request_tx: flume::Sender<generation::Request>,
+        bearer_token: Option<&str>,
+    ) -> ApiState {
+        ApiState {
+            request_tx,
+            bearer_token: bearer_token.map(str::to_string),
+            max_prompt_chars: Some(100),
+            cooldown: Duration::ZERO,
+            cooldowns: Default::default(),
+            batch_size: 8,
+            model_name: "mock".to_string(),
+            chat_template: config::ChatFormat::Plain.template(),
+            system_prompt: None,
+            history_window: Default::default(),
+            summarization: None,
+            summaries: Default::default(),
+        }
+    }
+
+    // function to post a request to the API, returning the status and the body
+    async fn post(
+        address: SocketAddr,
+        path: &str,
+        body: serde_json::Value,
+    ) -> (StatusCode, String) {
+        let response = reqwest::Client::new()
+            .post(format!("http://{address}{path}"))
+            .json(&body)
+            .timeout(Duration::from_secs(10))
+            .send()
+            .await
+            .unwrap();
+        (response.status(), response.text().await.unwrap())
+    }
+
+    // function to split a server-sent event stream into its events' data, leaving out
+    // comments. Every event has to be framed as `data:...` followed by a blank line
+    fn sse_data(body: &str) -> Vec<&str> {
+        assert!(body.ends_with("\n\n"), "unterminated event in {body:?}");
+        body.split_terminator("\n\n")
+            .filter(|event| !event.starts_with(':'))
+            .map(|event| {
+                let data = event
+                    .strip_prefix("data:")
+                    .unwrap_or_else(|| panic!("an event without data: {event:?}"));
+                // The space after the field name is optional
+                data.strip_prefix(' ').unwrap_or(data)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn completions_are_shaped_like_openai() {
+        let address = serve_mock("completions", " Hello\n world\n again\n", None).await;
+
+        let (status, body) = post(
+            address,
+            "/v1/completions",
+            json!({ "prompt": "Say hi", "max_tokens": 2 }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["object"], "text_completion");
+        assert_eq!(body["model"], "mock");
+        assert!(body["id"].as_str().unwrap().starts_with("cmpl-"));
+        assert_eq!(body["choices"][0]["text"], " Hello world");
+        assert_eq!(body["choices"][0]["finish_reason"], "length");
+    }
+
+    #[tokio::test]
+    async fn chat_completions_stop_at_the_next_turn() {
+        let address = serve_mock("chat", " Hi\n there\n\\nuser:\n more\n", None).await;
+
+        let (status, body) = post(
+            address,
+            "/v1/chat/completions",
+            json!({ "messages": [{ "role": "user", "content": "Hello" }] }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["object"], "chat.completion");
+        assert_eq!(body["choices"][0]["message"]["role"], "assistant");
+        assert_eq!(body["choices"][0]["message"]["content"], " Hi there\n");
+        assert_eq!(body["choices"][0]["finish_reason"], "stop");
+    }
+
+    #[tokio::test]
+    async fn streamed_chat_completions_send_the_role_once() {
+        let address = serve_mock("chat-stream", " Hi\n there\n", None).await;
+
+        let (status, body) = post(
+            address,
+            "/v1/chat/completions",
+            json!({ "messages": [{ "role": "user", "content": "Hello" }], "stream": true }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::OK);
+        let events = sse_data(&body);
+        assert_eq!(events.last(), Some(&"[DONE]"));
+
+        let chunks: Vec<serde_json::Value> = events[..events.len() - 1]
+            .iter()
+            .map(|data| serde_json::from_str(data).unwrap())
+            .collect();
+        let deltas: Vec<_> = chunks.iter().map(|c| &c["choices"][0]["delta"]).collect();
+        assert_eq!(
+            deltas,
+            [
+                &json!({ "role": "assistant", "content": "" }),
+                &json!({ "content": " Hi" }),
+                &json!({ "content": " there" }),
+                &json!({}),
+            ]
+        );
+        assert!(chunks
+            .iter()
+            .all(|c| c["object"] == "chat.completion.chunk"));
+        assert_eq!(chunks[3]["choices"][0]["finish_reason"], "stop");
+        // The seed is reported in a comment, which clients skip
+        assert!(body.contains("\n\n:seed: "));
+    }
+
+    #[tokio::test]
+    async fn streamed_completions_send_a_chunk_per_token() {
+        let address = serve_mock("completions-stream", " Hi\n there\n", None).await;
+
+        let (_, body) = post(
+            address,
+            "/v1/completions",
+            json!({ "prompt": "Say hi", "stream": true }),
+        )
+        .await;
+        let events = sse_data(&body);
+        assert_eq!(events.len(), 3);
+        let texts: Vec<serde_json::Value> = events[..2]
+            .iter()
+            .map(|data| serde_json::from_str::<serde_json::Value>(data).unwrap())
+            .map(|chunk| chunk["choices"][0]["text"].clone())
+            .collect();
+        assert_eq!(texts, [json!(" Hi"), json!(" there")]);
+        assert_eq!(events[2], "[DONE]");
+    }
+
+    #[tokio::test]
+    async fn requests_without_the_bearer_token_are_refused() {
+        let address = serve_mock("auth", " Hi\n", Some("secret")).await;
+
+        let (status, body) = post(address, "/v1/completions", json!({ "prompt": "Hi" })).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+    }
+
+    #[tokio::test]
+    async fn prompts_over_the_limit_are_refused() {
+        let address = serve_mock("too-long", " Hi\n", None).await;
+
+        let (status, body) = post(
+            address,
+            "/v1/completions",
+            json!({ "prompt": "a".repeat(101) }),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(body["error"]["type"], "invalid_request_error");
+    }
+
+    // Every API request used to share message ID 0, so cancelling one of them from `/queue`
+    // took every other waiting API request off the board with it
+    #[test]
+    fn cancelling_one_api_request_leaves_the_others_waiting() {
+        let (request_tx, request_rx) = flume::unbounded();
+        let state = state(request_tx, None);
+        let _first_rx = start_generation(&state, "One".into(), None, None, "completions", false)
+            .unwrap_or_else(|_| panic!("the first request wasn't sent"));
+        let _second_rx = start_generation(&state, "Two".into(), None, None, "completions", false)
+            .unwrap_or_else(|_| panic!("the second request wasn't sent"));
+
+        let estimator = schedule::Estimator::new(&config::Configuration::default().inference);
+        let mut queue = schedule::Queue::default();
+        let board = schedule::Board::default();
+        queue.extend(request_rx.try_iter(), &estimator);
+        board.publish(&queue, &estimator);
+
+        let waiting = board.snapshot().waiting;
+        assert_eq!(waiting.len(), 2);
+        assert_ne!(waiting[0].message_id, waiting[1].message_id);
+
+        let cancelled = waiting[0].message_id;
+        assert!(board.cancel(cancelled));
+        let waiting = board.snapshot().waiting;
+        assert_eq!(waiting.len(), 1);
+        assert_ne!(waiting[0].message_id, cancelled);
+
+        let removed = queue.remove(&board.take_cancelled());
+        assert_eq!(removed.len(), 1);
+        assert_eq!(removed[0].message_id, cancelled);
+        assert_eq!(queue.depth(), 1);
+    }
+}
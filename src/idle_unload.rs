@@ -0,0 +1,145 @@
+// This file holds the model's idle unloading, set with `idle_unload_minutes` under `[model]`.
+// A bot that's used a couple of times a day doesn't need to keep gigabytes of weights in
+// memory between uses, so once nothing has been generated for that long, the generation
+// thread drops the model. The next request loads it again, and waits while it does. If that
+// fails, the request gets the error and the next one tries again.
+use std::time::{Duration, Instant};
+
+use crate::generation::Model;
+
+// Loads the model again after it was unloaded
+pub type Loader = Box<dyn Fn() -> anyhow::Result<Model> + Send>;
+
+// When the model is unloaded, and how it's loaded again
+pub struct IdleUnload {
+    // How long the model has to go unused before it's unloaded
+    pub after: Duration,
+    // How the model is loaded again
+    pub load: Loader,
+}
+
+// The model the generation thread runs requests on, which may be unloaded while it's idle
+pub struct ModelSlot {
+    // The model, unless it has been unloaded
+    model: Option<Model>,
+    // When it's unloaded, if ever
+    idle_unload: Option<IdleUnload>,
+    // When the model was last used
+    last_used: Instant,
+}
+
+impl ModelSlot {
+    // function to hold a loaded model, which is never unloaded without `idle_unload`
+    pub fn new(model: Model, idle_unload: Option<IdleUnload>) -> Self {
+        Self {
+            model: Some(model),
+            idle_unload,
+            last_used: Instant::now(),
+        }
+    }
+
+    // Whether or not the model is loaded right now
+    pub fn is_loaded(&self) -> bool {
+        self.model.is_some()
+    }
+
+    // function to get the model to run a request on, loading it again if it was unloaded
+    pub fn get(&mut self) -> anyhow::Result<&Model> {
+        self.last_used = Instant::now();
+        if self.model.is_none() {
+            let idle_unload = self
+                .idle_unload
+                .as_ref()
+                .expect("the model is only unloaded when it can be loaded again");
+            self.model = Some((idle_unload.load)()?);
+        }
+        Ok(self.model.as_ref().unwrap())
+    }
+
+    // function to note that the model was just used, so that it isn't unloaded straight after
+    // a long generation
+    pub fn touch(&mut self) {
+        self.last_used = Instant::now();
+    }
+
+    // function to unload the model if it has gone unused for long enough. Returns whether it
+    // was unloaded just now
+    pub fn unload_if_idle(&mut self) -> bool {
+        let idle = self
+            .idle_unload
+            .as_ref()
+            .is_some_and(|i| self.last_used.elapsed() >= i.after);
+        if idle && self.model.is_some() {
+            // Dropping the model frees its memory (and unmaps its file)
+            self.model = None;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{config, mock};
+
+    fn mock_model() -> anyhow::Result<Model> {
+        Ok(Model::Mock(mock::MockModel::load(
+            &config::Mock::default(),
+            2048,
+        )?))
+    }
+
+    #[test]
+    fn unloads_when_idle_and_reloads_on_demand() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let counted = loads.clone();
+        let mut slot = ModelSlot::new(
+            mock_model().unwrap(),
+            Some(IdleUnload {
+                after: Duration::from_millis(50),
+                load: Box::new(move || {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    mock_model()
+                }),
+            }),
+        );
+
+        assert!(!slot.unload_if_idle());
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(slot.unload_if_idle());
+        assert!(!slot.is_loaded());
+
+        slot.get().unwrap();
+        assert!(slot.is_loaded());
+        assert_eq!(loads.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn failed_reload_is_tried_again() {
+        let attempts = Arc::new(AtomicUsize::new(0));
+        let counted = attempts.clone();
+        let mut slot = ModelSlot::new(
+            mock_model().unwrap(),
+            Some(IdleUnload {
+                after: Duration::ZERO,
+                load: Box::new(move || {
+                    if counted.fetch_add(1, Ordering::SeqCst) == 0 {
+                        anyhow::bail!("the model file is missing");
+                    }
+                    mock_model()
+                }),
+            }),
+        );
+
+        assert!(slot.unload_if_idle());
+        assert!(slot.get().is_err());
+        assert!(slot.get().is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,218 @@
+// This file holds the `/prompt` command, which shows what a configured command sends to the
+// model: its template, the placeholders the template uses and, given a sample prompt, the
+// whole prompt with everything filled in. Commands with `hide_template` only have their
+// options listed. The answer is only shown to whoever asked, and is attached as files when
+// it doesn't fit in a message.
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::Context as AnyhowContext;
+use serenity::{
+    builder::CreateApplicationCommand,
+    http::Http,
+    model::prelude::{
+        command::CommandOptionType,
+        interaction::{
+            application_command::ApplicationCommandInteraction,
+            autocomplete::AutocompleteInteraction, InteractionResponseType,
+        },
+        AttachmentType,
+    },
+};
+
+use crate::{
+    config::{self, Configuration},
+    constant, util,
+};
+
+// The longest message Discord accepts
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+// The most choices Discord shows for an autocompleted option
+const MAX_AUTOCOMPLETE_CHOICES: usize = 25;
+
+// function to handle `/prompt`. `system_prompt` finds the system prompt that would go in
+// front of a command's prompt here, if it uses one
+pub async fn prompt_command(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    config: &Configuration,
+    system_prompt: impl Fn(&config::Command) -> Option<String>,
+) -> anyhow::Result<()> {
+    use constant::value as v;
+
+    let name = util::get_value(&cmd.data.options, v::COMMAND)
+        .and_then(util::value_to_string)
+        .context("no command specified")?;
+    let command = config
+        .commands
+        .get(&name)
+        .filter(|c| c.enabled)
+        .ok_or_else(|| util::user_error(format!("There is no command named `/{name}`.")))?;
+
+    if command.hide_template {
+        return respond(cmd, http, hidden_template(&name, command), vec![]).await;
+    }
+
+    let placeholders = command.placeholder_names();
+    let placeholders = if placeholders.is_empty() {
+        "none".to_string()
+    } else {
+        placeholders
+            .iter()
+            .map(|p| format!("`{{{{{p}}}}}`"))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    // The sample is rendered the way the command would render it here, with the options'
+    // defaults and the system prompt it would get
+    let rendered = util::get_value(&cmd.data.options, v::SAMPLE)
+        .and_then(util::value_to_string)
+        .map(|sample| {
+            let sample = config.inference.preprocess_user_prompt(sample);
+            command
+                .assemble_prompt(
+                    config,
+                    system_prompt(command).as_deref(),
+                    &sample,
+                    None,
+                    &HashMap::new(),
+                )
+                .map(|assembled| assembled.prompt)
+                .map_err(|err| format!("{err}"))
+        });
+
+    let header = format!("**Template of `/{name}`**");
+    let placeholders_line = format!("**Placeholders:** {placeholders}");
+    let inline = match &rendered {
+        None => format!(
+            "{header}\n{}\n{placeholders_line}",
+            code_block(&command.prompt)
+        ),
+        Some(Ok(prompt)) => format!(
+            "{header}\n{}\n{placeholders_line}\n\n**Rendered with the sample**\n{}",
+            code_block(&command.prompt),
+            code_block(prompt)
+        ),
+        Some(Err(err)) => format!(
+            "{header}\n{}\n{placeholders_line}\n\nThe sample couldn't be rendered: {err}",
+            code_block(&command.prompt)
+        ),
+    };
+
+    // Anything that can't be shown in a single message (or in a code block) is attached
+    let fits = inline.chars().count() <= MAX_MESSAGE_LENGTH
+        && !command.prompt.contains("```")
+        && !matches!(&rendered, Some(Ok(prompt)) if prompt.contains("```"));
+    if fits {
+        return respond(cmd, http, inline, vec![]).await;
+    }
+
+    let mut files = vec![("template.txt", command.prompt.clone())];
+    let mut content = format!("{header} is attached.\n{placeholders_line}");
+    match rendered {
+        Some(Ok(prompt)) => files.push(("rendered.txt", prompt)),
+        Some(Err(err)) => content += &format!("\n\nThe sample couldn't be rendered: {err}"),
+        None => {}
+    }
+    respond(cmd, http, content, files).await
+}
+
+// function to describe a command whose template is hidden, by its options alone
+fn hidden_template(name: &str, command: &config::Command) -> String {
+    use constant::value as v;
+
+    let options: Vec<_> = [v::PROMPT, v::SEED, v::PREFIX]
+        .into_iter()
+        .chain(command.options.iter().map(|o| o.name.as_str()))
+        .map(|o| format!("`{o}`"))
+        .collect();
+    format!(
+        "The template of `/{name}` is hidden. Its options are {}.",
+        options.join(", ")
+    )
+}
+
+// function to put text in a code block
+fn code_block(text: &str) -> String {
+    format!("```\n{text}\n```")
+}
+
+// function to respond with a message (and files) that only the user can see
+async fn respond(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    content: String,
+    files: Vec<(&str, String)>,
+) -> anyhow::Result<()> {
+    let attachments = files.iter().map(|(filename, data)| AttachmentType::Bytes {
+        data: Cow::Borrowed(data.as_bytes()),
+        filename: filename.to_string(),
+    });
+
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| {
+                d.content(content)
+                    .add_files(attachments)
+                    .ephemeral(true)
+                    .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse())
+            })
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to suggest the enabled commands whose names contain what's been typed so far
+pub async fn autocomplete(
+    ac: &AutocompleteInteraction,
+    http: &Http,
+    config: &Configuration,
+) -> anyhow::Result<()> {
+    let typed = util::focused_value(&ac.data.options)
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let mut names: Vec<_> = config
+        .commands
+        .iter()
+        .filter(|(name, command)| command.enabled && name.to_lowercase().contains(&typed))
+        .map(|(name, _)| name)
+        .collect();
+    names.sort();
+
+    ac.create_autocomplete_response(http, |r| {
+        for name in names.into_iter().take(MAX_AUTOCOMPLETE_CHOICES) {
+            r.add_string_choice(name, name);
+        }
+        r
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to build the `/prompt` command, for registering with Discord
+pub fn command() -> CreateApplicationCommand {
+    use constant::value as v;
+
+    let mut prompt = CreateApplicationCommand::default();
+    prompt
+        .name(constant::command::PROMPT)
+        .description("Shows what a command sends to the model.")
+        .create_option(|opt| {
+            opt.name(v::COMMAND)
+                .description("The command.")
+                .kind(CommandOptionType::String)
+                .set_autocomplete(true)
+                .required(true)
+        })
+        .create_option(|opt| {
+            opt.name(v::SAMPLE)
+                .description("A prompt to fill the template in with.")
+                .kind(CommandOptionType::String)
+                .required(false)
+        });
+    prompt
+}
@@ -0,0 +1,61 @@
+// This file holds the `/invite` command, which gives a link for adding the bot to a server.
+// The link asks for the `bot` and `applications.commands` scopes, and only for the permissions
+// the enabled features need, so that server admins aren't asked for more than that.
+use serenity::{
+    builder::CreateApplicationCommand,
+    http::Http,
+    model::{
+        prelude::interaction::application_command::ApplicationCommandInteraction, Permissions,
+    },
+};
+
+use crate::{config::Configuration, constant, util::DiscordInteraction};
+
+// function to work out the permissions the bot needs in a channel, given its config.
+// Responses to commands are sent through the interaction, which needs no permissions, but
+// reminders and scheduled posts are sent as ordinary messages, with embeds for alternatives
+fn permissions(config: &Configuration) -> Permissions {
+    let mut permissions =
+        Permissions::VIEW_CHANNEL | Permissions::SEND_MESSAGES | Permissions::EMBED_LINKS;
+
+    // Reacting to a message needs its history to be readable
+    if config.inference.enable_reaction_feedback {
+        permissions |= Permissions::ADD_REACTIONS | Permissions::READ_MESSAGE_HISTORY;
+    }
+
+    permissions
+}
+
+// function to build the invite link for the bot's application
+fn url(application_id: u64, permissions: Permissions) -> String {
+    format!(
+        "https://discord.com/api/oauth2/authorize?client_id={application_id}\
+         &scope=bot+applications.commands&permissions={}",
+        permissions.bits()
+    )
+}
+
+// function to handle `/invite`
+pub async fn invite(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    config: &Configuration,
+) -> anyhow::Result<()> {
+    let info = http.get_current_application_info().await?;
+    let link = url(info.id.0, permissions(config));
+
+    cmd.create_ephemeral(
+        http,
+        &format!("Add me to a server with this link: <{link}>"),
+    )
+    .await
+}
+
+// function to build the `/invite` command, for registering with Discord
+pub fn command() -> CreateApplicationCommand {
+    let mut invite = CreateApplicationCommand::default();
+    invite
+        .name(constant::command::INVITE)
+        .description("Gives a link for adding the bot to a server.");
+    invite
+}
@@ -0,0 +1,97 @@
+// This file holds the limits a request is checked against before it's queued, whether it
+// comes from Discord or from the HTTP API: how long its prompt can be once it's rendered,
+// and how long a user has to wait between uses of a command.
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::notice::Rejection;
+
+// When each user can next use each command they're waiting for. Users are keyed by their
+// Discord ID; API requests have none, so they share the ID 0
+#[derive(Default)]
+pub struct Cooldowns(Mutex<HashMap<(u64, String), Instant>>);
+
+impl Cooldowns {
+    // function to find how long the user has left to wait before they can use the command
+    // again, if they have to wait at all
+    pub fn remaining(&self, user_id: u64, command: &str) -> Option<Duration> {
+        let ready_at = *self
+            .0
+            .lock()
+            .unwrap()
+            .get(&(user_id, command.to_string()))?;
+        let remaining = ready_at.saturating_duration_since(Instant::now());
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    // function to remember that the user has just used the command successfully, and has to
+    // wait `cooldown` before using it again
+    pub fn used(&self, user_id: u64, command: &str, cooldown: Duration) {
+        if cooldown.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut ready_at = self.0.lock().unwrap();
+        ready_at.retain(|_, ready_at| *ready_at > now);
+        ready_at.insert((user_id, command.to_string()), now + cooldown);
+    }
+}
+
+// function to refuse a request from a user who's still waiting out the command's cooldown,
+// or whose rendered prompt is longer than `max_prompt_chars`
+pub fn check(
+    cooldowns: &Cooldowns,
+    user_id: u64,
+    command: &str,
+    max_prompt_chars: Option<usize>,
+    prompt: &str,
+) -> Result<(), Rejection> {
+    if let Some(remaining) = cooldowns.remaining(user_id, command) {
+        return Err(Rejection::Cooldown {
+            command: command.to_string(),
+            retry_in: remaining,
+        });
+    }
+    check_prompt_length(max_prompt_chars, prompt)
+}
+
+// function to refuse a rendered prompt that's longer than `max` characters, if there's a max
+pub fn check_prompt_length(max: Option<usize>, prompt: &str) -> Result<(), Rejection> {
+    let Some(max) = max else {
+        return Ok(());
+    };
+
+    let length = prompt.chars().count();
+    if length > max {
+        return Err(Rejection::PromptTooLong { length, max });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_wait_out_the_cooldown_and_fit_the_limit() {
+        let cooldowns = Cooldowns::default();
+        assert!(check(&cooldowns, 1, "hallucinate", Some(5), "Hello").is_ok());
+        assert!(matches!(
+            check(&cooldowns, 1, "hallucinate", Some(4), "Hello"),
+            Err(Rejection::PromptTooLong { length: 5, max: 4 })
+        ));
+
+        cooldowns.used(1, "hallucinate", Duration::from_secs(60));
+        assert!(matches!(
+            check(&cooldowns, 1, "hallucinate", None, "Hello"),
+            Err(Rejection::Cooldown { .. })
+        ));
+        // Other users, and other commands, aren't held up
+        assert!(check(&cooldowns, 2, "hallucinate", None, "Hello").is_ok());
+        assert!(check(&cooldowns, 1, "summarize", None, "Hello").is_ok());
+    }
+}
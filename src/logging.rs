@@ -0,0 +1,230 @@
+// This file holds the bot's logging.
+// Every log line goes to the console, as it always has, and (if `[logging.file]` is
+// configured) also to a log file that is rotated by size, for setups where nothing captures
+// the console. The file is shared by the event handler tasks and the generation thread, so
+// writes and rotation happen under one lock. The file's level can be changed at runtime
+// with `set_level`, which the config reload uses.
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU8, Ordering},
+        Mutex, OnceLock,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as AnyhowContext;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+
+// How important a log line is
+#[derive(
+    Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Level {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        })
+    }
+}
+
+// The log file, if there is one
+static FILE: OnceLock<Mutex<RollingFile>> = OnceLock::new();
+
+// The least important level written to the log file
+static FILE_LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+// function to start writing to the log file described by the configuration, if any.
+// This should be called once, before anything is logged
+pub fn init(settings: &config::Logging) -> anyhow::Result<()> {
+    let Some(file) = &settings.file else {
+        return Ok(());
+    };
+
+    let rolling = RollingFile::open(&file.path, file.max_size_bytes, file.max_files)
+        .with_context(|| format!("failed to open log file {}", file.path.display()))?;
+    FILE.set(Mutex::new(rolling)).ok();
+    set_level(file.level);
+
+    Ok(())
+}
+
+// function to change the least important level written to the log file
+pub fn set_level(level: Level) {
+    FILE_LEVEL.store(level as u8, Ordering::SeqCst);
+}
+
+// function to write a log line. Use the `info!`, `warn!`, `error!` and `debug!` macros
+// rather than calling this directly
+pub fn write(level: Level, message: fmt::Arguments) {
+    // The console gets everything, as before; problems go to stderr
+    if level <= Level::Warn {
+        eprintln!("{level}: {message}");
+    } else {
+        println!("{message}");
+    }
+
+    if level as u8 > FILE_LEVEL.load(Ordering::SeqCst) {
+        return;
+    }
+    if let Some(file) = FILE.get() {
+        let line = format!("{} {level:<5} {message}\n", timestamp());
+        let mut file = file.lock().unwrap();
+        if let Err(err) = file.write_line(&line).and_then(|_| file.flush()) {
+            eprintln!("Failed to write to the log file: {err:?}");
+        }
+    }
+}
+
+// Logs a line at the `Info` level, with `format!` syntax
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Info, format_args!($($arg)*))
+    };
+}
+
+// Logs a line at the `Warn` level, with `format!` syntax
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+// Logs a line at the `Error` level, with `format!` syntax
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Error, format_args!($($arg)*))
+    };
+}
+
+// Logs a line at the `Debug` level, with `format!` syntax
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::logging::write($crate::logging::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+// A log file that is rotated once it reaches a maximum size.
+// `bot.log` is renamed to `bot.log.1`, `bot.log.1` to `bot.log.2`, and so on,
+// and the oldest file is deleted once there are `max_files` rotated files.
+// Writes are buffered, so whoever needs them on disk straight away should `flush`
+pub struct RollingFile {
+    path: PathBuf,
+    file: BufWriter<File>,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RollingFile {
+    // function to open (or create) the log file, appending to what's already there
+    pub fn open(path: &Path, max_size: u64, max_files: usize) -> std::io::Result<Self> {
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    // function to write a line, rotating first if it would take the file over its size
+    pub fn write_line(&mut self, line: &str) -> std::io::Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // function to move the current file aside and start a new one
+    fn rotate(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+
+        // Shift every rotated file along by one, dropping the oldest
+        if self.max_files == 0 {
+            fs::remove_file(&self.path).ok();
+        } else {
+            fs::remove_file(self.rotated_path(self.max_files)).ok();
+            for index in (1..self.max_files).rev() {
+                fs::rename(self.rotated_path(index), self.rotated_path(index + 1)).ok();
+            }
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.file = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?,
+        );
+        self.size = 0;
+        Ok(())
+    }
+
+    // function to write out anything still buffered
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+
+    // The path of the `index`th most recent rotated file
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{index}"));
+        path.into()
+    }
+}
+
+// function to format the current time as an ISO 8601 UTC timestamp
+pub fn timestamp() -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = now.as_secs();
+    let (days, time) = (seconds / 86_400, seconds % 86_400);
+
+    // Converts days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1_460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        time / 3_600,
+        time % 3_600 / 60,
+        time % 60,
+        now.subsec_millis()
+    )
+}
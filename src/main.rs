@@ -1,31 +1,179 @@
 use anyhow::Context as AnyhowContext;
+use clap::Parser;
 use serenity::{model::prelude::*, Client};
+use std::sync::{atomic::Ordering, Arc};
 
+// The logging macros (`info!` and friends) are used everywhere, so this comes first
+#[macro_use]
+mod logging;
+
+mod alert;
+mod attachments;
+mod bench;
+mod chat;
+mod checksum;
+mod cli;
 mod config;
+mod config_validate;
 mod constant;
+mod debug_generate;
+mod details;
+mod download;
+mod embedding;
+mod export;
+mod fallback;
+mod feedback;
 mod generation;
+mod generation_log;
 mod handler;
+mod health;
+mod http_api;
+mod idle_unload;
+mod inspect;
+mod invite;
+mod limits;
+mod memory_check;
+mod mock;
+mod moderation;
+mod notice;
+mod persona;
+mod postprocess;
+mod presence;
+mod prompt_cache;
+mod prompts;
+mod queue;
+mod recurring;
+mod registration;
+mod reminder;
+mod repetition;
+mod report;
+mod reroll;
+mod schedule;
+#[cfg(feature = "speculative")]
+mod speculative;
+mod store;
+mod summary;
+mod system_prompt;
+mod tokenizer_config;
 mod util;
 
 use config::Configuration;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let config = Configuration::load()?;
+    let args = cli::Args::parse();
 
-    let model = llm::load_dynamic(
-        config.model.architecture(),
-        &config.model.path,
-        llm::TokenizerSource::Embedded,
-        llm::ModelParameters {
-            prefer_mmap: config.model.prefer_mmap,
-            context_size: config.model.context_token_length,
-            use_gpu: config.model.use_gpu,
-            gpu_layers: config.model.gpu_layers,
-            ..Default::default()
-        },
-        llm::load_progress_callback_stdout,
-    )?;
+    // Printing the schema doesn't need a configuration, so it comes before loading one
+    if args.schema {
+        println!("{}", Configuration::schema()?);
+        return Ok(());
+    }
+
+    let mut config = Configuration::load()?;
+    logging::init(&config.logging)?;
+    apply_no_gpu_override(&mut config);
+
+    // Fetch the model file first if it's missing and can be downloaded
+    download::ensure_model(&config.model).await?;
+    if let (true, Some(draft)) = (
+        config.inference.enable_speculative_decoding,
+        &config.inference.draft_model,
+    ) {
+        download::ensure_model(draft).await?;
+    }
+    if args.download_only {
+        return Ok(());
+    }
+
+    // Refuses a model file that doesn't match its checksum, e.g. one cut short while copying.
+    // `hash-model` is for working out the right checksum, so it doesn't check the old one
+    if args.skip_checksum {
+        warn!("--skip-checksum is set: not checking the model file against model.sha256");
+    } else if !matches!(args.command, Some(cli::CliCommand::HashModel)) {
+        checksum::verify(&config.model)?;
+        if let (true, Some(draft)) = (
+            config.inference.enable_speculative_decoding,
+            &config.inference.draft_model,
+        ) {
+            checksum::verify(draft)?;
+        }
+    }
+
+    // Run the offline CLI subcommand instead of the bot, if one was given
+    if let Some(command) = args.command {
+        return cli::run(&config, command);
+    }
+
+    // Start the health endpoint before loading the model, so that
+    // liveness can be probed while the (potentially slow) load happens
+    let readiness = Arc::new(health::Readiness::default());
+    if let Some(health) = config.health.clone() {
+        let readiness = readiness.clone();
+        tokio::spawn(async move {
+            if let Err(err) = health::serve(&health, readiness).await {
+                error!("Health endpoint error: {err:?}");
+            }
+        });
+    }
+
+    let load_timer = std::time::Instant::now();
+    let model = load_model(&config)?;
+    readiness.model_loaded.store(true, Ordering::SeqCst);
+    readiness
+        .model_load_ms
+        .store(load_timer.elapsed().as_millis() as u64, Ordering::SeqCst);
+    if let Some(model) = model.llm() {
+        if let Some(tokenizer_config) = config.model.tokenizer_config()? {
+            tokenizer_config.check(model);
+        }
+    }
+
+    // The model can be unloaded while nobody is using it, and loaded again the same way
+    let idle_unload = config.model.idle_unload_minutes.map(|minutes| {
+        let config = config.clone();
+        idle_unload::IdleUnload {
+            after: std::time::Duration::from_secs(minutes * 60),
+            load: Box::new(move || load_model(&config)),
+        }
+    });
+    let model = idle_unload::ModelSlot::new(model, idle_unload);
+
+    // Open the store (in memory, unless persistence is enabled)
+    let store = store::Store::open(&config.persistence)?;
+
+    let reminders = reminder::Reminders::default();
+    let schedules = recurring::Schedules::new(config.schedules.clone());
+    let current_presence = Arc::new(presence::CurrentPresence::default());
+    let handler = handler::Handler::new(
+        config.clone(),
+        model,
+        readiness.clone(),
+        store,
+        reminders.clone(),
+        schedules.clone(),
+        current_presence.clone(),
+    );
+    let request_tx = handler.request_tx();
+    let active_requests = handler.active_requests();
+
+    // Start the OpenAI-compatible HTTP API if it's configured.
+    // It shares the handler's generation queue, so both frontends use the same model
+    if let Some(http_api) = config.http_api.clone() {
+        let config = config.clone();
+        let request_tx = request_tx.clone();
+        tokio::spawn(async move {
+            if let Err(err) = http_api::serve(&config, &http_api, request_tx).await {
+                error!("HTTP API error: {err:?}");
+            }
+        });
+    }
+
+    // Reactions are only needed for voting on responses
+    let mut intents = GatewayIntents::default();
+    if config.inference.enable_reaction_feedback {
+        intents |=
+            GatewayIntents::GUILD_MESSAGE_REACTIONS | GatewayIntents::DIRECT_MESSAGE_REACTIONS;
+    }
 
     let mut client = Client::builder(
         config
@@ -33,15 +181,178 @@ async fn main() -> anyhow::Result<()> {
             .discord_token
             .as_deref()
             .context("Expected authentication.discord_token to be filled in config")?,
-        GatewayIntents::default(),
+        intents,
     )
-    .event_handler(handler::Handler::new(config, model))
+    .event_handler(handler)
     .await
     .context("Error creating client")?;
 
-    if let Err(why) = client.start().await {
-        println!("Client error: {why:?}");
+    // Keep the bot's status up to date, if it's configured.
+    // The updater stops by itself once the bot starts shutting down
+    if let Some(settings) = config.presence.clone() {
+        tokio::spawn(presence::run(
+            settings,
+            readiness.clone(),
+            active_requests,
+            request_tx.clone(),
+            client.shard_manager.clone(),
+            current_presence,
+        ));
+    }
+
+    // Fire reminders scheduled with `/remind` as they come due
+    tokio::spawn(reminder::run(
+        reminders,
+        client.cache_and_http.http.clone(),
+        request_tx.clone(),
+        config.inference.batch_size,
+    ));
+
+    // Post the scheduled prompts as they come due
+    tokio::spawn(recurring::run(
+        schedules.clone(),
+        config.clone(),
+        client.cache_and_http.http.clone(),
+        request_tx,
+    ));
+
+    // Apply the settings that can change at runtime whenever the config is reloaded
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(schedules));
+
+    // On Ctrl-C, stop reporting ready first so that nothing new gets routed to us,
+    // then shut down the gateway connections
+    let shard_manager = client.shard_manager.clone();
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            readiness.shutting_down.store(true, Ordering::SeqCst);
+            shard_manager.lock().await.shutdown_all().await;
+        }
+    });
+
+    // Start as many gateway shards as configured; they all share the same generation queue
+    let started = match config.gateway.shards {
+        config::ShardCount::Fixed(shards) => client.start_shards(shards).await,
+        config::ShardCount::Auto(_) => client.start_autosharded().await,
+    };
+    if let Err(why) = started {
+        error!("Client error: {why:?}");
     }
 
     Ok(())
 }
+
+// Re-reads the config whenever the process gets SIGHUP, and applies the settings
+// that can change without a restart (currently, the log file's level and the schedules)
+#[cfg(unix)]
+async fn reload_on_hangup(schedules: recurring::Schedules) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let Ok(mut hangups) = signal(SignalKind::hangup()) else {
+        return;
+    };
+    while hangups.recv().await.is_some() {
+        match Configuration::load() {
+            Ok(config) => {
+                let level = config.logging.file.map(|f| f.level).unwrap_or_default();
+                logging::set_level(level);
+                let count = config.schedules.len();
+                schedules.set(config.schedules);
+                info!(
+                    "Reloaded the config; the log file's level is now {level}, \
+                     and there are {count} schedule(s)"
+                );
+            }
+            Err(err) => error!("Failed to reload the config: {err:?}"),
+        }
+    }
+}
+
+// Turns GPU offloading off if `LLMCORD_NO_GPU` is set to `1` or `true`, whatever the config
+// says. This is an escape hatch for testing CPU-only behaviour with a GPU-enabled build
+// (e.g. on CI machines without a GPU), without editing the config file
+fn apply_no_gpu_override(config: &mut Configuration) {
+    let disabled =
+        std::env::var("LLMCORD_NO_GPU").is_ok_and(|v| v == "1" || v.eq_ignore_ascii_case("true"));
+    if !disabled {
+        return;
+    }
+
+    warn!(
+        "LLMCORD_NO_GPU is set: ignoring the config's use_gpu = {} and gpu_layers = {:?}, \
+         and running on the CPU only",
+        config.model.use_gpu, config.model.gpu_layers
+    );
+    config.model.use_gpu = false;
+    config.model.gpu_layers = Some(0);
+    if let Some(draft) = &mut config.inference.draft_model {
+        draft.use_gpu = false;
+        draft.gpu_layers = Some(0);
+    }
+}
+
+// Loads the model described by the configuration.
+// This is shared between the bot and the offline CLI
+fn load_model(config: &Configuration) -> anyhow::Result<generation::Model> {
+    // The mock model skips `llm` entirely, so no model file is needed
+    if config.model.is_mock() {
+        info!("Using the built-in mock model");
+        let settings = config.model.mock.clone().unwrap_or_default();
+        return Ok(generation::Model::Mock(mock::MockModel::load(
+            &settings,
+            config.model.effective_context_length(),
+        )?));
+    }
+
+    let model = load_llm(&config.model, &config.inference)?;
+
+    // The draft model that proposes tokens for it, if speculative decoding is on
+    #[cfg(feature = "speculative")]
+    if let (true, Some(draft)) = (
+        config.inference.enable_speculative_decoding,
+        &config.inference.draft_model,
+    ) {
+        info!("Loading the draft model for speculative decoding");
+        let draft = load_llm(draft, &config.inference)?;
+        return Ok(generation::Model::Speculative(speculative::Models::new(
+            model,
+            draft,
+            config.inference.speculative_tokens,
+        )?));
+    }
+
+    Ok(generation::Model::Llm(model))
+}
+
+// Loads a model file through `llm`, for the model or its draft
+fn load_llm(
+    model: &config::Model,
+    inference: &config::Inference,
+) -> anyhow::Result<Box<dyn llm::Model>> {
+    // Refuses a model that won't fit, rather than running out of memory partway through
+    memory_check::check(model, inference)?;
+
+    let rope_overrides = model.rope_overrides()?;
+    if let Some(scaling) = &model.rope_context_scaling {
+        info!(
+            "Using {scaling:?} RoPE scaling, for an effective context length of {} tokens",
+            model.effective_context_length()
+        );
+    }
+
+    Ok(llm::load_dynamic(
+        model.architecture(),
+        &model.path,
+        llm::TokenizerSource::Embedded,
+        llm::ModelParameters {
+            prefer_mmap: model.prefer_mmap,
+            // The KV cache has to hold the whole scaled context that prompts are sized for
+            context_size: model.effective_context_length(),
+            use_gpu: model.use_gpu,
+            gpu_layers: model.gpu_layers,
+            rope_overrides,
+            ..Default::default()
+        },
+        llm::load_progress_callback_stdout,
+    )?)
+}
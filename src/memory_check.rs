@@ -0,0 +1,355 @@
+// This file holds the memory check that runs before the model is loaded.
+// A model that doesn't fit either gets the bot killed for running out of memory or fails with
+// an allocation error that doesn't say why, so the memory it will need is estimated up front
+// from the model file's size, its quantization (read from the file name), the context length
+// and the KV cache's precision, and compared with what the machine has free. If it won't fit,
+// the bot refuses to start and says what to change, unless `ignore_memory_check` is set.
+// The estimate assumes a LLaMA-shaped model, which is what the KV cache's size depends on.
+use crate::config;
+
+// Memory needed beyond the weights and the KV cache: scratch buffers and the like
+const OVERHEAD_BYTES: u64 = 512 * 1024 * 1024;
+
+// The bits per weight assumed when the quantization can't be read from the file name. Q4_0 is
+// the most common
+const DEFAULT_BITS_PER_WEIGHT: f64 = 4.5;
+
+// The widths and depths of the LLaMA sizes, by parameter count
+const LLAMA_SHAPES: &[(f64, u64, u64)] = &[
+    (3.4e9, 3200, 26),
+    (6.7e9, 4096, 32),
+    (13.0e9, 5120, 40),
+    (32.5e9, 6656, 60),
+    (65.2e9, 8192, 80),
+];
+
+// The weight formats models are commonly quantized to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Quantization {
+    F32,
+    F16,
+    Q8_0,
+    Q6K,
+    Q5_1,
+    Q5_0,
+    Q5K,
+    Q4_1,
+    Q4_0,
+    Q4K,
+    Q3K,
+    Q2K,
+}
+
+impl Quantization {
+    // The names the formats go by in file names, longest first so that `q4_k` isn't read as
+    // `q4`. `_K_S`/`_K_M`/`_K_L` variants are read as their `_K` format
+    const NAMES: &'static [(&'static str, Quantization)] = &[
+        ("q8_0", Quantization::Q8_0),
+        ("q6_k", Quantization::Q6K),
+        ("q5_1", Quantization::Q5_1),
+        ("q5_0", Quantization::Q5_0),
+        ("q5_k", Quantization::Q5K),
+        ("q4_1", Quantization::Q4_1),
+        ("q4_0", Quantization::Q4_0),
+        ("q4_k", Quantization::Q4K),
+        ("q3_k", Quantization::Q3K),
+        ("q2_k", Quantization::Q2K),
+        ("f32", Quantization::F32),
+        ("f16", Quantization::F16),
+    ];
+
+    // function to read the format from a model's file name, e.g. `llama-2-7b.Q4_K_M.gguf`
+    pub fn from_file_name(name: &str) -> Option<Self> {
+        let name = name.to_lowercase();
+        Self::NAMES
+            .iter()
+            .find(|(n, _)| name.contains(n))
+            .map(|(_, q)| *q)
+    }
+
+    // The bits each weight takes, on average, including the blocks' scales
+    pub fn bits_per_weight(self) -> f64 {
+        match self {
+            Quantization::F32 => 32.0,
+            Quantization::F16 => 16.0,
+            Quantization::Q8_0 => 8.5,
+            Quantization::Q6K => 6.5625,
+            Quantization::Q5_1 => 6.0,
+            Quantization::Q5_0 => 5.5,
+            Quantization::Q5K => 5.5,
+            Quantization::Q4_1 => 5.0,
+            Quantization::Q4_0 => 4.5,
+            Quantization::Q4K => 4.5,
+            Quantization::Q3K => 3.4375,
+            Quantization::Q2K => 2.5625,
+        }
+    }
+}
+
+// The memory a model is expected to need, in bytes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Estimate {
+    // The weights, which take about as much memory as the file does
+    pub weights_bytes: u64,
+    // The KV cache for the whole context
+    pub kv_cache_bytes: u64,
+    // Everything else
+    pub overhead_bytes: u64,
+    // The number of layers the model is expected to have, for working out how much of it
+    // goes to the GPU
+    pub layers: u64,
+}
+
+impl Estimate {
+    // function to estimate the memory a model file needs
+    pub fn new(
+        file_bytes: u64,
+        quantization: Option<Quantization>,
+        context_length: usize,
+        f16_kv: bool,
+    ) -> Self {
+        let bits_per_weight = quantization.map_or(DEFAULT_BITS_PER_WEIGHT, |q| q.bits_per_weight());
+        let parameters = file_bytes as f64 * 8.0 / bits_per_weight;
+
+        // The known size closest to the parameter count, on a log scale
+        let (_, width, layers) = LLAMA_SHAPES
+            .iter()
+            .copied()
+            .min_by(|(a, _, _), (b, _, _)| {
+                let distance = |size: f64| (parameters / size).ln().abs();
+                distance(*a).total_cmp(&distance(*b))
+            })
+            .unwrap();
+        let bytes_per_value = if f16_kv { 2 } else { 4 };
+
+        Self {
+            weights_bytes: file_bytes,
+            // A key and a value for every layer and every position
+            kv_cache_bytes: 2 * layers * context_length as u64 * width * bytes_per_value,
+            overhead_bytes: OVERHEAD_BYTES,
+            layers,
+        }
+    }
+
+    // function to estimate the memory the configured model needs, if its file can be read
+    fn of(model: &config::Model, inference: &config::Inference) -> Option<Self> {
+        let metadata = std::fs::metadata(&model.path).ok()?;
+        Some(Self::new(
+            metadata.len(),
+            quantization(model),
+            model.effective_context_length(),
+            inference.f16_kv,
+        ))
+    }
+
+    // The memory needed in total
+    pub fn total_bytes(&self) -> u64 {
+        self.weights_bytes + self.kv_cache_bytes + self.overhead_bytes
+    }
+
+    // function to split the memory needed between the system and the GPU, given how many
+    // layers are offloaded to it (`None` for all of them). The KV cache goes with the layers
+    pub fn split(&self, gpu_layers: Option<usize>) -> (u64, u64) {
+        let offloaded = gpu_layers.map_or(1.0, |l| (l as f64 / self.layers as f64).min(1.0));
+        let on_gpu = ((self.weights_bytes + self.kv_cache_bytes) as f64 * offloaded) as u64;
+        (self.total_bytes() - on_gpu, on_gpu)
+    }
+}
+
+// function to read a model's quantization from its file name
+fn quantization(model: &config::Model) -> Option<Quantization> {
+    let file_name = model.path.file_name()?.to_string_lossy();
+    Quantization::from_file_name(&file_name)
+}
+
+// function to write out a number of bytes in gigabytes
+fn gigabytes(bytes: u64) -> String {
+    format!("{:.1} GB", bytes as f64 / 1e9)
+}
+
+// function to compare what's needed with what's free, where it's known. Returns the problem,
+// spelled out, if it won't fit
+fn compare(
+    estimate: &Estimate,
+    context_length: usize,
+    needed: (u64, u64),
+    available: (Option<u64>, Option<u64>),
+) -> anyhow::Result<()> {
+    let (needed_system, needed_gpu) = needed;
+    let (available_system, available_gpu) = available;
+
+    let mut shortfalls = vec![];
+    if let Some(available) = available_system.filter(|a| needed_system > *a) {
+        shortfalls.push(format!(
+            "{} of system memory, but only {} is available",
+            gigabytes(needed_system),
+            gigabytes(available)
+        ));
+    }
+    if let Some(available) = available_gpu.filter(|a| needed_gpu > *a) {
+        shortfalls.push(format!(
+            "{} of GPU memory, but only {} is free",
+            gigabytes(needed_gpu),
+            gigabytes(available)
+        ));
+    }
+    if shortfalls.is_empty() {
+        return Ok(());
+    }
+
+    anyhow::bail!(
+        "The model probably won't fit in memory. It needs about {} \
+         ({} of weights, {} of KV cache for {context_length} tokens and {} of overhead): {}. \
+         Try a smaller quantization of the model (e.g. Q4_0 or Q2_K), a lower \
+         model.context_token_length or inference.f16_kv = true, or set \
+         model.ignore_memory_check = true to load it anyway.",
+        gigabytes(estimate.total_bytes()),
+        gigabytes(estimate.weights_bytes),
+        gigabytes(estimate.kv_cache_bytes),
+        gigabytes(estimate.overhead_bytes),
+        shortfalls.join("; ")
+    )
+}
+
+// function to check that the configured model will fit in memory before it's loaded
+// It also logs the size of the KV cache, which is only ever worked out here
+pub fn check(model: &config::Model, inference: &config::Inference) -> anyhow::Result<()> {
+    // A missing file is reported by the load itself
+    let Some(estimate) = Estimate::of(model, inference) else {
+        return Ok(());
+    };
+    let context_length = model.effective_context_length();
+    info!(
+        "KV cache: {:.1} MB for {context_length} tokens ({})",
+        estimate.kv_cache_bytes as f64 / 1e6,
+        if inference.f16_kv { "f16" } else { "f32" }
+    );
+    if model.ignore_memory_check {
+        return Ok(());
+    }
+
+    let needed = if model.use_gpu {
+        estimate.split(model.gpu_layers)
+    } else {
+        (estimate.total_bytes(), 0)
+    };
+    let available_gpu = if model.use_gpu {
+        free_vram_bytes()
+    } else {
+        None
+    };
+    info!(
+        "Estimated memory needed: {} of system memory, {} of GPU memory ({})",
+        gigabytes(needed.0),
+        gigabytes(needed.1),
+        quantization(model).map_or("unknown quantization".to_string(), |q| format!("{q:?}"))
+    );
+
+    compare(
+        &estimate,
+        context_length,
+        needed,
+        (available_system_bytes(), available_gpu),
+    )
+}
+
+// function to read how much system memory is available, from /proc/meminfo.
+// Returns `None` where it can't be read (e.g. on anything but Linux)
+fn available_system_bytes() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kilobytes: u64 = meminfo
+        .lines()
+        .find_map(|l| l.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+// Asks `nvidia-smi` how much VRAM is free on the first GPU.
+// Returns `None` if it isn't available (e.g. on non-NVIDIA hardware)
+fn free_vram_bytes() -> Option<u64> {
+    let output = std::process::Command::new("nvidia-smi")
+        .args(["--query-gpu=memory.free", "--format=csv,noheader,nounits"])
+        .output()
+        .ok()?;
+    let free_mib: u64 = String::from_utf8(output.stdout)
+        .ok()?
+        .lines()
+        .next()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(free_mib * 1024 * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GB: u64 = 1_000_000_000;
+
+    #[test]
+    fn reads_the_quantization_from_the_file_name() {
+        let cases = [
+            ("llama-2-7b-chat.ggmlv3.q2_K.bin", Some(Quantization::Q2K)),
+            ("nous-hermes-llama2-13b.Q4_0.gguf", Some(Quantization::Q4_0)),
+            ("mistral-7b-instruct.Q4_K_M.gguf", Some(Quantization::Q4K)),
+            ("llama-13b-f16.bin", Some(Quantization::F16)),
+            ("model.bin", None),
+        ];
+        for (name, expected) in cases {
+            assert_eq!(Quantization::from_file_name(name), expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn estimates_known_models() {
+        // Llama 2 7B at Q4_0 is a 3.8 GB file; its f16 KV cache for 2048 tokens is 1 GiB
+        let estimate = Estimate::new(3_830_000_000, Some(Quantization::Q4_0), 2048, true);
+        assert_eq!(estimate.layers, 32);
+        assert_eq!(estimate.kv_cache_bytes, 1 << 30);
+
+        // Llama 2 13B at f16 is a 26 GB file, with 40 layers 5120 wide
+        let estimate = Estimate::new(26 * GB, Some(Quantization::F16), 2048, true);
+        assert_eq!(estimate.layers, 40);
+        assert_eq!(estimate.kv_cache_bytes, 2 * 40 * 2048 * 5120 * 2);
+
+        // A full-precision cache is twice the size
+        let f32_kv = Estimate::new(26 * GB, Some(Quantization::F16), 2048, false);
+        assert_eq!(f32_kv.kv_cache_bytes, 2 * estimate.kv_cache_bytes);
+
+        // Llama 2 13B at Q2_K is a 5.4 GB file, which is still read as 13B
+        let estimate = Estimate::new(5_430_000_000, Some(Quantization::Q2K), 2048, true);
+        assert_eq!(estimate.layers, 40);
+    }
+
+    #[test]
+    fn refuses_a_model_that_wont_fit() {
+        let estimate = Estimate::new(26 * GB, Some(Quantization::F16), 2048, true);
+        let needed = (estimate.total_bytes(), 0);
+
+        let err = compare(&estimate, 2048, needed, (Some(8 * GB), None)).unwrap_err();
+        let message = err.to_string();
+        assert!(message.contains("only 8.0 GB is available"), "{message}");
+        assert!(message.contains("26.0 GB of weights"), "{message}");
+
+        assert!(compare(&estimate, 2048, needed, (Some(64 * GB), None)).is_ok());
+        // Nothing is refused when what's free isn't known
+        assert!(compare(&estimate, 2048, needed, (None, None)).is_ok());
+    }
+
+    #[test]
+    fn offloaded_layers_go_to_the_gpu() {
+        let estimate = Estimate::new(3_830_000_000, Some(Quantization::Q4_0), 2048, true);
+        let (system, gpu) = estimate.split(None);
+        assert_eq!(system, estimate.overhead_bytes);
+        assert_eq!(gpu, estimate.weights_bytes + estimate.kv_cache_bytes);
+
+        let (system, gpu) = estimate.split(Some(16));
+        assert_eq!(system + gpu, estimate.total_bytes());
+        assert_eq!(gpu, (estimate.weights_bytes + estimate.kv_cache_bytes) / 2);
+    }
+}
@@ -0,0 +1,276 @@
+// This file holds the built-in mock model.
+// It stands in for a real `llm` model when `model.architecture = "mock"`, so that the bot's
+// streaming, chunking, cancellation and timeouts can be exercised without any model weights.
+// The mock reports its output through the same `llm::InferenceResponse` callback that a
+// real model uses, so everything downstream of the model behaves exactly the same.
+use std::time::{Duration, Instant};
+
+use anyhow::Context as AnyhowContext;
+use rand::Rng;
+
+use crate::{
+    config::{self, MockMode},
+    generation::{self, InferenceError},
+};
+
+// The words that `lorem` mode picks from
+const LOREM_WORDS: &[&str] = &[
+    "lorem",
+    "ipsum",
+    "dolor",
+    "sit",
+    "amet",
+    "consectetur",
+    "adipiscing",
+    "elit",
+    "sed",
+    "do",
+    "eiusmod",
+    "tempor",
+    "incididunt",
+    "ut",
+    "labore",
+    "et",
+    "dolore",
+    "magna",
+    "aliqua",
+];
+
+// The mock model, configured from `config::Mock`
+pub struct MockModel {
+    // What the mock generates
+    mode: MockMode,
+    // The tokens to replay in `script` mode
+    script: Vec<String>,
+    // How long to wait between tokens
+    token_delay: Duration,
+    // The number of tokens to generate in `lorem` mode
+    max_tokens: usize,
+    // The context size the mock pretends to have
+    context_size: usize,
+}
+
+impl MockModel {
+    // function to create the mock model, reading the script file if one is needed
+    pub fn load(settings: &config::Mock, context_size: usize) -> anyhow::Result<Self> {
+        let script = if settings.mode == MockMode::Script {
+            let path = settings
+                .script_path
+                .as_ref()
+                .context("Expected model.mock.script_path to be set for `script` mode")?;
+            let script = std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read mock script {}", path.display()))?;
+
+            // One token per line, with `\n` standing in for newlines within a token
+            script.lines().map(|l| l.replace("\\n", "\n")).collect()
+        } else {
+            vec![]
+        };
+
+        Ok(Self {
+            mode: settings.mode,
+            script,
+            token_delay: Duration::from_secs_f32(1.0 / settings.tokens_per_second.max(0.001)),
+            max_tokens: settings.max_tokens,
+            context_size,
+        })
+    }
+
+    // The number of tokens that fit in the mock's (pretend) context
+    pub fn context_size(&self) -> usize {
+        self.context_size
+    }
+
+    // function to "generate" a response, reporting each token through the callback.
+    // The random number generator is the one seeded from the request, so `lorem`
+    // output is deterministic for a given seed
+    pub fn infer(
+        &self,
+        prompt: &str,
+        maximum_token_count: Option<usize>,
+        rng: &mut impl Rng,
+        mut callback: impl FnMut(
+            llm::InferenceResponse,
+        ) -> Result<llm::InferenceFeedback, InferenceError>,
+    ) -> Result<llm::InferenceStats, InferenceError> {
+        // Feed the prompt, as a real model would
+        let feed_start = Instant::now();
+        let prompt_tokens = prompt.split_inclusive(' ').count();
+        if let llm::InferenceFeedback::Halt =
+            callback(llm::InferenceResponse::PromptToken(prompt.to_string()))?
+        {
+            return Ok(llm::InferenceStats {
+                feed_prompt_duration: feed_start.elapsed(),
+                prompt_tokens,
+                predict_duration: Duration::ZERO,
+                predict_tokens: 0,
+            });
+        }
+        let feed_prompt_duration = feed_start.elapsed();
+
+        // Work out the full output up front
+        let tokens: Vec<String> = match self.mode {
+            MockMode::Echo => prompt.split_inclusive(' ').map(String::from).collect(),
+            MockMode::Lorem => (0..self.max_tokens)
+                .map(|_| format!(" {}", LOREM_WORDS[rng.gen_range(0..LOREM_WORDS.len())]))
+                .collect(),
+            MockMode::Script => self.script.clone(),
+        };
+
+        // Then emit it one token at a time, at the configured rate
+        let predict_start = Instant::now();
+        let limit = maximum_token_count.unwrap_or(usize::MAX);
+        let mut predict_tokens = 0;
+        let mut halted = false;
+        for token in tokens.iter().take(limit) {
+            std::thread::sleep(self.token_delay);
+            predict_tokens += 1;
+
+            if let llm::InferenceFeedback::Halt =
+                callback(llm::InferenceResponse::InferredToken(token.clone()))?
+            {
+                halted = true;
+                break;
+            }
+        }
+
+        // If the whole output fit within the limit, the "model" finished on its own
+        if !halted && tokens.len() <= limit {
+            callback(llm::InferenceResponse::EotToken)?;
+        }
+
+        Ok(llm::InferenceStats {
+            feed_prompt_duration,
+            prompt_tokens,
+            predict_duration: predict_start.elapsed(),
+            predict_tokens,
+        })
+    }
+}
+
+// The mock model needs no session of its own, so it generates by itself
+impl generation::Inference for &MockModel {
+    fn context_size(&self) -> usize {
+        MockModel::context_size(self)
+    }
+
+    fn infer(
+        &mut self,
+        request: &generation::Request,
+        rng: &mut rand::rngs::StdRng,
+        callback: &mut generation::TokenCallback<'_>,
+    ) -> Result<llm::InferenceStats, InferenceError> {
+        MockModel::infer(
+            self,
+            &request.prompt,
+            request.maximum_token_count,
+            rng,
+            callback,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    // function to make a fast mock model in the given mode
+    fn model(mode: MockMode, script: &[&str]) -> MockModel {
+        MockModel {
+            mode,
+            script: script.iter().map(|t| t.to_string()).collect(),
+            token_delay: Duration::ZERO,
+            max_tokens: 5,
+            context_size: 2048,
+        }
+    }
+
+    // function to run the mock, collecting the text of every token it generates. The callback
+    // can stop it, as the generation thread's does
+    fn run(
+        model: &MockModel,
+        maximum_token_count: Option<usize>,
+        seed: u64,
+        mut stop: impl FnMut(&str) -> Result<llm::InferenceFeedback, InferenceError>,
+    ) -> (
+        Vec<String>,
+        bool,
+        Result<llm::InferenceStats, InferenceError>,
+    ) {
+        let mut tokens = vec![];
+        let mut ended = false;
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let result = model.infer("Say hi to me", maximum_token_count, &mut rng, |response| {
+            match response {
+                llm::InferenceResponse::InferredToken(t) => {
+                    tokens.push(t.clone());
+                    return stop(&t);
+                }
+                llm::InferenceResponse::EotToken => ended = true,
+                _ => {}
+            }
+            Ok(llm::InferenceFeedback::Continue)
+        });
+        (tokens, ended, result)
+    }
+
+    // function to let the mock run to the end
+    fn go_on(_: &str) -> Result<llm::InferenceFeedback, InferenceError> {
+        Ok(llm::InferenceFeedback::Continue)
+    }
+
+    #[test]
+    fn each_mode_generates_what_it_should() {
+        let (tokens, ended, _) = run(&model(MockMode::Echo, &[]), None, 0, go_on);
+        assert_eq!(tokens, ["Say ", "hi ", "to ", "me"]);
+        assert!(ended);
+
+        let (tokens, ended, _) = run(&model(MockMode::Script, &["a", "b\n"]), None, 0, go_on);
+        assert_eq!(tokens, ["a", "b\n"]);
+        assert!(ended);
+
+        // Lorem output is the same for the same seed
+        let lorem = model(MockMode::Lorem, &[]);
+        let (first, _, _) = run(&lorem, None, 42, go_on);
+        let (second, _, _) = run(&lorem, None, 42, go_on);
+        assert_eq!(first.len(), 5);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn output_cut_off_at_the_token_limit_has_no_end() {
+        let (tokens, ended, result) = run(&model(MockMode::Echo, &[]), Some(2), 0, go_on);
+        assert_eq!(tokens, ["Say ", "hi "]);
+        assert!(!ended);
+        assert_eq!(result.unwrap().predict_tokens, 2);
+    }
+
+    #[test]
+    fn cancelling_mid_stream_stops_the_output() {
+        let script = ["one", "two", "three", "four"];
+        let (tokens, ended, result) = run(&model(MockMode::Script, &script), None, 0, |t| {
+            if t == "two" {
+                Err(InferenceError::Cancelled)
+            } else {
+                Ok(llm::InferenceFeedback::Continue)
+            }
+        });
+        assert_eq!(tokens, ["one", "two"]);
+        assert!(!ended);
+        assert!(matches!(result, Err(InferenceError::Cancelled)));
+
+        // Halting keeps what was generated, without the end of the text
+        let (tokens, ended, result) = run(&model(MockMode::Script, &script), None, 0, |t| {
+            Ok(if t == "three" {
+                llm::InferenceFeedback::Halt
+            } else {
+                llm::InferenceFeedback::Continue
+            })
+        });
+        assert_eq!(tokens.len(), 3);
+        assert!(!ended);
+        assert_eq!(result.unwrap().predict_tokens, 3);
+    }
+}
@@ -0,0 +1,103 @@
+// This file holds the check that stops a generation as soon as its output says something it
+// shouldn't (`moderation.halt_on_output`). Responses stream into Discord as they're generated,
+// so filtering them once they're finished would be too late. The generation thread passes each
+// generated token through a `Watch`, which looks at the end of the output so far, so that a
+// phrase split over several tokens is still caught.
+use regex::Regex;
+
+use crate::config;
+
+// How much of the end of the output is checked, in characters. Phrases longer than this
+// aren't caught
+const TAIL_CHARS: usize = 512;
+
+// The phrases that stop a generation. Cheap to share; every request is checked against the
+// same ones
+#[derive(Clone, Default)]
+pub struct OutputFilter {
+    patterns: Vec<Regex>,
+}
+
+impl OutputFilter {
+    // function to build the filter from the config. The config has already been validated,
+    // so its patterns compile
+    pub fn new(moderation: &config::Moderation) -> anyhow::Result<Self> {
+        Ok(Self {
+            patterns: moderation.halt_patterns()?,
+        })
+    }
+
+    // function to start watching a new output
+    pub fn watch(&self) -> Watch<'_> {
+        Watch {
+            filter: self,
+            tail: String::new(),
+        }
+    }
+}
+
+// The end of an output as it's generated, checked against the filter after every token
+pub struct Watch<'a> {
+    filter: &'a OutputFilter,
+    tail: String,
+}
+
+impl Watch<'_> {
+    // function to add a token to the output, returning whether the output now matches
+    pub fn push(&mut self, token: &str) -> bool {
+        if self.filter.patterns.is_empty() {
+            return false;
+        }
+
+        self.tail.push_str(token);
+        let matched = self.filter.patterns.iter().any(|p| p.is_match(&self.tail));
+
+        // Only the end is kept; anything that matched before it already stopped the output
+        let excess = self.tail.chars().count().saturating_sub(TAIL_CHARS);
+        if excess > 0 {
+            let start = self.tail.char_indices().nth(excess).map_or(0, |(i, _)| i);
+            self.tail.drain(..start);
+        }
+
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(phrases: &[&str]) -> OutputFilter {
+        OutputFilter::new(&config::Moderation {
+            halt_on_output: phrases.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn phrase_split_across_tokens() {
+        let filter = filter(&["forbidden phrase"]);
+        let mut watch = filter.watch();
+        assert!(!watch.push("This is a forb"));
+        assert!(!watch.push("idden phr"));
+        assert!(watch.push("ASE, isn't it"));
+    }
+
+    #[test]
+    fn match_in_the_first_token() {
+        let filter = filter(&["/sec(ret|urity) code/"]);
+        let mut watch = filter.watch();
+        assert!(watch.push("secret code"));
+    }
+
+    #[test]
+    fn tail_is_bounded() {
+        let filter = filter(&["needle"]);
+        let mut watch = filter.watch();
+        assert!(!watch.push(&"é".repeat(TAIL_CHARS * 2)));
+        assert_eq!(watch.tail.chars().count(), TAIL_CHARS);
+        assert!(!watch.push("nee"));
+        assert!(watch.push("dle"));
+    }
+}
@@ -0,0 +1,72 @@
+// This file holds the notices shown when a command is refused before anything is generated
+// (the user is on cooldown, lacks a permission, ...). They're only ever shown to the user
+// who was refused, so that limits don't fill channels or call anyone out, and they all say
+// what went wrong and when or where the user can try again, in the same way.
+// They never create the message a response streams into.
+use std::time::Duration;
+
+use serenity::http::Http;
+
+use crate::util::DiscordInteraction;
+
+// How long a notice that had to go in a public deferred response stays up before it's deleted
+const DEFERRED_NOTICE_LIFETIME: Duration = Duration::from_secs(15);
+
+// Why a command was refused. These are returned as errors, and shown through `send` by
+// `util::run_and_report_error`
+#[derive(Debug, thiserror::Error)]
+pub enum Rejection {
+    // The user used the command successfully a moment ago
+    #[error(
+        "You used `/{command}` a moment ago. You can use it again in {} seconds.",
+        seconds(*.retry_in)
+    )]
+    Cooldown { command: String, retry_in: Duration },
+    // The member lacks the permissions the command requires
+    #[error(
+        "You need the {permissions} permission to use `/{command}`. \
+         You can use it in a server where you have it, or ask an admin for it."
+    )]
+    MissingPermission {
+        command: String,
+        permissions: String,
+    },
+    // The user sent the same prompt again while the first is still being answered
+    #[error(
+        "You just submitted this prompt; your previous request is still running. \
+         You can send it again once that one has finished."
+    )]
+    DuplicatePrompt,
+    // The prompt doesn't fit the command's limit
+    #[error(
+        "The prompt is {length} characters long with the command's template, \
+         but at most {max} are allowed. Please shorten it and try again."
+    )]
+    PromptTooLong { length: usize, max: usize },
+}
+
+// function to round a wait up to whole seconds, so that it's never shown as 0
+fn seconds(duration: Duration) -> u64 {
+    duration.as_secs_f32().ceil().max(1.0) as u64
+}
+
+// function to show the user why their command was refused.
+// The notice is the ephemeral response to the interaction. If the interaction was already
+// deferred, its response is public, so the notice goes in it but only for a short while
+pub async fn send(interaction: &dyn DiscordInteraction, http: &Http, rejection: &Rejection) {
+    let text = rejection.to_string();
+    if interaction.create_ephemeral(http, &text).await.is_ok() {
+        return;
+    }
+
+    let result = async {
+        interaction.edit(http, &text).await?;
+        tokio::time::sleep(DEFERRED_NOTICE_LIFETIME).await;
+        interaction.delete(http).await
+    }
+    .await;
+    if let Err(err) = result {
+        // There's nowhere left to show the notice, so log it instead
+        warn!("Failed to show the notice `{text}` to the user: {err:?}");
+    }
+}
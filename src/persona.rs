@@ -0,0 +1,190 @@
+// This file holds the channels' personas, and the `/persona` command that switches between
+// the ones in the config. A channel's persona replaces the guild's system prompt for commands
+// that use one, and can change how their tokens are sampled. The chosen personas are kept in
+// the store, so that they survive restarts when persistence is on.
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::Context as AnyhowContext;
+use serenity::{
+    builder::CreateApplicationCommand,
+    http::Http,
+    model::prelude::{
+        command::CommandOptionType,
+        interaction::{
+            application_command::ApplicationCommandInteraction,
+            autocomplete::AutocompleteInteraction, InteractionResponseType,
+        },
+    },
+};
+
+use crate::{
+    config::{self, Configuration},
+    constant, store, util,
+};
+
+// The most choices Discord shows for an autocompleted option
+const MAX_AUTOCOMPLETE_CHOICES: usize = 25;
+
+// The personas chosen with `/persona`, by channel
+pub struct ChannelPersonas {
+    // The name of each channel's persona
+    channels: Mutex<HashMap<u64, String>>,
+    // The store that choices are persisted to
+    store: store::Store,
+}
+
+impl ChannelPersonas {
+    // function to load the choices that were persisted by earlier runs, if any.
+    // If they can't be read, every channel starts without a persona
+    pub fn load(store: store::Store) -> Self {
+        let channels = store.load_personas().unwrap_or_else(|err| {
+            warn!("Failed to load stored personas: {err:?}");
+            vec![]
+        });
+
+        Self {
+            channels: Mutex::new(channels.into_iter().collect()),
+            store,
+        }
+    }
+
+    // function to find a channel's persona, with its name. Personas that have since been
+    // removed from the config are ignored
+    pub fn active<'a>(
+        &self,
+        config: &'a Configuration,
+        channel_id: u64,
+    ) -> Option<(&'a str, &'a config::Persona)> {
+        let name = self.channels.lock().unwrap().get(&channel_id).cloned()?;
+        config
+            .personas
+            .get_key_value(&name)
+            .map(|(name, persona)| (name.as_str(), persona))
+    }
+
+    // function to set (or, with `None`, clear) a channel's persona
+    fn set(&self, channel_id: u64, persona: Option<String>) {
+        self.store.save_persona(channel_id, persona.clone());
+
+        let mut channels = self.channels.lock().unwrap();
+        match persona {
+            Some(persona) => channels.insert(channel_id, persona),
+            None => channels.remove(&channel_id),
+        };
+    }
+}
+
+// function to handle `/persona`, which sets, shows and clears the channel's persona
+pub async fn persona_command(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    config: &Configuration,
+    personas: &ChannelPersonas,
+) -> anyhow::Result<()> {
+    use constant::value as v;
+
+    let channel_id = cmd.channel_id.0;
+    let subcommand = cmd
+        .data
+        .options
+        .first()
+        .context("no subcommand specified")?;
+    let content = match subcommand.name.as_str() {
+        v::SET => {
+            let name = util::get_value(&subcommand.options, v::NAME)
+                .and_then(util::value_to_string)
+                .context("no persona specified")?;
+            if !config.personas.contains_key(&name) {
+                return Err(util::user_error(format!(
+                    "There is no persona named `{name}`."
+                )));
+            }
+            personas.set(channel_id, Some(name.clone()));
+            format!("This channel's persona is now **{name}**.")
+        }
+        v::SHOW => match personas.active(config, channel_id) {
+            Some((name, persona)) => format!(
+                "This channel's persona is **{name}**:\n>>> {}",
+                persona.system_prompt
+            ),
+            None => "This channel has no persona.".to_string(),
+        },
+        v::CLEAR => {
+            personas.set(channel_id, None);
+            "This channel no longer has a persona.".to_string()
+        }
+        other => anyhow::bail!("unknown subcommand `{other}`"),
+    };
+
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| {
+                d.content(content)
+                    .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse())
+            })
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to suggest the configured personas whose names contain what's been typed so far
+pub async fn autocomplete(
+    ac: &AutocompleteInteraction,
+    http: &Http,
+    config: &Configuration,
+) -> anyhow::Result<()> {
+    let typed = util::focused_value(&ac.data.options)
+        .unwrap_or_default()
+        .to_lowercase();
+
+    let mut names: Vec<_> = config
+        .personas
+        .keys()
+        .filter(|name| name.to_lowercase().contains(&typed))
+        .collect();
+    names.sort();
+
+    ac.create_autocomplete_response(http, |r| {
+        for name in names.into_iter().take(MAX_AUTOCOMPLETE_CHOICES) {
+            r.add_string_choice(name, name);
+        }
+        r
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to build the `/persona` command, for registering with Discord
+pub fn command() -> CreateApplicationCommand {
+    use constant::value as v;
+
+    let mut persona = CreateApplicationCommand::default();
+    persona
+        .name(constant::command::PERSONA)
+        .description("Chooses the persona the bot uses in this channel.")
+        .create_option(|sub| {
+            sub.name(v::SET)
+                .description("Sets this channel's persona.")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|opt| {
+                    opt.name(v::NAME)
+                        .description("The persona.")
+                        .kind(CommandOptionType::String)
+                        .set_autocomplete(true)
+                        .required(true)
+                })
+        })
+        .create_option(|sub| {
+            sub.name(v::SHOW)
+                .description("Shows this channel's persona.")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|sub| {
+            sub.name(v::CLEAR)
+                .description("Clears this channel's persona.")
+                .kind(CommandOptionType::SubCommand)
+        });
+    persona
+}
@@ -0,0 +1,327 @@
+// This file holds the post-processing of a response: the command's find-and-replace rules,
+// which are applied while it streams and once more when it's finished, and the trimming of
+// the finished response before it's shown for the last time (and exported). It's configured
+// per command, and only changes the generated output, never the prompt in front of it.
+// `StopFilter` applies stop sequences to output as it streams, for the HTTP API.
+// `strip_thinking` takes out the `<think>...</think>` blocks of chain-of-thought models.
+use std::borrow::Cow;
+
+use crate::config;
+
+// The characters that end a sentence
+const SENTENCE_ENDINGS: &[char] = &['.', '!', '?', '…', '。', '！', '？'];
+
+// The characters that can follow the end of a sentence and still belong to it
+const SENTENCE_CLOSERS: &[char] = &['"', '\'', ')', ']', '”', '’', '」', '』', '）'];
+
+// The tags chain-of-thought models put around their thinking
+const THINK_START: &str = "<think>";
+const THINK_END: &str = "</think>";
+
+// How a command shows the thinking chain-of-thought models write before their answer
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Thinking {
+    // As it's written, tags and all
+    #[default]
+    Show,
+    // Not at all
+    Strip,
+    // Behind a spoiler in front of the answer
+    Spoiler,
+}
+
+impl Thinking {
+    // function to find how a command shows thinking
+    pub fn of(command: &config::Command) -> Self {
+        match (
+            command.strip_thinking_tags,
+            command.show_thinking_as_spoiler,
+        ) {
+            (false, _) => Self::Show,
+            (true, false) => Self::Strip,
+            (true, true) => Self::Spoiler,
+        }
+    }
+}
+
+// function to take the thinking out of a response, or put it behind a spoiler. It's applied
+// to everything generated so far every time the response is shown, so thinking that hasn't
+// finished yet is held back (or shown in its spoiler so far), and so is the start of a
+// `<think>` tag that the response ends partway through. Some models' templates open the block
+// in the prompt, so a `</think>` with no `<think>` before it ends thinking that started with
+// the response
+pub fn strip_thinking(response: &str, thinking: Thinking) -> Cow<'_, str> {
+    if thinking == Thinking::Show {
+        return Cow::Borrowed(response);
+    }
+
+    let mut thoughts = vec![];
+    let mut rest = response;
+    if let Some(end) = rest.find(THINK_END) {
+        if !rest[..end].contains(THINK_START) {
+            thoughts.push(&rest[..end]);
+            rest = &rest[end + THINK_END.len()..];
+        }
+    }
+    let mut answer = String::new();
+    while let Some(start) = rest.find(THINK_START) {
+        answer.push_str(&rest[..start]);
+        let inside = &rest[start + THINK_START.len()..];
+        match inside.find(THINK_END) {
+            Some(end) => {
+                thoughts.push(&inside[..end]);
+                rest = &inside[end + THINK_END.len()..];
+            }
+            // Still thinking
+            None => {
+                thoughts.push(inside);
+                rest = "";
+            }
+        }
+    }
+    let partial = partial_stop_sequence(rest, &[THINK_START.to_string()]);
+    answer.push_str(&rest[..partial.unwrap_or(rest.len())]);
+
+    if thoughts.is_empty() {
+        return Cow::Owned(answer);
+    }
+    // The answer usually starts on a new line after the thinking
+    let answer = answer.trim_start();
+    let thoughts = thoughts.join("\n\n");
+    let thoughts = thoughts.trim();
+    Cow::Owned(match thinking {
+        Thinking::Spoiler if !thoughts.is_empty() => {
+            // A `||` in the thinking would end the spoiler early
+            format!("||{}||\n\n{answer}", thoughts.replace("||", "|\u{200b}|"))
+        }
+        _ => answer.to_string(),
+    })
+}
+
+// function to apply find-and-replace rules to a response, in order
+pub fn replace<'a>(response: &'a str, replacements: &[config::OutputReplacement]) -> Cow<'a, str> {
+    let mut response = Cow::Borrowed(response);
+    for r in replacements {
+        if let Cow::Owned(replaced) = r.pattern.replace_all(&response, r.replacement.as_str()) {
+            response = Cow::Owned(replaced);
+        }
+    }
+    response
+}
+
+// function to trim a finished response as the command's settings ask.
+// `hit_length_limit` is whether the generation stopped because it ran out of tokens,
+// rather than because the model ended it
+pub fn trim(response: &str, settings: &config::OutputTrim, hit_length_limit: bool) -> String {
+    let mut response = strip_stop_sequences(response, &settings.stop_sequences);
+    if settings.to_sentence_end && hit_length_limit {
+        response = trim_to_sentence_end(response);
+    }
+    response.trim_end().to_string()
+}
+
+// function to cut the response at the first stop sequence in it, or, if there isn't one,
+// at the start of a stop sequence that the response ends partway through
+fn strip_stop_sequences<'a>(response: &'a str, stop_sequences: &[String]) -> &'a str {
+    let end = first_stop_sequence(response, stop_sequences)
+        .or_else(|| partial_stop_sequence(response, stop_sequences))
+        .unwrap_or(response.len());
+    &response[..end]
+}
+
+// The position of the first stop sequence in the text, if there is one
+fn first_stop_sequence(text: &str, stop_sequences: &[String]) -> Option<usize> {
+    stop_sequences
+        .iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| text.find(s.as_str()))
+        .min()
+}
+
+// The position of a stop sequence that the text ends partway through, if it does.
+// The longest partial match wins, so that all of it is covered
+fn partial_stop_sequence(text: &str, stop_sequences: &[String]) -> Option<usize> {
+    stop_sequences
+        .iter()
+        .filter_map(|s| {
+            s.char_indices()
+                .skip(1)
+                .map(|(i, _)| &s[..i])
+                .filter(|prefix| text.ends_with(prefix))
+                .last()
+                .map(|prefix| text.len() - prefix.len())
+        })
+        .min()
+}
+
+// A filter for output that's passed on as it streams, which ends it at the first stop
+// sequence. Text that could be the start of a stop sequence is held back until it's
+// clear whether it is one
+pub struct StopFilter {
+    stop_sequences: Vec<String>,
+    // The text that's been held back
+    pending: String,
+    // Whether or not a stop sequence has been reached
+    stopped: bool,
+}
+
+impl StopFilter {
+    pub fn new(stop_sequences: Vec<String>) -> Self {
+        Self {
+            stop_sequences,
+            pending: String::new(),
+            stopped: false,
+        }
+    }
+
+    // function to add the next piece of output, returning the text that can be passed on.
+    // Nothing is passed on once a stop sequence has been reached
+    pub fn push(&mut self, text: &str) -> String {
+        if self.stopped {
+            return String::new();
+        }
+        self.pending.push_str(text);
+
+        if let Some(index) = first_stop_sequence(&self.pending, &self.stop_sequences) {
+            self.stopped = true;
+            self.pending.truncate(index);
+            return std::mem::take(&mut self.pending);
+        }
+
+        let held = partial_stop_sequence(&self.pending, &self.stop_sequences)
+            .unwrap_or(self.pending.len());
+        let rest = self.pending.split_off(held);
+        std::mem::replace(&mut self.pending, rest)
+    }
+
+    // Whether or not a stop sequence has been reached
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    // function to end the output, returning whatever was held back
+    pub fn finish(self) -> String {
+        self.pending
+    }
+}
+
+// function to cut the response after its last complete sentence.
+// A response without a complete sentence is left as it is, rather than emptied
+fn trim_to_sentence_end(response: &str) -> &str {
+    let Some((index, ending)) = response
+        .char_indices()
+        .rev()
+        .find(|(_, c)| SENTENCE_ENDINGS.contains(c))
+    else {
+        return response;
+    };
+
+    // Keep any closing quotes or brackets that finish the sentence
+    let mut end = index + ending.len_utf8();
+    for c in response[end..].chars() {
+        if !SENTENCE_CLOSERS.contains(&c) {
+            break;
+        }
+        end += c.len_utf8();
+    }
+    &response[..end]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thinking_is_held_back_while_it_streams_and_stripped_after() {
+        let strip = |response| strip_thinking(response, Thinking::Strip).into_owned();
+        assert_eq!(strip("<thi"), "");
+        assert_eq!(strip("<think>Let me see"), "");
+        assert_eq!(strip("<think>Let me see.</think>\n\nIt's 4."), "It's 4.");
+        // The template opened the block in the prompt
+        assert_eq!(strip("Let me see.</think>\n\nIt's 4."), "It's 4.");
+        assert_eq!(strip("No thinking here"), "No thinking here");
+
+        let spoiler = strip_thinking("<think>Let me see.</think>\nIt's 4.", Thinking::Spoiler);
+        assert_eq!(spoiler, "||Let me see.||\n\nIt's 4.");
+        assert_eq!(
+            strip_thinking("<think>x</think>y", Thinking::Show),
+            "<think>x</think>y"
+        );
+    }
+
+    // function to make the trim settings for a test
+    fn settings(stop_sequences: &[&str], to_sentence_end: bool) -> config::OutputTrim {
+        config::OutputTrim {
+            stop_sequences: stop_sequences.iter().map(|s| s.to_string()).collect(),
+            to_sentence_end,
+        }
+    }
+
+    #[test]
+    fn output_is_cut_at_the_first_stop_sequence() {
+        let settings = settings(&["###", "User:"], false);
+        assert_eq!(trim("Hi there.\nUser: more", &settings, false), "Hi there.");
+        assert_eq!(trim("A ### B User: C", &settings, false), "A");
+        // Output that ends partway through a stop sequence loses the start of it too
+        assert_eq!(trim("Hi there. Us", &settings, false), "Hi there.");
+        assert_eq!(trim("Hi there. #", &settings, false), "Hi there.");
+        // But text that only looks like one partway through is kept
+        assert_eq!(
+            trim("Use # for headers", &settings, false),
+            "Use # for headers"
+        );
+    }
+
+    #[test]
+    fn multibyte_text_is_cut_between_characters() {
+        let settings = settings(&["—終わり"], false);
+        assert_eq!(trim("café ☕—終わり", &settings, false), "café ☕");
+        // A stop sequence the output ends partway through, after multibyte text
+        assert_eq!(trim("naïve —終", &settings, false), "naïve");
+        assert_eq!(trim("über", &settings, false), "über");
+    }
+
+    #[test]
+    fn output_that_ran_out_of_tokens_is_cut_to_its_last_sentence() {
+        let settings = settings(&[], true);
+        assert_eq!(
+            trim("It's sunny. Tomorrow will be", &settings, true),
+            "It's sunny."
+        );
+        // Closing quotes and brackets belong to the sentence they end
+        assert_eq!(
+            trim("He said \"stop.\" Then he", &settings, true),
+            "He said \"stop.\""
+        );
+        // Output that the model ended itself is left whole
+        assert_eq!(
+            trim("It's sunny. Tomorrow will be", &settings, false),
+            "It's sunny. Tomorrow will be"
+        );
+    }
+
+    #[test]
+    fn cjk_punctuation_ends_sentences() {
+        let settings = settings(&[], true);
+        assert_eq!(
+            trim("今日は晴れです。明日は", &settings, true),
+            "今日は晴れです。"
+        );
+        assert_eq!(
+            trim("本当？ 「はい！」 それで", &settings, true),
+            "本当？ 「はい！」"
+        );
+        assert_eq!(trim("好的！我们", &settings, true), "好的！");
+    }
+
+    #[test]
+    fn output_without_a_complete_sentence_is_kept() {
+        let settings = settings(&[], true);
+        assert_eq!(
+            trim("a list of things like", &settings, true),
+            "a list of things like"
+        );
+        assert_eq!(trim("まだ終わらない  ", &settings, true), "まだ終わらない");
+        assert_eq!(trim("", &settings, true), "");
+    }
+}
@@ -0,0 +1,79 @@
+// This file holds the presence updater.
+// The bot's status shows whether it's busy (and how many requests are waiting) so that
+// users know whether to expect a wait. A background task watches the shared readiness state
+// and updates every shard's presence when the status text changes, no more often than
+// Discord's gateway limits comfortably allow.
+use std::{
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serenity::{
+    client::{bridge::gateway::ShardManager, Context},
+    model::gateway::Activity,
+};
+
+use crate::{config, generation, health, schedule};
+
+// How often the state is checked for changes
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+// The shortest time between two presence updates
+const MIN_UPDATE_INTERVAL: Duration = Duration::from_secs(15);
+
+// The status text that was last set, shared with the event handler so that it can
+// restore the presence on shards that have reconnected from scratch
+#[derive(Default)]
+pub struct CurrentPresence(Mutex<Option<String>>);
+
+impl CurrentPresence {
+    // function to set the current status on a shard that has just (re)connected
+    pub async fn restore(&self, ctx: &Context) {
+        let text = self.0.lock().unwrap().clone();
+        if let Some(text) = text {
+            ctx.set_activity(Activity::playing(text)).await;
+        }
+    }
+}
+
+// function to keep the presence up to date, until the bot starts shutting down
+pub async fn run(
+    settings: config::Presence,
+    readiness: Arc<health::Readiness>,
+    active_requests: generation::ActiveRequests,
+    request_tx: flume::Sender<generation::Request>,
+    shard_manager: Arc<tokio::sync::Mutex<ShardManager>>,
+    current: Arc<CurrentPresence>,
+) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut last_update: Option<Instant> = None;
+
+    while !readiness.shutting_down.load(Ordering::SeqCst) {
+        interval.tick().await;
+
+        // Skip the update if the last one was too recent; it'll be picked up on a later tick
+        if last_update.is_some_and(|t| t.elapsed() < MIN_UPDATE_INTERVAL) {
+            continue;
+        }
+
+        let text = settings.render(
+            active_requests.count(),
+            schedule::queue_depth(&readiness, &request_tx),
+            readiness.model_unloaded.load(Ordering::SeqCst),
+        );
+        if current.0.lock().unwrap().as_deref() == Some(text.as_str()) {
+            continue; // Nothing has changed
+        }
+
+        // Set the presence on every shard that is running
+        let shard_manager = shard_manager.lock().await;
+        for runner in shard_manager.runners.lock().await.values() {
+            runner
+                .runner_tx
+                .set_activity(Some(Activity::playing(text.clone())));
+        }
+
+        *current.0.lock().unwrap() = Some(text);
+        last_update = Some(Instant::now());
+    }
+}
@@ -0,0 +1,156 @@
+// This file holds the persistent prompt cache. When it's turned on, every completed
+// generation is remembered in the store under a hash of everything that decides its output
+// (the prompt, the sampling settings, the seed if one was asked for and the token limit).
+// A later request with the same hash gets the remembered output, sent in pieces with a short
+// pause between them so that it comes in the way a live generation would, without the model
+// running at all. Remembered outputs expire after `cache_ttl_hours`.
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::{
+    config,
+    generation::{
+        self, Cancellations, Completion, GenerationMetadata, InferenceError, StopReason, Token,
+    },
+    schedule, store,
+};
+
+// The prompt cache, on top of the store
+pub struct PromptCache {
+    store: store::Store,
+    ttl: Duration,
+    replay_delay: Duration,
+}
+
+impl PromptCache {
+    // function to set up the cache described by the configuration, if it's turned on.
+    // Without a database on disk there's nowhere to keep it, so it stays off
+    pub fn new(inference: &config::Inference, store: store::Store) -> Option<Self> {
+        if !inference.enable_persistent_cache {
+            return None;
+        }
+        if !store.is_persistent() {
+            warn!("The persistent cache needs [persistence] to be enabled; it's turned off");
+            return None;
+        }
+
+        Some(Self {
+            store,
+            ttl: Duration::from_secs(inference.cache_ttl_hours * 60 * 60),
+            replay_delay: Duration::from_millis(inference.cache_replay_delay_ms),
+        })
+    }
+
+    // function to find the remembered output of a request, if there is one that hasn't expired
+    pub fn lookup(&self, request: &generation::Request) -> Option<store::CachedGeneration> {
+        let key = key(request)?;
+        let created_after = store::now().saturating_sub(self.ttl.as_secs());
+        match self.store.load_cached_generation(&key, created_after) {
+            Ok(cached) => cached,
+            Err(err) => {
+                warn!("Failed to read the prompt cache: {err:?}");
+                None
+            }
+        }
+    }
+
+    // function to remember the output of a completed request
+    pub fn save(&self, request: &generation::Request, completion: &Completion) {
+        let Some(key) = key(request) else {
+            return;
+        };
+        // A loop is bad luck in sampling, which shouldn't be handed out again, and output cut
+        // off by the time limit says more about how busy the machine was than the prompt
+        if matches!(
+            completion.stop_reason,
+            StopReason::RepetitionLoop | StopReason::TimeLimit
+        ) {
+            return;
+        }
+
+        self.store.save_cached_generation(
+            store::CachedGeneration {
+                key,
+                text: completion.text.clone(),
+                seed: completion.seed,
+                generated_tokens: completion.stats.predict_tokens,
+                hit_token_limit: completion.stop_reason == StopReason::TokenLimit,
+            },
+            store::now().saturating_sub(self.ttl.as_secs()),
+        );
+    }
+
+    // function to send a remembered output to the requester as if it were being generated,
+    // a word at a time. It can be cancelled like a live generation
+    pub fn replay(
+        &self,
+        request: &generation::Request,
+        cached: store::CachedGeneration,
+        cancellations: &Cancellations,
+        board: &schedule::Board,
+    ) -> Result<Completion, InferenceError> {
+        let timer = std::time::Instant::now();
+        let send = |token: Token| {
+            request
+                .token_tx
+                .send(token)
+                .map_err(|_| InferenceError::custom("Failed to send token to channel."))
+        };
+
+        if request.echo_prompt {
+            send(Token::Token(request.prompt.clone()))?;
+        }
+        for piece in cached.text.split_inclusive(' ') {
+            if cancellations.is_cancelled(request.message_id) {
+                return Err(InferenceError::Cancelled);
+            }
+
+            send(Token::Token(piece.to_string()))?;
+            board.count_token();
+            std::thread::sleep(self.replay_delay);
+        }
+
+        let duration = timer.elapsed();
+        send(Token::Metadata(GenerationMetadata {
+            tokens_generated: cached.generated_tokens,
+            prompt_tokens: 0,
+            duration_ms: duration.as_millis() as u64,
+            tokens_per_second: cached.generated_tokens as f32 / duration.as_secs_f32().max(0.001),
+            seed: cached.seed,
+        }))?;
+
+        Ok(Completion {
+            stop_reason: if cached.hit_token_limit {
+                StopReason::TokenLimit
+            } else {
+                StopReason::EndOfText
+            },
+            stats: llm::InferenceStats {
+                feed_prompt_duration: Duration::ZERO,
+                prompt_tokens: 0,
+                predict_duration: duration,
+                predict_tokens: cached.generated_tokens,
+            },
+            text: cached.text,
+            seed: cached.seed,
+        })
+    }
+}
+
+// function to work out the key a request's output is remembered under, from everything that
+// decides it. Requests for several alternatives aren't cached, since a single text can't tell
+// them apart
+fn key(request: &generation::Request) -> Option<String> {
+    if request.n_sequences > 1 {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    hasher.update(request.prompt.len().to_le_bytes());
+    hasher.update(&request.prompt);
+    hasher.update(serde_json::to_string(&request.sampling).ok()?);
+    hasher.update(format!("{:?}", request.seed));
+    hasher.update(format!("{:?}", request.maximum_token_count));
+    Some(format!("{:x}", hasher.finalize()))
+}
@@ -0,0 +1,125 @@
+// This file holds the prompts used for one generation, and the formatting of its output.
+// It has no dependencies on the rest of the bot, so that the fuzz targets in `fuzz/`
+// can include it directly.
+
+// Definition of the Prompts struct
+#[derive(Clone)]
+pub struct Prompts {
+    pub show_prompt_template: bool,
+    pub processed: String,
+    pub user: String,
+    // The rendered template before and after the user's prompt
+    pub prefix: String,
+    pub suffix: String,
+}
+
+// Implementation of methods for the Prompts struct
+impl Prompts {
+    // Method to create a markdown message, incorporating user prompt and processed output
+    pub fn make_markdown_message(&self, message: &str) -> String {
+        // Determine whether to display the prompt template or the user's actual prompt
+        let (message, display_prompt) = if !self.show_prompt_template {
+            (self.decouple_prompt_from_message(message), &self.user)
+        } else {
+            (message.to_string(), &self.processed)
+        };
+
+        // Format the message with appropriate markdown styling
+        match message.strip_prefix(display_prompt) {
+            Some(msg) => format!("**{display_prompt}**{msg}"),
+            None => match display_prompt.strip_prefix(&message) {
+                Some(ungenerated) => {
+                    if message.is_empty() {
+                        format!("~~{ungenerated}~~")
+                    } else {
+                        format!("**{message}**~~{ungenerated}~~")
+                    }
+                }
+                None => message.to_string(),
+            },
+        }
+    }
+
+    // Method to decouple the prompt from the generated output in a message
+    pub fn decouple_prompt_from_message(&self, output: &str) -> String {
+        // The template around the user's prompt
+        let (prefix, suffix) = (self.prefix.as_str(), self.suffix.as_str());
+
+        // Retrieve the user's prompt
+        let prompt = &self.user;
+
+        // Strip the prefix from the generated output
+        let message = if let Some(msg) = output.strip_prefix(prefix) {
+            msg
+        } else {
+            return String::new();
+        };
+
+        // Strip the user prompt from the remaining message
+        let response = if let Some(resp) = message.strip_prefix(prompt) {
+            resp
+        } else {
+            return message.to_string();
+        };
+
+        // Strip the suffix from the final response. Anything after it is the response,
+        // including any text the response was asked to start with
+        let response = if let Some(resp) = response.strip_prefix(suffix) {
+            resp
+        } else {
+            return prompt.to_string();
+        };
+
+        // Add a newline if the suffix ends with a newline character
+        let newline = if suffix.ends_with('\n') { "\n" } else { "" };
+
+        // Format the decoupled prompt and response
+        format!("{prompt}{newline}{response}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // function to make the prompts for a template that wraps the user's prompt
+    fn prompts(user: &str) -> Prompts {
+        Prompts {
+            show_prompt_template: false,
+            processed: format!("Q: {user}\nA:"),
+            user: user.to_string(),
+            prefix: "Q: ".to_string(),
+            suffix: "\nA:".to_string(),
+        }
+    }
+
+    #[test]
+    fn the_template_is_taken_out_of_the_message() {
+        let prompts = prompts("Hi");
+        assert_eq!(
+            prompts.make_markdown_message("Q: Hi\nA: Hello!"),
+            "**Hi** Hello!"
+        );
+        // While the prompt is still being echoed, the rest of it is struck through
+        assert_eq!(prompts.make_markdown_message("Q: H"), "**H**~~i~~");
+    }
+
+    #[test]
+    fn a_response_prefix_that_repeats_the_prompt_is_shown_in_full() {
+        // The response was asked to start with "Hi", the same as the prompt
+        let prompts = prompts("Hi");
+        assert_eq!(
+            prompts.decouple_prompt_from_message("Q: Hi\nA:Hi there"),
+            "HiHi there"
+        );
+        assert_eq!(
+            prompts.make_markdown_message("Q: Hi\nA:Hi there"),
+            "**Hi**Hi there"
+        );
+        // Only the prompt itself is taken out, not a prefix that's the prompt and more
+        assert_eq!(
+            prompts.make_markdown_message("Q: Hi\nA:Hi, Hi again"),
+            "**Hi**Hi, Hi again"
+        );
+    }
+}
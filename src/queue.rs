@@ -0,0 +1,228 @@
+// This file holds the `/queue` command, which lists what the bot is generating and what's
+// waiting, in the order it will run. Prompts are only shown to the user who sent them and to
+// the admins of the server they were sent from, who also get buttons to cancel them.
+use std::time::Duration;
+
+use serenity::{
+    builder::{CreateApplicationCommand, CreateComponents},
+    http::Http,
+    model::prelude::{
+        component::ButtonStyle,
+        interaction::{
+            application_command::ApplicationCommandInteraction,
+            message_component::MessageComponentInteraction, InteractionResponseType,
+        },
+        GuildId, Member, MessageId,
+    },
+};
+
+use crate::{constant, generation, schedule};
+
+// The prefix of the cancel buttons' custom IDs; the request's ID follows it
+pub const CANCEL_BUTTON_PREFIX: &str = "queue_cancel";
+
+// The most waiting requests listed; the rest are only counted
+const MAX_LISTED: usize = 15;
+
+// The most characters of a prompt shown, so that a full list fits in one message
+const MAX_PROMPT_CHARS: usize = 50;
+
+// The most buttons Discord puts in a row
+const BUTTONS_PER_ROW: usize = 5;
+
+// function to handle `/queue`. `unclaimed` is the number of requests the generation thread
+// hasn't taken yet, which aren't on the board
+pub async fn queue_command(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    board: &schedule::Board,
+    unclaimed: usize,
+) -> anyhow::Result<()> {
+    let snapshot = board.snapshot();
+    let admin_of = admin_guild(cmd.member.as_ref(), cmd.guild_id);
+    let user_id = cmd.user.id.0;
+
+    let can_see = |context: &generation::RequestContext| {
+        context.user_id == user_id || (admin_of.is_some() && context.guild_id == admin_of)
+    };
+    let can_cancel =
+        |context: &generation::RequestContext| admin_of.is_some() && context.guild_id == admin_of;
+
+    // The requests the caller can cancel, with the labels of their buttons
+    let mut cancellable = vec![];
+
+    let mut lines = vec![];
+    match &snapshot.running {
+        Some((item, tokens_generated)) => {
+            lines.push(format!(
+                "**Generating:** {} · {tokens_generated} tokens so far · running for {}",
+                describe(item, can_see(&item.context)),
+                format_duration(item.elapsed)
+            ));
+            if can_cancel(&item.context) {
+                cancellable.push(("Cancel current".to_string(), item.message_id));
+            }
+        }
+        None => lines.push("Nothing is being generated.".to_string()),
+    }
+
+    let waiting = snapshot.waiting.len() + unclaimed;
+    if waiting == 0 {
+        lines.push("Nothing is waiting.".to_string());
+    } else {
+        lines.push(format!("**Waiting ({waiting}):**"));
+        for (i, item) in snapshot.waiting.iter().take(MAX_LISTED).enumerate() {
+            lines.push(format!(
+                "{}. {} · waiting for {}",
+                i + 1,
+                describe(item, can_see(&item.context)),
+                format_duration(item.elapsed)
+            ));
+            if can_cancel(&item.context) {
+                cancellable.push((format!("Cancel #{}", i + 1), item.message_id));
+            }
+        }
+
+        // Requests the thread hasn't taken yet can't be listed, only counted
+        let unlisted = waiting - snapshot.waiting.len().min(MAX_LISTED);
+        if unlisted > 0 {
+            lines.push(format!("…and {unlisted} more"));
+        }
+    }
+
+    let content = lines.join("\n");
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| {
+                d.content(content)
+                    .ephemeral(true)
+                    .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse())
+                    .set_components(cancel_buttons(&cancellable))
+            })
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to handle a press of one of the cancel buttons, which only admins of the server the
+// request was sent from can use
+pub async fn cancel_button(
+    cmp: &MessageComponentInteraction,
+    http: &Http,
+    board: &schedule::Board,
+    cancellations: &generation::Cancellations,
+    message_id: MessageId,
+) -> anyhow::Result<()> {
+    let snapshot = board.snapshot();
+    let running = snapshot
+        .running
+        .as_ref()
+        .map(|(item, _)| item)
+        .filter(|item| item.message_id == message_id);
+    let item = running.or_else(|| {
+        snapshot
+            .waiting
+            .iter()
+            .find(|item| item.message_id == message_id)
+    });
+
+    let admin_of = admin_guild(cmp.member.as_ref(), cmp.guild_id);
+    let content = match item {
+        None => "That request isn't in the queue anymore.",
+        Some(item) if admin_of.is_none() || item.context.guild_id != admin_of => {
+            "Only the admins of the server the request was sent from can cancel it."
+        }
+        Some(item) => {
+            // A waiting request is dropped from the queue; a running one is stopped
+            if running.is_some() || !board.cancel(item.message_id) {
+                cancellations.cancel(item.message_id);
+            }
+            "The request has been cancelled."
+        }
+    };
+
+    cmp.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| d.content(content).ephemeral(true))
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to find the server the member is an admin of, if they are one
+fn admin_guild(member: Option<&Member>, guild_id: Option<GuildId>) -> Option<u64> {
+    let permissions = member.and_then(|m| m.permissions).unwrap_or_default();
+    (permissions.administrator() || permissions.manage_guild())
+        .then_some(guild_id)
+        .flatten()
+        .map(|id| id.0)
+}
+
+// function to describe a request: who sent it, with which command, and its prompt if the
+// caller can see it
+fn describe(item: &schedule::Item, show_prompt: bool) -> String {
+    let prompt = if show_prompt {
+        let excerpt: String = item
+            .prompt
+            .chars()
+            .map(|c| match c {
+                '\n' => ' ',
+                '`' => '\'',
+                c => c,
+            })
+            .take(MAX_PROMPT_CHARS)
+            .collect();
+        let ellipsis = if item.prompt.chars().count() > MAX_PROMPT_CHARS {
+            "…"
+        } else {
+            ""
+        };
+        format!("`{}{ellipsis}`", excerpt.trim())
+    } else {
+        "*(prompt hidden)*".to_string()
+    };
+
+    format!(
+        "<@{}> · /{} · {prompt}",
+        item.context.user_id, item.context.command_name
+    )
+}
+
+// function to write out how long something has taken, e.g. "45s" or "2m 10s"
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    match seconds {
+        0..=59 => format!("{seconds}s"),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+// function to lay out the cancel buttons, a row at a time
+fn cancel_buttons(cancellable: &[(String, MessageId)]) -> CreateComponents {
+    let mut components = CreateComponents::default();
+    for row in cancellable.chunks(BUTTONS_PER_ROW) {
+        components.create_action_row(|r| {
+            for (label, message_id) in row {
+                r.create_button(|b| {
+                    b.custom_id(format!("{CANCEL_BUTTON_PREFIX}#{message_id}"))
+                        .style(ButtonStyle::Danger)
+                        .label(label)
+                });
+            }
+            r
+        });
+    }
+    components
+}
+
+// function to build the `/queue` command, for registering with Discord
+pub fn command() -> CreateApplicationCommand {
+    let mut queue = CreateApplicationCommand::default();
+    queue
+        .name(constant::command::QUEUE)
+        .description("Lists the requests being generated and waiting.");
+    queue
+}
@@ -0,0 +1,210 @@
+// This file holds the daily posts configured under `[[schedules]]`, like a "prompt of the day".
+// A background task checks every minute for schedules whose time of day has come since it last
+// looked, generates from the command's template with the day's prompt (going through the
+// prompts in turn) and posts the result in the schedule's channel. They wait behind users'
+// requests. A post that came due while the bot was down is skipped, rather than all of them
+// being posted at once when it starts. The owner-only `/schedules` command lists them.
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serenity::{
+    builder::CreateApplicationCommand,
+    http::Http,
+    model::{
+        prelude::interaction::{
+            application_command::ApplicationCommandInteraction, InteractionResponseType,
+        },
+        Permissions,
+    },
+};
+
+use crate::{
+    bench,
+    config::{self, Configuration},
+    constant, generation, reminder, store, util,
+};
+
+// How often the background task checks for due schedules
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// The length of a day, in seconds
+const DAY: u64 = 24 * 60 * 60;
+
+// The configured schedules. They're replaced when the config is reloaded, so that they can be
+// changed without a restart. Cheap to clone; every clone shares the same list
+#[derive(Clone, Default)]
+pub struct Schedules(Arc<Mutex<Vec<config::Schedule>>>);
+
+impl Schedules {
+    // function to start with the schedules from the config
+    pub fn new(schedules: Vec<config::Schedule>) -> Self {
+        Self(Arc::new(Mutex::new(schedules)))
+    }
+
+    // function to replace the schedules, when the config is reloaded
+    pub fn set(&self, schedules: Vec<config::Schedule>) {
+        *self.0.lock().unwrap() = schedules;
+    }
+
+    // function to get a copy of the schedules
+    fn list(&self) -> Vec<config::Schedule> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+// function to work out when a schedule was last due at or before `now`, in seconds since the
+// Unix epoch
+fn last_due(time_of_day: u64, now: u64) -> u64 {
+    let today = now - now % DAY + time_of_day;
+    if today <= now {
+        today
+    } else {
+        today.saturating_sub(DAY)
+    }
+}
+
+// function to post the schedules as they come due, for as long as the bot runs
+pub async fn run(
+    schedules: Schedules,
+    config: Configuration,
+    http: Arc<Http>,
+    request_tx: flume::Sender<generation::Request>,
+) {
+    // Anything due before now was missed while the bot was down, and is skipped
+    let mut last_checked = store::now();
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+        let now = store::now();
+
+        for schedule in schedules.list() {
+            let Ok(time_of_day) = schedule.time_of_day() else {
+                continue;
+            };
+            let due = last_due(time_of_day, now);
+            if due <= last_checked {
+                continue;
+            }
+
+            // Each post waits for its own generation, so that they don't hold each other up
+            let (config, http, request_tx) = (config.clone(), http.clone(), request_tx.clone());
+            tokio::spawn(async move {
+                if let Err(err) = fire(&config, &http, request_tx, &schedule, due).await {
+                    error!("Failed to post the schedule `{}`: {err:?}", schedule.name);
+                }
+            });
+        }
+
+        last_checked = now;
+    }
+}
+
+// function to generate a schedule's post for the day it came due, and post it
+async fn fire(
+    config: &Configuration,
+    http: &Http,
+    request_tx: flume::Sender<generation::Request>,
+    schedule: &config::Schedule,
+    due: u64,
+) -> anyhow::Result<()> {
+    let command = config
+        .commands
+        .get(&schedule.command)
+        .filter(|c| c.enabled)
+        .ok_or_else(|| anyhow::anyhow!("there is no command named `{}`", schedule.command))?;
+
+    // The prompts take turns, a day each
+    let day = (due / DAY) as usize;
+    let user_prompt = schedule.prompts[day % schedule.prompts.len()].clone();
+
+    // Render the prompt exactly as the command itself would, with the bot-wide system prompt.
+    // There's no way to give the command's own options, so they take their defaults
+    let user_prompt = config.inference.preprocess_user_prompt(user_prompt);
+    let prompt = command
+        .assemble_prompt(
+            config,
+            config.system_prompt(None),
+            &user_prompt,
+            None,
+            &HashMap::new(),
+        )?
+        .prompt;
+
+    reminder::post(
+        http,
+        request_tx,
+        config.inference.batch_size,
+        reminder::Post {
+            prompt,
+            context: generation::RequestContext {
+                guild_id: None,
+                channel_id: schedule.channel_id,
+                // No user asked for it
+                user_id: 0,
+                command_name: schedule.command.clone(),
+                shard_id: None,
+            },
+            low_priority: true,
+            header: format!("**{}** (/{}):", schedule.name, schedule.command),
+            ping: None,
+        },
+    )
+    .await
+}
+
+// function to handle `/schedules`, which lists the schedules and when they're next posted
+pub async fn schedules_command(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    schedules: &Schedules,
+) -> anyhow::Result<()> {
+    if !bench::is_owner(http, cmd).await? {
+        return Err(util::user_error("Only the bot's owner can use /schedules."));
+    }
+
+    let now = store::now();
+    let lines: Vec<_> = schedules
+        .list()
+        .iter()
+        .map(|s| {
+            let next = s.time_of_day().map_or("never".to_string(), |t| {
+                format!("<t:{}:R>", last_due(t, now) + DAY)
+            });
+            format!(
+                "**{}** · every day at {} UTC in <#{}> · /{} · {} prompt(s) · next {next}",
+                s.name,
+                s.at,
+                s.channel_id,
+                s.command,
+                s.prompts.len()
+            )
+        })
+        .collect();
+    let content = if lines.is_empty() {
+        "There are no schedules.".to_string()
+    } else {
+        lines.join("\n")
+    };
+
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| d.content(content).ephemeral(true))
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to build the `/schedules` command, for registering with Discord
+pub fn command() -> CreateApplicationCommand {
+    let mut schedules = CreateApplicationCommand::default();
+    schedules
+        .name(constant::command::SCHEDULES)
+        .description("Lists the scheduled posts (owner only).")
+        // Hidden from everyone but administrators; the owner check happens when it's used
+        .default_member_permissions(Permissions::ADMINISTRATOR);
+    schedules
+}
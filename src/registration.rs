@@ -0,0 +1,796 @@
+// This file holds the registration of the bot's slash commands (and context-menu commands)
+// with Discord. Every command the bot wants is built as a full payload up front, and compared
+// with what Discord already has, field by field (names, descriptions, option types, required
+// flags, choices, localizations and so on). Only the commands that differ are created, edited
+// or deleted, so that changing an option reaches Discord, and unchanged commands never
+// disappear from users' menus while the bot starts.
+use std::{collections::HashMap, future::Future, time::Duration};
+
+use serde_json::{json, Map, Value};
+use serenity::{
+    builder::CreateApplicationCommand,
+    http::Http,
+    model::prelude::{
+        command::{Command, CommandOptionType, CommandType},
+        CommandId, Permissions,
+    },
+};
+
+use crate::{
+    config::{CommandOptionKind, Configuration},
+    config_validate, constant, debug_generate, embedding, feedback, inspect, invite, persona,
+    queue, recurring, reminder, system_prompt,
+};
+
+// A change to make to the registered commands
+#[derive(Debug, PartialEq, Eq)]
+pub enum Change {
+    // Create the desired command at this index
+    Create(usize),
+    // Replace the registered command with the desired command at this index
+    Edit(CommandId, usize),
+    // Delete the registered command, which is no longer wanted
+    Delete(CommandId),
+}
+
+// How many times a registration call is attempted before giving up on it
+const MAX_ATTEMPTS: u32 = 5;
+
+// How long to wait before the first retry; each retry after that waits twice as long
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+// The longest to wait between retries
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+// function to bring Discord's commands in line with the commands the bot wants.
+// Each call to Discord is retried with exponential backoff if it fails for a transient
+// reason, and a change that still fails doesn't stop the others from being made.
+// Running this again (e.g. after a reconnect) only makes the changes that are still needed
+pub async fn register_commands(http: &Http, config: &Configuration) -> anyhow::Result<()> {
+    apply(http, &desired_commands(config)).await
+}
+
+// The calls registration makes to Discord, kept apart so that tests can stand in for Discord
+trait CommandApi {
+    // function to get the registered commands, as Discord describes them
+    async fn get(&self) -> serenity::Result<Vec<(CommandId, Value)>>;
+    async fn create(&self, command: &CreateApplicationCommand) -> serenity::Result<()>;
+    async fn edit(&self, id: CommandId, command: &CreateApplicationCommand)
+        -> serenity::Result<()>;
+    async fn delete(&self, id: CommandId) -> serenity::Result<()>;
+}
+
+impl CommandApi for Http {
+    async fn get(&self) -> serenity::Result<Vec<(CommandId, Value)>> {
+        let commands = Command::get_global_application_commands(self).await?;
+        let values = commands
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(commands.iter().map(|c| c.id).zip(values).collect())
+    }
+
+    async fn create(&self, command: &CreateApplicationCommand) -> serenity::Result<()> {
+        Command::create_global_application_command(self, |c| {
+            *c = command.clone();
+            c
+        })
+        .await
+        .map(|_| ())
+    }
+
+    async fn edit(
+        &self,
+        id: CommandId,
+        command: &CreateApplicationCommand,
+    ) -> serenity::Result<()> {
+        Command::edit_global_application_command(self, id, |c| {
+            *c = command.clone();
+            c
+        })
+        .await
+        .map(|_| ())
+    }
+
+    async fn delete(&self, id: CommandId) -> serenity::Result<()> {
+        Command::delete_global_application_command(self, id).await
+    }
+}
+
+// function to make the changes that turn the registered commands into the desired ones
+async fn apply(api: &impl CommandApi, desired: &[CreateApplicationCommand]) -> anyhow::Result<()> {
+    let registered = with_retries("fetch the registered commands", || api.get()).await?;
+
+    let payloads: Vec<_> = desired.iter().map(to_value).collect();
+    let changes = diff(&payloads, &registered);
+    if changes.is_empty() {
+        info!("The registered commands are up to date");
+        return Ok(());
+    }
+
+    let change_count = changes.len();
+    let mut failures = 0;
+    for change in changes {
+        let result = match change {
+            Change::Create(index) => {
+                let description = describe(&payloads[index]);
+                info!("Registering command {description}");
+                with_retries(&format!("register command {description}"), || {
+                    api.create(&desired[index])
+                })
+                .await
+            }
+            Change::Edit(id, index) => {
+                let description = describe(&payloads[index]);
+                info!("Updating command {description}");
+                with_retries(&format!("update command {description}"), || {
+                    api.edit(id, &desired[index])
+                })
+                .await
+            }
+            Change::Delete(id) => {
+                info!("Deleting command {id}, which is no longer configured");
+                with_retries(&format!("delete command {id}"), || api.delete(id)).await
+            }
+        };
+
+        if let Err(err) = result {
+            error!("{err:?}");
+            failures += 1;
+        }
+    }
+
+    if failures > 0 {
+        anyhow::bail!("{failures} of {change_count} command changes failed");
+    }
+    Ok(())
+}
+
+// function to make a call to Discord, retrying with exponential backoff while it fails
+// for a reason that might go away (rate limits, server errors and network problems)
+async fn with_retries<T, F, Fut>(action: &str, mut call: F) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = serenity::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match call().await {
+            Ok(value) => return Ok(value),
+            Err(err) => {
+                attempt += 1;
+                if attempt >= MAX_ATTEMPTS || !is_transient(&err) {
+                    return Err(anyhow::Error::new(err)
+                        .context(format!("failed to {action} after {attempt} attempt(s)")));
+                }
+
+                let delay = backoff(attempt);
+                warn!("Failed to {action} ({err}), retrying in {delay:?}");
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+}
+
+// function to get how long to wait after a number of failed attempts
+fn backoff(failed_attempts: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(2u32.saturating_pow(failed_attempts.saturating_sub(1)))
+        .min(MAX_BACKOFF)
+}
+
+// function to check whether an error is worth retrying.
+// Other errors (like Discord rejecting a payload) would only happen again
+fn is_transient(err: &serenity::Error) -> bool {
+    match err {
+        serenity::Error::Http(e) => match e.status_code() {
+            Some(status) => status.as_u16() == 429 || status.is_server_error(),
+            None => true, // The request never got a response
+        },
+        _ => false,
+    }
+}
+
+// function to work out the changes that turn the registered commands into the desired ones.
+// Commands are matched by name and type (a slash command and a context-menu command can share
+// a name), and a matched command is only edited if its normalized payload differs
+pub fn diff(desired: &[Value], registered: &[(CommandId, Value)]) -> Vec<Change> {
+    let mut by_key: HashMap<(String, u64), Vec<(CommandId, Value)>> = HashMap::new();
+    for (id, command) in registered {
+        by_key
+            .entry(key(command))
+            .or_default()
+            .push((*id, normalize(command)));
+    }
+
+    let mut changes = vec![];
+    for (index, command) in desired.iter().enumerate() {
+        let matches = by_key.get_mut(&key(command)).filter(|m| !m.is_empty());
+        match matches.map(|m| m.remove(0)) {
+            Some((id, existing)) if existing != normalize(command) => {
+                changes.push(Change::Edit(id, index));
+            }
+            Some(_) => {}
+            None => changes.push(Change::Create(index)),
+        }
+    }
+
+    // Whatever wasn't matched (including duplicates) isn't wanted any more
+    let mut leftovers: Vec<_> = by_key.into_values().flatten().map(|(id, _)| id).collect();
+    leftovers.sort();
+    changes.extend(leftovers.into_iter().map(Change::Delete));
+
+    changes
+}
+
+// function to build every command the bot wants registered
+fn desired_commands(config: &Configuration) -> Vec<CreateApplicationCommand> {
+    let mut commands = vec![];
+
+    // Iterate over the enabled commands in the bot's configuration
+    for (name, command) in config.commands.iter().filter(|(_, v)| v.enabled) {
+        let mut cmd = CreateApplicationCommand::default();
+        cmd.name(name)
+            .description(command.description.as_str())
+            .create_option(|opt| {
+                // Create an option for the prompt parameter
+                opt.name(constant::value::PROMPT)
+                    .description("The prompt.")
+                    .kind(CommandOptionType::String)
+                    .required(true)
+            });
+
+        // The command's own options; Discord wants the required ones first
+        let (required, optional): (Vec<_>, Vec<_>) =
+            command.options.iter().partition(|o| o.required);
+        for option in required.into_iter().chain(optional) {
+            cmd.create_option(|opt| {
+                opt.name(&option.name)
+                    .description(option.description.as_deref().unwrap_or(&option.name))
+                    .kind(match option.kind {
+                        CommandOptionKind::String => CommandOptionType::String,
+                        CommandOptionKind::Integer => CommandOptionType::Integer,
+                        CommandOptionKind::Number => CommandOptionType::Number,
+                        CommandOptionKind::Boolean => CommandOptionType::Boolean,
+                    })
+                    .required(option.required)
+            });
+        }
+
+        // Create additional parameters for the command
+        create_parameters(&mut cmd);
+
+        // Several variations can be asked for at once, unless the command already generates
+        // alternatives of its own
+        if command.has_count_option(&config.inference) {
+            cmd.create_option(|opt| {
+                opt.name(constant::value::COUNT)
+                    .kind(CommandOptionType::Integer)
+                    .description("How many variations to generate, each from its own seed.")
+                    .min_int_value(1)
+                    .max_int_value(config.inference.max_variations)
+                    .required(false)
+            });
+        }
+        commands.push(cmd);
+
+        // Commands that use `{{REPLY}}` or `{{ATTACHMENTS}}` can also be used on a message,
+        // from its context menu
+        if command.uses_reply() || command.uses_attachments() {
+            let mut cmd = CreateApplicationCommand::default();
+            cmd.name(name).kind(CommandType::Message);
+            commands.push(cmd);
+        }
+    }
+
+    // The built-in commands
+    commands.push(remind_command(config));
+
+    let mut bench = CreateApplicationCommand::default();
+    bench
+        .name(constant::command::BENCH)
+        .description("Benchmarks the model (owner only).")
+        // Hidden from everyone but administrators; the owner check happens when it's used
+        .default_member_permissions(Permissions::ADMINISTRATOR);
+    commands.push(bench);
+    commands.push(debug_generate::command());
+    commands.push(config_validate::command());
+    commands.push(recurring::command());
+
+    commands.extend(embedding::commands());
+    commands.push(system_prompt::command());
+
+    // There's nothing to choose between without any personas
+    if !config.personas.is_empty() {
+        commands.push(persona::command());
+    }
+
+    commands.push(inspect::command());
+    commands.push(invite::command());
+    commands.push(queue::command());
+
+    let mut status = CreateApplicationCommand::default();
+    status
+        .name(constant::command::STATUS)
+        .description("Shows how many requests are generating and waiting.");
+    commands.push(status);
+
+    // There's nothing to sum up without a way to vote or report
+    if config.inference.enable_reaction_feedback
+        || config.inference.enable_feedback_buttons
+        || config.report_channel_id.is_some()
+    {
+        commands.push(feedback::command());
+    }
+
+    commands
+}
+
+// function to add the parameters shared by the configured commands
+fn create_parameters(command: &mut CreateApplicationCommand) -> &mut CreateApplicationCommand {
+    // Create an option for the seed parameter
+    command.create_option(|opt| {
+        opt.name(constant::value::SEED)
+            .kind(CommandOptionType::Integer)
+            .description("The seed to use for sampling.")
+            .min_int_value(0)
+            .required(false)
+    });
+
+    // Create an option for text that the response starts with, for the model to continue
+    command.create_option(|opt| {
+        opt.name(constant::value::PREFIX)
+            .kind(CommandOptionType::String)
+            .description("Text the response starts with, which the model continues.")
+            .required(false)
+    })
+}
+
+// function to build the built-in `/remind` command
+fn remind_command(config: &Configuration) -> CreateApplicationCommand {
+    let mut cmd = CreateApplicationCommand::default();
+    cmd.name(constant::command::REMIND)
+        .description("Runs a command later, and posts the result in this channel.")
+        .create_option(|opt| {
+            opt.name(constant::value::COMMAND)
+                .description("The command to run.")
+                .kind(CommandOptionType::String)
+                .required(true);
+
+            // Offer the enabled commands as choices (Discord allows up to 25)
+            for name in config
+                .commands
+                .iter()
+                .filter(|(_, v)| v.enabled)
+                .map(|(k, _)| k)
+                .take(25)
+            {
+                opt.add_string_choice(name, name);
+            }
+            opt
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::PROMPT)
+                .description("The prompt.")
+                .kind(CommandOptionType::String)
+                .required(true)
+        })
+        .create_option(|opt| {
+            opt.name(constant::value::IN_MINUTES)
+                .description("How many minutes from now to run the command.")
+                .kind(CommandOptionType::Integer)
+                .min_int_value(1)
+                .max_int_value(reminder::MAX_MINUTES)
+                .required(true)
+        });
+    cmd
+}
+
+// function to turn a command builder into the JSON it sends to Discord
+fn to_value(command: &CreateApplicationCommand) -> Value {
+    Value::Object(
+        command
+            .0
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect(),
+    )
+}
+
+// The name and type that identify a command
+fn key(command: &Value) -> (String, u64) {
+    let name = command["name"].as_str().unwrap_or_default().to_string();
+    // Commands without a type are slash commands
+    let kind = command["type"]
+        .as_u64()
+        .unwrap_or(CommandType::ChatInput as u64);
+    (name, kind)
+}
+
+// function to write the message posted when the bot joins a guild, listing the slash
+// commands everyone can use (commands hidden from all but administrators are left out)
+pub fn welcome_message(config: &Configuration) -> String {
+    let mut lines: Vec<_> = desired_commands(config)
+        .iter()
+        .map(to_value)
+        .filter(|command| {
+            key(command).1 == CommandType::ChatInput as u64
+                && command["default_member_permissions"].is_null()
+        })
+        .map(|command| {
+            let (name, _) = key(&command);
+            format!(
+                "`/{name}`: {}",
+                command["description"].as_str().unwrap_or_default()
+            )
+        })
+        .collect();
+    lines.sort();
+    format!(
+        "Thanks for adding me! Here's what I can do:\n{}",
+        lines.join("\n")
+    )
+}
+
+// function to describe a command for the log
+fn describe(command: &Value) -> String {
+    let (name, kind) = key(command);
+    if kind == CommandType::ChatInput as u64 {
+        format!("/{name}")
+    } else {
+        format!("{name} (context menu)")
+    }
+}
+
+// function to reduce a command to the fields that matter, with Discord's defaults filled in,
+// so that a payload the bot builds and a command Discord returns can be compared directly.
+// Fields that Discord adds (IDs, versions) are left out
+fn normalize(command: &Value) -> Value {
+    let (name, kind) = key(command);
+    json!({
+        "name": name,
+        "type": kind,
+        "description": command["description"].as_str().unwrap_or_default(),
+        "name_localizations": localizations(&command["name_localizations"]),
+        "description_localizations": localizations(&command["description_localizations"]),
+        // Discord sends permissions as a string, but they may be serialized as a number
+        "default_member_permissions": match &command["default_member_permissions"] {
+            Value::Number(n) => Value::String(n.to_string()),
+            other => other.clone(),
+        },
+        "dm_permission": command["dm_permission"].as_bool().unwrap_or(true),
+        "options": normalize_options(&command["options"]),
+    })
+}
+
+// function to normalize a list of options (or subcommands), keeping their order
+fn normalize_options(options: &Value) -> Value {
+    let options = options.as_array().map(Vec::as_slice).unwrap_or_default();
+    Value::Array(
+        options
+            .iter()
+            .map(|option| {
+                let choices = option["choices"].as_array().map(Vec::as_slice);
+                json!({
+                    "type": option["type"],
+                    "name": option["name"],
+                    "description": option["description"].as_str().unwrap_or_default(),
+                    "name_localizations": localizations(&option["name_localizations"]),
+                    "description_localizations": localizations(&option["description_localizations"]),
+                    "required": option["required"].as_bool().unwrap_or(false),
+                    "autocomplete": option["autocomplete"].as_bool().unwrap_or(false),
+                    "choices": choices.unwrap_or_default().iter().map(|choice| json!({
+                        "name": choice["name"],
+                        "value": choice["value"],
+                        "name_localizations": localizations(&choice["name_localizations"]),
+                    })).collect::<Vec<_>>(),
+                    "channel_types": option.get("channel_types").cloned().unwrap_or(json!([])),
+                    "min_value": option["min_value"],
+                    "max_value": option["max_value"],
+                    "min_length": option["min_length"],
+                    "max_length": option["max_length"],
+                    "options": normalize_options(&option["options"]),
+                })
+            })
+            .collect(),
+    )
+}
+
+// function to normalize localizations; missing, null and empty all mean there are none
+fn localizations(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(map.clone()),
+        _ => Value::Object(Map::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, sync::Mutex};
+
+    use serenity::http::{error::ErrorResponse, HttpError};
+
+    use super::*;
+
+    // Discord, as far as registration sees it: the registered commands, the calls made, and
+    // the statuses the next calls fail with
+    #[derive(Default)]
+    struct FakeDiscord {
+        commands: Mutex<Vec<(CommandId, Value)>>,
+        calls: Mutex<Vec<String>>,
+        failures: Mutex<VecDeque<Option<u16>>>,
+    }
+
+    impl FakeDiscord {
+        // `None` lets a call through
+        fn failing_with(statuses: &[Option<u16>]) -> Self {
+            Self {
+                failures: Mutex::new(statuses.iter().copied().collect()),
+                ..Default::default()
+            }
+        }
+
+        // function to record a call, failing it if a failure is waiting
+        fn call(&self, call: String) -> Result<(), u16> {
+            self.calls.lock().unwrap().push(call);
+            match self.failures.lock().unwrap().pop_front().flatten() {
+                Some(status) => Err(status),
+                None => Ok(()),
+            }
+        }
+
+        fn take_calls(&self) -> Vec<String> {
+            std::mem::take(&mut self.calls.lock().unwrap())
+        }
+    }
+
+    impl CommandApi for FakeDiscord {
+        async fn get(&self) -> serenity::Result<Vec<(CommandId, Value)>> {
+            self.call("get".into()).map_err(http_error)?;
+            Ok(self.commands.lock().unwrap().clone())
+        }
+
+        async fn create(&self, command: &CreateApplicationCommand) -> serenity::Result<()> {
+            let command = to_value(command);
+            self.call(format!("create {}", describe(&command)))
+                .map_err(http_error)?;
+            let mut commands = self.commands.lock().unwrap();
+            let id = CommandId(commands.len() as u64 + 1);
+            commands.push((id, command));
+            Ok(())
+        }
+
+        async fn edit(
+            &self,
+            id: CommandId,
+            command: &CreateApplicationCommand,
+        ) -> serenity::Result<()> {
+            self.call(format!("edit {id}")).map_err(http_error)?;
+            let mut commands = self.commands.lock().unwrap();
+            if let Some(registered) = commands.iter_mut().find(|(i, _)| *i == id) {
+                registered.1 = to_value(command);
+            }
+            Ok(())
+        }
+
+        async fn delete(&self, id: CommandId) -> serenity::Result<()> {
+            self.call(format!("delete {id}")).map_err(http_error)?;
+            self.commands.lock().unwrap().retain(|(i, _)| *i != id);
+            Ok(())
+        }
+    }
+
+    // function to make the error Discord's API gives for a status
+    fn http_error(status: u16) -> serenity::Error {
+        serenity::Error::Http(Box::new(HttpError::UnsuccessfulRequest(ErrorResponse {
+            status_code: reqwest::StatusCode::from_u16(status).unwrap(),
+            url: "https://discord.com/api/v10/applications/1/commands"
+                .parse()
+                .unwrap(),
+            error: serde_json::from_value(json!({ "code": 0, "message": "failed" })).unwrap(),
+        })))
+    }
+
+    fn command(name: &str) -> CreateApplicationCommand {
+        let mut command = CreateApplicationCommand::default();
+        command.name(name).description("A command.");
+        command
+    }
+
+    // A slash command as the bot builds it
+    fn desired(name: &str, description: &str) -> Value {
+        json!({
+            "name": name,
+            "description": description,
+            "options": [{
+                "type": 3,
+                "name": "prompt",
+                "description": "The prompt.",
+                "required": true,
+            }],
+        })
+    }
+
+    // The same command as Discord returns it, with the fields it fills in
+    fn registered(id: u64, command: &Value) -> (CommandId, Value) {
+        let mut command = command.clone();
+        let fields = command.as_object_mut().unwrap();
+        fields.insert("id".into(), json!(id.to_string()));
+        fields.insert("application_id".into(), json!("1"));
+        fields.insert("version".into(), json!("7"));
+        fields.insert("type".into(), json!(1));
+        fields.insert("default_member_permissions".into(), Value::Null);
+        fields.insert("dm_permission".into(), json!(true));
+        fields.insert("name_localizations".into(), Value::Null);
+        (CommandId(id), command)
+    }
+
+    #[test]
+    fn unchanged_commands_are_left_alone() {
+        let command = desired("hallucinate", "Hallucinates some text.");
+        assert_eq!(
+            diff(std::slice::from_ref(&command), &[registered(1, &command)]),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn changed_descriptions_are_edited() {
+        let old = desired("hallucinate", "Hallucinates some text.");
+        let new = desired("hallucinate", "Makes something up.");
+        assert_eq!(
+            diff(&[new], &[registered(1, &old)]),
+            vec![Change::Edit(CommandId(1), 0)]
+        );
+    }
+
+    #[test]
+    fn added_options_are_edited() {
+        let old = desired("hallucinate", "Hallucinates some text.");
+        let mut new = old.clone();
+        new["options"].as_array_mut().unwrap().push(json!({
+            "type": 4,
+            "name": "seed",
+            "description": "The seed to use for sampling.",
+        }));
+        assert_eq!(
+            diff(&[new], &[registered(1, &old)]),
+            vec![Change::Edit(CommandId(1), 0)]
+        );
+    }
+
+    #[test]
+    fn changed_choices_are_edited() {
+        let with_choices = |choices: &[&str]| {
+            let mut command = desired("remind", "Runs a command later.");
+            command["options"][0]["choices"] = choices
+                .iter()
+                .map(|c| json!({ "name": c, "value": c }))
+                .collect();
+            command
+        };
+        let old = with_choices(&["alpaca"]);
+        assert_eq!(
+            diff(std::slice::from_ref(&old), &[registered(1, &old)]),
+            vec![]
+        );
+        assert_eq!(
+            diff(
+                &[with_choices(&["alpaca", "hallucinate"])],
+                &[registered(1, &old)]
+            ),
+            vec![Change::Edit(CommandId(1), 0)]
+        );
+    }
+
+    #[test]
+    fn changed_localizations_are_edited() {
+        let old = desired("hallucinate", "Hallucinates some text.");
+        let mut new = old.clone();
+        new["description_localizations"] = json!({ "fr": "Invente du texte." });
+        assert_eq!(
+            diff(&[new], &[registered(1, &old)]),
+            vec![Change::Edit(CommandId(1), 0)]
+        );
+    }
+
+    #[test]
+    fn slash_and_context_menu_commands_can_share_a_name() {
+        let slash = desired("summarize", "Summarizes a message.");
+        let menu = json!({ "name": "summarize", "type": CommandType::Message as u64 });
+        let mut registered_menu = menu.clone();
+        registered_menu["id"] = json!("2");
+
+        // The context-menu command is missing, and the slash command doesn't stand in for it
+        assert_eq!(
+            diff(&[slash.clone(), menu.clone()], &[registered(1, &slash)]),
+            vec![Change::Create(1)]
+        );
+        assert_eq!(
+            diff(
+                &[slash.clone(), menu],
+                &[registered(1, &slash), (CommandId(2), registered_menu)]
+            ),
+            vec![]
+        );
+    }
+
+    #[test]
+    fn duplicates_and_unwanted_commands_are_deleted() {
+        let command = desired("hallucinate", "Hallucinates some text.");
+        let removed = desired("alpaca", "Responds to the provided instruction.");
+        assert_eq!(
+            diff(
+                std::slice::from_ref(&command),
+                &[
+                    registered(1, &command),
+                    registered(2, &command),
+                    registered(3, &removed)
+                ]
+            ),
+            vec![Change::Delete(CommandId(2)), Change::Delete(CommandId(3))]
+        );
+    }
+
+    #[test]
+    fn the_welcome_message_lists_the_commands_everyone_can_use() {
+        let welcome = welcome_message(&Configuration::default());
+        assert!(welcome.contains("`/hallucinate`: "), "{welcome}");
+        assert!(welcome.contains("`/status`: "), "{welcome}");
+        // Owner-only commands aren't for everyone
+        assert!(!welcome.contains("/bench"), "{welcome}");
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_a_cap() {
+        let delays: Vec<_> = (1..=7).map(|a| backoff(a).as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30]);
+    }
+
+    #[tokio::test]
+    async fn rate_limits_are_retried() {
+        let discord = FakeDiscord::failing_with(&[Some(429)]);
+        apply(&discord, &[command("hallucinate")]).await.unwrap();
+        assert_eq!(
+            discord.take_calls(),
+            vec!["get", "get", "create /hallucinate"]
+        );
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried() {
+        let discord = FakeDiscord::failing_with(&[None, Some(503)]);
+        apply(&discord, &[command("hallucinate")]).await.unwrap();
+        assert_eq!(
+            discord.take_calls(),
+            vec!["get", "create /hallucinate", "create /hallucinate"]
+        );
+    }
+
+    #[tokio::test]
+    async fn client_errors_fail_without_retrying() {
+        let discord = FakeDiscord::failing_with(&[Some(403)]);
+        assert!(apply(&discord, &[]).await.is_err());
+        assert_eq!(discord.take_calls(), vec!["get"]);
+
+        // A rejected change doesn't stop the others
+        let discord = FakeDiscord::failing_with(&[None, Some(400)]);
+        let result = apply(&discord, &[command("alpaca"), command("hallucinate")]).await;
+        assert!(result.is_err());
+        assert_eq!(
+            discord.take_calls(),
+            vec!["get", "create /alpaca", "create /hallucinate"]
+        );
+    }
+
+    #[tokio::test]
+    async fn running_again_changes_nothing() {
+        let discord = FakeDiscord::default();
+        let desired = desired_commands(&Configuration::default());
+        apply(&discord, &desired).await.unwrap();
+        assert_eq!(discord.take_calls().len(), desired.len() + 1);
+
+        apply(&discord, &desired).await.unwrap();
+        assert_eq!(discord.take_calls(), vec!["get"]);
+    }
+}
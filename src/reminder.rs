@@ -0,0 +1,200 @@
+// This file holds the scheduled reminders created by `/remind`.
+// Reminders are kept in memory until they're due; a background task checks them every
+// minute, runs each due one through the generation queue, and posts the result as a new
+// message in the channel the reminder was made in (there's no interaction left to respond to
+// by then). Reminders that haven't fired yet are lost if the bot restarts.
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serenity::{
+    http::Http,
+    model::prelude::{ChannelId, MessageId, UserId},
+};
+
+use crate::{
+    generation::{self, Token},
+    store,
+};
+
+// How often the background task checks for due reminders
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// The furthest ahead a reminder can be, in minutes (a week). They're only kept in memory, so
+// one far in the future would most likely be lost to a restart first
+pub const MAX_MINUTES: u64 = 7 * 24 * 60;
+
+// The most characters put in one Discord message, leaving room under the 2000 character limit
+const MESSAGE_CHUNK_SIZE: usize = 1900;
+
+// A generation that has been scheduled for later
+pub struct ScheduledReminder {
+    // When the reminder is due, in seconds since the Unix epoch
+    pub due_at: u64,
+    // The prompt, already rendered into the command's template
+    pub prompt: String,
+    // Who scheduled the reminder, where, and with which command
+    pub context: generation::RequestContext,
+}
+
+// The list of reminders waiting to fire. Cheap to clone; every clone shares the same list
+#[derive(Clone, Default)]
+pub struct Reminders(Arc<Mutex<Vec<ScheduledReminder>>>);
+
+impl Reminders {
+    // function to schedule a reminder
+    pub fn add(&self, reminder: ScheduledReminder) {
+        self.0.lock().unwrap().push(reminder);
+    }
+
+    // function to remove and return every reminder that is due at `now`
+    fn take_due(&self, now: u64) -> Vec<ScheduledReminder> {
+        let mut reminders = self.0.lock().unwrap();
+        let (due, waiting) = reminders.drain(..).partition(|r| r.due_at <= now);
+        *reminders = waiting;
+        due
+    }
+}
+
+// function to check for due reminders every minute, for as long as the bot runs
+pub async fn run(
+    reminders: Reminders,
+    http: Arc<Http>,
+    request_tx: flume::Sender<generation::Request>,
+    batch_size: usize,
+) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        for reminder in reminders.take_due(store::now()) {
+            // Each reminder waits for its own generation, so that they don't hold each other up
+            let (http, request_tx) = (http.clone(), request_tx.clone());
+            tokio::spawn(async move {
+                let channel_id = ChannelId(reminder.context.channel_id);
+                if let Err(err) = fire(&http, request_tx, batch_size, reminder).await {
+                    error!("Failed to send reminder in channel {channel_id}: {err:?}");
+                }
+            });
+        }
+    }
+}
+
+// function to run a reminder's generation and post the result in its channel
+async fn fire(
+    http: &Http,
+    request_tx: flume::Sender<generation::Request>,
+    batch_size: usize,
+    reminder: ScheduledReminder,
+) -> anyhow::Result<()> {
+    let user_id = UserId(reminder.context.user_id);
+    let header = format!(
+        "<@{user_id}>, here's your reminder (/{}):",
+        reminder.context.command_name
+    );
+
+    post(
+        http,
+        request_tx,
+        batch_size,
+        Post {
+            prompt: reminder.prompt,
+            context: reminder.context,
+            low_priority: false,
+            header,
+            ping: Some(user_id),
+        },
+    )
+    .await
+}
+
+// A generation whose output is posted as new messages in its channel, when there's no
+// interaction to respond to
+pub struct Post {
+    // The prompt, already rendered into the command's template
+    pub prompt: String,
+    // Who it's for, where, and with which command
+    pub context: generation::RequestContext,
+    // Whether it waits behind the requests of users who are waiting on a response
+    pub low_priority: bool,
+    // The line the output is posted under
+    pub header: String,
+    // The only user the post can ping, if any
+    pub ping: Option<UserId>,
+}
+
+// function to run a generation and post the result in its channel, in as many messages as it
+// takes
+pub async fn post(
+    http: &Http,
+    request_tx: flume::Sender<generation::Request>,
+    batch_size: usize,
+    post: Post,
+) -> anyhow::Result<()> {
+    let channel_id = ChannelId(post.context.channel_id);
+
+    let (token_tx, token_rx) = flume::unbounded();
+    request_tx.send(generation::Request {
+        prompt: post.prompt,
+        batch_size,
+        // The whole output is collected before posting, so there's nothing to batch for
+        batch_decode: false,
+        token_buffer_size: 1,
+        token_tx,
+        // There is no Discord message to cancel from
+        message_id: MessageId(0),
+        seed: None,
+        maximum_token_count: None,
+        n_sequences: 1,
+        sampling: Default::default(),
+        low_priority: post.low_priority,
+        echo_prompt: false,
+        context: post.context,
+        progress_tx: None,
+        completion_tx: None,
+        trace: None,
+        queued_at: std::time::Instant::now(),
+    })?;
+
+    // The channel closes once the generation thread is done with the request
+    let mut output = String::new();
+    while let Ok(token) = token_rx.recv_async().await {
+        match token {
+            Token::Token(t) => output += &t,
+            Token::Metadata(_) | Token::SequenceStart(_) => {}
+            Token::Error(err) => {
+                output = format!("The generation failed: {err}");
+                break;
+            }
+        }
+    }
+
+    // Post the output in as many messages as it takes
+    let mut message = format!("{}\n", post.header);
+    for c in output.chars() {
+        if message.len() >= MESSAGE_CHUNK_SIZE {
+            send(http, channel_id, post.ping, &std::mem::take(&mut message)).await?;
+        }
+        message.push(c);
+    }
+    send(http, channel_id, post.ping, &message).await?;
+
+    Ok(())
+}
+
+// function to send a message that can only ping the given user, if any
+async fn send(
+    http: &Http,
+    channel_id: ChannelId,
+    ping: Option<UserId>,
+    content: &str,
+) -> anyhow::Result<()> {
+    channel_id
+        .send_message(http, |m| {
+            m.content(content)
+                .allowed_mentions(|am| am.empty_parse().users(ping))
+        })
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,221 @@
+// This file holds the detector for output that has fallen into a loop.
+// Smaller models sometimes start repeating the same sentence over and over until they run out
+// of context, which can take minutes. The generation thread passes each generated token
+// through a `LoopDetector`, which stops the generation once a stretch of `loop_ngram_tokens`
+// tokens has come back more than `loop_max_repeats` times at the same spacing. Stretches are
+// compared by a rolling hash, so each token costs a single lookup.
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+};
+
+use crate::config;
+
+// How far back, in tokens, a stretch is looked for. Stretches that come back further apart
+// than this aren't a loop
+const WINDOW_TOKENS: usize = 1024;
+
+// The multiplier of the rolling hash
+const BASE: u64 = 0x0000_0100_0000_01b3;
+
+// The thresholds of the detector
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LoopLimits {
+    // How many tokens long the stretches that are compared are
+    pub ngram_tokens: usize,
+    // The most times a stretch can come back in a row; 0 turns the detector off
+    pub max_repeats: usize,
+}
+
+impl LoopLimits {
+    pub fn new(inference: &config::Inference) -> Self {
+        Self {
+            ngram_tokens: inference.loop_ngram_tokens,
+            max_repeats: inference.loop_max_repeats,
+        }
+    }
+}
+
+// Where a stretch was last seen, and how regularly it has been coming back
+struct Seen {
+    // The position of the token it ended at
+    position: usize,
+    // The distance between its last two appearances
+    period: usize,
+    // How many times in a row it has appeared that far apart
+    repeats: usize,
+}
+
+// The detector for one output, fed its tokens as they're generated
+pub struct LoopDetector {
+    limits: LoopLimits,
+    // The hashes of the last `ngram_tokens` tokens, and the rolling hash of them together
+    recent: VecDeque<u64>,
+    hash: u64,
+    // `BASE` to the power of `ngram_tokens - 1`, to take the oldest token out of the hash
+    oldest_weight: u64,
+    // The stretches seen within the window, by their hash
+    seen: HashMap<u64, Seen>,
+    // The number of tokens so far
+    position: usize,
+}
+
+impl LoopDetector {
+    pub fn new(limits: LoopLimits) -> Self {
+        Self {
+            limits,
+            recent: VecDeque::new(),
+            hash: 0,
+            oldest_weight: (1..limits.ngram_tokens).fold(1, |w: u64, _| w.wrapping_mul(BASE)),
+            seen: HashMap::new(),
+            position: 0,
+        }
+    }
+
+    // function to add a token to the output, returning whether the output is now looping
+    pub fn push(&mut self, token: &str) -> bool {
+        let n = self.limits.ngram_tokens;
+        if self.limits.max_repeats == 0 || n == 0 {
+            return false;
+        }
+
+        let mut hasher = DefaultHasher::new();
+        token.hash(&mut hasher);
+        let token_hash = hasher.finish();
+
+        if self.recent.len() == n {
+            let oldest = self.recent.pop_front().unwrap();
+            self.hash = self
+                .hash
+                .wrapping_sub(oldest.wrapping_mul(self.oldest_weight));
+        }
+        self.hash = self.hash.wrapping_mul(BASE).wrapping_add(token_hash);
+        self.recent.push_back(token_hash);
+        self.position += 1;
+        if self.recent.len() < n {
+            return false;
+        }
+
+        // Stretches that have fallen out of the window are forgotten now and then
+        let position = self.position;
+        if position.is_multiple_of(WINDOW_TOKENS) {
+            self.seen
+                .retain(|_, seen| position - seen.position <= WINDOW_TOKENS);
+        }
+
+        let seen = self.seen.entry(self.hash).or_insert(Seen {
+            position,
+            period: 0,
+            repeats: 1,
+        });
+        let distance = position - seen.position;
+        // A stretch that overlaps its last appearance (like a run of the same token) is
+        // counted once per `ngram_tokens` tokens
+        if distance == 0 || distance < n {
+            return false;
+        }
+        if distance > WINDOW_TOKENS {
+            *seen = Seen {
+                position,
+                period: 0,
+                repeats: 1,
+            };
+            return false;
+        }
+
+        if distance == seen.period {
+            seen.repeats += 1;
+        } else {
+            seen.period = distance;
+            seen.repeats = 2;
+        }
+        seen.position = position;
+        seen.repeats > self.limits.max_repeats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULTS: LoopLimits = LoopLimits {
+        ngram_tokens: 8,
+        max_repeats: 5,
+    };
+
+    // function to split text into word-sized tokens, keeping the spaces in front of the words
+    // like a model's tokens do
+    fn tokens(text: &str) -> Vec<String> {
+        let mut tokens: Vec<String> = vec![];
+        for (i, word) in text.split(' ').enumerate() {
+            tokens.push(if i == 0 {
+                word.to_string()
+            } else {
+                format!(" {word}")
+            });
+        }
+        tokens
+    }
+
+    // function to feed text to a detector, returning how many tokens it took to stop it
+    fn stops_after(limits: LoopLimits, text: &str) -> Option<usize> {
+        let mut detector = LoopDetector::new(limits);
+        tokens(text)
+            .iter()
+            .position(|t| detector.push(t))
+            .map(|i| i + 1)
+    }
+
+    #[test]
+    fn true_loop() {
+        let sentence = "The cat sat on the mat and looked out of the window. ";
+        let sentence_tokens = tokens(sentence).len() - 1;
+
+        // Five times is fine, but the loop is stopped during the sixth
+        assert_eq!(stops_after(DEFAULTS, &sentence.repeat(5)), None);
+        let stopped = stops_after(DEFAULTS, &sentence.repeat(20)).unwrap();
+        assert!(stopped > sentence_tokens * 5 && stopped <= sentence_tokens * 6);
+    }
+
+    #[test]
+    fn near_loop_with_small_variations() {
+        let text: String = (1..=20)
+            .map(|i| {
+                format!("Step {i}: stir the mixture slowly and then let it rest for a while. ")
+            })
+            .collect();
+        assert!(stops_after(DEFAULTS, &text).is_some());
+    }
+
+    #[test]
+    fn run_of_one_token() {
+        assert!(stops_after(DEFAULTS, &"ha ".repeat(200)).is_some());
+    }
+
+    #[test]
+    fn lists_are_not_loops() {
+        let fruits = [
+            "apples", "bananas", "cherries", "dates", "figs", "grapes", "kiwis", "lemons", "limes",
+            "mangoes", "melons", "oranges", "peaches", "pears", "plums", "quinces",
+        ];
+        let shopping: String = fruits
+            .iter()
+            .map(|f| format!("- Buy some {f} from the market\n"))
+            .collect();
+        assert_eq!(stops_after(DEFAULTS, &shopping), None);
+
+        let numbered: String = (1..=50)
+            .map(|i| format!("{i}. Item number {i}\n"))
+            .collect();
+        assert_eq!(stops_after(DEFAULTS, &numbered), None);
+    }
+
+    #[test]
+    fn turned_off() {
+        let limits = LoopLimits {
+            max_repeats: 0,
+            ..DEFAULTS
+        };
+        assert_eq!(stops_after(limits, &"again and again ".repeat(200)), None);
+    }
+}
@@ -0,0 +1,186 @@
+// This file holds the "Report" button on finished responses, which is there when
+// `report_channel_id` is set. Anyone can press it to flag a response to the moderators: the
+// response, its prompt, who asked for it and who reported it are posted to the report channel
+// as an embed. Further reports of the same response update that post's count, rather than
+// posting it again. Reports are also kept in the store, for `/stats`.
+use std::{borrow::Cow, collections::HashSet, sync::Arc};
+
+use serenity::{
+    builder::{CreateActionRow, CreateEmbed},
+    http::Http,
+    model::prelude::{
+        component::ButtonStyle, interaction::message_component::MessageComponentInteraction,
+        AttachmentType, ChannelId, MessageId, UserId,
+    },
+};
+
+use crate::{
+    export, store,
+    util::{self, DiscordInteraction},
+};
+
+// The prefix of the button's custom ID; the first message's ID follows it
+pub const BUTTON_PREFIX: &str = "report";
+
+// How many reported responses are remembered, to update their posts
+const MAX_REPORTED: usize = 256;
+
+// The longest an embed's description can be; longer responses are attached as a file too
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+
+// The longest an embed field can be
+const MAX_FIELD_LENGTH: usize = 1024;
+
+// A response that has been reported
+struct Reported {
+    // The post in the report channel
+    post_id: MessageId,
+    // Everyone who has reported it
+    reporters: HashSet<UserId>,
+}
+
+// The responses reported recently, keyed by the ID of their first message. The lock is held
+// while a report is posted, so that two reports of the same response can't both post it.
+// Cheap to clone; every clone shares the same list
+#[derive(Clone)]
+pub struct Reports {
+    reported: Arc<tokio::sync::Mutex<util::BoundedMap<MessageId, Reported, MAX_REPORTED>>>,
+    store: store::Store,
+}
+
+impl Reports {
+    pub fn new(store: store::Store) -> Self {
+        Self {
+            reported: Default::default(),
+            store,
+        }
+    }
+}
+
+// function to add the button to the row of buttons under a finished response
+pub fn create_button(row: &mut CreateActionRow, first_id: MessageId) {
+    row.create_button(|b| {
+        b.custom_id(format!("{BUTTON_PREFIX}#{first_id}"))
+            .style(ButtonStyle::Danger)
+            .label("Report")
+    });
+}
+
+// function to handle a press of the button, by posting (or updating) the report and thanking
+// whoever pressed it
+pub async fn report(
+    cmp: &MessageComponentInteraction,
+    http: &Http,
+    reports: &Reports,
+    transcripts: &export::Transcripts,
+    channel_id: u64,
+    first_id: MessageId,
+) -> anyhow::Result<()> {
+    let Some((command_name, prompt, response, requester)) = transcripts.inspect(first_id, |t| {
+        (
+            t.record.command_name.clone(),
+            t.prompt.clone(),
+            t.response.clone(),
+            t.requester,
+        )
+    }) else {
+        return cmp
+            .create_ephemeral(http, "This response can no longer be reported.")
+            .await;
+    };
+
+    let store = reports.store.clone();
+    let mut reports = reports.reported.lock().await;
+    let already_reported = reports
+        .get(&first_id)
+        .is_some_and(|r| r.reporters.contains(&cmp.user.id));
+    if already_reported {
+        return cmp
+            .create_ephemeral(http, "You've already reported this response.")
+            .await;
+    }
+
+    let channel = ChannelId(channel_id);
+    let link = match cmp.guild_id {
+        Some(guild_id) => format!(
+            "https://discord.com/channels/{guild_id}/{}/{first_id}",
+            cmp.channel_id
+        ),
+        None => format!(
+            "https://discord.com/channels/@me/{}/{first_id}",
+            cmp.channel_id
+        ),
+    };
+    let embed = |count: usize| {
+        let mut embed = CreateEmbed::default();
+        embed
+            .title(format!("Reported response from /{command_name}"))
+            .url(&link)
+            .description(fit(&response, MAX_DESCRIPTION_LENGTH))
+            .field("Prompt", fit(&prompt, MAX_FIELD_LENGTH), false)
+            .field("Requested by", format!("<@{requester}>"), true)
+            .field("Latest report by", format!("<@{}>", cmp.user.id), true)
+            .field("Reports", count.to_string(), true);
+        embed
+    };
+
+    match reports.get_mut(&first_id) {
+        Some(reported) => {
+            reported.reporters.insert(cmp.user.id);
+            let embed = embed(reported.reporters.len());
+            channel
+                .edit_message(http, reported.post_id, |m| m.set_embed(embed))
+                .await?;
+        }
+        None => {
+            let embed = embed(1);
+            let post = channel
+                .send_message(http, |m| {
+                    m.set_embed(embed)
+                        .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse());
+                    // The embed only fits so much; the whole response goes along as a file
+                    if response.chars().count() > MAX_DESCRIPTION_LENGTH {
+                        m.add_file(AttachmentType::Bytes {
+                            data: Cow::Owned(response.clone().into_bytes()),
+                            filename: "response.md".into(),
+                        });
+                    }
+                    m
+                })
+                .await?;
+
+            reports.insert(
+                first_id,
+                Reported {
+                    post_id: post.id,
+                    reporters: HashSet::from([cmp.user.id]),
+                },
+            );
+        }
+    }
+    drop(reports);
+
+    store.save_report(store::ReportRecord {
+        response_id: first_id.0,
+        reporter_id: cmp.user.id.0,
+        command_name,
+    });
+
+    cmp.create_ephemeral(
+        http,
+        "Thanks, the moderators have been told about this response.",
+    )
+    .await
+}
+
+// function to fit text in part of an embed, which can't be empty or longer than `max` characters
+fn fit(text: &str, max: usize) -> String {
+    let text = text.trim();
+    if text.is_empty() {
+        return "*(empty)*".to_string();
+    }
+    if text.chars().count() <= max {
+        return text.to_string();
+    }
+    text.chars().take(max - 1).collect::<String>() + "…"
+}
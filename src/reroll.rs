@@ -0,0 +1,96 @@
+// This file holds the "Make it weirder" and "More focused" buttons on finished responses.
+// Pressing one generates the response again from the same prompt, at a temperature
+// `reroll_temperature_delta` higher or lower than the one it was last generated at, and
+// writes the new output over the old. Only the user who asked for the response can use
+// them, and they go away after `max_rerolls` rerolls.
+use std::sync::{Arc, Mutex};
+
+use serenity::{
+    builder::CreateActionRow,
+    model::prelude::{component::ButtonStyle, Message, MessageId, UserId},
+};
+
+use crate::{config, generation, prompts::Prompts, util};
+
+// The prefixes of the buttons' custom IDs; the first message's ID follows them
+pub const WEIRDER_PREFIX: &str = "reroll_weirder";
+pub const FOCUSED_PREFIX: &str = "reroll_focused";
+
+// How many finished responses are remembered for rerolling
+const MAX_REROLLABLE: usize = 256;
+
+// The lowest and highest temperatures a reroll can go to
+const MIN_TEMPERATURE: f32 = 0.1;
+const MAX_TEMPERATURE: f32 = 2.0;
+
+// What's needed to generate a finished response again
+#[derive(Clone)]
+pub struct Reroll {
+    // The user who asked for the response, who's the only one who can reroll it
+    pub user_id: UserId,
+    // The command that was used
+    pub command_name: String,
+    // The prompt the model was given
+    pub prompt: String,
+    // The prompts the response is shown with
+    pub prompts: Prompts,
+    // The sampling settings it was generated with, temperature included
+    pub sampling: config::Sampling,
+    // The persona it was generated with, if any
+    pub persona: Option<String>,
+    // Who asked, and where
+    pub context: generation::RequestContext,
+    // The messages the response is shown in
+    pub messages: Vec<Message>,
+    // How many times it has been rerolled
+    pub rerolls: usize,
+}
+
+impl Reroll {
+    // The temperature the response was last generated at
+    pub fn temperature(&self) -> f32 {
+        self.sampling
+            .temperature
+            .unwrap_or(generation::DEFAULT_TEMPERATURE)
+    }
+
+    // function to move the temperature a step up (or down) for the next reroll, within bounds
+    pub fn step_temperature(&mut self, weirder: bool, delta: f32) {
+        let step = if weirder { delta } else { -delta };
+        self.sampling.temperature =
+            Some((self.temperature() + step).clamp(MIN_TEMPERATURE, MAX_TEMPERATURE));
+    }
+}
+
+// The most recently finished responses that can be rerolled, keyed by the ID of their first
+// message. A response is taken out while it's being rerolled, so that it can't be rerolled
+// twice at once. Cheap to clone; every clone shares the same list
+#[derive(Clone, Default)]
+pub struct Rerolls(Arc<Mutex<util::BoundedMap<MessageId, Reroll, MAX_REROLLABLE>>>);
+
+impl Rerolls {
+    // function to remember a finished response, forgetting the oldest one if there are too many
+    pub fn insert(&self, first_id: MessageId, reroll: Reroll) {
+        self.0.lock().unwrap().insert(first_id, reroll);
+    }
+
+    // function to take a response out to reroll it, if it's still remembered and not already
+    // being rerolled
+    pub fn take(&self, first_id: MessageId) -> Option<Reroll> {
+        self.0.lock().unwrap().remove(&first_id)
+    }
+}
+
+// function to add the reroll buttons to the row of buttons under a finished response
+pub fn create_buttons(row: &mut CreateActionRow, first_id: MessageId) {
+    row.create_button(|b| {
+        b.custom_id(format!("{WEIRDER_PREFIX}#{first_id}"))
+            .style(ButtonStyle::Secondary)
+            .label("Make it weirder")
+    })
+    .create_button(|b| {
+        b.custom_id(format!("{FOCUSED_PREFIX}#{first_id}"))
+            .style(ButtonStyle::Secondary)
+            .label("More focused")
+    });
+}
@@ -0,0 +1,778 @@
+// This file holds the scheduling of generation requests. The generation thread estimates how
+// long each waiting request will take from how long recent ones took, and runs the shortest
+// first, so that a quick question isn't stuck behind a long story. Requests are also moved up
+// the longer they wait, so that long ones still get their turn when the bot is busy.
+// Requests are also split into two lanes by how much they can generate: short ones go in the
+// fast lane, which runs first, but after `fast_lane_streak` of them in a row a waiting request
+// from the slow lane gets its turn, so that a steady stream of short questions can't hold the
+// long ones back forever.
+// The thread publishes the order it would run them in on a board, which waiting users are
+// told their place in the queue (and how long they have left to wait) from.
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serenity::model::prelude::MessageId;
+
+use crate::{config, generation, health};
+
+// How much of each new sample goes into the estimator's averages.
+// An exponential moving average, weighted towards recent requests
+const SAMPLE_WEIGHT: f64 = 0.2;
+
+// An estimate of how long a request takes, fitted from the requests that have finished.
+// A request is modelled as a cost per prompt token plus a cost per generated token
+pub struct Estimator {
+    // How long feeding each prompt token takes, in milliseconds
+    ms_per_prompt_token: f64,
+    // How long generating each token takes, in milliseconds
+    ms_per_generated_token: f64,
+    // How many tokens requests usually generate, for requests without a limit
+    generated_tokens: f64,
+    // How far off the estimates usually are, as a fraction of the estimate
+    relative_error: f64,
+}
+
+impl Estimator {
+    // function to make an estimator that starts from the configured cost per generated token
+    pub fn new(inference: &config::Inference) -> Self {
+        Self {
+            ms_per_prompt_token: 0.0,
+            ms_per_generated_token: inference.n_predict_per_token_ms as f64,
+            generated_tokens: 0.0,
+            relative_error: 0.0,
+        }
+    }
+
+    // Whether or not a request has finished to estimate from. Until then, estimates only
+    // count the prompt
+    pub fn is_warm(&self) -> bool {
+        self.generated_tokens > 0.0
+    }
+
+    // function to estimate how long a request will take, in milliseconds.
+    // The prompt hasn't been tokenized yet, so its length is estimated from its characters
+    pub fn estimate_ms(&self, request: &generation::Request) -> u64 {
+        let prompt_tokens = request.prompt.len().div_ceil(config::CHARS_PER_TOKEN) as f64;
+        // Every alternative feeds the prompt and generates its own output
+        let sequences = request.n_sequences.max(1) as f64;
+        (prompt_tokens * self.ms_per_prompt_token * sequences
+            + self.expected_tokens(request) * self.ms_per_generated_token) as u64
+    }
+
+    // function to estimate how many tokens a request will generate, over all its alternatives
+    fn expected_tokens(&self, request: &generation::Request) -> f64 {
+        let generated_tokens = match request.maximum_token_count {
+            Some(maximum) => self.generated_tokens.min(maximum as f64),
+            None => self.generated_tokens,
+        };
+        generated_tokens * request.n_sequences.max(1) as f64
+    }
+
+    // function to fold a finished request's statistics (added up over its `sequences`
+    // alternatives) into the estimates
+    pub fn record(&mut self, stats: &llm::InferenceStats, sequences: usize) {
+        if stats.prompt_tokens > 0 {
+            let sample =
+                stats.feed_prompt_duration.as_secs_f64() * 1000.0 / stats.prompt_tokens as f64;
+            update(&mut self.ms_per_prompt_token, sample);
+        }
+        if stats.predict_tokens > 0 {
+            let sample =
+                stats.predict_duration.as_secs_f64() * 1000.0 / stats.predict_tokens as f64;
+            update(&mut self.ms_per_generated_token, sample);
+        }
+        let sequences = sequences.max(1) as f64;
+        update(
+            &mut self.generated_tokens,
+            stats.predict_tokens as f64 / sequences,
+        );
+    }
+
+    // function to fold how long a request actually took into how far off estimates usually are
+    pub fn record_duration(&mut self, estimate_ms: u64, duration: Duration) {
+        if estimate_ms > 0 {
+            let actual_ms = duration.as_millis() as f64;
+            let sample = (actual_ms - estimate_ms as f64).abs() / estimate_ms as f64;
+            update(&mut self.relative_error, sample);
+        }
+    }
+}
+
+// function to fold a sample into an average; an average that hasn't been set yet becomes it
+fn update(average: &mut f64, sample: f64) {
+    *average = if *average == 0.0 {
+        sample
+    } else {
+        *average * (1.0 - SAMPLE_WEIGHT) + sample * SAMPLE_WEIGHT
+    };
+}
+
+// Which lane a request waits in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Lane {
+    // Requests that can only generate a little, which run first
+    Fast,
+    // Everything else
+    Slow,
+}
+
+// How requests are split into lanes. Taken from the config once, when the generation thread
+// starts. The default puts everything in the slow lane, i.e. there are no lanes
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lanes {
+    // Requests that can generate fewer tokens than this go in the fast lane (0 for no lanes)
+    fast_tokens: usize,
+    // How many fast lane requests run in a row while the slow lane waits
+    streak: usize,
+    // The model's context length, which bounds how much any request can generate
+    context_length: usize,
+}
+
+impl Lanes {
+    // function to take the lanes from the config
+    pub fn new(inference: &config::Inference, context_length: usize) -> Self {
+        Self {
+            fast_tokens: inference.fast_lane_tokens,
+            streak: inference.fast_lane_streak,
+            context_length,
+        }
+    }
+
+    // function to work out which lane a request goes in, from the most it can generate: its
+    // token limit, or whatever the prompt leaves of the context. The prompt hasn't been
+    // tokenized yet, so its length is estimated from its characters
+    pub fn classify(&self, request: &generation::Request) -> Lane {
+        let prompt_tokens = request.prompt.len().div_ceil(config::CHARS_PER_TOKEN);
+        let room = self.context_length.saturating_sub(prompt_tokens);
+        let maximum = request.maximum_token_count.map_or(room, |m| m.min(room));
+        if maximum < self.fast_tokens {
+            Lane::Fast
+        } else {
+            Lane::Slow
+        }
+    }
+}
+
+// A request waiting for the generation thread, with when it arrived, how long it should take
+// and which lane it's in
+struct Pending {
+    request: generation::Request,
+    queued_at: Instant,
+    estimate_ms: u64,
+    lane: Lane,
+}
+
+impl Pending {
+    // The key the queue runs requests in order of, lowest first
+    fn priority(&self, now: Instant) -> (bool, i64) {
+        let waited_ms = now.duration_since(self.queued_at).as_millis() as i64;
+        (
+            self.request.low_priority,
+            self.estimate_ms as i64 - waited_ms,
+        )
+    }
+}
+
+// The requests waiting for the generation thread
+#[derive(Default)]
+pub struct Queue {
+    pending: Vec<Pending>,
+    lanes: Lanes,
+    // How many fast lane requests have run in a row
+    fast_streak: usize,
+}
+
+impl Queue {
+    // function to make an empty queue that splits requests into the given lanes
+    pub fn new(lanes: Lanes) -> Self {
+        Self {
+            lanes,
+            ..Default::default()
+        }
+    }
+
+    // function to add newly received requests to the queue. Returns whether any arrived
+    pub fn extend(
+        &mut self,
+        requests: impl Iterator<Item = generation::Request>,
+        estimator: &Estimator,
+    ) -> bool {
+        let depth = self.pending.len();
+        for request in requests {
+            self.pending.push(Pending {
+                estimate_ms: estimator.estimate_ms(&request),
+                queued_at: Instant::now(),
+                lane: self.lanes.classify(&request),
+                request,
+            });
+        }
+        self.pending.len() > depth
+    }
+
+    // function to take the requests with the given IDs out of the queue, if they're in it
+    pub fn remove(&mut self, message_ids: &[MessageId]) -> Vec<generation::Request> {
+        let (removed, kept) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| message_ids.contains(&p.request.message_id));
+        self.pending = kept;
+        removed.into_iter().map(|p: Pending| p.request).collect()
+    }
+
+    // function to take the requests that have waited longer than `max_wait` since they were
+    // sent out of the queue. Low priority requests have nobody waiting on them, so they're
+    // never taken
+    pub fn remove_expired(&mut self, max_wait: Duration) -> Vec<generation::Request> {
+        let (expired, kept) = std::mem::take(&mut self.pending)
+            .into_iter()
+            .partition(|p| !p.request.low_priority && p.request.queued_at.elapsed() > max_wait);
+        self.pending = kept;
+        expired.into_iter().map(|p: Pending| p.request).collect()
+    }
+
+    // function to take the request to run next, with its estimate: the one with the shortest
+    // estimate, less how long it has waited, from the fast lane unless the slow lane's turn
+    // has come. Requests with the same estimate run in the order they arrived, and low
+    // priority requests only run when nothing else is waiting
+    pub fn pop(&mut self) -> Option<(generation::Request, u64)> {
+        let pending: Vec<_> = self.pending.iter().collect();
+        let index = next(
+            &pending,
+            self.fast_streak,
+            self.lanes.streak,
+            Instant::now(),
+        )?;
+        let pending = self.pending.remove(index);
+        self.count_streak(&pending);
+        Some((pending.request, pending.estimate_ms))
+    }
+
+    // function to keep count of the fast lane requests run in a row
+    fn count_streak(&mut self, pending: &Pending) {
+        if pending.lane == Lane::Fast && !pending.request.low_priority {
+            self.fast_streak += 1;
+        } else if !pending.request.low_priority {
+            self.fast_streak = 0;
+        }
+    }
+
+    // The waiting requests, in the order they'd run if no more arrived
+    fn in_order(&self) -> Vec<Entry> {
+        let now = Instant::now();
+        let mut pending: Vec<_> = self.pending.iter().collect();
+        let mut streak = self.fast_streak;
+        let mut ordered = Vec::with_capacity(pending.len());
+        while let Some(index) = next(&pending, streak, self.lanes.streak, now) {
+            let p = pending.remove(index);
+            if !p.request.low_priority {
+                streak = if p.lane == Lane::Fast { streak + 1 } else { 0 };
+            }
+            ordered.push(Entry::new(&p.request, p.estimate_ms, p.queued_at));
+        }
+        ordered
+    }
+
+    // The number of requests waiting
+    pub fn depth(&self) -> usize {
+        self.pending.len()
+    }
+
+    // The number of requests waiting in the fast and slow lanes. Low priority requests are
+    // counted in the lane they'd be in
+    pub fn lane_depths(&self) -> (usize, usize) {
+        let fast = self.pending.iter().filter(|p| p.lane == Lane::Fast).count();
+        (fast, self.pending.len() - fast)
+    }
+
+    // How long all the waiting requests should take, in milliseconds
+    pub fn estimated_ms(&self) -> u64 {
+        self.pending.iter().map(|p| p.estimate_ms).sum()
+    }
+}
+
+// function to find which of the waiting requests runs next, given how many fast lane requests
+// have run in a row and how many may before the slow lane gets a turn
+fn next(
+    pending: &[&Pending],
+    fast_streak: usize,
+    max_streak: usize,
+    now: Instant,
+) -> Option<usize> {
+    let best = |lane: Option<Lane>| {
+        pending
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| lane.is_none() || (!p.request.low_priority && Some(p.lane) == lane))
+            .min_by_key(|(_, p)| p.priority(now))
+            .map(|(index, _)| index)
+    };
+    match (best(Some(Lane::Fast)), best(Some(Lane::Slow))) {
+        (Some(_), Some(slow)) if fast_streak >= max_streak => Some(slow),
+        (Some(fast), _) => Some(fast),
+        (None, Some(slow)) => Some(slow),
+        // Only low priority requests are left
+        (None, None) => best(None),
+    }
+}
+
+// function to estimate how long a new request would wait for the local model: the requests
+// the generation thread is holding, plus those it hasn't picked up yet (at the average time).
+// What's left of the current generation isn't known, so it's left out
+pub fn estimated_wait(
+    readiness: &health::Readiness,
+    request_tx: &flume::Sender<generation::Request>,
+) -> Duration {
+    let held = readiness.estimated_queue_ms.load(Ordering::SeqCst);
+    let average = readiness.average_generation_ms.load(Ordering::SeqCst);
+    Duration::from_millis(held + average * request_tx.len() as u64)
+}
+
+// function to count the requests waiting for the local model, wherever they are
+pub fn queue_depth(
+    readiness: &health::Readiness,
+    request_tx: &flume::Sender<generation::Request>,
+) -> usize {
+    readiness.queue_depth.load(Ordering::SeqCst) + request_tx.len()
+}
+
+// How far off estimates are assumed to be before any request has finished
+const DEFAULT_RELATIVE_ERROR: f64 = 0.25;
+
+// The schedule as the generation thread last published it: the request it's running and the
+// ones waiting, in the order they'd run. Cheap to clone; every clone shares the same board
+#[derive(Clone, Default)]
+pub struct Board {
+    state: Arc<Mutex<BoardState>>,
+    // How many tokens the running request has generated so far. It's counted as each token
+    // is generated, so it's kept out of the lock
+    tokens_generated: Arc<AtomicUsize>,
+}
+
+#[derive(Default)]
+struct BoardState {
+    running: Option<Running>,
+    // The waiting requests, in the order they'd run
+    waiting: Vec<Entry>,
+    // Waiting requests that have been cancelled, for the generation thread to drop
+    cancelled: Vec<MessageId>,
+    // Requests sent from Discord that haven't started, with the senders that tell their
+    // handlers if they're cancelled before they do
+    submitted: HashMap<MessageId, flume::Sender<()>>,
+    // The estimator's figures, for working out how long requests have left
+    warm: bool,
+    ms_per_generated_token: f64,
+    typical_ms: u64,
+    relative_error: f64,
+}
+
+// A request on the board
+#[derive(Clone)]
+struct Entry {
+    message_id: MessageId,
+    context: generation::RequestContext,
+    prompt: String,
+    estimate_ms: u64,
+    // When it arrived, or when it started once it's running
+    since: Instant,
+}
+
+impl Entry {
+    fn new(request: &generation::Request, estimate_ms: u64, since: Instant) -> Self {
+        Self {
+            message_id: request.message_id,
+            context: request.context.clone(),
+            prompt: request.prompt.clone(),
+            estimate_ms,
+            since,
+        }
+    }
+}
+
+// The request being generated
+struct Running {
+    entry: Entry,
+    // How many tokens it should generate, and the most it can
+    expected_tokens: f64,
+    maximum_tokens: Option<usize>,
+}
+
+// Where a waiting request stands in the queue
+pub struct Standing {
+    // Its place among the waiting requests, from 1
+    pub position: usize,
+    // How many requests are waiting, including it
+    pub waiting: usize,
+    // How long it should wait before it starts, from soonest to latest. `None` until a request
+    // has finished to estimate from
+    pub wait: Option<(Duration, Duration)>,
+}
+
+// A request in a snapshot of the board
+pub struct Item {
+    pub message_id: MessageId,
+    pub context: generation::RequestContext,
+    pub prompt: String,
+    // How long it has been waiting, or running
+    pub elapsed: Duration,
+}
+
+// What the board shows at one moment
+pub struct Snapshot {
+    // The request being generated, with how many tokens it has generated so far
+    pub running: Option<(Item, usize)>,
+    // The waiting requests, in the order they'd run
+    pub waiting: Vec<Item>,
+}
+
+impl Board {
+    // function to publish the waiting requests, in the order they'd run
+    pub fn publish(&self, queue: &Queue, estimator: &Estimator) {
+        let mut state = self.state.lock().unwrap();
+        state.waiting = queue.in_order();
+        state.warm = estimator.is_warm();
+        state.ms_per_generated_token = estimator.ms_per_generated_token;
+        state.typical_ms = (estimator.generated_tokens * estimator.ms_per_generated_token) as u64;
+        state.relative_error = if estimator.relative_error > 0.0 {
+            estimator.relative_error
+        } else {
+            DEFAULT_RELATIVE_ERROR
+        };
+    }
+
+    // function to publish that a request has started, taking it off the waiting list.
+    // Returns `false` if it was cancelled while it waited, in which case it mustn't run
+    pub fn start(
+        &self,
+        request: &generation::Request,
+        estimate_ms: u64,
+        estimator: &Estimator,
+    ) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.waiting.retain(|e| e.message_id != request.message_id);
+        state.submitted.remove(&request.message_id);
+        if let Some(index) = state
+            .cancelled
+            .iter()
+            .position(|id| *id == request.message_id)
+        {
+            state.cancelled.remove(index);
+            return false;
+        }
+
+        state.running = Some(Running {
+            entry: Entry::new(request, estimate_ms, Instant::now()),
+            expected_tokens: estimator.expected_tokens(request),
+            maximum_tokens: request
+                .maximum_token_count
+                .map(|maximum| maximum * request.n_sequences.max(1)),
+        });
+        self.tokens_generated.store(0, Ordering::SeqCst);
+        true
+    }
+
+    // function to count a token generated by the running request
+    pub fn count_token(&self) {
+        self.tokens_generated.fetch_add(1, Ordering::SeqCst);
+    }
+
+    // function to publish that the running request has finished
+    pub fn finish(&self) {
+        self.state.lock().unwrap().running = None;
+    }
+
+    // function to note that a request is about to be sent to the generation thread, so that
+    // it can be cancelled before the thread takes it. The receiver gets a message if it's
+    // cancelled before it starts, and is disconnected once it starts
+    pub fn submit(&self, message_id: MessageId) -> flume::Receiver<()> {
+        let (removed_tx, removed_rx) = flume::bounded(1);
+        self.state
+            .lock()
+            .unwrap()
+            .submitted
+            .insert(message_id, removed_tx);
+        removed_rx
+    }
+
+    // function to cancel a request while it waits. Returns `false` if it isn't waiting (it may
+    // be running, which the generation thread's cancellation channel is for)
+    pub fn cancel(&self, message_id: MessageId) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let waiting = state.waiting.len();
+        state.waiting.retain(|e| e.message_id != message_id);
+        let submitted = state.submitted.remove(&message_id);
+        if state.waiting.len() == waiting && submitted.is_none() {
+            return false;
+        }
+        if let Some(removed_tx) = submitted {
+            removed_tx.try_send(()).ok();
+        }
+        state.cancelled.push(message_id);
+        true
+    }
+
+    // function to take the IDs of the waiting requests that have been cancelled
+    pub fn take_cancelled(&self) -> Vec<MessageId> {
+        std::mem::take(&mut self.state.lock().unwrap().cancelled)
+    }
+
+    // function to take a snapshot of the running and waiting requests
+    pub fn snapshot(&self) -> Snapshot {
+        let state = self.state.lock().unwrap();
+        let item = |entry: &Entry| Item {
+            message_id: entry.message_id,
+            context: entry.context.clone(),
+            prompt: entry.prompt.clone(),
+            elapsed: entry.since.elapsed(),
+        };
+        Snapshot {
+            running: state.running.as_ref().map(|running| {
+                (
+                    item(&running.entry),
+                    self.tokens_generated.load(Ordering::SeqCst),
+                )
+            }),
+            waiting: state.waiting.iter().map(item).collect(),
+        }
+    }
+
+    // function to find where a request stands, if it's waiting. `unclaimed` is the number of
+    // requests sent to the generation thread that it hasn't taken yet (it takes them between
+    // generations); a request that isn't on the board yet is counted as the last of those
+    pub fn standing(&self, message_id: MessageId, unclaimed: usize) -> Option<Standing> {
+        let state = self.state.lock().unwrap();
+        if state.running.as_ref().map(|r| r.entry.message_id) == Some(message_id) {
+            return None;
+        }
+
+        let waiting_ms = |waiting: &[Entry]| waiting.iter().map(|e| e.estimate_ms).sum();
+        let (position, ahead_ms): (usize, u64) = match state
+            .waiting
+            .iter()
+            .position(|e| e.message_id == message_id)
+        {
+            Some(index) => (index + 1, waiting_ms(&state.waiting[..index])),
+            // Not taken by the thread yet, or not sent at all
+            None if unclaimed > 0 => (
+                state.waiting.len() + unclaimed,
+                waiting_ms(&state.waiting) + state.typical_ms * (unclaimed as u64 - 1),
+            ),
+            None => return None,
+        };
+
+        let wait = state.warm.then(|| {
+            let wait_ms = (state.running_remaining_ms(&self.tokens_generated) + ahead_ms) as f64;
+            (
+                Duration::from_millis((wait_ms * (1.0 - state.relative_error).max(0.0)) as u64),
+                Duration::from_millis((wait_ms * (1.0 + state.relative_error)) as u64),
+            )
+        });
+        Some(Standing {
+            position,
+            waiting: state.waiting.len() + unclaimed,
+            wait,
+        })
+    }
+}
+
+impl BoardState {
+    // function to estimate how long the running request has left, from how many tokens it has
+    // generated out of how many it should
+    fn running_remaining_ms(&self, tokens_generated: &AtomicUsize) -> u64 {
+        let Some(running) = &self.running else {
+            return 0;
+        };
+        let running_since = running.entry.since;
+        let estimate_ms = running.entry.estimate_ms;
+
+        // Until the prompt has been fed, there's only the estimate to go on
+        let generated = tokens_generated.load(Ordering::SeqCst) as f64;
+        if generated == 0.0 {
+            let elapsed_ms = running_since.elapsed().as_millis() as u64;
+            return estimate_ms.saturating_sub(elapsed_ms);
+        }
+
+        // A request that has gone past its estimate is expected to go on as long again
+        let mut expected = running.expected_tokens;
+        if generated >= expected {
+            expected = generated * 2.0;
+        }
+        if let Some(maximum) = running.maximum_tokens {
+            expected = expected.min(maximum as f64);
+        }
+        ((expected - generated).max(0.0) * self.ms_per_generated_token) as u64
+    }
+}
+
+impl Standing {
+    // function to describe where the request stands, for telling its user
+    pub fn describe(&self) -> String {
+        let place = format!("Position {} of {}", self.position, self.waiting);
+        match self.wait {
+            Some((soonest, latest)) => {
+                format!("{place} · estimated wait {}", format_range(soonest, latest))
+            }
+            None => format!("{place} · no estimate yet"),
+        }
+    }
+}
+
+// function to write out a range of durations roughly, e.g. "~3–5 min" or "~20–40 s"
+fn format_range(soonest: Duration, latest: Duration) -> String {
+    let (soonest, latest, unit) = if latest.as_secs() >= 60 {
+        (soonest.as_secs() / 60, latest.as_secs().div_ceil(60), "min")
+    } else {
+        // Seconds are rounded to fives; nobody needs the exact second
+        (
+            soonest.as_secs() / 5 * 5,
+            latest.as_secs().div_ceil(5).max(1) * 5,
+            "s",
+        )
+    };
+
+    if soonest >= latest {
+        format!("~{latest} {unit}")
+    } else {
+        format!("~{soonest}–{latest} {unit}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // function to build a request for the test that can generate up to `maximum` tokens
+    fn request(message_id: u64, maximum: usize, low_priority: bool) -> generation::Request {
+        generation::Request {
+            prompt: "Hello".to_string(),
+            batch_size: 1,
+            batch_decode: false,
+            token_buffer_size: 1,
+            token_tx: flume::unbounded().0,
+            message_id: MessageId(message_id),
+            seed: None,
+            maximum_token_count: Some(maximum),
+            n_sequences: 1,
+            sampling: Default::default(),
+            low_priority,
+            echo_prompt: false,
+            context: generation::RequestContext {
+                guild_id: None,
+                channel_id: 1,
+                user_id: 1,
+                command_name: "test".to_string(),
+                shard_id: None,
+            },
+            progress_tx: None,
+            completion_tx: None,
+            trace: None,
+            queued_at: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn fast_lane_runs_first_but_yields_to_the_slow_lane() {
+        let inference = config::Configuration::default().inference;
+        let lanes = Lanes {
+            fast_tokens: 512,
+            streak: 2,
+            context_length: 2048,
+        };
+        assert_eq!(lanes.classify(&request(0, 100, false)), Lane::Fast);
+        assert_eq!(lanes.classify(&request(0, 1000, false)), Lane::Slow);
+
+        // A slow request arrives first, then a stream of fast ones, and a reminder
+        let mut queue = Queue::new(lanes);
+        let requests = [
+            request(1, 1000, false),
+            request(2, 100, false),
+            request(3, 100, false),
+            request(4, 100, false),
+            request(5, 100, true),
+        ];
+        queue.extend(requests.into_iter(), &Estimator::new(&inference));
+        assert_eq!(queue.lane_depths(), (4, 1));
+
+        let order: Vec<_> = queue.in_order().iter().map(|e| e.message_id.0).collect();
+        assert_eq!(order, [2, 3, 1, 4, 5]);
+        let popped: Vec<_> = std::iter::from_fn(|| queue.pop())
+            .map(|(r, _)| r.message_id.0)
+            .collect();
+        assert_eq!(popped, order);
+    }
+
+    // function to publish two waiting requests that are each expected to take a second,
+    // and find where the second stands
+    fn second_in_line(estimator: &Estimator) -> Standing {
+        let inference = config::Configuration::default().inference;
+        let mut queue = Queue::new(Lanes::new(&inference, 2048));
+        queue.extend(
+            [request(1, 1000, false), request(2, 1000, false)].into_iter(),
+            estimator,
+        );
+        let board = Board::default();
+        board.publish(&queue, estimator);
+        board.standing(MessageId(2), 0).unwrap()
+    }
+
+    // function to finish a request that generated 100 tokens in a second, for an estimator
+    // to learn from
+    fn finish_request(estimator: &mut Estimator) {
+        estimator.record(
+            &llm::InferenceStats {
+                feed_prompt_duration: Duration::ZERO,
+                prompt_tokens: 0,
+                predict_duration: Duration::from_secs(1),
+                predict_tokens: 100,
+            },
+            1,
+        );
+    }
+
+    #[test]
+    fn there_is_no_estimate_before_a_request_finishes() {
+        let estimator = Estimator::new(&config::Configuration::default().inference);
+        assert!(!estimator.is_warm());
+
+        let standing = second_in_line(&estimator);
+        assert_eq!((standing.position, standing.waiting), (2, 2));
+        assert!(standing.wait.is_none());
+        assert_eq!(standing.describe(), "Position 2 of 2 · no estimate yet");
+    }
+
+    #[test]
+    fn the_wait_range_widens_with_the_recorded_error() {
+        let mut estimator = Estimator::new(&config::Configuration::default().inference);
+        finish_request(&mut estimator);
+        assert!(estimator.is_warm());
+
+        // Without any errors recorded, estimates are assumed to be a quarter out either way
+        let standing = second_in_line(&estimator);
+        assert_eq!(
+            standing.wait,
+            Some((Duration::from_millis(750), Duration::from_millis(1250)))
+        );
+
+        // A request that took half as long again as its estimate widens the range to match
+        estimator.record_duration(1000, Duration::from_millis(1500));
+        let standing = second_in_line(&estimator);
+        assert_eq!(
+            standing.wait,
+            Some((Duration::from_millis(500), Duration::from_millis(1500)))
+        );
+        assert_eq!(
+            standing.describe(),
+            "Position 2 of 2 · estimated wait ~0–5 s"
+        );
+
+        // An error over the whole estimate can't make the soonest wait negative
+        estimator.record_duration(1000, Duration::from_millis(10_000));
+        let (soonest, latest) = second_in_line(&estimator).wait.unwrap();
+        assert_eq!(soonest, Duration::ZERO);
+        assert!(latest > Duration::from_millis(1500));
+    }
+}
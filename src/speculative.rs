@@ -0,0 +1,250 @@
+// This file holds speculative decoding, turned on with `enable_speculative_decoding` under
+// `[inference]` (and the `speculative` feature). A small draft model sharing the model's
+// tokenizer proposes `speculative_tokens` tokens, one after another, which is cheap. The model
+// then reads them all at once, which costs about as much as generating one token itself, and
+// keeps them for as long as it would have picked the same ones. At the first it wouldn't have,
+// it takes its own pick instead, and both models forget the rest. The output comes from the
+// same distribution as the model's alone; it's only faster when the draft guesses well.
+use std::time::Instant;
+
+use crate::generation::{self, InferenceError};
+
+// The model and the draft model that proposes tokens for it
+pub struct Models {
+    pub main: Box<dyn llm::Model>,
+    pub draft: Box<dyn llm::Model>,
+    // How many tokens the draft proposes at a time
+    pub tokens: usize,
+}
+
+impl Models {
+    // function to pair a model with its draft, if they can work together
+    pub fn new(
+        main: Box<dyn llm::Model>,
+        draft: Box<dyn llm::Model>,
+        tokens: usize,
+    ) -> anyhow::Result<Self> {
+        // The draft's tokens are checked by their IDs, which only line up with the same tokenizer
+        let (main_vocabulary, draft_vocabulary) = (main.tokenizer().len(), draft.tokenizer().len());
+        if main_vocabulary != draft_vocabulary {
+            anyhow::bail!(
+                "the draft model's vocabulary has {draft_vocabulary} tokens, but the model's \
+                 has {main_vocabulary}; they need to share a tokenizer"
+            );
+        }
+        // Rejected tokens are taken back out of both sessions
+        if !main.supports_rewind() || !draft.supports_rewind() {
+            anyhow::bail!("speculative decoding isn't supported for this model architecture");
+        }
+        Ok(Self {
+            main,
+            draft,
+            tokens,
+        })
+    }
+}
+
+// function to feed a request's prompt to the model and generate from it, like
+// `llm::InferenceSession::infer`, with the draft model proposing the tokens.
+// `session` is the model's own; the draft's session is started here
+pub fn infer(
+    models: &Models,
+    session: &mut llm::InferenceSession,
+    session_config: llm::InferenceSessionConfig,
+    rng: &mut impl rand::Rng,
+    request: &generation::Request,
+    params: &llm::InferenceParameters,
+    mut callback: impl FnMut(llm::InferenceResponse) -> Result<llm::InferenceFeedback, InferenceError>,
+) -> Result<llm::InferenceStats, llm::InferenceError> {
+    let (main, draft) = (models.main.as_ref(), models.draft.as_ref());
+    let mut draft_session = draft.start_session(session_config);
+    let vocabulary = main.tokenizer().len();
+    let mut stats = llm::InferenceStats::default();
+    let mut text = generation::Utf8Buffer::default();
+
+    // Both models read the prompt, but only the model's reading is passed on
+    let started = Instant::now();
+    let mut halted = false;
+    let prompt_logits = feed(main, session, &request.prompt, |bytes| {
+        let Some(token) = text.push(bytes) else {
+            return Ok(llm::InferenceFeedback::Continue);
+        };
+        let feedback = callback(llm::InferenceResponse::PromptToken(token))?;
+        halted = matches!(feedback, llm::InferenceFeedback::Halt);
+        Ok(feedback)
+    })?;
+    let mut main_logits = last_row(&prompt_logits, vocabulary);
+    stats.prompt_tokens = session.tokens().len();
+    if halted {
+        stats.feed_prompt_duration = started.elapsed();
+        return Ok(stats);
+    }
+    let mut draft_logits = last_row(
+        &feed(draft, &mut draft_session, &request.prompt, ignore)?,
+        vocabulary,
+    );
+    stats.feed_prompt_duration = started.elapsed();
+
+    let started = Instant::now();
+    let context_size = main.context_size().min(draft.context_size());
+    let (mut proposed, mut accepted) = (0, 0);
+    let result = loop {
+        let remaining = request
+            .maximum_token_count
+            .map_or(usize::MAX, |maximum| maximum - stats.predict_tokens);
+        if remaining == 0 {
+            break Ok(());
+        }
+        let room = context_size.saturating_sub(session.tokens().len());
+        if room == 0 {
+            break Err(llm::InferenceError::ContextFull);
+        }
+
+        // The draft proposes its tokens, reading each one before it picks the next
+        let mut proposal = vec![];
+        for _ in 0..models.tokens.min(remaining).min(room) {
+            let token = sample(params, rng, draft_session.tokens(), &draft_logits)?;
+            proposal.push(token);
+            if token == draft.eot_token_id() {
+                break;
+            }
+            draft_logits = last_row(
+                &feed(draft, &mut draft_session, &[token][..], ignore)?,
+                vocabulary,
+            );
+        }
+        let draft_read = draft_session.tokens().len();
+        proposed += proposal.len();
+
+        // The model reads the whole proposal at once, which gives what it would pick after each
+        // of its tokens, and keeps the tokens for as long as it agrees with them
+        let start = session.tokens().len();
+        let rows = feed(main, session, &proposal[..], ignore)?;
+        let mut disagreement = None;
+        let mut stopped = false;
+        for (i, &token) in proposal.iter().enumerate() {
+            let logits = if i == 0 {
+                &main_logits[..]
+            } else {
+                &rows[(i - 1) * vocabulary..i * vocabulary]
+            };
+            let pick = sample(params, rng, &session.tokens()[..start + i], logits)?;
+            if pick != token {
+                disagreement = Some((i, pick));
+                break;
+            }
+            accepted += 1;
+            stats.predict_tokens += 1;
+            if emit(main, token, &mut text, &mut callback)? {
+                stopped = true;
+                break;
+            }
+        }
+        if stopped {
+            break Ok(());
+        }
+
+        match disagreement {
+            // Every token was kept, so the model goes on from after the last of them
+            None => main_logits = last_row(&rows, vocabulary),
+            // Both models forget the rest of the proposal, and read the model's pick instead
+            Some((i, pick)) => {
+                rewind(main, session, proposal.len() - i)?;
+                rewind(draft, &mut draft_session, draft_read - (start + i))?;
+                stats.predict_tokens += 1;
+                if emit(main, pick, &mut text, &mut callback)? {
+                    break Ok(());
+                }
+                main_logits = last_row(&feed(main, session, &[pick][..], ignore)?, vocabulary);
+                draft_logits = last_row(
+                    &feed(draft, &mut draft_session, &[pick][..], ignore)?,
+                    vocabulary,
+                );
+            }
+        }
+    };
+    stats.predict_duration = started.elapsed();
+
+    debug!("The model kept {accepted} of the {proposed} tokens its draft proposed");
+    result.map(|_| stats)
+}
+
+// function to feed a prompt, or tokens, to a session, returning the model's logits after each
+// of the tokens it was fed last (up to a batch of them)
+fn feed<'a>(
+    model: &dyn llm::Model,
+    session: &mut llm::InferenceSession,
+    prompt: impl Into<llm::Prompt<'a>>,
+    callback: impl FnMut(&[u8]) -> Result<llm::InferenceFeedback, InferenceError>,
+) -> Result<Vec<f32>, llm::InferenceError> {
+    let mut output = llm::OutputRequest {
+        all_logits: Some(vec![]),
+        ..Default::default()
+    };
+    session.feed_prompt(model, prompt, &mut output, callback)?;
+    Ok(output.all_logits.unwrap_or_default())
+}
+
+// The callback for feeding tokens that aren't passed on
+fn ignore(_: &[u8]) -> Result<llm::InferenceFeedback, InferenceError> {
+    Ok(llm::InferenceFeedback::Continue)
+}
+
+// function to take the logits after the last token fed, out of the logits after every token
+fn last_row(logits: &[f32], vocabulary: usize) -> Vec<f32> {
+    logits[logits.len().saturating_sub(vocabulary)..].to_vec()
+}
+
+// function to pick the next token from a model's logits, the same way generation normally does
+fn sample(
+    params: &llm::InferenceParameters,
+    rng: &mut impl rand::Rng,
+    previous_tokens: &[llm::TokenId],
+    logits: &[f32],
+) -> Result<llm::TokenId, llm::InferenceError> {
+    llm::samplers::sample_token(
+        params.sampler.clone(),
+        rng,
+        previous_tokens,
+        logits.iter().copied(),
+    )
+}
+
+// function to take the last `count` tokens back out of a session
+fn rewind(
+    model: &dyn llm::Model,
+    session: &mut llm::InferenceSession,
+    count: usize,
+) -> Result<(), llm::InferenceError> {
+    if count == 0 {
+        return Ok(());
+    }
+    session
+        .rewind(model, count)
+        .map(|_| ())
+        // Errors from the callback are the ones that are passed back as the bot's own
+        .map_err(|e| {
+            llm::InferenceError::UserCallback(Box::new(InferenceError::custom(format!(
+                "failed to take back rejected tokens: {e}"
+            ))))
+        })
+}
+
+// function to pass a generated token to the callback. Returns whether generation should stop
+fn emit(
+    model: &dyn llm::Model,
+    token: llm::TokenId,
+    text: &mut generation::Utf8Buffer,
+    callback: &mut impl FnMut(llm::InferenceResponse) -> Result<llm::InferenceFeedback, InferenceError>,
+) -> Result<bool, llm::InferenceError> {
+    let callback_error = |e| llm::InferenceError::UserCallback(Box::new(e));
+    if token == model.eot_token_id() {
+        callback(llm::InferenceResponse::EotToken).map_err(callback_error)?;
+        return Ok(true);
+    }
+    let Some(text) = text.push(&model.tokenizer().token(token as usize)) else {
+        return Ok(false);
+    };
+    let feedback = callback(llm::InferenceResponse::InferredToken(text)).map_err(callback_error)?;
+    Ok(matches!(feedback, llm::InferenceFeedback::Halt))
+}
@@ -0,0 +1,764 @@
+// This file holds the persistent store, backed by SQLite.
+// Features that need durable storage (usage stats, quotas, conversations, embeddings,
+// system prompts, personas, feedback, reports, cached generations) go through
+// this module rather than each writing their own files. Writes are sent over a channel
+// to a background thread that batches them into transactions, so they never hold up
+// the Discord handler or the generation thread.
+// When persistence is disabled, the same schema lives in an in-memory database instead.
+use std::{
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as AnyhowContext;
+use rusqlite::{params, Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+
+use crate::{config, feedback::Vote, generation::RequestContext};
+
+// The schema migrations, applied in order. The database's `user_version` records
+// how many of them have been applied, so new migrations must only ever be appended
+const MIGRATIONS: &[&str] = &[
+    // 1: requests, conversations and quota counters
+    "
+    CREATE TABLE requests (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        user_id INTEGER NOT NULL,
+        guild_id INTEGER,
+        channel_id INTEGER NOT NULL,
+        command TEXT NOT NULL,
+        prompt_hash TEXT NOT NULL,
+        prompt_tokens INTEGER NOT NULL,
+        generated_tokens INTEGER NOT NULL,
+        started_at INTEGER NOT NULL,
+        finished_at INTEGER NOT NULL
+    );
+    CREATE INDEX requests_by_user ON requests (user_id, started_at);
+
+    CREATE TABLE conversations (
+        id INTEGER PRIMARY KEY AUTOINCREMENT,
+        conversation_key TEXT NOT NULL,
+        turn INTEGER NOT NULL,
+        role TEXT NOT NULL,
+        content TEXT NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX conversations_by_key ON conversations (conversation_key, turn);
+
+    CREATE TABLE quota_counters (
+        user_id INTEGER NOT NULL,
+        period TEXT NOT NULL,
+        count INTEGER NOT NULL,
+        PRIMARY KEY (user_id, period)
+    );
+    ",
+    // 2: named embeddings stored with `/embed`
+    "
+    CREATE TABLE embeddings (
+        scope TEXT NOT NULL,
+        name TEXT NOT NULL,
+        text TEXT NOT NULL,
+        vector BLOB NOT NULL,
+        created_at INTEGER NOT NULL,
+        PRIMARY KEY (scope, name)
+    );
+    ",
+    // 3: guilds' system prompts set with `/system`
+    "
+    CREATE TABLE system_prompts (
+        guild_id INTEGER PRIMARY KEY,
+        system_prompt TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    ",
+    // 4: channels' personas chosen with `/persona`
+    "
+    CREATE TABLE channel_personas (
+        channel_id INTEGER PRIMARY KEY,
+        persona TEXT NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    ",
+    // 5: users' votes on responses, from the feedback reactions
+    "
+    CREATE TABLE feedback (
+        response_id INTEGER NOT NULL,
+        user_id INTEGER NOT NULL,
+        command TEXT NOT NULL,
+        vote INTEGER NOT NULL,
+        voted_at INTEGER NOT NULL,
+        PRIMARY KEY (response_id, user_id)
+    );
+    ",
+    // 6: completed generations, reused for identical requests
+    "
+    CREATE TABLE prompt_cache (
+        cache_key TEXT PRIMARY KEY,
+        text TEXT NOT NULL,
+        seed INTEGER NOT NULL,
+        generated_tokens INTEGER NOT NULL,
+        hit_token_limit INTEGER NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE INDEX prompt_cache_by_age ON prompt_cache (created_at);
+    ",
+    // 7: what each voted-on response was generated with, for `/stats`. Votes recorded before
+    // this have neither
+    "
+    ALTER TABLE feedback ADD COLUMN template_hash TEXT;
+    ALTER TABLE feedback ADD COLUMN model TEXT;
+    ",
+    // 8: responses reported to the moderators
+    "
+    CREATE TABLE reports (
+        response_id INTEGER NOT NULL,
+        reporter_id INTEGER NOT NULL,
+        command TEXT NOT NULL,
+        reported_at INTEGER NOT NULL,
+        PRIMARY KEY (response_id, reporter_id)
+    );
+    ",
+];
+
+// The most writes that are grouped into a single transaction
+const MAX_WRITE_BATCH: usize = 64;
+
+// A completed generation, as recorded in the `requests` table
+pub struct RequestRecord {
+    // Who made the request, and where
+    pub context: RequestContext,
+    // The prompt that was generated from (only its hash is stored)
+    pub prompt: String,
+    // The number of tokens in the prompt
+    pub prompt_tokens: usize,
+    // The number of tokens that were generated
+    pub generated_tokens: usize,
+    // When generation started and finished, in seconds since the Unix epoch
+    pub started_at: u64,
+    pub finished_at: u64,
+}
+
+// A named piece of text and its embedding, as stored in the `embeddings` table
+pub struct EmbeddingRecord {
+    // Where the name is valid (a guild, or a user's DMs)
+    pub scope: String,
+    // The name it was stored under
+    pub name: String,
+    // The text that was embedded
+    pub text: String,
+    // The embedding itself
+    pub vector: Vec<f32>,
+}
+
+// A user's vote on a response, as stored in the `feedback` table
+pub struct FeedbackRecord {
+    // The ID of the response's first message
+    pub response_id: u64,
+    // Who voted
+    pub user_id: u64,
+    // The command the response is from
+    pub command_name: String,
+    // The hash of the command's template and the model the response was generated with
+    // (see `details::GenerationRecord`)
+    pub template_hash: String,
+    pub model: String,
+    // The vote, or `None` if it was taken back
+    pub vote: Option<Vote>,
+}
+
+// A user's report of a response, as stored in the `reports` table
+pub struct ReportRecord {
+    // The ID of the response's first message
+    pub response_id: u64,
+    // Who reported it
+    pub reporter_id: u64,
+    // The command the response is from
+    pub command_name: String,
+}
+
+// How a command's responses have been received, as summed up from the `feedback`, `reports`
+// and `requests` tables
+pub struct FeedbackStats {
+    // The command the responses are from
+    pub command_name: String,
+    // How many votes were up and down
+    pub up: u64,
+    pub down: u64,
+    // How many of its responses were reported, and how many requests it has had
+    pub reported: u64,
+    pub requests: u64,
+}
+
+// A completed generation, as stored in the `prompt_cache` table
+pub struct CachedGeneration {
+    // The hash of the request it was generated for (see `prompt_cache::key`)
+    pub key: String,
+    // The generated text, without the prompt
+    pub text: String,
+    // The seed it was sampled with
+    pub seed: u64,
+    // The number of tokens that were generated
+    pub generated_tokens: usize,
+    // Whether generation stopped at the token limit, rather than at the end of the text
+    pub hit_token_limit: bool,
+}
+
+// A write to be applied by the background writer thread
+enum Write {
+    // Records a completed request, and counts it towards the user's daily quota
+    Request(RequestRecord),
+    // Stores an embedding, replacing any with the same scope and name
+    Embedding(EmbeddingRecord),
+    // Sets a guild's system prompt, or clears it if there isn't one
+    SystemPrompt(u64, Option<String>),
+    // Sets a channel's persona, or clears it if there isn't one
+    Persona(u64, Option<String>),
+    // Records a user's vote on a response, replacing any earlier one, or removes it
+    Feedback(FeedbackRecord),
+    // Records a user's report of a response
+    Report(ReportRecord),
+    // Stores a completed generation, and removes the ones created before the given time
+    CachedGeneration(CachedGeneration, u64),
+}
+
+// A handle to the store. This is cheap to clone, and every clone shares the same database
+#[derive(Clone)]
+pub struct Store {
+    // Sender for writes, which are applied in batches by the writer thread
+    write_tx: flume::Sender<Write>,
+    // The path to the database, if it's on disk, so that it can be read from at startup
+    path: Option<PathBuf>,
+}
+
+impl Store {
+    // function to open the store described by the configuration,
+    // creating and migrating the database as needed
+    pub fn open(persistence: &config::Persistence) -> anyhow::Result<Self> {
+        let mut connection = if persistence.enabled {
+            Connection::open(&persistence.path).with_context(|| {
+                format!(
+                    "failed to open database {}",
+                    persistence.path.to_string_lossy()
+                )
+            })?
+        } else {
+            Connection::open_in_memory()?
+        };
+        migrate(&mut connection)?;
+
+        let (write_tx, write_rx) = flume::unbounded();
+
+        // The writer thread owns the connection, and stops once every `Store`
+        // (and so every sender) is dropped
+        std::thread::spawn(move || {
+            while let Ok(first) = write_rx.recv() {
+                // Group whatever else is already waiting into the same transaction
+                let mut batch = vec![first];
+                batch.extend(write_rx.try_iter().take(MAX_WRITE_BATCH - 1));
+
+                if let Err(err) = apply_writes(&mut connection, batch) {
+                    error!("Failed to write to the store: {err:?}");
+                }
+            }
+        });
+
+        Ok(Self {
+            write_tx,
+            path: persistence.enabled.then(|| persistence.path.clone()),
+        })
+    }
+
+    // function to record a completed request. This returns immediately;
+    // the write happens in the background
+    pub fn record_request(&self, record: RequestRecord) {
+        self.write_tx.send(Write::Request(record)).ok();
+    }
+
+    // function to store an embedding. This returns immediately;
+    // the write happens in the background
+    pub fn save_embedding(&self, record: EmbeddingRecord) {
+        self.write_tx.send(Write::Embedding(record)).ok();
+    }
+
+    // function to set (or, with `None`, clear) a guild's system prompt. This returns
+    // immediately; the write happens in the background
+    pub fn save_system_prompt(&self, guild_id: u64, system_prompt: Option<String>) {
+        self.write_tx
+            .send(Write::SystemPrompt(guild_id, system_prompt))
+            .ok();
+    }
+
+    // function to read every guild's stored system prompt, for startup (see `load_embeddings`)
+    pub fn load_system_prompts(&self) -> anyhow::Result<Vec<(u64, String)>> {
+        let Some(path) = &self.path else {
+            return Ok(vec![]);
+        };
+
+        let connection = Connection::open(path)?;
+        let mut statement =
+            connection.prepare("SELECT guild_id, system_prompt FROM system_prompts")?;
+        let prompts = statement
+            .query_map([], |r| Ok((r.get::<_, i64>(0)? as u64, r.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(prompts)
+    }
+
+    // function to set (or, with `None`, clear) a channel's persona. This returns
+    // immediately; the write happens in the background
+    pub fn save_persona(&self, channel_id: u64, persona: Option<String>) {
+        self.write_tx.send(Write::Persona(channel_id, persona)).ok();
+    }
+
+    // function to read every channel's stored persona, for startup (see `load_embeddings`)
+    pub fn load_personas(&self) -> anyhow::Result<Vec<(u64, String)>> {
+        let Some(path) = &self.path else {
+            return Ok(vec![]);
+        };
+
+        let connection = Connection::open(path)?;
+        let mut statement =
+            connection.prepare("SELECT channel_id, persona FROM channel_personas")?;
+        let personas = statement
+            .query_map([], |r| Ok((r.get::<_, i64>(0)? as u64, r.get(1)?)))?
+            .collect::<Result<_, _>>()?;
+
+        Ok(personas)
+    }
+
+    // function to record (or, without a vote, remove) a user's vote on a response. This
+    // returns immediately; the write happens in the background
+    pub fn save_feedback(&self, record: FeedbackRecord) {
+        self.write_tx.send(Write::Feedback(record)).ok();
+    }
+
+    // Whether or not the store is kept on disk (rather than starting empty every time)
+    pub fn is_persistent(&self) -> bool {
+        self.path.is_some()
+    }
+
+    // function to store a completed generation, and forget the ones created before
+    // `expires_before`. This returns immediately; the write happens in the background
+    pub fn save_cached_generation(&self, record: CachedGeneration, expires_before: u64) {
+        self.write_tx
+            .send(Write::CachedGeneration(record, expires_before))
+            .ok();
+    }
+
+    // function to read the generation stored under a key, if it was created after
+    // `created_after`. Unlike the other reads, this happens while the bot runs, on the
+    // generation thread; it's a single indexed lookup
+    pub fn load_cached_generation(
+        &self,
+        key: &str,
+        created_after: u64,
+    ) -> anyhow::Result<Option<CachedGeneration>> {
+        let Some(path) = &self.path else {
+            return Ok(None);
+        };
+
+        let connection = Connection::open(path)?;
+        let record = connection
+            .query_row(
+                "SELECT text, seed, generated_tokens, hit_token_limit FROM prompt_cache
+                WHERE cache_key = ?1 AND created_at > ?2",
+                params![key, created_after as i64],
+                |r| {
+                    Ok(CachedGeneration {
+                        key: key.to_string(),
+                        text: r.get(0)?,
+                        seed: r.get::<_, i64>(1)? as u64,
+                        generated_tokens: r.get::<_, i64>(2)? as usize,
+                        hit_token_limit: r.get(3)?,
+                    })
+                },
+            )
+            .optional()?;
+
+        Ok(record)
+    }
+
+    // function to record a user's report of a response. This returns immediately;
+    // the write happens in the background
+    pub fn save_report(&self, record: ReportRecord) {
+        self.write_tx.send(Write::Report(record)).ok();
+    }
+
+    // function to sum up the votes on and reports of each command's responses, for `/stats`.
+    // Like `load_cached_generation`, this happens while the bot runs
+    pub fn load_feedback_stats(&self) -> anyhow::Result<Vec<FeedbackStats>> {
+        let Some(path) = &self.path else {
+            return Ok(vec![]);
+        };
+
+        let connection = Connection::open(path)?;
+        let mut statement = connection.prepare(
+            "SELECT c.command,
+                (SELECT COUNT(*) FROM feedback f WHERE f.command = c.command AND f.vote > 0),
+                (SELECT COUNT(*) FROM feedback f WHERE f.command = c.command AND f.vote < 0),
+                (SELECT COUNT(DISTINCT response_id) FROM reports r WHERE r.command = c.command),
+                (SELECT COUNT(*) FROM requests q WHERE q.command = c.command)
+            FROM (SELECT command FROM feedback UNION SELECT command FROM reports) c
+            ORDER BY c.command",
+        )?;
+        let stats = statement
+            .query_map([], |r| {
+                Ok(FeedbackStats {
+                    command_name: r.get(0)?,
+                    up: r.get::<_, i64>(1)? as u64,
+                    down: r.get::<_, i64>(2)? as u64,
+                    reported: r.get::<_, i64>(3)? as u64,
+                    requests: r.get::<_, i64>(4)? as u64,
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(stats)
+    }
+
+    // function to read every stored embedding. This is meant for startup, and uses its own
+    // connection; an in-memory store starts empty, so there's nothing to read from it
+    pub fn load_embeddings(&self) -> anyhow::Result<Vec<EmbeddingRecord>> {
+        let Some(path) = &self.path else {
+            return Ok(vec![]);
+        };
+
+        let connection = Connection::open(path)?;
+        let mut statement =
+            connection.prepare("SELECT scope, name, text, vector FROM embeddings")?;
+        let records = statement
+            .query_map([], |r| {
+                Ok(EmbeddingRecord {
+                    scope: r.get(0)?,
+                    name: r.get(1)?,
+                    text: r.get(2)?,
+                    vector: decode_vector(&r.get::<_, Vec<u8>>(3)?),
+                })
+            })?
+            .collect::<Result<_, _>>()?;
+
+        Ok(records)
+    }
+}
+
+// function to bring the database schema up to date
+fn migrate(connection: &mut Connection) -> anyhow::Result<()> {
+    let version: usize = connection.query_row("PRAGMA user_version", [], |r| r.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        // Each migration is applied atomically, along with the version bump
+        let transaction = connection.transaction()?;
+        transaction
+            .execute_batch(migration)
+            .with_context(|| format!("failed to apply store migration {}", index + 1))?;
+        transaction.pragma_update(None, "user_version", index + 1)?;
+        transaction.commit()?;
+    }
+
+    Ok(())
+}
+
+// function to apply a batch of writes in a single transaction
+fn apply_writes(connection: &mut Connection, batch: Vec<Write>) -> anyhow::Result<()> {
+    let transaction = connection.transaction()?;
+
+    for write in batch {
+        match write {
+            Write::Request(record) => {
+                let context = &record.context;
+                transaction.execute(
+                    "INSERT INTO requests (user_id, guild_id, channel_id, command, prompt_hash,
+                        prompt_tokens, generated_tokens, started_at, finished_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+                    params![
+                        context.user_id as i64,
+                        context.guild_id.map(|id| id as i64),
+                        context.channel_id as i64,
+                        context.command_name,
+                        hash_prompt(&record.prompt),
+                        record.prompt_tokens as i64,
+                        record.generated_tokens as i64,
+                        record.started_at as i64,
+                        record.finished_at as i64,
+                    ],
+                )?;
+
+                // Count the request towards the user's quota for the day it started
+                transaction.execute(
+                    "INSERT INTO quota_counters (user_id, period, count) VALUES (?1, ?2, 1)
+                    ON CONFLICT (user_id, period) DO UPDATE SET count = count + 1",
+                    params![context.user_id as i64, day_period(record.started_at)],
+                )?;
+            }
+            Write::Embedding(record) => {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO embeddings (scope, name, text, vector, created_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![
+                        record.scope,
+                        record.name,
+                        record.text,
+                        encode_vector(&record.vector),
+                        now() as i64,
+                    ],
+                )?;
+            }
+            Write::SystemPrompt(guild_id, Some(system_prompt)) => {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO system_prompts (guild_id, system_prompt, updated_at)
+                    VALUES (?1, ?2, ?3)",
+                    params![guild_id as i64, system_prompt, now() as i64],
+                )?;
+            }
+            Write::SystemPrompt(guild_id, None) => {
+                transaction.execute(
+                    "DELETE FROM system_prompts WHERE guild_id = ?1",
+                    params![guild_id as i64],
+                )?;
+            }
+            Write::Persona(channel_id, Some(persona)) => {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO channel_personas (channel_id, persona, updated_at)
+                    VALUES (?1, ?2, ?3)",
+                    params![channel_id as i64, persona, now() as i64],
+                )?;
+            }
+            Write::Persona(channel_id, None) => {
+                transaction.execute(
+                    "DELETE FROM channel_personas WHERE channel_id = ?1",
+                    params![channel_id as i64],
+                )?;
+            }
+            Write::Feedback(FeedbackRecord {
+                response_id,
+                user_id,
+                command_name,
+                template_hash,
+                model,
+                vote: Some(vote),
+            }) => {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO feedback
+                        (response_id, user_id, command, template_hash, model, vote, voted_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                    params![
+                        response_id as i64,
+                        user_id as i64,
+                        command_name,
+                        template_hash,
+                        model,
+                        vote.value(),
+                        now() as i64,
+                    ],
+                )?;
+            }
+            Write::Feedback(FeedbackRecord {
+                response_id,
+                user_id,
+                vote: None,
+                ..
+            }) => {
+                transaction.execute(
+                    "DELETE FROM feedback WHERE response_id = ?1 AND user_id = ?2",
+                    params![response_id as i64, user_id as i64],
+                )?;
+            }
+            Write::Report(ReportRecord {
+                response_id,
+                reporter_id,
+                command_name,
+            }) => {
+                transaction.execute(
+                    "INSERT OR IGNORE INTO reports (response_id, reporter_id, command, reported_at)
+                    VALUES (?1, ?2, ?3, ?4)",
+                    params![
+                        response_id as i64,
+                        reporter_id as i64,
+                        command_name,
+                        now() as i64,
+                    ],
+                )?;
+            }
+            Write::CachedGeneration(record, expires_before) => {
+                transaction.execute(
+                    "INSERT OR REPLACE INTO prompt_cache
+                        (cache_key, text, seed, generated_tokens, hit_token_limit, created_at)
+                    VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                    params![
+                        record.key,
+                        record.text,
+                        record.seed as i64,
+                        record.generated_tokens as i64,
+                        record.hit_token_limit,
+                        now() as i64,
+                    ],
+                )?;
+                transaction.execute(
+                    "DELETE FROM prompt_cache WHERE created_at < ?1",
+                    params![expires_before as i64],
+                )?;
+            }
+        }
+    }
+
+    Ok(transaction.commit()?)
+}
+
+// function to hash a prompt, so that prompts can be compared without storing them
+fn hash_prompt(prompt: &str) -> String {
+    format!("{:x}", Sha256::digest(prompt.as_bytes()))
+}
+
+// function to encode an embedding as a blob of little-endian floats
+fn encode_vector(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+// function to decode an embedding stored with `encode_vector`
+fn decode_vector(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+// function to get the quota period (the UTC day number) that a timestamp falls into
+fn day_period(timestamp: u64) -> String {
+    format!("day:{}", timestamp / (24 * 60 * 60))
+}
+
+// function to get the current time as seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    // function to count the rows of a table
+    fn count(connection: &Connection, table: &str) -> i64 {
+        connection
+            .query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |r| r.get(0))
+            .unwrap()
+    }
+
+    // function to make a request record for a user
+    fn request(user_id: u64, started_at: u64) -> RequestRecord {
+        RequestRecord {
+            context: RequestContext {
+                guild_id: Some(1),
+                channel_id: 2,
+                user_id,
+                command_name: "hallucinate".to_string(),
+                shard_id: None,
+            },
+            prompt: "Hello".to_string(),
+            prompt_tokens: 3,
+            generated_tokens: 10,
+            started_at,
+            finished_at: started_at + 1,
+        }
+    }
+
+    #[test]
+    fn a_database_at_any_version_is_migrated_to_the_latest() {
+        for version in 0..=MIGRATIONS.len() {
+            // A database left at `version` by an older build
+            let mut connection = Connection::open_in_memory().unwrap();
+            for migration in &MIGRATIONS[..version] {
+                connection.execute_batch(migration).unwrap();
+            }
+            connection
+                .pragma_update(None, "user_version", version)
+                .unwrap();
+            if version >= 1 {
+                connection
+                    .execute(
+                        "INSERT INTO quota_counters (user_id, period, count) VALUES (1, 'day:1', 5)",
+                        [],
+                    )
+                    .unwrap();
+            }
+
+            migrate(&mut connection).unwrap();
+            let migrated: usize = connection
+                .query_row("PRAGMA user_version", [], |r| r.get(0))
+                .unwrap();
+            assert_eq!(migrated, MIGRATIONS.len(), "from version {version}");
+
+            // Every table is there, and what was stored before is kept
+            for table in [
+                "requests",
+                "embeddings",
+                "feedback",
+                "prompt_cache",
+                "reports",
+            ] {
+                count(&connection, table);
+            }
+            assert_eq!(
+                count(&connection, "quota_counters"),
+                (version >= 1) as i64,
+                "from version {version}"
+            );
+
+            // Migrating again changes nothing
+            migrate(&mut connection).unwrap();
+        }
+    }
+
+    #[test]
+    fn requests_recorded_from_many_threads_are_all_kept() {
+        let path =
+            std::env::temp_dir().join(format!("llmcord-store-test-{}.sqlite3", std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let store = Store::open(&config::Persistence {
+            enabled: true,
+            path: path.clone(),
+        })
+        .unwrap();
+
+        // Eight threads record 50 requests each, for two users on the same day
+        let started_at = 1_700_000_000;
+        let threads: Vec<_> = (0..8)
+            .map(|thread| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for _ in 0..50 {
+                        store.record_request(request(thread % 2, started_at));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // The writes land in the background, so wait for them
+        let connection = Connection::open(&path).unwrap();
+        connection.busy_timeout(Duration::from_secs(5)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while count(&connection, "requests") < 400 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(count(&connection, "requests"), 400);
+
+        // Each user's requests are counted towards their quota exactly once
+        let counts: Vec<(i64, i64)> = connection
+            .prepare("SELECT user_id, count FROM quota_counters ORDER BY user_id")
+            .unwrap()
+            .query_map([], |r| Ok((r.get(0)?, r.get(1)?)))
+            .unwrap()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(counts, [(0, 200), (1, 200)]);
+
+        drop((store, connection));
+        std::fs::remove_file(path).ok();
+    }
+}
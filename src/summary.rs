@@ -0,0 +1,82 @@
+// This file holds the summaries of long conversations' oldest turns.
+// When a conversation is over the summarization threshold, the turns that don't fit are
+// summarized by the model and the summary takes their place in the prompt. Summaries are
+// remembered by the turns they cover, so that the next message in the same conversation
+// reuses the summary, and a conversation that keeps growing has its summary summarized
+// together with the newly old turns, rather than summarizing everything again.
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+
+use crate::{chat::Role, config, util};
+
+// What the summary is introduced with in the prompt, to mark it as a summary
+pub const SUMMARY_PREFIX: &str = "Summary of the earlier conversation: ";
+
+// How many summaries are remembered; the oldest are forgotten first
+const MAX_SUMMARIES: usize = 256;
+
+// The summaries generated so far, keyed by the turns they cover.
+// Cheap to clone; every clone shares the same list
+#[derive(Clone, Default)]
+pub struct Summaries(Arc<Mutex<util::BoundedMap<String, String, MAX_SUMMARIES>>>);
+
+impl Summaries {
+    // function to find the remembered summary that covers the most of the first turns of a
+    // conversation, with the number of turns it covers
+    pub fn find(&self, turns: &[(Role, &str)]) -> Option<(usize, String)> {
+        let keys = prefix_keys(turns);
+        let summaries = self.0.lock().unwrap();
+        keys.iter()
+            .enumerate()
+            .rev()
+            .find_map(|(i, key)| Some((i + 1, summaries.get(key)?.clone())))
+    }
+
+    // function to remember the summary of the first turns of a conversation
+    pub fn insert(&self, turns: &[(Role, &str)], summary: String) {
+        let Some(key) = prefix_keys(turns).pop() else {
+            return;
+        };
+
+        self.0.lock().unwrap().insert(key, summary);
+    }
+}
+
+// function to write out the prompt that summarizes some turns, starting from an earlier
+// summary of the turns before them if there is one
+pub fn prompt(
+    settings: &config::Summarization,
+    previous: Option<&str>,
+    turns: &[(Role, &str)],
+) -> String {
+    let mut lines: Vec<String> = previous
+        .map(|summary| format!("summary: {summary}"))
+        .into_iter()
+        .collect();
+    lines.extend(
+        turns
+            .iter()
+            .map(|(role, content)| format!("{}: {content}", role.as_str())),
+    );
+
+    settings
+        .prompt
+        .replace("{{CONVERSATION}}", &lines.join("\n"))
+}
+
+// function to work out a key for each of the first turns of a conversation (the first one,
+// the first two, ...), each built on the one before it
+fn prefix_keys(turns: &[(Role, &str)]) -> Vec<String> {
+    let mut hasher = Sha256::new();
+    turns
+        .iter()
+        .map(|(role, content)| {
+            // Lengths go in too, so that moving text between turns changes the key
+            hasher.update(role.as_str());
+            hasher.update(content.len().to_le_bytes());
+            hasher.update(content);
+            format!("{:x}", hasher.clone().finalize())
+        })
+        .collect()
+}
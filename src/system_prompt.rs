@@ -0,0 +1,197 @@
+// This file holds the guilds' system prompts, and the `/system` command that server admins
+// change them with. A prompt set with `/system` takes precedence over the guild's one in the
+// config, which takes precedence over the global one. Prompts set at runtime are kept in the
+// store, so that they survive restarts when persistence is on.
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::Context as AnyhowContext;
+use serenity::{
+    builder::CreateApplicationCommand,
+    http::Http,
+    model::{
+        prelude::{
+            command::CommandOptionType,
+            interaction::{
+                application_command::ApplicationCommandInteraction, InteractionResponseType,
+            },
+        },
+        Permissions,
+    },
+};
+
+use crate::{
+    config::{self, Configuration},
+    constant, persona, store, util,
+};
+
+// The longest system prompt that can be set, in characters
+const MAX_SYSTEM_PROMPT_LENGTH: usize = 4000;
+
+// The system prompts set with `/system`, by guild
+pub struct SystemPrompts {
+    // The prompt for each guild that has one
+    guilds: Mutex<HashMap<u64, String>>,
+    // The store that prompts are persisted to
+    store: store::Store,
+}
+
+impl SystemPrompts {
+    // function to load the prompts that were persisted by earlier runs, if any.
+    // If they can't be read, the bot starts with the ones in the config
+    pub fn load(store: store::Store) -> Self {
+        let guilds = store.load_system_prompts().unwrap_or_else(|err| {
+            warn!("Failed to load stored system prompts: {err:?}");
+            vec![]
+        });
+
+        Self {
+            guilds: Mutex::new(guilds.into_iter().collect()),
+            store,
+        }
+    }
+
+    // function to find the system prompt for a guild (`None` for DMs), if it has one
+    pub fn resolve(&self, config: &Configuration, guild_id: Option<u64>) -> Option<String> {
+        let set = guild_id.and_then(|id| self.guilds.lock().unwrap().get(&id).cloned());
+        set.or_else(|| config.system_prompt(guild_id).map(str::to_string))
+    }
+
+    // function to set (or, with `None`, clear) a guild's system prompt
+    fn set(&self, guild_id: u64, system_prompt: Option<String>) {
+        self.store
+            .save_system_prompt(guild_id, system_prompt.clone());
+
+        let mut guilds = self.guilds.lock().unwrap();
+        match system_prompt {
+            Some(system_prompt) => guilds.insert(guild_id, system_prompt),
+            None => guilds.remove(&guild_id),
+        };
+    }
+}
+
+// function to find the system prompt a command gets in a channel: none unless the command
+// uses one, and otherwise the channel's persona's, or else the guild's (`None` for DMs).
+// The persona brings its own sampling changes too, so it's returned with its name if it's used
+pub fn for_channel<'a>(
+    config: &'a Configuration,
+    command: &config::Command,
+    personas: &persona::ChannelPersonas,
+    system_prompts: &SystemPrompts,
+    guild_id: Option<u64>,
+    channel_id: u64,
+) -> (Option<(&'a str, &'a config::Persona)>, Option<String>) {
+    if !command.use_system_prompt {
+        return (None, None);
+    }
+    match personas.active(config, channel_id) {
+        Some(persona) => (Some(persona), Some(persona.1.system_prompt.clone())),
+        None => (None, system_prompts.resolve(config, guild_id)),
+    }
+}
+
+// function to handle `/system`, which sets, shows and clears the guild's system prompt
+pub async fn system_command(
+    cmd: &ApplicationCommandInteraction,
+    http: &Http,
+    config: &Configuration,
+    system_prompts: &SystemPrompts,
+) -> anyhow::Result<()> {
+    use constant::value as v;
+
+    let guild_id = cmd
+        .guild_id
+        .ok_or_else(|| util::user_error("This command can only be used in a server."))?
+        .0;
+
+    // Discord hides the command from everyone else, but that can be overridden per server
+    let permissions = cmd
+        .member
+        .as_ref()
+        .and_then(|m| m.permissions)
+        .unwrap_or_default();
+    if !permissions.administrator() && !permissions.manage_guild() {
+        return Err(util::user_error(
+            "You need the Manage Server permission to use this command.",
+        ));
+    }
+
+    let subcommand = cmd
+        .data
+        .options
+        .first()
+        .context("no subcommand specified")?;
+    let content = match subcommand.name.as_str() {
+        v::SET => {
+            let text = util::get_value(&subcommand.options, v::TEXT)
+                .and_then(util::value_to_string)
+                .context("no text specified")?;
+            if text.chars().count() > MAX_SYSTEM_PROMPT_LENGTH {
+                return Err(util::user_error(format!(
+                    "The system prompt can be at most {MAX_SYSTEM_PROMPT_LENGTH} characters long."
+                )));
+            }
+            system_prompts.set(guild_id, Some(text));
+            "The system prompt has been set.".to_string()
+        }
+        v::SHOW => match system_prompts.resolve(config, Some(guild_id)) {
+            Some(system_prompt) => format!("The system prompt is:\n>>> {system_prompt}"),
+            None => "There is no system prompt.".to_string(),
+        },
+        v::CLEAR => {
+            system_prompts.set(guild_id, None);
+            match config.system_prompt(Some(guild_id)) {
+                Some(_) => "The system prompt has been reset to the one in the config.",
+                None => "The system prompt has been cleared.",
+            }
+            .to_string()
+        }
+        other => anyhow::bail!("unknown subcommand `{other}`"),
+    };
+
+    cmd.create_interaction_response(http, |r| {
+        r.kind(InteractionResponseType::ChannelMessageWithSource)
+            .interaction_response_data(|d| {
+                d.content(content)
+                    .ephemeral(true)
+                    .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse())
+            })
+    })
+    .await?;
+
+    Ok(())
+}
+
+// function to build the `/system` command, for registering with Discord
+pub fn command() -> CreateApplicationCommand {
+    use constant::value as v;
+
+    let mut system = CreateApplicationCommand::default();
+    system
+        .name(constant::command::SYSTEM)
+        .description("Sets the system prompt the bot uses in this server.")
+        // Only for server admins, and only in servers
+        .default_member_permissions(Permissions::MANAGE_GUILD)
+        .dm_permission(false)
+        .create_option(|sub| {
+            sub.name(v::SET)
+                .description("Sets the system prompt.")
+                .kind(CommandOptionType::SubCommand)
+                .create_sub_option(|opt| {
+                    opt.name(v::TEXT)
+                        .description("The system prompt.")
+                        .kind(CommandOptionType::String)
+                        .required(true)
+                })
+        })
+        .create_option(|sub| {
+            sub.name(v::SHOW)
+                .description("Shows the system prompt.")
+                .kind(CommandOptionType::SubCommand)
+        })
+        .create_option(|sub| {
+            sub.name(v::CLEAR)
+                .description("Clears the system prompt set with this command.")
+                .kind(CommandOptionType::SubCommand)
+        });
+    system
+}
@@ -0,0 +1,102 @@
+// This file holds the sidecar tokenizer config set with `tokenizer_config_file` under `[model]`.
+// It's the `tokenizer_config.json` that comes with a model on Hugging Face. Some quantized models
+// end their answers with a marker that the file format doesn't know is special, such as
+// `<|im_end|>`; it's produced as text, and would otherwise be shown and generated past. Its
+// `eos_token` ends generation just like the model's own end-of-text token does.
+// `llm` always starts prompts with the model's own beginning-of-text token, so `bos_token` is only
+// checked against it. `chat_template` is a Jinja template, which the bot can't run; `chat_format`
+// describes conversations instead.
+use std::path::Path;
+
+use anyhow::Context as AnyhowContext;
+use serde::Deserialize;
+
+// The parts of a `tokenizer_config.json` the bot uses
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct TokenizerConfig {
+    // The text of the token that prompts start with
+    #[serde(default, deserialize_with = "token_text")]
+    pub bos_token: Option<String>,
+    // The text of the token that answers end with
+    #[serde(default, deserialize_with = "token_text")]
+    pub eos_token: Option<String>,
+    // The template conversations are written out with
+    #[serde(default)]
+    pub chat_template: Option<String>,
+}
+
+// A token is written either as its text, or as an object with the text as its `content`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Token {
+    Text(String),
+    Added { content: String },
+}
+
+// function to read a token as its text, whichever way it's written
+fn token_text<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<String>, D::Error> {
+    let token: Option<Token> = Option::deserialize(deserializer)?;
+    Ok(token
+        .map(|t| match t {
+            Token::Text(text) | Token::Added { content: text } => text,
+        })
+        .filter(|text| !text.is_empty()))
+}
+
+impl TokenizerConfig {
+    // function to read the file
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read {}", path.display()))?;
+        serde_json::from_str(&text).with_context(|| format!("failed to parse {}", path.display()))
+    }
+
+    // function to warn about anything in the file that doesn't match the loaded model, or
+    // that the bot doesn't use
+    pub fn check(&self, model: &dyn llm::Model) {
+        if let Some(bos_token) = &self.bos_token {
+            let bos_id = model
+                .tokenizer()
+                .tokenize(bos_token, false)
+                .ok()
+                .and_then(|tokens| match tokens[..] {
+                    [(_, id)] => Some(id),
+                    _ => None,
+                });
+            if bos_id.is_none() || bos_id != model.bot_token_id() {
+                warn!(
+                    "The tokenizer config's bos_token `{bos_token}` isn't the model's own \
+                     beginning-of-text token, which prompts start with regardless"
+                );
+            }
+        }
+        if self.chat_template.is_some() {
+            info!("The tokenizer config's chat_template isn't used; set model.chat_format instead");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_tokens_written_either_way() {
+        let config: TokenizerConfig = serde_json::from_str(
+            r#"{
+                "bos_token": { "content": "<s>", "lstrip": false },
+                "eos_token": "<|im_end|>",
+                "add_bos_token": true
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(config.bos_token.as_deref(), Some("<s>"));
+        assert_eq!(config.eos_token.as_deref(), Some("<|im_end|>"));
+        assert!(config.chat_template.is_none());
+
+        let config: TokenizerConfig = serde_json::from_str(r#"{ "eos_token": null }"#).unwrap();
+        assert!(config.eos_token.is_none());
+    }
+}
@@ -17,7 +17,9 @@ use serenity::{
         user::User,
     },
 };
-use std::future::Future;
+use std::{collections::VecDeque, future::Future};
+
+use crate::notice;
 
 // The Function to get prompt and seed from the discord
 pub fn get_value<'a>(
@@ -46,6 +48,30 @@ pub fn value_to_integer(v: &CommandDataOptionValue) -> Option<i64> {
     }
 }
 
+// Function to find the text of the option being typed in an autocomplete interaction,
+// which may be in a subcommand
+pub fn focused_value(options: &[CommandDataOption]) -> Option<String> {
+    options.iter().find_map(|o| {
+        if o.focused {
+            o.value.as_ref()?.as_str().map(str::to_string)
+        } else {
+            focused_value(&o.options)
+        }
+    })
+}
+
+// Function for converting the value of a command's own option to the text that fills in
+// its placeholder, whatever its type
+pub fn value_to_text(v: &CommandDataOptionValue) -> Option<String> {
+    match v {
+        CommandDataOptionValue::String(v) => Some(v.clone()),
+        CommandDataOptionValue::Integer(v) => Some(v.to_string()),
+        CommandDataOptionValue::Number(v) => Some(v.to_string()),
+        CommandDataOptionValue::Boolean(v) => Some(v.to_string()),
+        _ => None,
+    }
+}
+
 // This is a trait (interface) for Discord interactions with methods for handling the interations with discord
 #[async_trait] // This indicates that the trait has asynchronous methods
 pub trait DiscordInteraction: Send + Sync {
@@ -54,6 +80,10 @@ pub trait DiscordInteraction: Send + Sync {
     async fn get_interaction_message(&self, http: &Http) -> anyhow::Result<Message>;
     async fn edit(&self, http: &Http, message: &str) -> anyhow::Result<()>;
     async fn create_or_edit(&self, http: &Http, message: &str) -> anyhow::Result<()>;
+    async fn create_ephemeral(&self, http: &Http, message: &str) -> anyhow::Result<()>;
+    async fn create_ephemeral_followup(&self, http: &Http, message: &str) -> anyhow::Result<()>;
+    async fn delete(&self, http: &Http) -> anyhow::Result<()>;
+    async fn followup(&self, http: &Http, message: &str) -> anyhow::Result<Message>;
 
     fn channel_id(&self) -> ChannelId;
     fn guild_id(&self) -> Option<GuildId>;
@@ -104,6 +134,50 @@ macro_rules! implement_interaction {
                     },
                 )
             }
+            // Function to respond to the interaction with a message that only the user can
+            // see. This fails if the interaction has already been responded to
+            async fn create_ephemeral(&self, http: &Http, message: &str) -> anyhow::Result<()> {
+                Ok(self
+                    .create_interaction_response(http, |response| {
+                        response
+                            .kind(InteractionResponseType::ChannelMessageWithSource)
+                            .interaction_response_data(|m| m.content(message).ephemeral(true))
+                    })
+                    .await?)
+            }
+            // Function to send a new message that only the user can see, leaving any existing
+            // response (which might hold generated content) untouched.
+            // A followup can only be sent once the interaction has been responded to,
+            // so if it hasn't been yet, the ephemeral message becomes the response instead
+            async fn create_ephemeral_followup(
+                &self,
+                http: &Http,
+                message: &str,
+            ) -> anyhow::Result<()> {
+                let responded = self.create_ephemeral(http, message).await.is_ok();
+
+                if !responded {
+                    self.create_followup_message(http, |m| m.content(message).ephemeral(true))
+                        .await?;
+                }
+
+                Ok(())
+            }
+            // Function to send an additional message in response to the interaction, once it
+            // has been responded to. Mentions in it don't ping anyone, as it may hold
+            // generated content
+            async fn followup(&self, http: &Http, message: &str) -> anyhow::Result<Message> {
+                Ok(self
+                    .create_followup_message(http, |m| {
+                        m.content(message)
+                            .allowed_mentions(|m| m.empty_roles().empty_users().empty_parse())
+                    })
+                    .await?)
+            }
+            // Function to delete the interaction's response
+            async fn delete(&self, http: &Http) -> anyhow::Result<()> {
+                Ok(self.delete_original_interaction_response(http).await?)
+            }
 
             // Function to get the channel ID associated with the current interaction
             fn channel_id(&self) -> ChannelId {
@@ -152,16 +226,230 @@ implement_interaction!(ModalSubmitInteraction);
 // Modal Submit Interactions typically refer to interactions involving modals,
 // which are graphical user interfaces that overlay the Discord client
 
-// Runs the [body] and edits the interaction response if an error occurs.
+// Runs the [body] and reports the error to the user if one occurs.
 pub async fn run_and_report_error(
     interaction: &dyn DiscordInteraction,
     http: &Http,
     body: impl Future<Output = anyhow::Result<()>>,
 ) {
     if let Err(err) = body.await {
-        interaction
-            .create_or_edit(http, &format!("Error: {err}"))
-            .await
-            .unwrap();
+        // Commands refused before generating get a notice rather than an error
+        match err.downcast_ref::<notice::Rejection>() {
+            Some(rejection) => notice::send(interaction, http, rejection).await,
+            None => send_ephemeral_error(interaction, http, &err).await,
+        }
+    }
+}
+
+// Shows an error to the user who triggered the interaction, in a new message that only they
+// can see. This never edits the existing response, which might hold generated content.
+// Every user-facing error should go through here, so that they all look the same
+pub async fn send_ephemeral_error(
+    interaction: &dyn DiscordInteraction,
+    http: &Http,
+    err: &anyhow::Error,
+) {
+    if let Err(send_err) = interaction
+        .create_ephemeral_followup(http, &format!("Error: {err}"))
+        .await
+    {
+        // There's nowhere left to show the error, so log both of them instead
+        error!("Failed to report error `{err}` to the user: {send_err:?}");
+    }
+}
+
+// An error caused by how the user used a command, rather than by something going wrong.
+// These are shown to the user like any other error, but aren't reported to the operator
+#[derive(Debug, thiserror::Error)]
+#[error("{0}")]
+struct UserError(String);
+
+// Creates an error that is the user's mistake, rather than ours
+pub fn user_error(message: impl Into<String>) -> anyhow::Error {
+    UserError(message.into()).into()
+}
+
+// Whether or not the error is the user's mistake (see `user_error`), or the command was
+// refused (see `notice::Rejection`)
+pub fn is_user_error(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<UserError>().is_some() || err.downcast_ref::<notice::Rejection>().is_some()
+}
+
+// A map that remembers at most `CAPACITY` entries, forgetting the oldest first. It's for the
+// things kept about recent responses, which are only looked up for a while after they're made.
+// Inserting a key that's already there replaces its entry, which then counts as the newest
+pub struct BoundedMap<K, V, const CAPACITY: usize>(VecDeque<(K, V)>);
+
+impl<K, V, const CAPACITY: usize> Default for BoundedMap<K, V, CAPACITY> {
+    fn default() -> Self {
+        Self(VecDeque::new())
+    }
+}
+
+impl<K: PartialEq, V, const CAPACITY: usize> BoundedMap<K, V, CAPACITY> {
+    // function to add an entry, returning the oldest one if it had to be forgotten to make room
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        self.0.retain(|(k, _)| *k != key);
+        let forgotten = if self.0.len() >= CAPACITY {
+            self.0.pop_front()
+        } else {
+            None
+        };
+        self.0.push_back((key, value));
+        forgotten
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        self.0.iter_mut().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let index = self.0.iter().position(|(k, _)| k == key)?;
+        self.0.remove(index).map(|(_, v)| v)
+    }
+
+    // function to forget the entries that don't pass the test
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &V) -> bool) {
+        self.0.retain(|(k, v)| keep(k, v));
+    }
+}
+
+// A stand-in for a Discord interaction, for testing the code that responds to interactions
+// without a connection to Discord. Everything it's asked to send is recorded instead
+#[cfg(test)]
+pub mod test_interaction {
+    use std::sync::{Arc, Mutex};
+
+    use serenity::{
+        async_trait,
+        http::Http,
+        model::{
+            prelude::{ChannelId, GuildId, Message},
+            user::User,
+        },
+    };
+
+    use super::DiscordInteraction;
+
+    #[allow(dead_code)] // What the interaction is about is only read by the tests that need it
+    pub struct TestInteraction {
+        pub channel_id: ChannelId,
+        pub guild_id: Option<GuildId>,
+        pub user: User,
+        // The content of every message created or edited, in order
+        pub created_messages: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl TestInteraction {
+        // function to record a message that would have been sent
+        fn record(&self, message: &str) {
+            self.created_messages
+                .lock()
+                .unwrap()
+                .push(message.to_string());
+        }
+    }
+
+    #[async_trait]
+    impl DiscordInteraction for TestInteraction {
+        async fn create(&self, _http: &Http, message: &str) -> anyhow::Result<()> {
+            self.record(message);
+            Ok(())
+        }
+        // There are no real messages to hand back, so asking for one fails
+        async fn get_interaction_message(&self, _http: &Http) -> anyhow::Result<Message> {
+            anyhow::bail!("a test interaction has no messages")
+        }
+        async fn edit(&self, _http: &Http, message: &str) -> anyhow::Result<()> {
+            self.record(message);
+            Ok(())
+        }
+        async fn create_or_edit(&self, _http: &Http, message: &str) -> anyhow::Result<()> {
+            self.record(message);
+            Ok(())
+        }
+        async fn create_ephemeral(&self, _http: &Http, message: &str) -> anyhow::Result<()> {
+            self.record(message);
+            Ok(())
+        }
+        async fn create_ephemeral_followup(
+            &self,
+            _http: &Http,
+            message: &str,
+        ) -> anyhow::Result<()> {
+            self.record(message);
+            Ok(())
+        }
+        async fn delete(&self, _http: &Http) -> anyhow::Result<()> {
+            Ok(())
+        }
+        async fn followup(&self, _http: &Http, message: &str) -> anyhow::Result<Message> {
+            self.record(message);
+            anyhow::bail!("a test interaction has no messages")
+        }
+
+        fn channel_id(&self) -> ChannelId {
+            self.channel_id
+        }
+        fn guild_id(&self) -> Option<GuildId> {
+            self.guild_id
+        }
+        fn message(&self) -> Option<&Message> {
+            None
+        }
+        fn user(&self) -> &User {
+            &self.user
+        }
+    }
+
+    #[tokio::test]
+    async fn errors_are_reported_to_the_user() {
+        let interaction = TestInteraction {
+            channel_id: ChannelId(1),
+            guild_id: None,
+            user: User::default(),
+            created_messages: Default::default(),
+        };
+        let http = Http::new("");
+
+        super::run_and_report_error(&interaction, &http, async {
+            Err(super::user_error("That didn't work."))
+        })
+        .await;
+        super::run_and_report_error(&interaction, &http, async { Ok(()) }).await;
+
+        assert_eq!(
+            *interaction.created_messages.lock().unwrap(),
+            vec!["Error: That didn't work.".to_string()]
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BoundedMap;
+
+    #[test]
+    fn a_bounded_map_forgets_its_oldest_entries() {
+        let mut map = BoundedMap::<u64, &str, 2>::default();
+        assert_eq!(map.insert(1, "a"), None);
+        assert_eq!(map.insert(2, "b"), None);
+        assert_eq!(map.insert(3, "c"), Some((1, "a")));
+        assert_eq!(map.get(&1), None);
+        assert_eq!(map.get(&2), Some(&"b"));
+
+        // Replacing an entry makes it the newest, so the other one goes first
+        assert_eq!(map.insert(2, "B"), None);
+        assert_eq!(map.insert(4, "d"), Some((3, "c")));
+        assert_eq!(map.get(&2), Some(&"B"));
+
+        assert_eq!(map.remove(&2), Some("B"));
+        assert_eq!(map.remove(&2), None);
+        map.retain(|k, _| *k != 4);
+        assert_eq!(map.get(&4), None);
     }
 }